@@ -44,8 +44,10 @@ impl Platform for PlatformImpl {
         kernel_stack: &kernel::vmm::Stack,
         user_stack: &kernel::vmm::Stack,
         task_entry_point: VAddr,
+        argument: usize,
+        thread_pointer: VAddr,
     ) -> Self::TaskContext {
-        task::new_task_context(kernel_stack, user_stack, task_entry_point)
+        task::new_task_context(kernel_stack, user_stack, task_entry_point, argument, thread_pointer)
     }
 
     fn new_task_page_tables() -> Self::PageTable {
@@ -60,7 +62,25 @@ impl Platform for PlatformImpl {
         task::drop_into_userspace(context)
     }
 
+    unsafe fn set_thread_pointer(thread_pointer: VAddr) {
+        task::set_thread_pointer(thread_pointer)
+    }
+
     fn rearm_interrupt(_interrupt: usize) {}
+
+    fn idle() {
+        /*
+         * We're called from inside the kernel, where interrupts are disabled. `wfi` still wakes up when an
+         * interrupt becomes pending, so wait for one, and then enable interrupts for long enough to take it
+         * (a pending interrupt is taken as soon as it's enabled). Doing it in this order means we can't take the
+         * interrupt before waiting, and then sleep until the next one.
+         */
+        unsafe { core::arch::asm!("wfi", "csrsi sstatus, 2", "csrci sstatus, 2") };
+    }
+
+    fn scheduler() -> &'static Scheduler<Self> {
+        SCHEDULER.get()
+    }
 }
 
 pub static VMM: InitGuard<Vmm<PlatformImpl>> = InitGuard::uninit();
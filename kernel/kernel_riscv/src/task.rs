@@ -1,6 +1,8 @@
+use crate::trap::TrapFrame;
 use core::{
     arch::{asm, global_asm},
     cell::{Cell, SyncUnsafeCell},
+    mem,
     ptr,
 };
 use hal::memory::VAddr;
@@ -85,7 +87,13 @@ pub struct TaskContext {
     kernel_stack_pointer: VAddr,
 }
 
-pub fn new_task_context(kernel_stack: &Stack, user_stack: &Stack, task_entry_point: VAddr) -> TaskContext {
+pub fn new_task_context(
+    kernel_stack: &Stack,
+    user_stack: &Stack,
+    task_entry_point: VAddr,
+    argument: usize,
+    thread_pointer: VAddr,
+) -> TaskContext {
     /*
      * Initialize the kernel stack. Firstly, we need to make sure the top of the stack is 16-byte
      * aligned, according to the Sys-V ABI.
@@ -105,8 +113,8 @@ pub fn new_task_context(kernel_stack: &Stack, user_stack: &Stack, task_entry_poi
         sp: usize::from(kernel_stack_pointer),
         s0: usize::from(task_entry_point),
         s1: usize::from(user_stack_pointer),
-        s2: 0,
-        s3: 0,
+        s2: argument,
+        s3: usize::from(thread_pointer),
         s4: 0,
         s5: 0,
         s6: 0,
@@ -142,3 +150,13 @@ pub unsafe fn drop_into_userspace(context: *const TaskContext) -> ! {
 
     unsafe { do_drop_to_userspace(&raw const (*context).context_switch_frame) }
 }
+
+/// Set the thread pointer of the running task. Its user `tp` is restored from the trap frame pushed when it made
+/// the current system call, which sits at the top of its kernel stack, so we change it there.
+pub unsafe fn set_thread_pointer(thread_pointer: VAddr) {
+    unsafe {
+        let kernel_stack_pointer = (*SCRATCH.get()).kernel_stack_pointer;
+        let trap_frame = (kernel_stack_pointer - mem::size_of::<TrapFrame>()).mut_ptr() as *mut TrapFrame;
+        (*trap_frame).tp = usize::from(thread_pointer);
+    }
+}
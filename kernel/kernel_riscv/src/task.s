@@ -1,3 +1,10 @@
+/*
+ * Used to enter a task for the first time. The initial context-switch frame holds:
+ *     - `s0`: the task's entry point
+ *     - `s1`: the task's user stack pointer
+ *     - `s2`: the argument to pass to the task, in `a0`
+ *     - `s3`: the task's thread pointer
+ */
 .global task_entry_trampoline
 task_entry_trampoline:
     // Clear SPP in `sstatus` - this makes `sret` return to U-mode
@@ -13,6 +20,10 @@ task_entry_trampoline:
     // Switch to the user's stack
     mv sp, s1
 
+    // Load the task's argument and thread pointer
+    mv a0, s2
+    mv tp, s3
+
     sret
 
 .global do_drop_to_userspace
@@ -48,6 +59,10 @@ do_drop_to_userspace:
     // Switch to the user's stack
     mv sp, s1
 
+    // Load the task's argument and thread pointer
+    mv a0, s2
+    mv tp, s3
+
     sret
 
 .global do_context_switch
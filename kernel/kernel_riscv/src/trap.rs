@@ -1,18 +1,24 @@
 use crate::interrupts;
 use core::arch::naked_asm;
 use hal::memory::VAddr;
-use hal_riscv::hw::csr::{Scause, Sepc, Stvec};
+use hal_riscv::hw::csr::{Scause, Sepc, Sstatus, Stvec};
+use kernel::object::address_space::FaultAccess;
+use poplar::syscall::{Fault, FaultKind};
 use tracing::info;
 
 /// Install the proper trap handler. This handler is able to take traps from both S-mode and
 /// U-mode, but requires the `sscratch` context to be correctly installed to facilitate switching
-/// to the kernel's stack correctly. It therefore cannot be used during early initialization.
+/// to the kernel's stack correctly. It therefore cannot be used during early initialization. Traps
+/// from S-mode are handled on the stack the kernel was already using.
 pub fn install_full_handler() {
     Stvec::set(VAddr::new(trap_handler_shim as extern "C" fn() -> ! as usize));
 }
 
 #[no_mangle]
 extern "C" fn trap_handler(trap_frame: &mut TrapFrame, scause: usize, stval: usize) {
+    // Whether the trap came from a user task. This must be read before we can be interrupted again.
+    let from_user = Sstatus::trap_from_user_mode();
+
     match Scause::try_from(scause) {
         Ok(Scause::UEnvironmentCall) => {
             // TODO: it'd be cool to be finer-grained with allowing user memory access? We have
@@ -28,6 +34,7 @@ extern "C" fn trap_handler(trap_frame: &mut TrapFrame, scause: usize, stval: usi
                 trap_frame.a3,
                 trap_frame.a4,
                 trap_frame.a5,
+                trap_frame.a6,
             );
             hal_riscv::hw::csr::Sstatus::disable_user_memory_access();
             trap_frame.sepc += 4;
@@ -35,17 +42,47 @@ extern "C" fn trap_handler(trap_frame: &mut TrapFrame, scause: usize, stval: usi
         Ok(Scause::SupervisorExternalInterrupt) => {
             interrupts::handle_external_interrupt();
         }
+        Ok(cause @ (Scause::InstructionPageFault | Scause::LoadPageFault | Scause::StorePageFault))
+            if handle_page_fault(cause, stval) =>
+        {
+            // The fault has been resolved, so we return and retry the faulting access
+        }
+        Ok(
+            cause @ (Scause::InstructionPageFault
+            | Scause::LoadPageFault
+            | Scause::StorePageFault
+            | Scause::InstructionAccessFault
+            | Scause::LoadAccessFault
+            | Scause::StoreAccessFault
+            | Scause::IllegalInstruction),
+        ) if from_user => {
+            // The fault was caused by a user task, so terminate it
+            let fault = match cause {
+                Scause::IllegalInstruction => {
+                    Fault { kind: FaultKind::InvalidOpcode, address: 0, instruction_pointer: trap_frame.sepc }
+                }
+                _ => Fault { kind: FaultKind::PageFault, address: stval, instruction_pointer: trap_frame.sepc },
+            };
+            kernel::handle_user_fault(crate::SCHEDULER.get(), fault);
+        }
         Ok(Scause::SupervisorTimerInterrupt) => {
             // Schedule the next tick in 20ms time (TODO: I have no idea what a sensible interval
             // should be). `Timer::advance` returns a `Turn` struct that tells us when the next
             // deadline is - the most efficient thing if this is all we need the timer interrupt
             // for would be to wait til then?
-            // TODO: we don't actually use this for anything anymore. Maybe get rid of it.
             sbi::timer::set_timer(hal_riscv::hw::csr::Time::read() as u64 + 0x989680 / 50).unwrap();
+
+            /*
+             * The tick drives the scheduler's timeslices. Only pre-empt the running task if we interrupted it in
+             * userspace, as the kernel could be holding locks that the next task would need.
+             */
+            if from_user {
+                crate::SCHEDULER.get().timer_tick();
+            }
         }
         Ok(other) => {
             info!("Trap! Cause = {:?}. Stval = {:#x?}", other, stval);
-            if trap_frame.sepc < usize::from(crate::VMM.get().higher_half_start) {
+            if from_user {
                 let cpu_scheduler = crate::SCHEDULER.get().for_this_cpu();
                 info!("Trap occurred in user task: {}", cpu_scheduler.running_task.as_ref().unwrap().name);
             }
@@ -56,6 +93,17 @@ extern "C" fn trap_handler(trap_frame: &mut TrapFrame, scause: usize, stval: usi
     }
 }
 
+/// Try to resolve a page fault, which may have been caused by a task touching memory that is committed on demand.
+fn handle_page_fault(cause: Scause, address: usize) -> bool {
+    let access = match cause {
+        Scause::InstructionPageFault => FaultAccess::Execute,
+        Scause::StorePageFault => FaultAccess::Write,
+        _ => FaultAccess::Read,
+    };
+
+    kernel::handle_page_fault(crate::SCHEDULER.get(), crate::VMM.get(), VAddr::new(address), access)
+}
+
 #[derive(Clone, Debug)]
 #[repr(C)]
 pub struct TrapFrame {
@@ -63,7 +111,7 @@ pub struct TrapFrame {
     ra: usize,
     sp: usize,
     gp: usize,
-    tp: usize,
+    pub tp: usize,
     t0: usize,
     t1: usize,
     t2: usize,
@@ -104,9 +152,18 @@ extern "C" fn trap_handler_shim() -> ! {
             // Swap `sscratch` and `t6` to provide an initial working register
             csrrw t6, sscratch, t6
 
-            // Save the current stack pointer and move to the kernel stack
+            // Save the current stack pointer and move to the kernel stack. If the trap came from S-mode (e.g.
+            // an interrupt taken while the kernel is idle), we're already on the kernel stack, so stay on it.
+            // `sp` is used as a working register to check `sstatus.SPP`, as it's been saved.
             sd sp, 24(t6)
+            csrr sp, sstatus
+            andi sp, sp, 0x100
+            bnez sp, 1f
             ld sp, 0(t6)
+            j 2f
+        1:
+            ld sp, 24(t6)
+        2:
 
             // Push a trap frame
             addi sp, sp, -256
@@ -6,7 +6,7 @@ use acpi::{
 use alloc::vec;
 use bit_field::BitField;
 use core::{str::FromStr, time::Duration};
-use hal::memory::{Flags, FrameSize, PAddr, Size4KiB};
+use hal::memory::{Flags, FrameSize, PAddr, Size4KiB, VAddr};
 use hal_x86_64::hw::{
     cpu::CpuInfo,
     gdt::PrivilegeLevel,
@@ -16,7 +16,9 @@ use hal_x86_64::hw::{
     lapic::LocalApic,
     registers::read_control_reg,
 };
+use kernel::object::address_space::FaultAccess;
 use mulch::{BinaryPrettyPrint, InitGuard};
+use poplar::syscall::{Fault, FaultKind};
 use spinning_top::Spinlock;
 use tracing::{error, info, warn};
 
@@ -312,10 +314,18 @@ impl InterruptController {
     }
 }
 
-extern "C" fn local_apic_timer_handler(_: &InterruptStackFrame) {
+extern "C" fn local_apic_timer_handler(stack_frame: &InterruptStackFrame) {
     unsafe {
         LOCAL_APIC.get().send_eoi();
     }
+
+    /*
+     * Only pre-empt the running task if we interrupted it in userspace. If we interrupted the kernel, it could be
+     * holding locks that the next task would need.
+     */
+    if is_from_usermode(stack_frame.code_segment) {
+        crate::SCHEDULER.get().timer_tick();
+    }
 }
 
 extern "C" fn spurious_handler(_: &InterruptStackFrame) {}
@@ -323,6 +333,12 @@ extern "C" fn spurious_handler(_: &InterruptStackFrame) {}
 /*
  * Exception handlers
  */
+
+/// Check if an exception was raised while running in usermode, from the code segment saved when it was raised.
+fn is_from_usermode(code_segment: u64) -> bool {
+    code_segment.get_bits(0..2) == PrivilegeLevel::Ring3 as u64
+}
+
 pub extern "C" fn nmi_handler(_: &InterruptStackFrame) {
     info!("NMI occured!");
 }
@@ -352,6 +368,17 @@ pub extern "C" fn breakpoint_handler(stack_frame: &InterruptStackFrame) {
 }
 
 pub extern "C" fn invalid_opcode_handler(stack_frame: &InterruptStackFrame) {
+    if is_from_usermode(stack_frame.code_segment) {
+        kernel::handle_user_fault(
+            crate::SCHEDULER.get(),
+            Fault {
+                kind: FaultKind::InvalidOpcode,
+                address: 0,
+                instruction_pointer: usize::from(stack_frame.instruction_pointer),
+            },
+        );
+    }
+
     error!("INVALID OPCODE AT: {:#x}", stack_frame.instruction_pointer);
     error!("Stack frame: {:x?}", stack_frame);
 
@@ -379,12 +406,40 @@ pub extern "C" fn invalid_opcode_handler(stack_frame: &InterruptStackFrame) {
 }
 
 pub extern "C" fn general_protection_fault_handler(stack_frame: &ExceptionWithErrorStackFrame) {
+    if is_from_usermode(stack_frame.code_segment) {
+        kernel::handle_user_fault(
+            crate::SCHEDULER.get(),
+            Fault {
+                kind: FaultKind::GeneralProtectionFault,
+                address: 0,
+                instruction_pointer: usize::from(stack_frame.instruction_pointer),
+            },
+        );
+    }
+
     error!("General protection fault (error code = {:#x}). Interrupt stack frame: ", stack_frame.error_code);
     error!("{:#x?}", stack_frame);
     panic!("Unrecoverable fault");
 }
 
 pub extern "C" fn page_fault_handler(stack_frame: &ExceptionWithErrorStackFrame) {
+    /*
+     * Try to resolve the fault first, as it may be a task touching memory that is committed on demand.
+     */
+    if let (Some(scheduler), Some(vmm)) = (crate::SCHEDULER.try_get(), crate::VMM.try_get()) {
+        let access = if stack_frame.error_code.get_bit(4) {
+            FaultAccess::Execute
+        } else if stack_frame.error_code.get_bit(1) {
+            FaultAccess::Write
+        } else {
+            FaultAccess::Read
+        };
+        let address = VAddr::new(read_control_reg!(cr2) as usize);
+        if kernel::handle_page_fault(scheduler, vmm, address, access) {
+            return;
+        }
+    }
+
     error!(
         "PAGE_FAULT: {} ({:#x})",
         match (
@@ -414,16 +469,20 @@ pub extern "C" fn page_fault_handler(stack_frame: &ExceptionWithErrorStackFrame)
         read_control_reg!(cr2) // CR2 holds the address of the page that caused the #PF
     );
 
+    if stack_frame.error_code.get_bit(2) {
+        kernel::handle_user_fault(
+            crate::SCHEDULER.get(),
+            Fault {
+                kind: FaultKind::PageFault,
+                address: read_control_reg!(cr2) as usize,
+                instruction_pointer: usize::from(stack_frame.instruction_pointer),
+            },
+        );
+    }
+
     error!("Error code: {}", BinaryPrettyPrint(stack_frame.error_code));
     error!("{:#x?}", stack_frame);
 
-    /*
-     * Page-faults can be recovered from and so are faults, but we never will so just give up.
-     */
-    /*
-     * In the future, page faults can be used for demand paging and so are recoverable. At the moment, they're
-     * always bad, so we panic here.
-     */
     panic!("Unrecoverable fault");
 }
 
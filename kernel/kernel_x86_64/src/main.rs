@@ -54,8 +54,14 @@ impl Platform for PlatformImpl {
     type Clocksource = TscClocksource;
     type TaskContext = task::TaskContext;
 
-    fn new_task_context(kernel_stack: &Stack, user_stack: &Stack, task_entry_point: VAddr) -> Self::TaskContext {
-        task::new_task_context(kernel_stack, user_stack, task_entry_point)
+    fn new_task_context(
+        kernel_stack: &Stack,
+        user_stack: &Stack,
+        task_entry_point: VAddr,
+        argument: usize,
+        thread_pointer: VAddr,
+    ) -> Self::TaskContext {
+        task::new_task_context(kernel_stack, user_stack, task_entry_point, argument, thread_pointer)
     }
 
     fn new_task_page_tables() -> Self::PageTable {
@@ -83,12 +89,28 @@ impl Platform for PlatformImpl {
         task::drop_into_userspace(context)
     }
 
+    unsafe fn set_thread_pointer(thread_pointer: VAddr) {
+        task::set_thread_pointer(thread_pointer)
+    }
+
     fn rearm_interrupt(interrupt: usize) {
         // TODO: this should be replaced by a spinlock that actually disables interrupts...
         unsafe { core::arch::asm!("cli") };
         interrupts::INTERRUPT_CONTROLLER.get().try_lock().unwrap().rearm_interrupt(interrupt as u32);
         unsafe { core::arch::asm!("sti") };
     }
+
+    fn idle() {
+        /*
+         * We're called from inside the kernel, where interrupts are disabled. Enable them for long enough to wait
+         * for the next one, and then disable them again before returning.
+         */
+        unsafe { core::arch::asm!("sti; hlt; cli") };
+    }
+
+    fn scheduler() -> &'static Scheduler<Self> {
+        SCHEDULER.get()
+    }
 }
 
 pub static VMM: InitGuard<Vmm<PlatformImpl>> = InitGuard::uninit();
@@ -8,7 +8,7 @@
  *    - does not save `rsp`. It is our responsibility to deal with the stack(s).
  *
  * Register summary:
- *    rax => system call result
+ *    rax - f, then system call result
  *    rbx - MUST BE PRESERVED
  *    rcx - users' rip
  *    rdx - b
@@ -25,8 +25,9 @@
  *    r15 - MUST BE PRESERVED
  *
  * This is only different from the Sys-V ABI in that `c` is in `r10` and not `rcx` (because `rcx` is being
- * used by syscall). To call into the Rust function (as long as it is using the C ABI), we only need to
- * move that one parameter.
+ * used by syscall), and that `f` is in `rax` (the Sys-V ABI passes it on the stack, but we don't want to touch
+ * the user stack). To call into the Rust function (as long as it is using the C ABI), we only need to move
+ * those two parameters.
  */
 .global syscall_handler
 syscall_handler:
@@ -61,8 +62,13 @@ syscall_handler:
     // stack.
     mov rcx, r10
 
+    // Pass `f` on the stack. We pad it to 16 bytes to keep the stack aligned for the call.
+    sub rsp, 8
+    push rax
+
     // Call the Rust handler. From this point, `rax` contains the return value, so musn't be trashed!
     call rust_syscall_entry
+    add rsp, 16
 
     // Restore registers
     pop r15
@@ -1,6 +1,6 @@
 use core::{arch::global_asm, mem, ptr};
 use hal::memory::VAddr;
-use hal_x86_64::hw::registers::{write_msr, CpuFlags};
+use hal_x86_64::hw::registers::{read_msr, write_msr, CpuFlags, IA32_FS_BASE};
 use kernel::vmm::Stack;
 
 global_asm!(include_str!("task.s"));
@@ -28,7 +28,15 @@ extern "C" {
 /// This function is called by `syscall_handler` to enter Rust. This is just required to call the correct `Platform`
 /// monomorphization of the common syscall handler.
 #[no_mangle]
-extern "C" fn rust_syscall_entry(number: usize, a: usize, b: usize, c: usize, d: usize, e: usize) -> usize {
+extern "C" fn rust_syscall_entry(
+    number: usize,
+    a: usize,
+    b: usize,
+    c: usize,
+    d: usize,
+    e: usize,
+    f: usize,
+) -> usize {
     kernel::syscall::handle_syscall::<crate::PlatformImpl>(
         crate::SCHEDULER.get(),
         crate::VMM.get(),
@@ -38,6 +46,7 @@ extern "C" fn rust_syscall_entry(number: usize, a: usize, b: usize, c: usize, d:
         c,
         d,
         e,
+        f,
     )
 }
 
@@ -65,12 +74,26 @@ pub struct ContextSwitchFrame {
 /// The context stored for each task. We track the user and kernel stack pointers, as we need to
 /// keep the per-CPU versions of each of them coordinated with the scheduled task. On x64, the
 /// context switch frame is stored on the kernel stack itself, so doesn't need to be stored here.
+///
+/// We also track the task's thread pointer, which is held in the FS base. The kernel doesn't use FS itself, so
+/// this only needs to be saved and restored when we switch tasks.
 pub struct TaskContext {
     kernel_stack_pointer: VAddr,
+    /// The kernel stack pointer the task enters the kernel with, when it has nothing on its kernel stack. This is
+    /// installed when we switch to the task, so that the stack doesn't creep downwards each time the task is
+    /// interrupted in userspace after having been switched away from deeper in the kernel.
+    kernel_stack_top: VAddr,
     user_stack_pointer: VAddr,
+    thread_pointer: VAddr,
 }
 
-pub fn new_task_context(kernel_stack: &Stack, user_stack: &Stack, task_entry_point: VAddr) -> TaskContext {
+pub fn new_task_context(
+    kernel_stack: &Stack,
+    user_stack: &Stack,
+    task_entry_point: VAddr,
+    argument: usize,
+    thread_pointer: VAddr,
+) -> TaskContext {
     /*
      * These are the set of flags we enter the task for the first time with. We allow, set the parity flag to
      * even, and leave everything else unset.
@@ -93,6 +116,7 @@ pub fn new_task_context(kernel_stack: &Stack, user_stack: &Stack, task_entry_poi
     unsafe {
         ptr::write(kernel_stack_pointer.mut_ptr() as *mut u64, 0x0);
     }
+    let kernel_stack_top = kernel_stack_pointer;
 
     /*
      * Next, we construct the context-switch frame that is used when a task is switched to for
@@ -108,7 +132,7 @@ pub fn new_task_context(kernel_stack: &Stack, user_stack: &Stack, task_entry_poi
                 r15: usize::from(task_entry_point) as u64,
                 // TODO: if we keep the new flags thing, revisit this
                 r14: INITIAL_RFLAGS.into(),
-                r13: 0x0,
+                r13: argument as u64,
                 r12: 0x0,
                 rbp: 0x0,
                 rbx: 0x0,
@@ -117,14 +141,18 @@ pub fn new_task_context(kernel_stack: &Stack, user_stack: &Stack, task_entry_poi
         );
     }
 
-    TaskContext { kernel_stack_pointer, user_stack_pointer }
+    TaskContext { kernel_stack_pointer, kernel_stack_top, user_stack_pointer, thread_pointer }
 }
 
 pub unsafe fn context_switch(from_context: *mut TaskContext, to_context: *const TaskContext) {
     let per_cpu = unsafe { crate::per_cpu::get_per_cpu_data() };
     (*from_context).user_stack_pointer = per_cpu.user_stack_pointer();
     per_cpu.set_user_stack_pointer((*to_context).user_stack_pointer);
-    per_cpu.set_kernel_stack_pointer((*to_context).kernel_stack_pointer);
+    per_cpu.set_kernel_stack_pointer((*to_context).kernel_stack_top);
+
+    (*from_context).thread_pointer = VAddr::new(read_msr(IA32_FS_BASE) as usize);
+    write_msr(IA32_FS_BASE, usize::from((*to_context).thread_pointer) as u64);
+
     do_context_switch(&raw mut (*from_context).kernel_stack_pointer, (*to_context).kernel_stack_pointer);
 }
 
@@ -132,9 +160,16 @@ pub unsafe fn drop_into_userspace(context: *const TaskContext) -> ! {
     let per_cpu = unsafe { crate::per_cpu::get_per_cpu_data() };
     per_cpu.set_kernel_stack_pointer((*context).kernel_stack_pointer);
     per_cpu.set_user_stack_pointer((*context).user_stack_pointer);
+    write_msr(IA32_FS_BASE, usize::from((*context).thread_pointer) as u64);
     do_drop_to_usermode();
 }
 
+/// Set the thread pointer of the running task. This takes effect straight away, as the FS base isn't touched
+/// again until we switch to another task.
+pub unsafe fn set_thread_pointer(thread_pointer: VAddr) {
+    write_msr(IA32_FS_BASE, usize::from(thread_pointer) as u64);
+}
+
 /// We use the `syscall` instruction to make system calls, as it's always present on supported systems. We need
 /// to set a few MSRs to configure how the `syscall` instruction works:
 ///     - `IA32_LSTAR` contains the address that `syscall` jumps to
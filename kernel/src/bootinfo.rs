@@ -91,6 +91,7 @@ impl BootInfo {
                 num_segments: raw.num_segments as usize,
                 segments: raw.segments,
                 entry_point: raw.entry_point,
+                capabilities: &raw.capabilities[0..(raw.capabilities_len as usize)],
             }
         })
     }
@@ -115,6 +116,8 @@ pub struct LoadedImage<'a> {
     pub num_segments: usize,
     pub segments: [LoadedSegment; seed_bootinfo::LOADED_IMAGE_MAX_SEGMENTS],
     pub entry_point: u64,
+    /// The image's capabilities, still in their encoded form.
+    pub capabilities: &'a [u8],
 }
 
 pub struct EarlyFrameAllocator<'a> {
@@ -0,0 +1,139 @@
+//! Futexes allow tasks to block until a value in their memory changes, and are what userspace builds its blocking
+//! synchronisation primitives on. The kernel doesn't keep any state for a futex apart from the tasks waiting on
+//! it - the value itself lives in userspace, and the kernel only reads it to check whether it's still worth
+//! waiting.
+//!
+//! Waiters are keyed by the physical address of the futex, so tasks that map the same memory at different
+//! addresses, or into different address spaces, can wait on and wake each other.
+
+use crate::scheduler::WaitQueue;
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicBool, Ordering};
+use hal::memory::PAddr;
+use spinning_top::Spinlock;
+
+pub static FUTEXES: Futexes = Futexes::new();
+
+/// A task waiting on a futex. It is woken when another task wakes the futex, or when its deadline passes.
+#[derive(Debug)]
+pub struct FutexWaiter {
+    woken: AtomicBool,
+    /// The time, in nanoseconds since boot, at which the waiter stops waiting. `None` if it waits until it is
+    /// woken.
+    deadline: Option<u64>,
+    /// Reads the current time, in nanoseconds since boot. This is the platform's `Clocksource`.
+    now: fn() -> u64,
+    /// Wakes the task blocked on this waiter when the futex is woken. Nothing wakes it when its deadline passes,
+    /// so the scheduler has to poll waiters that have one.
+    pub waiters: WaitQueue,
+}
+
+impl FutexWaiter {
+    pub fn is_woken(&self) -> bool {
+        self.woken.load(Ordering::SeqCst)
+    }
+
+    pub fn has_deadline(&self) -> bool {
+        self.deadline.is_some()
+    }
+
+    pub fn has_timed_out(&self) -> bool {
+        match self.deadline {
+            Some(deadline) => (self.now)() >= deadline,
+            None => false,
+        }
+    }
+
+    pub fn can_wake(&self) -> bool {
+        self.is_woken() || self.has_timed_out()
+    }
+}
+
+pub struct Futexes {
+    waiters: Spinlock<BTreeMap<PAddr, VecDeque<Arc<FutexWaiter>>>>,
+}
+
+impl Futexes {
+    pub const fn new() -> Futexes {
+        Futexes { waiters: Spinlock::new(BTreeMap::new()) }
+    }
+
+    /// Add a waiter to the futex at `address`, if `should_wait` returns `true`. `should_wait` is called with the
+    /// futexes locked, so the futex can't be woken between it checking the futex's value and the waiter being
+    /// added. Returns `None` if `should_wait` returned `false`.
+    pub fn wait<F>(
+        &self,
+        address: PAddr,
+        deadline: Option<u64>,
+        now: fn() -> u64,
+        should_wait: F,
+    ) -> Option<Arc<FutexWaiter>>
+    where
+        F: FnOnce() -> bool,
+    {
+        let mut waiters = self.waiters.lock();
+        if !should_wait() {
+            return None;
+        }
+
+        let waiter =
+            Arc::new(FutexWaiter { woken: AtomicBool::new(false), deadline, now, waiters: WaitQueue::new() });
+        waiters.entry(address).or_default().push_back(waiter.clone());
+        Some(waiter)
+    }
+
+    /// Stop waiting on the futex at `address`, because the waiter has timed out. Returns `false` if the waiter
+    /// was woken before it could be removed, in which case the wake should be honoured.
+    pub fn cancel(&self, address: PAddr, waiter: &Arc<FutexWaiter>) -> bool {
+        let mut waiters = self.waiters.lock();
+        if waiter.is_woken() {
+            return false;
+        }
+
+        if let Some(queue) = waiters.get_mut(&address) {
+            queue.retain(|other| !Arc::ptr_eq(other, waiter));
+            if queue.is_empty() {
+                waiters.remove(&address);
+            }
+        }
+        true
+    }
+
+    /// Wake up to `count` of the tasks waiting on the futex at `address`, in the order they started waiting.
+    /// Returns the number of tasks woken.
+    pub fn wake(&self, address: PAddr, count: usize) -> usize {
+        let woken = {
+            let mut waiters = self.waiters.lock();
+            let queue = match waiters.get_mut(&address) {
+                Some(queue) => queue,
+                None => return 0,
+            };
+
+            let mut woken = Vec::new();
+            while woken.len() < count {
+                match queue.pop_front() {
+                    Some(waiter) => {
+                        waiter.woken.store(true, Ordering::SeqCst);
+                        woken.push(waiter);
+                    }
+                    None => break,
+                }
+            }
+
+            if queue.is_empty() {
+                waiters.remove(&address);
+            }
+            woken
+        };
+
+        // Make the woken tasks ready once the futexes have been unlocked
+        for waiter in &woken {
+            waiter.waiters.wake_all();
+        }
+        woken.len()
+    }
+}
@@ -13,9 +13,11 @@ extern crate alloc;
 
 pub mod bootinfo;
 pub mod clocksource;
+pub mod futex;
 pub mod object;
 pub mod pci;
 pub mod pmm;
+pub mod quota;
 pub mod scheduler;
 pub mod syscall;
 pub mod tasklets;
@@ -27,10 +29,15 @@ use clocksource::Clocksource;
 use core::ptr;
 use hal::memory::{FrameSize, PAddr, PageTable, Size4KiB, VAddr};
 use mulch::InitGuard;
-use object::{address_space::AddressSpace, memory_object::MemoryObject, task::Task};
+use object::{
+    address_space::{AddressSpace, FaultAccess},
+    memory_object::MemoryObject,
+    task::Task,
+};
 use pci::{PciInfo, PciInterruptConfigurator, PciResolver};
 use pci_types::ConfigRegionAccess as PciConfigRegionAccess;
 use pmm::Pmm;
+use poplar::syscall::{ExitStatus, Fault};
 use scheduler::Scheduler;
 use spinning_top::{RwSpinlock, Spinlock};
 use vmm::{Stack, Vmm};
@@ -50,8 +57,16 @@ pub trait Platform: Sized + 'static {
     type Clocksource: Clocksource;
     type TaskContext;
 
-    /// Create a `TaskContext` for a new task with the supplied kernel and user stacks.
-    fn new_task_context(kernel_stack: &Stack, user_stack: &Stack, task_entry_point: VAddr) -> Self::TaskContext;
+    /// Create a `TaskContext` for a new task with the supplied kernel and user stacks. The task will start
+    /// executing at `task_entry_point`, with `argument` in its first argument register and its thread pointer set
+    /// to `thread_pointer`.
+    fn new_task_context(
+        kernel_stack: &Stack,
+        user_stack: &Stack,
+        task_entry_point: VAddr,
+        argument: usize,
+        thread_pointer: VAddr,
+    ) -> Self::TaskContext;
 
     /// Create a set of page tables for a new task, with the kernel mapped into the higher-half.
     fn new_task_page_tables() -> Self::PageTable;
@@ -63,7 +78,19 @@ pub trait Platform: Sized + 'static {
     /// Do the actual drop into usermode. This assumes that the task's page tables have already been installed.
     unsafe fn drop_into_userspace(context: *const Self::TaskContext) -> !;
 
+    /// Set the thread pointer of the task running on this CPU, which it will see when it next returns to
+    /// userspace. This must only be called while handling a system call made by that task.
+    unsafe fn set_thread_pointer(thread_pointer: VAddr);
+
     fn rearm_interrupt(interrupt: usize);
+
+    /// Idle the current processor until an interrupt arrives. This is used by the scheduler when there are no
+    /// tasks ready to run.
+    fn idle();
+
+    /// Get the global `Scheduler`. This is used to make a blocked task ready again when the kernel object it's
+    /// blocked on wakes it.
+    fn scheduler() -> &'static Scheduler<Self>;
 }
 
 pub fn load_userspace<P>(scheduler: &Scheduler<P>, boot_info: &BootInfo, vmm: &Vmm<P>)
@@ -72,7 +99,8 @@ where
 {
     use hal::memory::Flags;
     use object::{task::Handles, SENTINEL_KERNEL_ID};
-    use poplar::manifest::BootstrapManifest;
+    use poplar::{manifest::BootstrapManifest, syscall::Priority};
+    use quota::Quotas;
 
     if boot_info.num_loaded_images() == 0 {
         return;
@@ -83,12 +111,13 @@ where
     let pmm = PMM.get();
     let bootstrap_task = loaded_images.next().unwrap();
     let address_space = AddressSpace::new(SENTINEL_KERNEL_ID);
-    let handles = Handles::new();
+    let quotas = Quotas::new_root();
+    let handles = Handles::new(quotas.handles.clone());
 
     for segment in &bootstrap_task.segments {
         // TODO: this now uses the wrong task id...
         let memory_object = MemoryObject::from_boot_info(SENTINEL_KERNEL_ID, segment);
-        handles.add(memory_object.clone());
+        handles.add(memory_object.clone()).expect("Bootstrap task has too many handles");
         address_space.map_memory_object(memory_object, VAddr::new(segment.virt_addr as usize), pmm).unwrap();
     }
 
@@ -97,15 +126,24 @@ where
      */
     let mut manifest = BootstrapManifest { task_name: bootstrap_task.name.to_string(), boot_tasks: Vec::new() };
     for image in loaded_images {
+        let capabilities = match Task::<P>::decode_capabilities(image.capabilities) {
+            Ok(capabilities) => capabilities,
+            Err(err) => {
+                tracing::warn!("Not loading image '{}': {:?}", image.name, err);
+                continue;
+            }
+        };
+
         let mut service = poplar::manifest::BootTask {
             name: image.name.to_string(),
             entry_point: image.entry_point as usize,
             segments: Vec::new(),
+            capabilities: capabilities.bits(),
         };
         for segment in &image.segments {
             // TODO: this uses the wrong task ID...
             let memory_object = MemoryObject::from_boot_info(SENTINEL_KERNEL_ID, segment);
-            let handle = handles.add(memory_object);
+            let handle = handles.add(memory_object).expect("Bootstrap task has too many handles");
             service.segments.push((segment.virt_addr as usize, handle.0));
         }
         manifest.boot_tasks.push(service);
@@ -116,26 +154,32 @@ where
     const MANIFEST_ADDRESS: VAddr = VAddr::new(0x2000_0000);
     let mem_object_len = mulch::math::align_up(bytes_written, Size4KiB::SIZE);
     let manifest_object = {
-        let phys = pmm.alloc(mem_object_len / Size4KiB::SIZE);
+        let phys = pmm.alloc(mem_object_len / Size4KiB::SIZE).expect("Failed to allocate memory for manifest");
         unsafe {
             let virt = vmm.physical_to_virtual(phys);
             ptr::write(virt.mut_ptr(), bytes_written as u32);
             ptr::copy(buffer.as_ptr(), (virt + 4).mut_ptr() as *mut u8, bytes_written);
         }
-        MemoryObject::new(
+        MemoryObject::new_pmm_backed(
             SENTINEL_KERNEL_ID,
             phys,
             mem_object_len,
             Flags { user_accessible: true, ..Default::default() },
+            None,
         )
     };
     address_space.map_memory_object(manifest_object, MANIFEST_ADDRESS, pmm).unwrap();
 
+    let capabilities =
+        Task::<P>::decode_capabilities(bootstrap_task.capabilities).expect("Failed to load bootstrapping task");
     let task = Task::new(
         SENTINEL_KERNEL_ID,
         address_space.clone(),
         bootstrap_task.name.to_string(),
         VAddr::new(bootstrap_task.entry_point as usize),
+        capabilities,
+        quotas,
+        Priority::Realtime,
         handles,
         pmm,
         vmm,
@@ -144,6 +188,41 @@ where
     scheduler.add_task(task);
 }
 
+/// Try to resolve a page fault at `address`, caused either by the running task or by the kernel accessing the task's
+/// memory on its behalf. This commits memory that is allocated on demand. Returns `true` if the fault has been
+/// resolved and the faulting access can be retried.
+pub fn handle_page_fault<P>(scheduler: &Scheduler<P>, vmm: &Vmm<P>, address: VAddr, access: FaultAccess) -> bool
+where
+    P: Platform,
+{
+    if address >= vmm.higher_half_start {
+        return false;
+    }
+
+    // Clone what we need out of the task so we don't hold the scheduler lock while we handle the fault
+    let (address_space, frame_quota) = match scheduler.for_this_cpu().running_task.as_ref() {
+        Some(task) => (task.address_space(), task.quotas.frames.clone()),
+        None => return false,
+    };
+    address_space.handle_page_fault(address, access, &frame_quota, vmm, PMM.get())
+}
+
+/// Handle a fault caused by the running task while it was in usermode. The task is terminated with a record of the
+/// fault as its exit status, and another task is scheduled in its place. The caller must not hold any references to
+/// the faulting task.
+pub fn handle_user_fault<P>(scheduler: &Scheduler<P>, fault: Fault) -> !
+where
+    P: Platform,
+{
+    {
+        let cpu_scheduler = scheduler.for_this_cpu();
+        let task = cpu_scheduler.running_task.as_ref().unwrap();
+        tracing::warn!("Task '{}' caused a fault ({:x?}) and will be terminated", task.name, fault);
+    }
+
+    scheduler.exit_current(ExitStatus::Faulted(fault))
+}
+
 pub fn create_framebuffer(video_info: &seed_bootinfo::VideoModeInfo) {
     use hal::memory::{Flags, Size4KiB};
     use poplar::syscall::{FramebufferInfo, PixelFormat};
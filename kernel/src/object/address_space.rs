@@ -1,13 +1,37 @@
-use super::{alloc_kernel_object_id, memory_object::MemoryObject, KernelObject, KernelObjectId, KernelObjectType};
+use super::{
+    alloc_kernel_object_id,
+    memory_object::{Backing, Mapping, MemoryObject},
+    KernelObject,
+    KernelObjectId,
+    KernelObjectType,
+};
 use crate::{
     pmm::Pmm,
+    quota::{Charge, Quota},
     vmm::{Stack, Vmm},
     Platform,
 };
-use alloc::{collections::btree_map::BTreeMap, sync::Arc};
-use hal::memory::{mebibytes, Bytes, FrameAllocator, FrameSize, PageTable, Size4KiB, VAddr};
+use alloc::{
+    collections::btree_map::BTreeMap,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use bit_field::BitField;
+use core::ptr;
+use hal::memory::{
+    gibibytes,
+    mebibytes,
+    Bytes,
+    Flags,
+    FrameAllocator,
+    FrameSize,
+    PAddr,
+    PageTable,
+    Size4KiB,
+    VAddr,
+};
 use mulch::bitmap::Bitmap;
-use poplar::syscall::MapMemoryObjectError;
+use poplar::syscall::{MapMemoryObjectError, ProtectMappingError, UnmapMemoryObjectError};
 use spinning_top::Spinlock;
 
 // TODO: we need some way of getting this from the platform I guess?
@@ -15,6 +39,23 @@ use spinning_top::Spinlock;
 const USER_STACK_BASE: VAddr = VAddr::new(0x00000002_00000000);
 const USER_STACK_SLOT_SIZE: Bytes = mebibytes(1);
 
+/*
+ * When a task doesn't care where a `MemoryObject` is mapped, we pick an address for it from this region. It needs
+ * to fit within the lower half of the smallest address space we support (Sv39 on RISC-V). Each object is mapped at
+ * the start of its own aligned slot, which leaves space for most objects to grow in place if they're resized.
+ */
+const USER_ALLOC_BASE: VAddr = VAddr::new(0x00000010_00000000);
+const USER_ALLOC_END: VAddr = VAddr::new(0x00000040_00000000);
+const USER_ALLOC_ALIGN: Bytes = gibibytes(1);
+
+/// The kind of access that caused a page fault.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FaultAccess {
+    Read,
+    Write,
+    Execute,
+}
+
 #[derive(PartialEq, Eq, Debug)]
 pub enum State {
     NotActive,
@@ -38,6 +79,10 @@ where
     pub mappings: Spinlock<BTreeMap<VAddr, Arc<MemoryObject>>>,
     pub page_table: Spinlock<P::PageTable>,
     slot_bitmap: Spinlock<u64>,
+    /// Pages that user stacks have grown into on demand, below the part of each stack allocated with its slot.
+    /// Maps the address of each page to the frame backing it, and the charge for the frame against the quota of
+    /// the task whose stack grew.
+    grown_stack_pages: Spinlock<BTreeMap<VAddr, (PAddr, Charge)>>,
 }
 
 impl<P> AddressSpace<P>
@@ -52,41 +97,377 @@ where
             mappings: Spinlock::new(BTreeMap::new()),
             page_table: Spinlock::new(P::new_task_page_tables()),
             slot_bitmap: Spinlock::new(0),
+            grown_stack_pages: Spinlock::new(BTreeMap::new()),
         })
     }
 
     pub fn map_memory_object(
-        &self,
+        self: &Arc<Self>,
+        memory_object: Arc<MemoryObject>,
+        virtual_address: VAddr,
+        allocator: &Pmm,
+    ) -> Result<(), MapMemoryObjectError> {
+        let flags = memory_object.flags();
+        self.map_memory_object_with_flags(memory_object, virtual_address, flags, allocator)
+    }
+
+    /// Map a `MemoryObject` with a set of flags that may differ from the object's own. This is used to map
+    /// writable objects read-only, when they are mapped through a handle that cannot modify them.
+    pub fn map_memory_object_with_flags(
+        self: &Arc<Self>,
         memory_object: Arc<MemoryObject>,
         virtual_address: VAddr,
+        flags: Flags,
+        allocator: &Pmm,
+    ) -> Result<(), MapMemoryObjectError> {
+        /*
+         * Hold the mappings lock until the object has been inserted, so nothing else can be mapped over the
+         * region between checking it's free and mapping the object into it.
+         */
+        let mut mappings = self.mappings.lock();
+        if !Self::range_free_in(&mappings, virtual_address, memory_object.size()) {
+            return Err(MapMemoryObjectError::RegionAlreadyMapped);
+        }
+
+        self.map_backing(&memory_object, virtual_address, flags, allocator)?;
+        self.record_mapping(&memory_object, virtual_address, flags);
+        mappings.insert(virtual_address, memory_object);
+        Ok(())
+    }
+
+    /// Map a `MemoryObject` at a free address chosen by the kernel, returning the address it was mapped at.
+    pub fn map_memory_object_anywhere(
+        self: &Arc<Self>,
+        memory_object: Arc<MemoryObject>,
+        flags: Flags,
+        allocator: &Pmm,
+    ) -> Result<VAddr, MapMemoryObjectError> {
+        /*
+         * We hold the mappings lock until the object has been inserted, so nothing else can be mapped into the
+         * region we've picked in the meantime.
+         */
+        let mut mappings = self.mappings.lock();
+        let size = memory_object.inner.lock().size;
+
+        /*
+         * Find the first free slot that is large enough to hold the object, walking the existing mappings in the
+         * region in address order.
+         */
+        let mut candidate = USER_ALLOC_BASE;
+        for (&address, mapped) in mappings.range(USER_ALLOC_BASE..USER_ALLOC_END) {
+            if candidate + size <= address {
+                break;
+            }
+            let mapped_end = address + mapped.inner.lock().size;
+            if mapped_end > candidate {
+                candidate = mapped_end.align_up(USER_ALLOC_ALIGN);
+            }
+        }
+        if candidate + size > USER_ALLOC_END {
+            return Err(MapMemoryObjectError::AddressSpaceFull);
+        }
+
+        self.map_backing(&memory_object, candidate, flags, allocator)?;
+        self.record_mapping(&memory_object, candidate, flags);
+        mappings.insert(candidate, memory_object);
+        Ok(candidate)
+    }
+
+    /// Returns `true` if nothing is mapped into the `size` bytes starting at `start`.
+    pub fn is_range_free(&self, start: VAddr, size: usize) -> bool {
+        Self::range_free_in(&self.mappings.lock(), start, size)
+    }
+
+    /// Returns `true` if nothing in `mappings`, or any task's user stack, is in the `size` bytes starting at
+    /// `start`. This takes the mappings so it can be used while their lock is held.
+    fn range_free_in(mappings: &BTreeMap<VAddr, Arc<MemoryObject>>, start: VAddr, size: usize) -> bool {
+        let end = start + size;
+
+        let stack_end = USER_STACK_BASE + USER_STACK_SLOT_SIZE * u64::BITS as usize;
+        if start < stack_end && end > USER_STACK_BASE {
+            return false;
+        }
+
+        /*
+         * Mappings never overlap each other, so we only need to check the last mapping that starts before the
+         * end of the range.
+         */
+        match mappings.range(..end).next_back() {
+            Some((&address, memory_object)) => address + memory_object.size() <= start,
+            None => true,
+        }
+    }
+
+    fn record_mapping(self: &Arc<Self>, memory_object: &MemoryObject, address: VAddr, flags: Flags) {
+        memory_object.add_mapping(Mapping {
+            address_space: Arc::downgrade(self) as Weak<dyn KernelObject>,
+            address_space_id: self.id,
+            address,
+            flags,
+            protections: Vec::new(),
+        });
+    }
+
+    fn map_backing(
+        &self,
+        memory_object: &MemoryObject,
+        virtual_address: VAddr,
+        flags: Flags,
         allocator: &Pmm,
     ) -> Result<(), MapMemoryObjectError> {
         use hal::memory::PagingError;
 
-        {
-            let mut current_virtual = virtual_address;
-            let inner = memory_object.inner.lock();
-            for (backing, size) in &inner.backing {
-                self.page_table
-                    .lock()
-                    .map_area(current_virtual, *backing, *size, inner.flags, allocator)
-                    .map_err(|err| match err {
-                        // XXX: these are explicity enumerated to avoid a bug if variants are added to `PagingError`.
-                        PagingError::AlreadyMapped => MapMemoryObjectError::RegionAlreadyMapped,
-                    })?;
-                current_virtual += *size;
+        let map_err = |err| match err {
+            // XXX: these are explicity enumerated to avoid a bug if variants are added to `PagingError`.
+            PagingError::AlreadyMapped => MapMemoryObjectError::RegionAlreadyMapped,
+            PagingError::OutOfMemory => MapMemoryObjectError::OutOfMemory,
+        };
+
+        let inner = memory_object.inner.lock();
+        let mut page_table = self.page_table.lock();
+        let result = match &inner.backing {
+            Backing::Eager(ranges) => {
+                let mut current_virtual = virtual_address;
+                ranges.iter().try_for_each(|(backing, size)| {
+                    page_table.map_area(current_virtual, *backing, *size, flags, allocator)?;
+                    current_virtual += *size;
+                    Ok(())
+                })
+            }
+            Backing::Lazy(pages) => {
+                /*
+                 * Only the pages that have already been committed (through another mapping of the object) are
+                 * mapped now. The rest are mapped as they're faulted in. Pages that are shared with a clone of
+                 * the object are mapped read-only, so they're copied when they're written to.
+                 */
+                pages.iter().try_for_each(|(offset, frame)| {
+                    let flags = Flags { writable: flags.writable && Arc::strong_count(frame) == 1, ..flags };
+                    page_table.map_area(virtual_address + *offset, frame.address, Size4KiB::SIZE, flags, allocator)
+                })
+            }
+        };
+
+        /*
+         * If mapping fails partway through (e.g. because we run out of memory for page tables), unmap whatever was
+         * mapped, as the mapping won't be recorded and so would never be unmapped. The caller has checked that
+         * nothing else is mapped in the object's region, so we can unmap all of it.
+         */
+        if let Err(err) = result {
+            page_table.unmap_area(virtual_address, inner.size);
+            return Err(map_err(err));
+        }
+
+        Ok(())
+    }
+
+    /// Unmap the `MemoryObject` mapped at `virtual_address`, returning it. The object's memory is not freed
+    /// until the last reference to the object is dropped.
+    pub fn unmap_memory_object(
+        &self,
+        virtual_address: VAddr,
+    ) -> Result<Arc<MemoryObject>, UnmapMemoryObjectError> {
+        let memory_object =
+            self.mappings.lock().remove(&virtual_address).ok_or(UnmapMemoryObjectError::NotMapped)?;
+        memory_object.remove_mapping(self.id, virtual_address);
+
+        self.page_table.lock().unmap_area(virtual_address, memory_object.size());
+        Ok(memory_object)
+    }
+
+    /// Change the permissions of the `size` bytes starting at `address`, which must lie within a single mapped
+    /// `MemoryObject`. The region can't be given permissions that the object was not mapped with.
+    pub fn protect_mapping(
+        &self,
+        address: VAddr,
+        size: usize,
+        writable: bool,
+        executable: bool,
+        allocator: &Pmm,
+    ) -> Result<(), ProtectMappingError> {
+        if !address.is_aligned(Size4KiB::SIZE) || size % Size4KiB::SIZE != 0 {
+            return Err(ProtectMappingError::NotAligned);
+        }
+
+        let mappings = self.mappings.lock();
+        let (start, memory_object) = match mappings.range(..=address).next_back() {
+            Some((&start, memory_object)) if address + size <= start + memory_object.size() => {
+                (start, memory_object)
             }
+            _ => return Err(ProtectMappingError::NotMapped),
+        };
+
+        let offset = usize::from(address) - usize::from(start);
+        let flags =
+            memory_object.protect_mapping(self.id, start, offset..(offset + size), writable, executable)?;
+
+        /*
+         * Pages of lazily-committed objects may be shared copy-on-write, and must stay read-only until they're
+         * copied even if the region is made writable. Instead of rewriting their entries, we unmap them, and
+         * they're faulted back in with the correct flags.
+         */
+        let mut page_table = self.page_table.lock();
+        if memory_object.is_lazy() {
+            page_table.unmap_area(address, size);
+        } else {
+            page_table
+                .protect_area(address, size, flags, allocator)
+                .map_err(|_| ProtectMappingError::OutOfMemory)?;
         }
 
-        self.mappings.lock().insert(virtual_address, memory_object);
         Ok(())
     }
 
+    /// Try to resolve a page fault at `address` by mapping memory that is committed on demand - either a page of
+    /// a lazily-committed `MemoryObject`, or a page that a user stack has grown into - or by copying a page that
+    /// is shared copy-on-write. Returns `false` if the fault can't be resolved like this, in which case it's a
+    /// real fault. Pages that a stack grows into are charged to `stack_quota`.
+    pub fn handle_page_fault(
+        &self,
+        address: VAddr,
+        access: FaultAccess,
+        stack_quota: &Arc<Quota>,
+        vmm: &Vmm<P>,
+        allocator: &Pmm,
+    ) -> bool {
+        let page = address.align_down(Size4KiB::SIZE);
+        let already_mapped = self.page_table.lock().translate(page).is_some();
+
+        let stack_end = USER_STACK_BASE + USER_STACK_SLOT_SIZE * u64::BITS as usize;
+        if address >= USER_STACK_BASE && address < stack_end {
+            return !already_mapped && self.grow_stack(page, access, stack_quota, vmm, allocator);
+        }
+
+        let mappings = self.mappings.lock();
+        let (start, memory_object) = match mappings.range(..=address).next_back() {
+            Some((&start, memory_object)) if address < start + memory_object.size() => (start, memory_object),
+            _ => return false,
+        };
+        let offset = usize::from(page) - usize::from(start);
+        let flags = match memory_object.mapping_flags(self.id, start, offset) {
+            Some(flags) => flags,
+            None => return false,
+        };
+        let permitted = match access {
+            FaultAccess::Read => true,
+            FaultAccess::Write => flags.writable,
+            FaultAccess::Execute => flags.executable,
+        };
+        /*
+         * If the page is already mapped, the only fault we can resolve is a write to a page that is mapped
+         * read-only because it's shared copy-on-write. Anything else is an access violation.
+         */
+        if !permitted || (already_mapped && access != FaultAccess::Write) {
+            return false;
+        }
+
+        let committed = match memory_object.commit_page(offset, access == FaultAccess::Write, |copy_from| {
+            Self::alloc_frame(vmm, allocator, copy_from)
+        }) {
+            Some(committed) => committed,
+            None => return false,
+        };
+
+        {
+            let flags = Flags { writable: flags.writable && committed.exclusive, ..flags };
+            let mut page_table = self.page_table.lock();
+            if already_mapped {
+                page_table.unmap_area(page, Size4KiB::SIZE);
+            }
+            if page_table.map_area(page, committed.frame, Size4KiB::SIZE, flags, allocator).is_err() {
+                return false;
+            }
+        }
+
+        /*
+         * If the page was copied, other mappings of the object still map the old frame. Unmap the page from them,
+         * so they fault the new one in when they next touch it.
+         */
+        if committed.copied {
+            for mapping in memory_object.mappings() {
+                if mapping.address_space_id == self.id && mapping.address == start {
+                    continue;
+                }
+                if let Some(address_space) = mapping
+                    .address_space
+                    .upgrade()
+                    .and_then(|object| object.downcast_arc::<AddressSpace<P>>().ok())
+                {
+                    address_space.page_table.lock().unmap_area(mapping.address + offset, Size4KiB::SIZE);
+                }
+            }
+        }
+
+        true
+    }
+
+    fn grow_stack(
+        &self,
+        page: VAddr,
+        access: FaultAccess,
+        quota: &Arc<Quota>,
+        vmm: &Vmm<P>,
+        allocator: &Pmm,
+    ) -> bool {
+        let index = (usize::from(page) - usize::from(USER_STACK_BASE)) / USER_STACK_SLOT_SIZE;
+        let slot_bottom = USER_STACK_BASE + USER_STACK_SLOT_SIZE * index;
+
+        /*
+         * The bottom page of each slot is never mapped, and acts as a guard page. This means a stack that overflows
+         * its slot faults, instead of growing into the stack below it.
+         */
+        if access == FaultAccess::Execute || page == slot_bottom || !self.slot_bitmap.lock().get_bit(index) {
+            return false;
+        }
+
+        let charge = match quota.charge(1) {
+            Ok(charge) => charge,
+            Err(_) => return false,
+        };
+        let frame = match Self::alloc_frame(vmm, allocator, None) {
+            Some(frame) => frame,
+            None => return false,
+        };
+        if self
+            .page_table
+            .lock()
+            .map_area(
+                page,
+                frame,
+                Size4KiB::SIZE,
+                Flags { writable: true, user_accessible: true, ..Default::default() },
+                allocator,
+            )
+            .is_err()
+        {
+            allocator.free(frame, 1);
+            return false;
+        }
+        self.grown_stack_pages.lock().insert(page, (frame, charge));
+        true
+    }
+
+    /// Allocate a frame, and fill it with a copy of `copy_from` if supplied, or zero it if not. Returns `None` if
+    /// the frame can't be allocated.
+    fn alloc_frame(vmm: &Vmm<P>, allocator: &Pmm, copy_from: Option<PAddr>) -> Option<PAddr> {
+        let frame = allocator.alloc(1)?;
+        let frame_ptr = vmm.physical_to_virtual(frame).mut_ptr::<u8>();
+        unsafe {
+            match copy_from {
+                Some(source) => ptr::copy_nonoverlapping(
+                    vmm.physical_to_virtual(source).ptr::<u8>(),
+                    frame_ptr,
+                    Size4KiB::SIZE,
+                ),
+                None => ptr::write_bytes(frame_ptr, 0, Size4KiB::SIZE),
+            }
+        }
+        Some(frame)
+    }
+
     /// Try to allocate a slot for a Task. Creates a user stack with `initial_stack_size` bytes initially
-    /// allocated. Returs `None` if no more tasks can be created in this Address Space.
+    /// allocated. Returs `None` if no more tasks can be created in this Address Space, or if the stack can't be
+    /// allocated.
     pub fn alloc_task_slot(&self, initial_stack_size: usize, allocator: &Pmm) -> Option<TaskSlot> {
-        use hal::memory::Flags;
-
         let index = self.slot_bitmap.lock().alloc(1)?;
 
         let user_stack = {
@@ -94,8 +475,15 @@ where
             let top = slot_bottom + USER_STACK_SLOT_SIZE - 1;
             let stack_bottom = (top + 1) - initial_stack_size;
 
-            let physical_start = allocator.alloc(initial_stack_size / Size4KiB::SIZE);
-            self.page_table
+            let physical_start = match allocator.alloc(initial_stack_size / Size4KiB::SIZE) {
+                Some(physical_start) => physical_start,
+                None => {
+                    self.slot_bitmap.lock().free(index, 1);
+                    return None;
+                }
+            };
+            if self
+                .page_table
                 .lock()
                 .map_area(
                     stack_bottom,
@@ -104,7 +492,14 @@ where
                     Flags { writable: true, user_accessible: true, ..Default::default() },
                     allocator,
                 )
-                .unwrap();
+                .is_err()
+            {
+                // Any part of the stack that was mapped before we ran out of memory must be unmapped again
+                self.page_table.lock().unmap_area(stack_bottom, initial_stack_size);
+                allocator.free(physical_start, initial_stack_size / Size4KiB::SIZE);
+                self.slot_bitmap.lock().free(index, 1);
+                return None;
+            }
 
             Stack { top, slot_bottom, stack_bottom, physical_start }
         };
@@ -112,6 +507,31 @@ where
         Some(TaskSlot { index, user_stack })
     }
 
+    /// Free a slot previously allocated by `alloc_task_slot`, unmapping and freeing its user stack (including
+    /// any pages it has grown into). The task that used the slot must no longer be running.
+    pub fn free_task_slot(&self, slot: TaskSlot, allocator: &Pmm) {
+        let stack = slot.user_stack;
+        let size = usize::from(stack.top) + 1 - usize::from(stack.stack_bottom);
+        self.page_table.lock().unmap_area(stack.stack_bottom, size);
+        allocator.free(stack.physical_start, size / Size4KiB::SIZE);
+
+        let grown_pages = {
+            let mut grown_stack_pages = self.grown_stack_pages.lock();
+            let pages = grown_stack_pages
+                .range(stack.slot_bottom..stack.stack_bottom)
+                .map(|(&page, _)| page)
+                .collect::<Vec<_>>();
+            pages.into_iter().map(|page| (page, grown_stack_pages.remove(&page).unwrap())).collect::<Vec<_>>()
+        };
+        // Dropping each page's charge releases it from the quota of the task that grew the stack
+        for (page, (frame, _charge)) in grown_pages {
+            self.page_table.lock().unmap_area(page, Size4KiB::SIZE);
+            allocator.free(frame, 1);
+        }
+
+        self.slot_bitmap.lock().free(slot.index, 1);
+    }
+
     pub fn switch_to(&self) {
         assert_eq!(*self.state.lock(), State::NotActive);
         unsafe {
@@ -126,6 +546,24 @@ where
     }
 }
 
+impl<P> Drop for AddressSpace<P>
+where
+    P: Platform,
+{
+    fn drop(&mut self) {
+        /*
+         * Every task in this address space has exited and released its resources (each task holds a reference to
+         * its address space until then), so nothing can be using these page tables any more. Dropping the
+         * mappings releases the memory objects mapped into the address space, and then we can free the tables
+         * themselves.
+         */
+        self.mappings.get_mut().clear();
+        unsafe {
+            self.page_table.get_mut().free_lower_half(crate::PMM.get());
+        }
+    }
+}
+
 impl<P> KernelObject for AddressSpace<P>
 where
     P: Platform,
@@ -137,4 +575,8 @@ where
     fn typ(&self) -> KernelObjectType {
         KernelObjectType::AddressSpace
     }
+
+    fn owner(&self) -> KernelObjectId {
+        self.owner
+    }
 }
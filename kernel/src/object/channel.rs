@@ -1,11 +1,23 @@
-use super::{alloc_kernel_object_id, KernelObject, KernelObjectId, KernelObjectType};
+use super::{alloc_kernel_object_id, KernelObject, KernelObjectId, KernelObjectType, Signals};
+use crate::{quota::Charge, scheduler::WaitQueue};
 use alloc::{
-    collections::VecDeque,
+    collections::{BTreeMap, VecDeque},
     fmt,
     sync::{Arc, Weak},
     vec::Vec,
 };
-use poplar::syscall::{GetMessageError, SendMessageError, CHANNEL_MAX_NUM_HANDLES};
+use poplar::{
+    syscall::{
+        ChannelCallError,
+        GetMessageError,
+        SendMessageError,
+        CHANNEL_DEFAULT_MAX_QUEUED_BYTES,
+        CHANNEL_DEFAULT_MAX_QUEUED_MESSAGES,
+        CHANNEL_MAX_NUM_BYTES,
+        CHANNEL_MAX_NUM_HANDLES,
+    },
+    HandleRights,
+};
 use spinning_top::Spinlock;
 use tracing::warn;
 
@@ -13,25 +25,67 @@ use tracing::warn;
 pub struct ChannelEnd {
     pub id: KernelObjectId,
     pub owner: KernelObjectId,
-    pub messages: Spinlock<VecDeque<Message>>,
+    pub messages: Spinlock<MessageQueue>,
     /// The other end of the channel. If this is `None`, the channel's messages come from the kernel.
     other_end: Option<Weak<ChannelEnd>>,
+    /// Calls made through this end that are waiting for their replies.
+    calls: Spinlock<Calls>,
+    /// Woken when a message or reply arrives at this end, when the other end receives a message (and so may
+    /// have space for another), and when the other end is closed.
+    pub waiters: WaitQueue,
+}
+
+/// Each call made through a `ChannelEnd` is identified by a transaction ID, which is assigned by the kernel and
+/// attached to the call's message, so that the reply can be matched up with the call.
+#[derive(Debug, Default)]
+struct Calls {
+    last_transaction: u16,
+    /// The calls waiting for replies, keyed by transaction ID. A call's reply is stored here when it arrives,
+    /// until the caller collects it.
+    pending: BTreeMap<u16, Option<Message>>,
+}
+
+impl Calls {
+    /// Allocate a transaction ID for a new call. Returns `None` if `max_pending` calls are already waiting for
+    /// replies, or if every ID is in use.
+    fn begin(&mut self, max_pending: usize) -> Option<u16> {
+        if self.pending.len() >= usize::min(max_pending, u16::MAX as usize) {
+            return None;
+        }
+
+        // Transaction IDs are never `0`, which is used to mark messages that aren't calls
+        let mut transaction = self.last_transaction;
+        loop {
+            transaction = transaction.checked_add(1).unwrap_or(1);
+            if !self.pending.contains_key(&transaction) {
+                break;
+            }
+        }
+
+        self.last_transaction = transaction;
+        self.pending.insert(transaction, None);
+        Some(transaction)
+    }
 }
 
 impl ChannelEnd {
-    pub fn new_channel(owner: KernelObjectId) -> (Arc<ChannelEnd>, Arc<ChannelEnd>) {
+    pub fn new_channel(owner: KernelObjectId, limits: QueueLimits) -> (Arc<ChannelEnd>, Arc<ChannelEnd>) {
         let mut end_a = Arc::new(ChannelEnd {
             id: alloc_kernel_object_id(),
             owner,
-            messages: Spinlock::new(VecDeque::new()),
+            messages: Spinlock::new(MessageQueue::new(limits)),
             other_end: Some(Weak::default()),
+            calls: Spinlock::new(Calls::default()),
+            waiters: WaitQueue::new(),
         });
 
         let end_b = Arc::new(ChannelEnd {
             id: alloc_kernel_object_id(),
             owner,
-            messages: Spinlock::new(VecDeque::new()),
+            messages: Spinlock::new(MessageQueue::new(limits)),
             other_end: Some(Arc::downgrade(&end_a)),
+            calls: Spinlock::new(Calls::default()),
+            waiters: WaitQueue::new(),
         });
 
         // TODO: is there a nicer way of doing this?
@@ -46,34 +100,131 @@ impl ChannelEnd {
         Arc::new(ChannelEnd {
             id: alloc_kernel_object_id(),
             owner,
-            messages: Spinlock::new(VecDeque::new()),
+            messages: Spinlock::new(MessageQueue::new(QueueLimits::default())),
             other_end: None,
+            calls: Spinlock::new(Calls::default()),
+            waiters: WaitQueue::new(),
         })
     }
 
-    /// Add a message *to* this `ChannelEnd`. Use `send` if you want to send a message *through* this
-    /// `ChannelEnd` (i.e. to the other end of the Channel).
+    /// Add a message *to* this `ChannelEnd`, ignoring the limits on its queue. This is meant for messages from
+    /// the kernel - use `send` if you want to send a message *through* this `ChannelEnd` (i.e. to the other end
+    /// of the Channel). Any tasks blocked waiting for a message on this `ChannelEnd` are made ready.
     pub fn add_message(&self, message: Message) {
         self.messages.lock().push_back(message);
+        self.waiters.wake_all();
     }
 
-    /// Send a message through this `ChannelEnd`, to be received by the other end. If this is a kernel channel, the
-    /// message is discarded.
-    pub fn send(&self, message: Message) -> Result<(), SendMessageError> {
+    /// Send a message of `num_bytes` bytes through this `ChannelEnd`, to be received by the other end. The message
+    /// is only created, by calling `make_message`, once we know there's space for it in the other end's queue -
+    /// this means a failed send doesn't consume any of the handles it was going to transfer. If this is a kernel
+    /// channel, the message is discarded.
+    pub fn send<F>(&self, num_bytes: usize, make_message: F) -> Result<(), SendMessageError>
+    where
+        F: FnOnce() -> Result<Message, SendMessageError>,
+    {
         if let Some(ref other_end) = self.other_end {
-            match other_end.upgrade() {
-                Some(other_end) => {
-                    other_end.add_message(message);
-                    Ok(())
+            let other_end = other_end.upgrade().ok_or(SendMessageError::OtherEndDisconnected)?;
+            {
+                let mut queue = other_end.messages.lock();
+                if !queue.has_space_for(num_bytes) {
+                    return Err(SendMessageError::QueueFull);
                 }
-                None => Err(SendMessageError::OtherEndDisconnected),
+                queue.push_back(make_message()?);
             }
+            other_end.waiters.wake_all();
+            Ok(())
         } else {
             warn!("Discarding message sent down kernel channel");
+            make_message()?;
             Ok(())
         }
     }
 
+    /// Make a call through this `ChannelEnd`. This sends a message, which is created by `make_message` as for
+    /// `send`, tagged with a new transaction ID. The transaction ID is returned, and the reply can be collected
+    /// with `take_reply` once it arrives. Calls can't be made through kernel channels.
+    ///
+    /// Replies are held with their calls rather than in this end's queue, so the number of calls waiting for
+    /// replies is limited to the number of messages the queue can hold instead.
+    pub fn call<F>(&self, num_bytes: usize, make_message: F) -> Result<u16, ChannelCallError>
+    where
+        F: FnOnce(u16) -> Result<Message, SendMessageError>,
+    {
+        if self.other_end.is_none() {
+            return Err(ChannelCallError::OtherEndDisconnected);
+        }
+
+        let max_pending = self.messages.lock().limits.max_messages;
+        let transaction = self.calls.lock().begin(max_pending).ok_or(ChannelCallError::TooManyCalls)?;
+        match self.send(num_bytes, || make_message(transaction)) {
+            Ok(()) => Ok(transaction),
+            Err(err) => {
+                self.calls.lock().pending.remove(&transaction);
+                Err(err.into())
+            }
+        }
+    }
+
+    /// Send the reply to a call received from this `ChannelEnd`. The reply is stored with the call at the other
+    /// end until the caller collects it, so it isn't subject to the limits on the other end's queue.
+    pub fn reply<F>(&self, transaction: u16, make_message: F) -> Result<(), SendMessageError>
+    where
+        F: FnOnce() -> Result<Message, SendMessageError>,
+    {
+        let other_end = match self.other_end {
+            Some(ref other_end) => other_end.upgrade().ok_or(SendMessageError::OtherEndDisconnected)?,
+            None => return Err(SendMessageError::InvalidTransaction),
+        };
+
+        {
+            let mut calls = other_end.calls.lock();
+            match calls.pending.get_mut(&transaction) {
+                Some(reply @ None) => *reply = Some(make_message()?),
+                _ => return Err(SendMessageError::InvalidTransaction),
+            }
+        }
+        other_end.waiters.wake_all();
+        Ok(())
+    }
+
+    /// Take the reply to a call made through this `ChannelEnd`, if it has arrived. Returns
+    /// `Err(ChannelCallError::InvalidTransaction)` if there is no such call waiting for a reply.
+    pub fn take_reply(&self, transaction: u16) -> Result<Option<Message>, ChannelCallError> {
+        let mut calls = self.calls.lock();
+        match calls.pending.get(&transaction) {
+            Some(Some(_)) => Ok(calls.pending.remove(&transaction).unwrap()),
+            Some(None) => Ok(None),
+            None => Err(ChannelCallError::InvalidTransaction),
+        }
+    }
+
+    /// Put back a reply taken with `take_reply`, if it could not be delivered to the caller. It can then be taken
+    /// again later.
+    pub fn restore_reply(&self, transaction: u16, reply: Message) {
+        self.calls.lock().pending.insert(transaction, Some(reply));
+    }
+
+    /// Stop waiting for the reply to a call. If the reply arrives later, it is rejected.
+    pub fn cancel_call(&self, transaction: u16) {
+        self.calls.lock().pending.remove(&transaction);
+    }
+
+    /// Returns `true` if the reply to the given call has arrived.
+    pub fn has_reply(&self, transaction: u16) -> bool {
+        matches!(self.calls.lock().pending.get(&transaction), Some(Some(_)))
+    }
+
+    /// Returns `true` if the other end of this channel has been closed, and so no more messages can arrive at
+    /// this end (although messages sent before it was closed can still be received). Kernel channels are never
+    /// closed from the other end.
+    pub fn is_peer_closed(&self) -> bool {
+        match self.other_end {
+            Some(ref other_end) => other_end.strong_count() == 0,
+            None => false,
+        }
+    }
+
     /// Try to "receive" a message from this `ChannelEnd`, potentially removing it from the queue. Note that this
     /// keeps a lock over the message queue while the passed function is called - if the handling of the message
     /// fails (for example, the buffer to put it into is too small), the passed function can return it with
@@ -84,14 +235,47 @@ impl ChannelEnd {
         F: FnOnce(Message) -> Result<R, (Message, GetMessageError)>,
     {
         let mut message_queue = self.messages.lock();
-        match f(message_queue.pop_front().ok_or(GetMessageError::NoMessage)?) {
-            Ok(value) => Ok(value),
+        let message = match message_queue.pop_front() {
+            Some(message) => message,
+            None if self.is_peer_closed() => return Err(GetMessageError::PeerClosed),
+            None => return Err(GetMessageError::NoMessage),
+        };
+
+        match f(message) {
+            Ok(value) => {
+                drop(message_queue);
+                // The other end may have been waiting for space in our queue
+                if let Some(other_end) = self.other_end.as_ref().and_then(Weak::upgrade) {
+                    other_end.waiters.wake_all();
+                }
+                Ok(value)
+            }
             Err((message, err)) => {
                 message_queue.push_front(message);
                 Err(err)
             }
         }
     }
+
+    /// Get the number of bytes and handles in the next message waiting to be received from this `ChannelEnd`,
+    /// without removing it from the queue.
+    pub fn peek(&self) -> Result<(usize, usize), GetMessageError> {
+        match self.messages.lock().front() {
+            Some(message) => Ok((message.bytes.len(), message.num_handles())),
+            None if self.is_peer_closed() => Err(GetMessageError::PeerClosed),
+            None => Err(GetMessageError::NoMessage),
+        }
+    }
+}
+
+/// Closing one end of a channel wakes the tasks blocked on the other end, so they find out that nothing more
+/// will arrive.
+impl Drop for ChannelEnd {
+    fn drop(&mut self) {
+        if let Some(other_end) = self.other_end.as_ref().and_then(Weak::upgrade) {
+            other_end.waiters.wake_all();
+        }
+    }
 }
 
 impl KernelObject for ChannelEnd {
@@ -102,14 +286,134 @@ impl KernelObject for ChannelEnd {
     fn typ(&self) -> KernelObjectType {
         KernelObjectType::Channel
     }
+
+    fn owner(&self) -> KernelObjectId {
+        self.owner
+    }
+
+    fn poll_ready(&self) -> Option<bool> {
+        Some(!self.messages.lock().is_empty() || self.is_peer_closed())
+    }
+
+    /// A `ChannelEnd` is writable when the other end has space for a message of any size. It's also writable if
+    /// the other end has been closed, so tasks waiting to send find out that they can't. Kernel channels are
+    /// always writable, as their messages are discarded.
+    fn poll_writable(&self) -> Option<bool> {
+        Some(match self.other_end {
+            Some(ref other_end) => match other_end.upgrade() {
+                Some(other_end) => other_end.messages.lock().has_space_for(CHANNEL_MAX_NUM_BYTES),
+                None => true,
+            },
+            None => true,
+        })
+    }
+
+    /// As well as being readable and writable, a `ChannelEnd` asserts `REPLY` when the reply to any call made
+    /// through it has arrived (or when the other end is closed, as pending calls will never be replied to), and
+    /// `PEER_CLOSED` when the other end has been closed.
+    fn poll_signals(&self) -> Option<Signals> {
+        let mut signals = Signals::empty();
+        signals.set(Signals::READABLE, self.poll_ready()?);
+        signals.set(Signals::WRITABLE, self.poll_writable()?);
+        signals.set(
+            Signals::REPLY,
+            self.calls.lock().pending.values().any(|reply| reply.is_some()) || self.is_peer_closed(),
+        );
+        signals.set(Signals::PEER_CLOSED, self.is_peer_closed());
+        Some(signals)
+    }
+
+    fn waiters(&self) -> Option<&WaitQueue> {
+        Some(&self.waiters)
+    }
+
+    fn supports_signals(&self, _signals: Signals) -> bool {
+        true
+    }
+}
+
+/// Limits on how many messages can be queued at a `ChannelEnd`, waiting to be received.
+#[derive(Clone, Copy, Debug)]
+pub struct QueueLimits {
+    pub max_messages: usize,
+    pub max_bytes: usize,
+}
+
+impl Default for QueueLimits {
+    fn default() -> Self {
+        QueueLimits {
+            max_messages: CHANNEL_DEFAULT_MAX_QUEUED_MESSAGES,
+            max_bytes: CHANNEL_DEFAULT_MAX_QUEUED_BYTES,
+        }
+    }
+}
+
+/// The messages queued at a `ChannelEnd`, waiting to be received.
+#[derive(Debug)]
+pub struct MessageQueue {
+    messages: VecDeque<Message>,
+    /// The total size of the queued messages, in bytes.
+    num_bytes: usize,
+    limits: QueueLimits,
+}
+
+impl MessageQueue {
+    pub fn new(limits: QueueLimits) -> MessageQueue {
+        MessageQueue { messages: VecDeque::new(), num_bytes: 0, limits }
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    pub fn num_bytes(&self) -> usize {
+        self.num_bytes
+    }
+
+    /// Returns `true` if a message of `num_bytes` bytes can be added to the queue without exceeding its limits.
+    /// An empty queue always has space for one message, so a message larger than the byte limit can still be sent.
+    pub fn has_space_for(&self, num_bytes: usize) -> bool {
+        self.messages.len() < self.limits.max_messages
+            && (self.messages.is_empty() || self.num_bytes + num_bytes <= self.limits.max_bytes)
+    }
+
+    fn push_back(&mut self, message: Message) {
+        self.num_bytes += message.bytes.len();
+        self.messages.push_back(message);
+    }
+
+    fn push_front(&mut self, message: Message) {
+        self.num_bytes += message.bytes.len();
+        self.messages.push_front(message);
+    }
+
+    fn front(&self) -> Option<&Message> {
+        self.messages.front()
+    }
+
+    fn pop_front(&mut self) -> Option<Message> {
+        let message = self.messages.pop_front()?;
+        self.num_bytes -= message.bytes.len();
+        Some(message)
+    }
 }
 
 pub struct Message {
     pub bytes: Vec<u8>,
-    /// The actual objects extracted from the handles transferred by a message. When a task receives this message,
-    /// these objects are added to that task, and the new handles are put into the message. The non-`None` entries
-    /// of this array must be contiguous - there cannot be a `None` entry before more non-`None` entries.
-    pub handle_objects: [Option<Arc<dyn KernelObject>>; CHANNEL_MAX_NUM_HANDLES],
+    /// The actual objects extracted from the handles transferred by a message, along with the rights of the
+    /// transferred handles. When a task receives this message, these objects are added to that task with the same
+    /// rights, and the new handles are put into the message. The non-`None` entries of this array must be
+    /// contiguous - there cannot be a `None` entry before more non-`None` entries.
+    pub handle_objects: [Option<(Arc<dyn KernelObject>, HandleRights)>; CHANNEL_MAX_NUM_HANDLES],
+    /// The message's bytes are charged against the sending task's quota until the message is received (and so
+    /// dropped). Messages created by the kernel are not charged.
+    pub charge: Option<Charge>,
+    /// If this message is a call, or the reply to one, the transaction ID of the call. Otherwise, this is `0`.
+    pub transaction: u16,
 }
 
 impl fmt::Debug for Message {
@@ -123,3 +427,79 @@ impl Message {
         self.handle_objects.iter().fold(0, |n, ref handle| if handle.is_some() { n + 1 } else { n })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(num_bytes: usize) -> Message {
+        Message {
+            bytes: vec![0; num_bytes],
+            handle_objects: core::array::from_fn(|_| None),
+            charge: None,
+            transaction: 0,
+        }
+    }
+
+    #[test]
+    fn test_empty_queue_has_space() {
+        let queue = MessageQueue::new(QueueLimits { max_messages: 4, max_bytes: 16 });
+        assert!(queue.has_space_for(0));
+        assert!(queue.has_space_for(16));
+
+        // An empty queue accepts one message, however big it is
+        assert!(queue.has_space_for(1000));
+    }
+
+    #[test]
+    fn test_queue_limits() {
+        let mut queue = MessageQueue::new(QueueLimits { max_messages: 2, max_bytes: 16 });
+        queue.push_back(message(10));
+        assert!(queue.has_space_for(6));
+        assert!(!queue.has_space_for(7));
+
+        // The queue can't take any more messages, even though it has bytes to spare
+        queue.push_back(message(2));
+        assert_eq!(queue.num_bytes(), 12);
+        assert!(!queue.has_space_for(0));
+
+        queue.pop_front();
+        assert_eq!(queue.num_bytes(), 2);
+        assert!(queue.has_space_for(14));
+        assert!(!queue.has_space_for(15));
+    }
+
+    #[test]
+    fn test_transaction_ids_wrap() {
+        let mut calls = Calls::default();
+        assert_eq!(calls.begin(8), Some(1));
+        assert_eq!(calls.begin(8), Some(2));
+
+        // IDs wrap around to `1`, skipping `0` and any that are still in use
+        calls.last_transaction = u16::MAX - 1;
+        assert_eq!(calls.begin(8), Some(u16::MAX));
+        assert_eq!(calls.begin(8), Some(3));
+    }
+
+    #[test]
+    fn test_max_pending_calls() {
+        let mut calls = Calls::default();
+        for transaction in 1..=3 {
+            assert_eq!(calls.begin(3), Some(transaction));
+        }
+        assert_eq!(calls.begin(3), None);
+
+        // Once a call has finished, another can be made in its place
+        calls.pending.remove(&2);
+        assert_eq!(calls.begin(3), Some(4));
+    }
+
+    #[test]
+    fn test_all_transaction_ids_in_use() {
+        let mut calls = Calls::default();
+        for transaction in 1..=u16::MAX {
+            assert_eq!(calls.begin(usize::MAX), Some(transaction));
+        }
+        assert_eq!(calls.begin(usize::MAX), None);
+    }
+}
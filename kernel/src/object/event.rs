@@ -1,21 +1,34 @@
 use super::{KernelObject, KernelObjectId, KernelObjectType};
+use crate::scheduler::WaitQueue;
 use alloc::sync::Arc;
 use core::sync::atomic::{AtomicBool, Ordering};
 
+/// An `Event` is a flag that can be signalled and cleared, and waited on until it is signalled. They can be
+/// created by tasks, and passed to other tasks to notify them of things happening.
 #[derive(Debug)]
 pub struct Event {
     pub id: KernelObjectId,
+    pub owner: KernelObjectId,
     pub signalled: AtomicBool,
+    /// Woken when the event is signalled.
+    pub waiters: WaitQueue,
 }
 
 impl Event {
-    pub fn new() -> Arc<Event> {
-        Arc::new(Event { id: super::alloc_kernel_object_id(), signalled: AtomicBool::new(false) })
+    pub fn new(owner: KernelObjectId) -> Arc<Event> {
+        Arc::new(Event {
+            id: super::alloc_kernel_object_id(),
+            owner,
+            signalled: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+        })
     }
 
+    /// Signal the event. Any tasks blocked waiting for it are made ready.
     pub fn signal(&self) {
         // TODO: ordering?
         self.signalled.store(true, Ordering::SeqCst);
+        self.waiters.wake_all();
     }
 
     pub fn clear(&self) {
@@ -32,4 +45,16 @@ impl KernelObject for Event {
     fn typ(&self) -> KernelObjectType {
         KernelObjectType::Event
     }
+
+    fn owner(&self) -> KernelObjectId {
+        self.owner
+    }
+
+    fn poll_ready(&self) -> Option<bool> {
+        Some(self.signalled.load(Ordering::SeqCst))
+    }
+
+    fn waiters(&self) -> Option<&WaitQueue> {
+        Some(&self.waiters)
+    }
 }
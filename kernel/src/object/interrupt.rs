@@ -1,5 +1,5 @@
-use super::{KernelObject, KernelObjectId, KernelObjectType};
-use crate::Platform;
+use super::{KernelObject, KernelObjectId, KernelObjectType, SENTINEL_KERNEL_ID};
+use crate::{scheduler::WaitQueue, Platform};
 use alloc::sync::Arc;
 use core::sync::atomic::{AtomicBool, Ordering};
 
@@ -11,16 +11,25 @@ pub struct Interrupt {
     /// The vector that this interrupt is triggered by. The value of this is determined by the
     /// platform-specific interrupt layer, and is effectively opaque to the common kernel.
     pub rearm_irq: Option<usize>,
+
+    /// Woken when the interrupt is triggered.
+    pub waiters: WaitQueue,
 }
 
 impl Interrupt {
     pub fn new(rearm_irq: Option<usize>) -> Arc<Interrupt> {
-        Arc::new(Interrupt { id: super::alloc_kernel_object_id(), triggered: AtomicBool::new(false), rearm_irq })
+        Arc::new(Interrupt {
+            id: super::alloc_kernel_object_id(),
+            triggered: AtomicBool::new(false),
+            rearm_irq,
+            waiters: WaitQueue::new(),
+        })
     }
 
     pub fn trigger(&self) {
         // TODO: ordering?
         self.triggered.store(true, Ordering::SeqCst);
+        self.waiters.wake_all();
     }
 
     pub fn rearm<P>(&self)
@@ -44,4 +53,16 @@ impl KernelObject for Interrupt {
     fn typ(&self) -> KernelObjectType {
         KernelObjectType::Interrupt
     }
+
+    fn owner(&self) -> KernelObjectId {
+        SENTINEL_KERNEL_ID
+    }
+
+    fn poll_ready(&self) -> Option<bool> {
+        Some(self.triggered.load(Ordering::SeqCst))
+    }
+
+    fn waiters(&self) -> Option<&WaitQueue> {
+        Some(&self.waiters)
+    }
 }
@@ -1,6 +1,16 @@
 use super::{alloc_kernel_object_id, KernelObject, KernelObjectId, KernelObjectType};
-use alloc::{sync::Arc, vec::Vec};
-use hal::memory::{Flags, PAddr};
+use crate::quota::{Charge, QuotaExceeded};
+use alloc::{
+    collections::btree_map::BTreeMap,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use hal::memory::{Flags, FrameSize, PAddr, Size4KiB, VAddr};
+use poplar::syscall::{CloneMemoryObjectError, ProtectMappingError};
 use spinning_top::Spinlock;
 
 #[derive(Debug)]
@@ -8,6 +18,17 @@ pub struct MemoryObject {
     pub id: KernelObjectId,
     pub owner: KernelObjectId,
     pub inner: Spinlock<Inner>,
+    /// The places this object is mapped. This is used to keep every mapping in sync when the object is resized.
+    mappings: Spinlock<Vec<Mapping>>,
+    /// Whether the physical memory backing this object was allocated from the PMM. If it was, it is returned to
+    /// the PMM when the last reference to the object is dropped. Objects that describe memory that the kernel
+    /// does not own (e.g. the framebuffer, or PCI BARs) do not free their backing memory.
+    pmm_backed: bool,
+    /// Whether this object's memory must stay at the same physical addresses. This is the case for objects that
+    /// describe memory the kernel does not own (e.g. the framebuffer, or PCI BARs), and for objects whose physical
+    /// address has been given to userspace (e.g. so it can be used for DMA). Pinned objects can't be cloned, as
+    /// that could move their pages to new physical memory.
+    pinned: AtomicBool,
 }
 
 #[derive(Debug)]
@@ -15,7 +36,68 @@ pub struct Inner {
     /// Size of this MemoryObject in bytes.
     pub size: usize,
     pub flags: Flags,
-    pub backing: Vec<(PAddr, usize)>,
+    pub backing: Backing,
+    /// The frames allocated for this object are charged against the quota of the task that created it. For
+    /// objects with eager backing, this holds the charge for their memory. For lazily-committed objects, each
+    /// committed frame holds its own charge, and this is used to charge new frames as they're committed. Objects
+    /// created by the kernel are not charged.
+    pub charge: Option<Charge>,
+}
+
+#[derive(Debug)]
+pub enum Backing {
+    /// The object is backed by one or more ranges of contiguous physical memory, which are allocated up front.
+    Eager(Vec<(PAddr, usize)>),
+    /// Each page of the object is only backed by physical memory when it is first touched. This maps the offset
+    /// of each page that has been committed to the frame backing it. Frames can be shared with copy-on-write
+    /// clones of the object.
+    Lazy(BTreeMap<usize, Arc<PageFrame>>),
+}
+
+/// A frame backing a page of a lazily-committed `MemoryObject`. Frames are shared between an object and its
+/// copy-on-write clones until one of them writes to the page, and are freed when no object uses them any more.
+#[derive(Debug)]
+pub struct PageFrame {
+    pub address: PAddr,
+    /// Whether the frame was allocated from the PMM, and so should be freed when it's no longer used.
+    owned: bool,
+    /// The charge for this frame, which is released when the frame is freed.
+    _charge: Option<Charge>,
+}
+
+impl Drop for PageFrame {
+    fn drop(&mut self) {
+        if self.owned {
+            crate::PMM.get().free(self.address, 1);
+        }
+    }
+}
+
+/// A page of a lazily-committed `MemoryObject` that has been committed by `commit_page`.
+#[derive(Clone, Copy, Debug)]
+pub struct CommittedPage {
+    pub frame: PAddr,
+    /// Whether the frame is only used by this object. If it isn't, it must be mapped read-only, so writes to it
+    /// fault and the page can be copied.
+    pub exclusive: bool,
+    /// Whether the page was shared, and has been copied into a new frame. Any other mappings of the object still
+    /// refer to the old frame, and must be updated.
+    pub copied: bool,
+}
+
+/// Records that a `MemoryObject` is mapped into an `AddressSpace`. This only holds a weak reference to the address
+/// space, as the address space holds a strong reference to each object mapped into it.
+#[derive(Clone, Debug)]
+pub struct Mapping {
+    pub address_space: Weak<dyn KernelObject>,
+    pub address_space_id: KernelObjectId,
+    pub address: VAddr,
+    /// The flags the object is mapped with, which may differ from the object's own (e.g. if it was mapped
+    /// read-only through a handle that cannot modify it).
+    pub flags: Flags,
+    /// Ranges of offsets into the object that have had their flags changed since it was mapped. Later entries
+    /// take precedence over earlier ones.
+    pub protections: Vec<(Range<usize>, Flags)>,
 }
 
 impl MemoryObject {
@@ -23,7 +105,58 @@ impl MemoryObject {
         Arc::new(MemoryObject {
             id: alloc_kernel_object_id(),
             owner,
-            inner: Spinlock::new(Inner { size, flags, backing: vec![(physical_address, size)] }),
+            inner: Spinlock::new(Inner {
+                size,
+                flags,
+                backing: Backing::Eager(vec![(physical_address, size)]),
+                charge: None,
+            }),
+            mappings: Spinlock::new(Vec::new()),
+            pmm_backed: false,
+            pinned: AtomicBool::new(true),
+        })
+    }
+
+    /// Create a `MemoryObject` backed by physical memory that has been allocated from the PMM. The memory is
+    /// freed when the object is dropped, and `charge` (if present) should cover the frames that were allocated.
+    pub fn new_pmm_backed(
+        owner: KernelObjectId,
+        physical_address: PAddr,
+        size: usize,
+        flags: Flags,
+        charge: Option<Charge>,
+    ) -> Arc<MemoryObject> {
+        Arc::new(MemoryObject {
+            id: alloc_kernel_object_id(),
+            owner,
+            inner: Spinlock::new(Inner {
+                size,
+                flags,
+                backing: Backing::Eager(vec![(physical_address, size)]),
+                charge,
+            }),
+            mappings: Spinlock::new(Vec::new()),
+            pmm_backed: true,
+            pinned: AtomicBool::new(false),
+        })
+    }
+
+    /// Create a `MemoryObject` that is lazily committed - physical memory is only allocated for each page when it
+    /// is first accessed, and is zeroed. The memory is freed when the object is dropped. If `charge` is present,
+    /// each frame is charged against its quota as it's committed.
+    pub fn new_lazy(
+        owner: KernelObjectId,
+        size: usize,
+        flags: Flags,
+        charge: Option<Charge>,
+    ) -> Arc<MemoryObject> {
+        Arc::new(MemoryObject {
+            id: alloc_kernel_object_id(),
+            owner,
+            inner: Spinlock::new(Inner { size, flags, backing: Backing::Lazy(BTreeMap::new()), charge }),
+            mappings: Spinlock::new(Vec::new()),
+            pmm_backed: true,
+            pinned: AtomicBool::new(false),
         })
     }
 
@@ -40,22 +173,295 @@ impl MemoryObject {
             inner: Spinlock::new(Inner {
                 size: segment.size as usize,
                 flags,
-                backing: vec![(PAddr::new(segment.phys_addr as usize).unwrap(), segment.size as usize)],
+                backing: Backing::Eager(vec![(
+                    PAddr::new(segment.phys_addr as usize).unwrap(),
+                    segment.size as usize,
+                )]),
+                charge: None,
             }),
+            mappings: Spinlock::new(Vec::new()),
+            pmm_backed: false,
+            pinned: AtomicBool::new(false),
         })
     }
 
+    /// Charge `num_frames` more frames to the quota this object's memory is charged against. This should be done
+    /// before the frames are allocated to extend the object. Objects that are not charged always succeed.
+    pub fn charge_frames(&self, num_frames: usize) -> Result<(), QuotaExceeded> {
+        let mut inner = self.inner.lock();
+        match inner.charge {
+            Some(ref mut charge) => charge.grow(num_frames),
+            None => Ok(()),
+        }
+    }
+
+    /// Release `num_frames` frames charged by `charge_frames`, if the frames could not be allocated.
+    pub fn uncharge_frames(&self, num_frames: usize) {
+        if let Some(ref mut charge) = self.inner.lock().charge {
+            charge.shrink(num_frames);
+        }
+    }
+
     /// Extend this `MemoryObject` by `extend_by` bytes. The new portion of the object is backed
-    /// by physical memory starting at `new_backing`.
+    /// by physical memory starting at `new_backing`, which should already have been charged with
+    /// `charge_frames`.
     ///
     /// ### Note
     /// Note that this does not map the new portion of the object into address spaces that this
-    /// memory object is already mapped into.
+    /// memory object is already mapped into - the caller should do that using the object's `mappings`.
     pub unsafe fn extend(&self, extend_by: usize, new_backing: PAddr) {
         assert!(extend_by > 0);
         let mut inner = self.inner.lock();
         inner.size += extend_by;
-        inner.backing.push((new_backing, extend_by));
+        match &mut inner.backing {
+            Backing::Eager(ranges) => ranges.push((new_backing, extend_by)),
+            Backing::Lazy(_) => panic!("Tried to extend lazily-committed memory object with eager backing"),
+        }
+    }
+
+    /// Extend a lazily-committed `MemoryObject` by `extend_by` bytes. No memory is committed for the new portion
+    /// of the object until it is touched.
+    pub fn extend_lazy(&self, extend_by: usize) {
+        assert!(extend_by > 0);
+        let mut inner = self.inner.lock();
+        assert!(matches!(inner.backing, Backing::Lazy(_)));
+        inner.size += extend_by;
+    }
+
+    /// Shrink this `MemoryObject` by `shrink_by` bytes, which must be a multiple of the page size. The backing
+    /// memory of the removed portion is freed if the object owns it.
+    ///
+    /// ### Safety
+    /// The removed portion of the object must already have been unmapped from every address space it was mapped
+    /// into.
+    pub unsafe fn shrink(&self, shrink_by: usize) {
+        let mut inner = self.inner.lock();
+        assert!(shrink_by > 0 && shrink_by <= inner.size);
+        inner.size -= shrink_by;
+        let new_size = inner.size;
+        let inner = &mut *inner;
+
+        match &mut inner.backing {
+            Backing::Eager(ranges) => {
+                if let Some(ref mut charge) = inner.charge {
+                    charge.shrink(shrink_by / Size4KiB::SIZE);
+                }
+
+                let mut remaining = shrink_by;
+                while remaining > 0 {
+                    let (base, size) = ranges.last_mut().unwrap();
+                    let removed = usize::min(*size, remaining);
+                    if self.pmm_backed {
+                        /*
+                         * The range was allocated as a single block, so the PMM frees whole blocks from the end
+                         * of it. It keeps the rest as a block, which is freed when the object is dropped.
+                         */
+                        crate::PMM.get().shrink(*base, *size / Size4KiB::SIZE, (*size - removed) / Size4KiB::SIZE);
+                    }
+                    *size -= removed;
+                    if *size == 0 {
+                        ranges.pop();
+                    }
+                    remaining -= removed;
+                }
+            }
+            Backing::Lazy(pages) => {
+                // Frames are freed when the last object using them drops its reference
+                pages.split_off(&new_size);
+            }
+        }
+    }
+
+    pub fn is_lazy(&self) -> bool {
+        matches!(self.inner.lock().backing, Backing::Lazy(_))
+    }
+
+    /// Get the frame backing the page at `offset` into a lazily-committed object, committing a new frame if the
+    /// page has not been touched before. If `write` is `true` and the page is shared with a clone of this object,
+    /// it is copied into a new frame. `alloc_frame` is used to allocate new frames - it is passed the frame to copy
+    /// from if the page is being copied, and should zero the new frame otherwise. Returns `None` if the object is
+    /// not lazily committed, or if a new frame is needed but can't be charged or allocated.
+    pub fn commit_page(
+        &self,
+        offset: usize,
+        write: bool,
+        alloc_frame: impl FnOnce(Option<PAddr>) -> Option<PAddr>,
+    ) -> Option<CommittedPage> {
+        let mut inner = self.inner.lock();
+        if offset >= inner.size {
+            return None;
+        }
+
+        let inner = &mut *inner;
+        let pages = match &mut inner.backing {
+            Backing::Eager(_) => return None,
+            Backing::Lazy(pages) => pages,
+        };
+        let charge_frame = || match inner.charge {
+            Some(ref charge) => charge.quota().charge(1).ok().map(Some),
+            None => Some(None),
+        };
+
+        let existing = pages.get(&offset).map(|page| (page.address, Arc::strong_count(page) == 1));
+        match existing {
+            Some((frame, exclusive)) if exclusive || !write => {
+                Some(CommittedPage { frame, exclusive, copied: false })
+            }
+            Some((shared_frame, _)) => {
+                let charge = charge_frame()?;
+                let frame = alloc_frame(Some(shared_frame))?;
+                pages.insert(offset, Arc::new(PageFrame { address: frame, owned: true, _charge: charge }));
+                Some(CommittedPage { frame, exclusive: true, copied: true })
+            }
+            None => {
+                let charge = charge_frame()?;
+                let frame = alloc_frame(None)?;
+                pages.insert(offset, Arc::new(PageFrame { address: frame, owned: true, _charge: charge }));
+                Some(CommittedPage { frame, exclusive: true, copied: false })
+            }
+        }
+    }
+
+    /// Record that the physical address of this object's memory has been given to userspace. This pins the
+    /// object, stopping it from being cloned, as that could move its pages to new physical memory.
+    pub fn mark_physical_address_exposed(&self) {
+        self.pinned.store(true, Ordering::SeqCst);
+    }
+
+    /// Create a copy-on-write clone of this object. The clone shares this object's frames until either of them
+    /// writes to a shared page, at which point the page is copied. Objects with eager backing are converted to be
+    /// lazily committed, so their pages can be shared individually.
+    ///
+    /// Pinned objects can't be cloned. Objects whose memory wasn't allocated from the PMM (e.g. the segments of
+    /// the boot image) can be, but their frames are never freed.
+    ///
+    /// The caller must unmap the pages of this object from all of its existing mappings, so that they're faulted
+    /// back in read-only while they're shared. Pages that the clone copies are charged to `charge`.
+    pub fn clone_cow(
+        &self,
+        owner: KernelObjectId,
+        charge: Option<Charge>,
+    ) -> Result<Arc<MemoryObject>, CloneMemoryObjectError> {
+        if self.pinned.load(Ordering::SeqCst) {
+            return Err(CloneMemoryObjectError::MemoryObjectCannotBeCloned);
+        }
+
+        let mut inner = self.inner.lock();
+        let inner = &mut *inner;
+
+        if let Backing::Eager(ranges) = &inner.backing {
+            let mut pages = BTreeMap::new();
+            let mut offset = 0;
+            for (base, size) in ranges {
+                for page_offset in (0..*size).step_by(Size4KiB::SIZE) {
+                    // The object's charge is split between its frames, as they're now freed individually
+                    let frame_charge = inner.charge.as_mut().map(|charge| charge.split_off(1));
+                    pages.insert(
+                        offset + page_offset,
+                        Arc::new(PageFrame {
+                            address: *base + page_offset,
+                            owned: self.pmm_backed,
+                            _charge: frame_charge,
+                        }),
+                    );
+                }
+                offset += *size;
+
+                /*
+                 * Allocations from the PMM are rounded up to a power-of-2 number of frames. The frames are now
+                 * freed individually, so free the ones past the end of the range now too.
+                 */
+                if self.pmm_backed {
+                    let num_frames = *size / Size4KiB::SIZE;
+                    for frame in num_frames..num_frames.next_power_of_two() {
+                        crate::PMM.get().free(*base + frame * Size4KiB::SIZE, 1);
+                    }
+                }
+            }
+            inner.backing = Backing::Lazy(pages);
+        }
+
+        let pages = match &inner.backing {
+            Backing::Lazy(pages) => pages.clone(),
+            Backing::Eager(_) => unreachable!(),
+        };
+
+        Ok(Arc::new(MemoryObject {
+            id: alloc_kernel_object_id(),
+            owner,
+            inner: Spinlock::new(Inner {
+                size: inner.size,
+                flags: inner.flags,
+                backing: Backing::Lazy(pages),
+                charge,
+            }),
+            mappings: Spinlock::new(Vec::new()),
+            pmm_backed: true,
+            pinned: AtomicBool::new(false),
+        }))
+    }
+
+    pub fn add_mapping(&self, mapping: Mapping) {
+        self.mappings.lock().push(mapping);
+    }
+
+    /// Get the flags that the page at `offset` into this object is mapped with, for the mapping at `address` in
+    /// the given address space. Returns `None` if the object is not mapped there.
+    pub fn mapping_flags(&self, address_space_id: KernelObjectId, address: VAddr, offset: usize) -> Option<Flags> {
+        self.mappings
+            .lock()
+            .iter()
+            .find(|mapping| mapping.address_space_id == address_space_id && mapping.address == address)
+            .map(|mapping| {
+                mapping
+                    .protections
+                    .iter()
+                    .rev()
+                    .find(|(range, _)| range.contains(&offset))
+                    .map_or(mapping.flags, |(_, flags)| *flags)
+            })
+    }
+
+    /// Change the permissions of the range `offsets` of the mapping at `address` in the given address space. The
+    /// range can't be given permissions the object was not mapped with. Returns the flags the range should now be
+    /// mapped with - the caller is responsible for updating the page tables.
+    pub fn protect_mapping(
+        &self,
+        address_space_id: KernelObjectId,
+        address: VAddr,
+        offsets: Range<usize>,
+        writable: bool,
+        executable: bool,
+    ) -> Result<Flags, ProtectMappingError> {
+        let mut mappings = self.mappings.lock();
+        let mapping = mappings
+            .iter_mut()
+            .find(|mapping| mapping.address_space_id == address_space_id && mapping.address == address)
+            .ok_or(ProtectMappingError::NotMapped)?;
+
+        if (writable && !mapping.flags.writable) || (executable && !mapping.flags.executable) {
+            return Err(ProtectMappingError::PermissionDenied);
+        }
+
+        // Ranges that are entirely covered by the new one no longer have any effect
+        let flags = Flags { writable, executable, ..mapping.flags };
+        mapping.protections.retain(|(range, _)| !(offsets.start <= range.start && range.end <= offsets.end));
+        mapping.protections.push((offsets, flags));
+        Ok(flags)
+    }
+
+    pub fn remove_mapping(&self, address_space_id: KernelObjectId, address: VAddr) {
+        self.mappings
+            .lock()
+            .retain(|mapping| !(mapping.address_space_id == address_space_id && mapping.address == address));
+    }
+
+    /// Get the places this object is currently mapped. Mappings into address spaces that have since been
+    /// destroyed are pruned.
+    pub fn mappings(&self) -> Vec<Mapping> {
+        let mut mappings = self.mappings.lock();
+        mappings.retain(|mapping| mapping.address_space.strong_count() > 0);
+        mappings.clone()
     }
 
     pub fn size(&self) -> usize {
@@ -75,6 +481,27 @@ impl KernelObject for MemoryObject {
     fn typ(&self) -> KernelObjectType {
         KernelObjectType::MemoryObject
     }
+
+    fn owner(&self) -> KernelObjectId {
+        self.owner
+    }
+}
+
+impl Drop for MemoryObject {
+    fn drop(&mut self) {
+        if self.pmm_backed {
+            let pmm = crate::PMM.get();
+            match &self.inner.get_mut().backing {
+                Backing::Eager(ranges) => {
+                    for (base, size) in ranges {
+                        pmm.free(*base, *size / Size4KiB::SIZE);
+                    }
+                }
+                // Frames are freed when the last object using them drops its reference
+                Backing::Lazy(_) => (),
+            }
+        }
+    }
 }
 
 impl PartialEq for MemoryObject {
@@ -3,16 +3,27 @@ pub mod channel;
 pub mod event;
 pub mod interrupt;
 pub mod memory_object;
+pub mod port;
 pub mod task;
+pub mod timer;
 
+use crate::scheduler::WaitQueue;
 use core::sync::atomic::{AtomicU64, Ordering};
 use mulch::{downcast::DowncastSync, impl_downcast};
 
+pub use poplar::syscall::{KernelObjectType, Signals};
+
 /// Each kernel object is assigned a unique 64-bit ID, which is never reused. An ID of `0` is never allocated, and
 /// is used as a sentinel value.
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub struct KernelObjectId(u64);
 
+impl From<KernelObjectId> for u64 {
+    fn from(id: KernelObjectId) -> u64 {
+        id.0
+    }
+}
+
 /// A kernel object ID of `0` is reserved as a sentinel value that will never point to a real kernel object. It is
 /// used to mark things like the `owner` of a kernel object being the kernel itself.
 pub const SENTINEL_KERNEL_ID: KernelObjectId = KernelObjectId(0);
@@ -25,16 +36,6 @@ pub fn alloc_kernel_object_id() -> KernelObjectId {
     KernelObjectId(KERNEL_OBJECT_ID_COUNTER.fetch_add(1, Ordering::Relaxed))
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
-pub enum KernelObjectType {
-    AddressSpace,
-    Task,
-    MemoryObject,
-    Channel,
-    Event,
-    Interrupt,
-}
-
 /// This trait should be implemented by all types that implement kernel objects, and allows common code to
 /// be generic over all kernel objects. Kernel objects are generally handled as `Arc<T>` where `T` is the type
 /// implementing `KernelObject`, and so interior mutability should be used for data that needs to be mutable within
@@ -42,7 +43,43 @@ pub enum KernelObjectType {
 pub trait KernelObject: DowncastSync {
     fn id(&self) -> KernelObjectId;
     fn typ(&self) -> KernelObjectType;
-    // fn owner(&self) -> KernelObjectId;
+
+    /// The ID of the task that created this object, or `SENTINEL_KERNEL_ID` if it was created by the kernel.
+    fn owner(&self) -> KernelObjectId;
+
+    /// Check if the object is "ready" - if a task waiting on it has something to act on (for example, a message
+    /// has arrived on a `ChannelEnd`). Returns `None` if the object can't be waited on.
+    fn poll_ready(&self) -> Option<bool> {
+        None
+    }
+
+    /// Check if the object can be written to without failing (for example, the other end of a `ChannelEnd` has
+    /// space for another message). Returns `None` if the object can't be waited on to become writable.
+    fn poll_writable(&self) -> Option<bool> {
+        None
+    }
+
+    /// Poll the object for each of the signals it supports. Returns `None` if the object can't be waited on.
+    fn poll_signals(&self) -> Option<Signals> {
+        let mut signals = Signals::empty();
+        signals.set(Signals::READABLE, self.poll_ready()?);
+        signals.set(Signals::WRITABLE, self.poll_writable() == Some(true));
+        Some(signals)
+    }
+
+    /// The wait queue woken whenever the object's signals may have changed. Every object that can be waited on
+    /// must have one, as `Port`s rely on it to find out when bound objects become ready.
+    fn waiters(&self) -> Option<&WaitQueue> {
+        None
+    }
+
+    /// Returns `true` if the object can be waited on for all of `signals`. Only `ChannelEnd`s can assert `REPLY`
+    /// and `PEER_CLOSED`, and override this to allow them.
+    fn supports_signals(&self, signals: Signals) -> bool {
+        (!signals.contains(Signals::READABLE) || self.poll_ready().is_some())
+            && (!signals.contains(Signals::WRITABLE) || self.poll_writable().is_some())
+            && !signals.intersects(Signals::REPLY | Signals::PEER_CLOSED)
+    }
 }
 
 impl_downcast!(sync KernelObject);
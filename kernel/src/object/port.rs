@@ -0,0 +1,155 @@
+use super::{alloc_kernel_object_id, KernelObject, KernelObjectId, KernelObjectType, Signals};
+use crate::scheduler::WaitQueue;
+use alloc::{
+    collections::VecDeque,
+    fmt,
+    sync::{Arc, Weak},
+    task::Wake,
+    vec::Vec,
+};
+use core::task::Waker;
+use spinning_top::Spinlock;
+
+/// A `Port` allows a task to wait on many kernel objects at once. Objects are bound to the port with a key chosen
+/// by the task, and when a bound object becomes ready, a packet carrying its key is queued on the port. Bindings
+/// are one-shot - once a packet has been queued for an object, it must be bound again to be waited on again.
+pub struct Port {
+    pub id: KernelObjectId,
+    pub owner: KernelObjectId,
+    bindings: Spinlock<Vec<Binding>>,
+    packets: Spinlock<VecDeque<u64>>,
+    /// The tasks blocked waiting for a packet to be queued on the port.
+    pub waiters: WaitQueue,
+}
+
+/// Bindings only hold a weak reference to the bound object, so binding an object to a port doesn't stop it from
+/// being destroyed when the last handle to it is closed (which would stop e.g. the other end of a `Channel` from
+/// seeing that it has been closed).
+struct Binding {
+    key: u64,
+    object: Weak<dyn KernelObject>,
+    signals: Signals,
+    /// Registered with the bound object, which wakes it whenever its signals may have changed. See
+    /// `BindingWaker`.
+    waker: Waker,
+}
+
+impl Port {
+    pub fn new(owner: KernelObjectId) -> Arc<Port> {
+        Arc::new(Port {
+            id: alloc_kernel_object_id(),
+            owner,
+            bindings: Spinlock::new(Vec::new()),
+            packets: Spinlock::new(VecDeque::new()),
+            waiters: WaitQueue::new(),
+        })
+    }
+
+    /// Bind an object to this port, to be told when any of `signals` is asserted on it. If there is already a
+    /// binding with the same key, it is replaced. The caller must check that the object can be waited on for the
+    /// given signals (see `KernelObject::supports_signals`).
+    pub fn bind(self: &Arc<Self>, key: u64, object: &Arc<dyn KernelObject>, signals: Signals) {
+        {
+            let mut bindings = self.bindings.lock();
+            // Bindings to objects that have been destroyed will never be woken, so clear them out as we go
+            bindings.retain(|binding| binding.key != key && binding.object.strong_count() > 0);
+            bindings.push(Binding {
+                key,
+                object: Arc::downgrade(object),
+                signals,
+                waker: Waker::from(Arc::new(BindingWaker { port: Arc::downgrade(self), key })),
+            });
+        }
+
+        // The object may already be asserting the signals
+        self.check_binding(key);
+    }
+
+    /// Check whether the object bound with `key` is asserting any of the signals it's bound for. If it is, a
+    /// packet is queued and the binding is removed. If not, the binding is registered with the object, so it's
+    /// checked again when the object's signals next change. Bindings to objects that have been destroyed are
+    /// removed without queueing a packet.
+    fn check_binding(&self, key: u64) {
+        let mut bindings = self.bindings.lock();
+        let index = match bindings.iter().position(|binding| binding.key == key) {
+            Some(index) => index,
+            None => return,
+        };
+        let object = match bindings[index].object.upgrade() {
+            Some(object) => object,
+            None => {
+                bindings.swap_remove(index);
+                return;
+            }
+        };
+
+        // Register the binding before checking the signals, so we can't miss them being asserted in between
+        if let Some(waiters) = object.waiters() {
+            waiters.register(&bindings[index].waker);
+        }
+        let asserted = object.poll_signals().map_or(false, |signals| signals.intersects(bindings[index].signals));
+        if asserted {
+            bindings.swap_remove(index);
+            self.packets.lock().push_back(key);
+        }
+
+        /*
+         * Unlock the bindings before dropping our reference to the object, and before waking any tasks waiting
+         * for a packet, as both can end up checking bindings again.
+         */
+        drop(bindings);
+        drop(object);
+        if asserted {
+            self.waiters.wake_all();
+        }
+    }
+
+    /// Returns `true` if there is a packet waiting to be taken from the port.
+    pub fn has_packets(&self) -> bool {
+        !self.packets.lock().is_empty()
+    }
+
+    /// Take the next packet from the port, if there is one.
+    pub fn take_packet(&self) -> Option<u64> {
+        self.packets.lock().pop_front()
+    }
+}
+
+/// Checks a binding again when the bound object wakes it. Objects wake their waiters whenever their signals may
+/// have changed, so this is how a packet comes to be queued when an object becomes ready.
+struct BindingWaker {
+    port: Weak<Port>,
+    key: u64,
+}
+
+impl Wake for BindingWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if let Some(port) = self.port.upgrade() {
+            port.check_binding(self.key);
+        }
+    }
+}
+
+impl fmt::Debug for Port {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Port").field("id", &self.id).field("owner", &self.owner).finish_non_exhaustive()
+    }
+}
+
+impl KernelObject for Port {
+    fn id(&self) -> KernelObjectId {
+        self.id
+    }
+
+    fn typ(&self) -> KernelObjectType {
+        KernelObjectType::Port
+    }
+
+    fn owner(&self) -> KernelObjectId {
+        self.owner
+    }
+}
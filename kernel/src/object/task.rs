@@ -1,28 +1,112 @@
 use super::{
     address_space::{AddressSpace, TaskSlot},
     alloc_kernel_object_id,
+    channel::ChannelEnd,
     event::Event,
+    port::Port,
     KernelObject,
     KernelObjectId,
     KernelObjectType,
 };
 use crate::{
+    futex::FutexWaiter,
+    quota::{Quota, QuotaExceeded, Quotas},
+    scheduler::{TaskWaker, WaitQueue},
     vmm::{Stack, Vmm},
     Platform,
     Pmm,
 };
-use alloc::{collections::BTreeMap, string::String, sync::Arc};
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
 use core::{
     cell::UnsafeCell,
-    sync::atomic::{AtomicU32, Ordering},
+    convert::TryFrom,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU8, AtomicUsize, Ordering},
+    task::Waker,
 };
 use hal::memory::VAddr;
-use poplar::Handle;
+use poplar::{
+    caps::Capabilities,
+    syscall::{ExitStatus, Priority},
+    Handle,
+    HandleRights,
+};
 use spinning_top::{RwSpinlock, Spinlock};
 
 #[derive(Clone, Debug)]
 pub enum TaskBlock {
     OnEvent(Arc<Event>),
+    /// The task is waiting for a message to arrive on a `ChannelEnd`, or for the other end to be closed.
+    OnChannel(Arc<ChannelEnd>),
+    /// The task is waiting for a packet to be queued on a `Port`.
+    OnPort(Arc<Port>),
+    /// The task is waiting for the reply to a call it made through a `ChannelEnd`, or for the other end to be
+    /// closed.
+    OnCall(Arc<ChannelEnd>, u16),
+    /// The task is waiting on a futex, until it is woken or its timeout expires.
+    OnFutex(Arc<FutexWaiter>),
+    /// The task is waiting for another task to exit. This holds the other task's `ExitSignal`, rather than the
+    /// task itself, so `TaskBlock` doesn't need to be generic over the platform.
+    OnTaskExit(Arc<ExitSignal>),
+}
+
+impl TaskBlock {
+    /// Register `waker` with whatever the task is blocked on, so it's woken when the condition may have been met.
+    /// Returns `false` if the condition can be met without anything waking the task (e.g. because it depends on
+    /// a deadline passing), in which case the scheduler has to poll it with `can_wake`.
+    pub fn register(&self, waker: &Waker) -> bool {
+        match self {
+            TaskBlock::OnEvent(event) => {
+                event.waiters.register(waker);
+                true
+            }
+            TaskBlock::OnChannel(channel) | TaskBlock::OnCall(channel, _) => {
+                channel.waiters.register(waker);
+                true
+            }
+            TaskBlock::OnPort(port) => {
+                port.waiters.register(waker);
+                true
+            }
+            TaskBlock::OnFutex(waiter) => {
+                waiter.waiters.register(waker);
+                !waiter.has_deadline()
+            }
+            TaskBlock::OnTaskExit(exited) => {
+                exited.waiters.register(waker);
+                true
+            }
+        }
+    }
+
+    /// Returns `true` if the condition the task is blocked on has been met, and so the task can be woken.
+    pub fn can_wake(&self) -> bool {
+        match self {
+            TaskBlock::OnEvent(event) => event.signalled.load(Ordering::SeqCst),
+            TaskBlock::OnChannel(channel) => !channel.messages.lock().is_empty() || channel.is_peer_closed(),
+            TaskBlock::OnPort(port) => port.has_packets(),
+            TaskBlock::OnCall(channel, transaction) => channel.has_reply(*transaction) || channel.is_peer_closed(),
+            TaskBlock::OnFutex(waiter) => waiter.can_wake(),
+            TaskBlock::OnTaskExit(exited) => exited.has_exited(),
+        }
+    }
+}
+
+/// Records whether a task has exited, and wakes the tasks waiting for it to when it does.
+#[derive(Default, Debug)]
+pub struct ExitSignal {
+    exited: AtomicBool,
+    waiters: WaitQueue,
+}
+
+impl ExitSignal {
+    pub fn has_exited(&self) -> bool {
+        self.exited.load(Ordering::SeqCst)
+    }
+
+    fn signal(&self) {
+        self.exited.store(true, Ordering::SeqCst);
+        self.waiters.wake_all();
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -30,6 +114,8 @@ pub enum TaskState {
     Ready,
     Running,
     Blocked(TaskBlock),
+    /// The task has exited, and will not be scheduled again.
+    Exited(ExitStatus),
 }
 
 impl TaskState {
@@ -53,6 +139,13 @@ impl TaskState {
             _ => false,
         }
     }
+
+    pub fn is_exited(&self) -> bool {
+        match self {
+            TaskState::Exited(_) => true,
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -77,15 +170,35 @@ where
     id: KernelObjectId,
     owner: KernelObjectId,
     pub name: String,
-    pub address_space: Arc<AddressSpace<P>>,
+    /// The address space the task runs in. This is `None` once the task has exited and its resources have been
+    /// released, so the address space can be freed once the last task in it is gone, even if handles to this
+    /// task are still held.
+    address_space: Spinlock<Option<Arc<AddressSpace<P>>>>,
     pub state: Spinlock<TaskState>,
+    /// Signalled when the task exits. This is shared with tasks that are blocked waiting for this one to exit.
+    pub exited: Arc<ExitSignal>,
+    /// The capabilities the task has been granted. These are fixed when the task is created.
+    pub capabilities: Capabilities,
+    /// The limits on the resources the task can use.
+    pub quotas: Quotas,
+    /// The task's current `Priority`. This should only be changed through the scheduler, so that the task is
+    /// moved to the right queue.
+    priority: AtomicU8,
+    /// The highest priority the task can be given. This is set by the task's owner when it is created.
+    pub max_priority: Priority,
 
-    pub user_slot: Spinlock<TaskSlot>,
-    pub kernel_stack: Spinlock<Stack>,
+    /// The task's slot in its address space, and its kernel stack. These are `None` once the task has exited
+    /// and they have been freed.
+    pub user_slot: Spinlock<Option<TaskSlot>>,
+    pub kernel_stack: Spinlock<Option<Stack>>,
 
     pub context: UnsafeCell<P::TaskContext>,
 
-    pub handles: Handles,
+    /// The task's handles. Threads created with `new_thread` share the handles of the task that created them.
+    pub handles: Arc<Handles>,
+
+    /// Registered with the kernel objects the task blocks on, so they can wake it. See `TaskWaker`.
+    pub waker: Waker,
 }
 
 /*
@@ -100,37 +213,159 @@ impl<P> Task<P>
 where
     P: Platform,
 {
+    /// Decode the capabilities encoded in a task's image.
+    pub fn decode_capabilities(encoding: &[u8]) -> Result<Capabilities, TaskCreationError> {
+        Capabilities::decode(encoding).ok_or(TaskCreationError::InvalidCapabilityEncoding)
+    }
+
     pub fn new(
         owner: KernelObjectId,
         address_space: Arc<AddressSpace<P>>,
         name: String,
         entry_point: VAddr,
+        capabilities: Capabilities,
+        quotas: Quotas,
+        max_priority: Priority,
         handles: Handles,
         allocator: &Pmm,
         vmm: &Vmm<P>,
+    ) -> Result<Arc<Task<P>>, TaskCreationError> {
+        Task::create(
+            owner,
+            address_space,
+            name,
+            capabilities,
+            quotas,
+            Priority::min(Priority::Normal, max_priority),
+            max_priority,
+            Arc::new(handles),
+            entry_point,
+            0,
+            VAddr::new(0x0),
+            allocator,
+            vmm,
+        )
+    }
+
+    /// Create a new thread in `parent`'s address space. It shares `parent`'s handles, quotas, and capabilities,
+    /// starts with the same priority, and starts running at `entry_point`, with `argument` passed as its first
+    /// argument and its thread pointer set to `thread_pointer`.
+    pub fn new_thread(
+        parent: &Task<P>,
+        entry_point: VAddr,
+        argument: usize,
+        thread_pointer: VAddr,
+        allocator: &Pmm,
+        vmm: &Vmm<P>,
+    ) -> Result<Arc<Task<P>>, TaskCreationError> {
+        Task::create(
+            parent.id,
+            parent.address_space(),
+            parent.name.clone(),
+            parent.capabilities,
+            parent.quotas.clone(),
+            parent.priority(),
+            parent.max_priority,
+            parent.handles.share(),
+            entry_point,
+            argument,
+            thread_pointer,
+            allocator,
+            vmm,
+        )
+        .inspect_err(|_| parent.handles.release())
+    }
+
+    fn create(
+        owner: KernelObjectId,
+        address_space: Arc<AddressSpace<P>>,
+        name: String,
+        capabilities: Capabilities,
+        quotas: Quotas,
+        priority: Priority,
+        max_priority: Priority,
+        handles: Arc<Handles>,
+        entry_point: VAddr,
+        argument: usize,
+        thread_pointer: VAddr,
+        allocator: &Pmm,
+        vmm: &Vmm<P>,
     ) -> Result<Arc<Task<P>>, TaskCreationError> {
         let id = alloc_kernel_object_id();
 
         // TODO: better way of getting initial stack sizes
         let task_slot =
             address_space.alloc_task_slot(0x8000, allocator).ok_or(TaskCreationError::AddressSpaceFull)?;
-        let kernel_stack = vmm.alloc_kernel_stack(0x4000).ok_or(TaskCreationError::NoKernelStackSlots)?;
+        let kernel_stack = match vmm.alloc_kernel_stack(0x4000) {
+            Some(kernel_stack) => kernel_stack,
+            None => {
+                address_space.free_task_slot(task_slot, allocator);
+                return Err(TaskCreationError::NoKernelStackSlots);
+            }
+        };
 
-        let context = P::new_task_context(&kernel_stack, &task_slot.user_stack, entry_point);
+        let context =
+            P::new_task_context(&kernel_stack, &task_slot.user_stack, entry_point, argument, thread_pointer);
 
-        Ok(Arc::new(Task {
+        Ok(Arc::new_cyclic(|task| Task {
             id,
             owner,
             name,
-            address_space,
+            address_space: Spinlock::new(Some(address_space)),
             state: Spinlock::new(TaskState::Ready),
-            user_slot: Spinlock::new(task_slot),
-            kernel_stack: Spinlock::new(kernel_stack),
+            exited: Arc::new(ExitSignal::default()),
+            capabilities,
+            quotas,
+            priority: AtomicU8::new(priority as u8),
+            max_priority,
+            user_slot: Spinlock::new(Some(task_slot)),
+            kernel_stack: Spinlock::new(Some(kernel_stack)),
             context: UnsafeCell::new(context),
 
             handles,
+            waker: TaskWaker::new_waker(task.clone()),
         }))
     }
+
+    /// Get the address space the task runs in. Panics if the task has exited and its resources have been
+    /// released.
+    pub fn address_space(&self) -> Arc<AddressSpace<P>> {
+        self.address_space.lock().clone().expect("Task's address space has already been released")
+    }
+
+    /// Mark the task as having exited with the given status. This wakes any tasks waiting for it to exit, so
+    /// must not be called with the scheduler locked.
+    pub fn mark_exited(&self, status: ExitStatus) {
+        *self.state.lock() = TaskState::Exited(status);
+        self.exited.signal();
+    }
+
+    pub fn priority(&self) -> Priority {
+        Priority::try_from(self.priority.load(Ordering::Relaxed) as usize).unwrap()
+    }
+
+    /// Change the task's priority. This doesn't move the task between the scheduler's queues, so should only be
+    /// called by the scheduler - use `Scheduler::set_priority` instead.
+    pub(crate) fn store_priority(&self, priority: Priority) {
+        self.priority.store(priority as u8, Ordering::Relaxed);
+    }
+
+    /// Release the resources held by a task that has exited - its handles (unless they're shared with other
+    /// threads that are still running), user stack slot, kernel stack, and its reference to its address space.
+    /// The task must not be running, as this frees the kernel stack it would be running on. This must only be
+    /// called once for each task.
+    pub fn release_resources(&self, allocator: &Pmm, vmm: &Vmm<P>) {
+        assert!(self.state.lock().is_exited());
+
+        self.handles.release();
+        let address_space = self.address_space.lock().take().unwrap();
+        if let Some(slot) = self.user_slot.lock().take() {
+            address_space.free_task_slot(slot, allocator);
+        }
+        if let Some(kernel_stack) = self.kernel_stack.lock().take() {
+            vmm.free_kernel_stack(kernel_stack);
+        }
+    }
 }
 
 impl<P> KernelObject for Task<P>
@@ -144,33 +379,144 @@ where
     fn typ(&self) -> KernelObjectType {
         KernelObjectType::Task
     }
+
+    fn owner(&self) -> KernelObjectId {
+        self.owner
+    }
+
+    fn poll_ready(&self) -> Option<bool> {
+        Some(self.state.lock().is_exited())
+    }
+
+    fn waiters(&self) -> Option<&WaitQueue> {
+        Some(&self.exited.waiters)
+    }
+}
+
+/// The reasons `Handles::remove_all` can fail.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RemoveHandlesError {
+    /// One of the handles does not exist.
+    InvalidHandle,
+    /// The same handle appears more than once.
+    DuplicateHandle,
+    /// One of the handles does not have the required rights.
+    MissingRights,
 }
 
 pub struct Handles {
-    handles: RwSpinlock<BTreeMap<Handle, Arc<dyn KernelObject>>>,
+    handles: RwSpinlock<BTreeMap<Handle, (Arc<dyn KernelObject>, HandleRights)>>,
     next: AtomicU32,
+    /// Each handle in this set is charged against this quota.
+    quota: Arc<Quota>,
+    /// The number of tasks using this set that have not yet exited. The set is cleared when this reaches zero,
+    /// which breaks any reference cycles through it (e.g. a task that holds a handle to one of its own threads).
+    users: AtomicUsize,
 }
 
 impl Handles {
-    pub fn new() -> Handles {
+    pub fn new(quota: Arc<Quota>) -> Handles {
         Handles {
             handles: RwSpinlock::new(BTreeMap::new()),
             // XXX: 0 is a special handle value, so start at 1
             next: AtomicU32::new(1),
+            quota,
+            users: AtomicUsize::new(1),
         }
     }
 
-    pub fn add(&self, object: Arc<dyn KernelObject>) -> Handle {
+    /// Share this set with another task. That task must call `release` when it exits.
+    pub fn share(self: &Arc<Self>) -> Arc<Handles> {
+        self.users.fetch_add(1, Ordering::Relaxed);
+        self.clone()
+    }
+
+    /// Stop using this set, because a task using it has exited. The set is cleared if no other task is using it.
+    pub fn release(&self) {
+        if self.users.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.clear();
+        }
+    }
+
+    /// Add a handle to `object` with every right.
+    pub fn add(&self, object: Arc<dyn KernelObject>) -> Result<Handle, QuotaExceeded> {
+        self.add_with_rights(object, HandleRights::all())
+    }
+
+    /// Add a handle to `object` with the given rights. Fails if the set's owner can't hold any more handles.
+    pub fn add_with_rights(
+        &self,
+        object: Arc<dyn KernelObject>,
+        rights: HandleRights,
+    ) -> Result<Handle, QuotaExceeded> {
+        self.quota.acquire(1)?;
         let handle_num = self.next.fetch_add(1, Ordering::Relaxed);
-        self.handles.write().insert(Handle(handle_num), object);
-        Handle(handle_num)
+        self.handles.write().insert(Handle(handle_num), (object, rights));
+        Ok(Handle(handle_num))
     }
 
-    pub fn remove(&self, handle: Handle) {
-        self.handles.write().remove(&handle);
+    /// Remove a handle from this set, returning the kernel object it referred to and the handle's rights (if it
+    /// existed).
+    pub fn remove(&self, handle: Handle) -> Option<(Arc<dyn KernelObject>, HandleRights)> {
+        let removed = self.handles.write().remove(&handle);
+        if removed.is_some() {
+            self.quota.release(1);
+        }
+        removed
+    }
+
+    /// Remove several handles from this set at once, returning the kernel objects they referred to and the
+    /// handles' rights, in the same order. Every handle must exist, appear only once, and have all of the
+    /// `required` rights - if any doesn't, no handles are removed.
+    pub fn remove_all(
+        &self,
+        handles: &[Handle],
+        required: HandleRights,
+    ) -> Result<Vec<(Arc<dyn KernelObject>, HandleRights)>, RemoveHandlesError> {
+        /*
+         * Hold the write lock over both checking and removing the handles, so another thread sharing this set
+         * can't change them in between.
+         */
+        let mut map = self.handles.write();
+        for (i, handle) in handles.iter().enumerate() {
+            if handles[..i].contains(handle) {
+                return Err(RemoveHandlesError::DuplicateHandle);
+            }
+            let (_, rights) = map.get(handle).ok_or(RemoveHandlesError::InvalidHandle)?;
+            if !rights.contains(required) {
+                return Err(RemoveHandlesError::MissingRights);
+            }
+        }
+        let removed: Vec<_> = handles.iter().map(|handle| map.remove(handle).unwrap()).collect();
+        drop(map);
+
+        self.quota.release(removed.len());
+        Ok(removed)
     }
 
     pub fn get(&self, handle: Handle) -> Option<Arc<dyn KernelObject>> {
+        self.handles.read().get(&handle).map(|(object, _rights)| object.clone())
+    }
+
+    pub fn get_with_rights(&self, handle: Handle) -> Option<(Arc<dyn KernelObject>, HandleRights)> {
         self.handles.read().get(&handle).cloned()
     }
+
+    /// Remove every handle from this set, releasing the kernel objects they referred to.
+    pub fn clear(&self) {
+        /*
+         * Take the handles out of the lock before dropping them, as dropping a kernel object may need to take
+         * other locks.
+         */
+        let handles = core::mem::take(&mut *self.handles.write());
+        self.quota.release(handles.len());
+        drop(handles);
+    }
+}
+
+impl Drop for Handles {
+    fn drop(&mut self) {
+        // Release the charges for any handles left in the set, e.g. if a task failed to be created
+        self.quota.release(self.handles.get_mut().len());
+    }
 }
@@ -0,0 +1,99 @@
+use super::{alloc_kernel_object_id, KernelObject, KernelObjectId, KernelObjectType};
+use crate::scheduler::WaitQueue;
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use spinning_top::Spinlock;
+
+/// The timers that have a deadline, but haven't fired yet. Nothing else notices the clock reaching a timer's
+/// deadline, so these are checked regularly by the scheduler (see `fire_expired_timers`).
+static ARMED_TIMERS: Spinlock<Vec<Weak<Timer>>> = Spinlock::new(Vec::new());
+
+/// A `Timer` fires once the monotonic clock reaches its deadline. It stays fired until it is given a new deadline
+/// or disarmed.
+#[derive(Debug)]
+pub struct Timer {
+    pub id: KernelObjectId,
+    pub owner: KernelObjectId,
+    /// The time, in nanoseconds since boot, at which the timer fires. `None` if the timer is disarmed.
+    deadline: Spinlock<Option<u64>>,
+    /// Reads the current time, in nanoseconds since boot. This is the platform's `Clocksource`.
+    now: fn() -> u64,
+    /// Woken when the timer fires.
+    pub waiters: WaitQueue,
+}
+
+impl Timer {
+    pub fn new(owner: KernelObjectId, now: fn() -> u64) -> Arc<Timer> {
+        Arc::new(Timer {
+            id: alloc_kernel_object_id(),
+            owner,
+            deadline: Spinlock::new(None),
+            now,
+            waiters: WaitQueue::new(),
+        })
+    }
+
+    /// Set the deadline of the timer, replacing any previous deadline. Passing `None` disarms the timer.
+    pub fn set(self: &Arc<Self>, deadline: Option<u64>) {
+        *self.deadline.lock() = deadline;
+
+        let this = Arc::downgrade(self);
+        let mut armed = ARMED_TIMERS.lock();
+        armed.retain(|timer| !Weak::ptr_eq(timer, &this));
+        if deadline.is_some() {
+            armed.push(this);
+        }
+    }
+
+    pub fn has_fired(&self) -> bool {
+        match *self.deadline.lock() {
+            Some(deadline) => (self.now)() >= deadline,
+            None => false,
+        }
+    }
+}
+
+impl KernelObject for Timer {
+    fn id(&self) -> KernelObjectId {
+        self.id
+    }
+
+    fn typ(&self) -> KernelObjectType {
+        KernelObjectType::Timer
+    }
+
+    fn owner(&self) -> KernelObjectId {
+        self.owner
+    }
+
+    fn poll_ready(&self) -> Option<bool> {
+        Some(self.has_fired())
+    }
+
+    fn waiters(&self) -> Option<&WaitQueue> {
+        Some(&self.waiters)
+    }
+}
+
+/// Wake anything waiting for an armed timer whose deadline has passed. This must not be called with the
+/// scheduler locked, as waking a task makes it ready.
+pub fn fire_expired_timers() {
+    let fired = {
+        let mut fired = Vec::new();
+        ARMED_TIMERS.lock().retain(|timer| match timer.upgrade() {
+            Some(timer) if timer.has_fired() => {
+                fired.push(timer);
+                false
+            }
+            Some(_) => true,
+            None => false,
+        });
+        fired
+    };
+
+    for timer in fired {
+        timer.waiters.wake_all();
+    }
+}
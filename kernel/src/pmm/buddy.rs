@@ -113,6 +113,29 @@ impl BuddyAllocator {
         self.free_block(base, order);
     }
 
+    /// Shrink an allocation of `count` base-blocks starting at `base` to `new_count` base-blocks, freeing the
+    /// blocks it no longer needs. Allocations are rounded up to a power-of-2 number of blocks, so this keeps a
+    /// block of `new_count.next_power_of_two()` at `base`, which can later be freed by passing `new_count` to
+    /// `free`. If `new_count` is zero, the whole allocation is freed.
+    pub fn shrink(&mut self, base: PAddr, count: usize, new_count: usize) {
+        assert!(new_count <= count);
+        let allocated = count.next_power_of_two();
+        if new_count == 0 {
+            self.free(base, allocated);
+            return;
+        }
+
+        /*
+         * The rest of the allocation after the kept block is made up of blocks that are each double the size of
+         * the last, each of which is correctly aligned for its order.
+         */
+        let mut block_size = new_count.next_power_of_two();
+        while block_size < allocated {
+            self.free_block(base + block_size * BASE_SIZE, block_size.trailing_zeros() as usize);
+            block_size *= 2;
+        }
+    }
+
     /// Tries to allocate a block of the given order. If no blocks of the correct size are
     /// available, tries to recursively split a larger block to form a block of the requested size.
     fn allocate_block(&mut self, order: usize) -> Option<PAddr> {
@@ -404,4 +427,33 @@ mod tests {
         // Allocate another frame - this should force a larger block to split
         assert_eq!(allocator.alloc(1), Some(PAddr::new(0x8000).unwrap()));
     }
+
+    #[test]
+    fn test_shrink() {
+        let mut allocator = BuddyAllocator::new();
+        allocator.free_range(n_frames_at(0x0, 16));
+        assert_eq!(allocator.alloc(16), Some(PAddr::new(0x0).unwrap()));
+
+        /*
+         * Shrinking to 3 frames keeps an order-2 block, and frees the rest of the allocation as well-aligned
+         * blocks.
+         */
+        allocator.shrink(PAddr::new(0x0).unwrap(), 16, 3);
+        check_bins(allocator.clone(), vec![Block::new(2, 0x4000), Block::new(3, 0x8000)]);
+
+        allocator.shrink(PAddr::new(0x0).unwrap(), 3, 1);
+        check_bins(
+            allocator.clone(),
+            vec![Block::new(0, 0x1000), Block::new(1, 0x2000), Block::new(2, 0x4000), Block::new(3, 0x8000)],
+        );
+
+        // Freeing the rest of the allocation with its new size coalesces it back into the original block
+        allocator.free(PAddr::new(0x0).unwrap(), 1);
+        check_bins(allocator.clone(), vec![Block::new(4, 0x0)]);
+
+        // Shrinking to no frames frees the whole allocation
+        assert_eq!(allocator.alloc(5), Some(PAddr::new(0x0).unwrap()));
+        allocator.shrink(PAddr::new(0x0).unwrap(), 5, 0);
+        check_bins(allocator, vec![Block::new(4, 0x0)]);
+    }
 }
@@ -27,17 +27,29 @@ impl Pmm {
         Pmm { buddy: Spinlock::new(buddy_allocator) }
     }
 
-    /// Allocate `count` frames.
-    pub fn alloc(&self, count: usize) -> PAddr {
-        self.buddy.lock().alloc(count).expect("Failed to allocate requested physical memory")
+    /// Allocate `count` frames. Returns `None` if there isn't a large enough contiguous block of free memory.
+    pub fn alloc(&self, count: usize) -> Option<PAddr> {
+        self.buddy.lock().alloc(count)
     }
 
-    /// Free `count` frames, starting at address `base`.
+    /// Free `count` frames, starting at address `base`. Allocations are rounded up to a power-of-2 number of
+    /// frames, so `count` is rounded up in the same way here - the same count passed to `alloc` can be used.
     pub fn free(&self, base: PAddr, count: usize) {
-        self.buddy.lock().free(base, count)
+        self.buddy.lock().free(base, count.next_power_of_two())
+    }
+
+    /// Shrink an allocation of `count` frames at `base` to `new_count` frames, freeing the frames it no longer
+    /// needs. The rest of the allocation should later be freed by passing `new_count` to `free`.
+    pub fn shrink(&self, base: PAddr, count: usize, new_count: usize) {
+        self.buddy.lock().shrink(base, count, new_count)
     }
 }
 
+/*
+ * The page table implementations allocate their tables with `try_allocate`, so running out of memory while mapping
+ * is reported as an error. `allocate_n` is infallible, and is only used where the kernel can't recover anyway (e.g.
+ * while it's booting). Other allocations the kernel can fail gracefully should use `Pmm::alloc` instead.
+ */
 impl<S> FrameAllocator<S> for Pmm
 where
     S: FrameSize,
@@ -48,6 +60,10 @@ where
         Frame::<S>::starts_with(start)..(Frame::<S>::starts_with(start) + n)
     }
 
+    fn try_allocate(&self) -> Option<Frame<S>> {
+        self.buddy.lock().alloc(S::SIZE / Size4KiB::SIZE).map(Frame::<S>::starts_with)
+    }
+
     fn free_n(&self, start: Frame<S>, num_frames: usize) {
         self.buddy.lock().free(start.start, num_frames * S::SIZE / Size4KiB::SIZE);
     }
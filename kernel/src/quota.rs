@@ -0,0 +1,161 @@
+//! Quotas limit how much of the system's resources each task can use, so that a single misbehaving task can't
+//! exhaust physical memory (or the kernel's other resources) and take down the rest of the system.
+//!
+//! Quotas are hierarchical. The quotas of a spawned task are children of its parent's quotas, and everything
+//! charged to a child is also charged to its parent. This means a task can't get around its own limits by
+//! spawning more tasks - it and all of its descendants share them.
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The number of frames of physical memory a task can allocate through the `MemoryObject`s it creates.
+pub const DEFAULT_FRAME_LIMIT: usize = 16384;
+/// The number of handles a task can hold at once.
+pub const DEFAULT_HANDLE_LIMIT: usize = 1024;
+/// The number of bytes a task can have queued in `Channel`s at once, in messages it has sent that have not yet
+/// been received.
+pub const DEFAULT_CHANNEL_BYTES_LIMIT: usize = 0x10_0000;
+
+#[derive(Clone, Copy, Debug)]
+pub struct QuotaExceeded;
+
+/// Tracks how much of a resource is in use against a limit. Quotas are shared (through an `Arc`) by the objects
+/// that hold resources charged to them, as those objects can outlive the task the quota belongs to.
+#[derive(Debug)]
+pub struct Quota {
+    limit: usize,
+    used: AtomicUsize,
+    /// Everything acquired from this quota is also acquired from its parent, if it has one.
+    parent: Option<Arc<Quota>>,
+}
+
+impl Quota {
+    pub fn new(limit: usize) -> Arc<Quota> {
+        Arc::new(Quota { limit, used: AtomicUsize::new(0), parent: None })
+    }
+
+    /// Create a quota with its own `limit`, which also charges everything acquired from it to `parent`.
+    pub fn new_child(parent: &Arc<Quota>, limit: usize) -> Arc<Quota> {
+        Arc::new(Quota { limit, used: AtomicUsize::new(0), parent: Some(parent.clone()) })
+    }
+
+    /// Use `amount` more of the resource, failing if that would exceed the limit of this quota or of any of its
+    /// parents.
+    pub fn acquire(&self, amount: usize) -> Result<(), QuotaExceeded> {
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                used.checked_add(amount).filter(|&new_used| new_used <= self.limit)
+            })
+            .map_err(|_| QuotaExceeded)?;
+
+        if let Some(ref parent) = self.parent {
+            if let Err(err) = parent.acquire(amount) {
+                self.used.fetch_sub(amount, Ordering::SeqCst);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop using `amount` of the resource, which must have been acquired previously.
+    pub fn release(&self, amount: usize) {
+        let previous = self.used.fetch_sub(amount, Ordering::SeqCst);
+        assert!(previous >= amount, "Released more of a quota than was acquired");
+        if let Some(ref parent) = self.parent {
+            parent.release(amount);
+        }
+    }
+
+    /// Acquire `amount` of the resource, returning a `Charge` that releases it again when dropped.
+    pub fn charge(self: &Arc<Self>, amount: usize) -> Result<Charge, QuotaExceeded> {
+        self.acquire(amount)?;
+        Ok(Charge { quota: self.clone(), amount })
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::SeqCst)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+}
+
+/// An amount of a resource that has been acquired from a `Quota`, and is released when this is dropped. This
+/// allows objects to hold on to resources charged to a task without needing to know about the task.
+#[derive(Debug)]
+pub struct Charge {
+    quota: Arc<Quota>,
+    amount: usize,
+}
+
+impl Charge {
+    /// Create an empty charge against `quota`, which can be grown later.
+    pub fn new(quota: Arc<Quota>) -> Charge {
+        Charge { quota, amount: 0 }
+    }
+
+    /// Acquire `amount` more of the resource as part of this charge.
+    pub fn grow(&mut self, amount: usize) -> Result<(), QuotaExceeded> {
+        self.quota.acquire(amount)?;
+        self.amount += amount;
+        Ok(())
+    }
+
+    /// Release `amount` of the resource held by this charge.
+    pub fn shrink(&mut self, amount: usize) {
+        assert!(amount <= self.amount);
+        self.quota.release(amount);
+        self.amount -= amount;
+    }
+
+    /// Move `amount` of the resource held by this charge into a new `Charge`, without releasing it.
+    pub fn split_off(&mut self, amount: usize) -> Charge {
+        assert!(amount <= self.amount);
+        self.amount -= amount;
+        Charge { quota: self.quota.clone(), amount }
+    }
+
+    pub fn quota(&self) -> &Arc<Quota> {
+        &self.quota
+    }
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        self.quota.release(self.amount);
+    }
+}
+
+/// The set of quotas a task's resource usage is checked against.
+#[derive(Clone, Debug)]
+pub struct Quotas {
+    /// Frames of physical memory backing `MemoryObject`s the task has created.
+    pub frames: Arc<Quota>,
+    /// Handles held by the task.
+    pub handles: Arc<Quota>,
+    /// Bytes of messages the task has sent down `Channel`s that have not yet been received.
+    pub channel_bytes: Arc<Quota>,
+}
+
+impl Quotas {
+    /// Create the quotas for the root of the task hierarchy (the bootstrap task). These are only limited by what
+    /// the system has, but every task spawned below it is given the default limits by `new_child`.
+    pub fn new_root() -> Quotas {
+        Quotas {
+            frames: Quota::new(usize::MAX),
+            handles: Quota::new(usize::MAX),
+            channel_bytes: Quota::new(usize::MAX),
+        }
+    }
+
+    /// Create the quotas for a task spawned by the task that owns these quotas. The new task has the default
+    /// limits, and everything charged to it is also charged to these quotas.
+    pub fn new_child(&self) -> Quotas {
+        Quotas {
+            frames: Quota::new_child(&self.frames, DEFAULT_FRAME_LIMIT),
+            handles: Quota::new_child(&self.handles, DEFAULT_HANDLE_LIMIT),
+            channel_bytes: Quota::new_child(&self.channel_bytes, DEFAULT_CHANNEL_BYTES_LIMIT),
+        }
+    }
+}
@@ -1,12 +1,28 @@
 use crate::{
-    object::task::{Task, TaskState},
+    clocksource::Clocksource,
+    object::{
+        task::{Task, TaskBlock, TaskState},
+        timer,
+    },
     tasklets::TaskletScheduler,
+    vmm::Vmm,
     Platform,
 };
-use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use alloc::{
+    collections::VecDeque,
+    sync::{Arc, Weak},
+    task::Wake,
+    vec::Vec,
+};
+use core::{convert::TryFrom, mem, task::Waker};
+use poplar::syscall::{ExitStatus, Priority};
 use spinning_top::{guard::SpinlockGuard, Spinlock};
 use tracing::{info, trace};
 
+/// How long a task can run for, in nanoseconds, before it is pre-empted in favour of another ready task with the
+/// same priority. Tasks are only pre-empted on the platform's timer tick, so this is rounded up to the next tick.
+const TIMESLICE_NANOS: u64 = 20_000_000;
+
 /// The global `Scheduler` coordinates the main 'run loop' of the kernel, allocating CPU time to
 /// userspace tasks. There is one global `Scheduler` instance, which then holds a `CpuScheduler`
 /// for each running processor to coordinate tasks running on that processor.
@@ -32,9 +48,19 @@ where
     P: Platform,
 {
     pub running_task: Option<Arc<Task<P>>>,
-    /// List of Tasks ready to be scheduled. Backed by a `VecDeque` so we can rotate objects in the queue efficiently.
-    ready_queue: VecDeque<Arc<Task<P>>>,
+    /// The time, in nanoseconds since boot, at which the running task's timeslice ends.
+    timeslice_end: u64,
+    /// Lists of Tasks ready to be scheduled, one for each `Priority`. Backed by `VecDeque`s so we can rotate
+    /// objects in the queues efficiently.
+    ready_queues: [VecDeque<Arc<Task<P>>>; Priority::NUM_PRIORITIES],
+    /// Tasks that are blocked until the kernel object they're blocked on wakes them.
     blocked_queue: Vec<Arc<Task<P>>>,
+    /// Tasks that are blocked on something that can't wake them (for example, a deadline passing). These are
+    /// polled each time the scheduler runs, and made ready once they can make progress.
+    polled_queue: Vec<Arc<Task<P>>>,
+    /// Tasks that have exited, but whose resources have not yet been released. An exiting task is still running
+    /// on its kernel stack, so it can't free it itself - instead, this is done later from another task.
+    exited_tasks: Vec<Arc<Task<P>>>,
 }
 
 impl<P> CpuScheduler<P>
@@ -42,13 +68,97 @@ where
     P: Platform,
 {
     pub fn new() -> CpuScheduler<P> {
-        CpuScheduler { running_task: None, ready_queue: VecDeque::new(), blocked_queue: Vec::new() }
+        CpuScheduler {
+            running_task: None,
+            timeslice_end: 0,
+            ready_queues: core::array::from_fn(|_| VecDeque::new()),
+            blocked_queue: Vec::new(),
+            polled_queue: Vec::new(),
+            exited_tasks: Vec::new(),
+        }
     }
 
-    /// Choose the next task to be run. Returns `None` if no suitable task could be found to be run.
+    /// Choose the next task to be run - the task that has been waiting longest out of those with the highest
+    /// priority. Returns `None` if no suitable task could be found to be run.
     fn choose_next(&mut self) -> Option<Arc<Task<P>>> {
-        // TODO: in the future, this should consider task priorities etc.
-        self.ready_queue.pop_front()
+        self.poll_blocked_tasks();
+        self.ready_queues.iter_mut().rev().find_map(|queue| queue.pop_front())
+    }
+
+    /// The priority of the highest-priority task that is ready to run, if there are any.
+    fn highest_ready_priority(&self) -> Option<Priority> {
+        self.ready_queues
+            .iter()
+            .rposition(|queue| !queue.is_empty())
+            .map(|index| Priority::try_from(index).unwrap())
+    }
+
+    fn push_ready(&mut self, task: Arc<Task<P>>) {
+        self.ready_queues[task.priority() as usize].push_back(task);
+    }
+
+    /// Block `task`, which must not be on any of the queues. A waker for the task is registered with whatever
+    /// it's blocked on, and it's only polled by the scheduler if that can't wake it.
+    fn block(&mut self, task: Arc<Task<P>>, block: TaskBlock) {
+        let wakes_task = block.register(&task.waker);
+
+        /*
+         * The condition the task is blocked on may have been met before the waker was registered, in which case
+         * nothing is going to wake it. If so, it can run again straight away.
+         */
+        if block.can_wake() {
+            *task.state.lock() = TaskState::Ready;
+            self.push_ready(task);
+            return;
+        }
+
+        *task.state.lock() = TaskState::Blocked(block);
+        if wakes_task {
+            self.blocked_queue.push(task);
+        } else {
+            self.polled_queue.push(task);
+        }
+    }
+
+    /// Move `task` onto the ready queues, if it's blocked. Tasks can be woken when the condition they were
+    /// blocked on has not actually been met (or has since stopped being met), so they must check it again
+    /// once they're running.
+    fn wake(&mut self, task: &Arc<Task<P>>) {
+        let task = if let Some(index) = self.blocked_queue.iter().position(|other| Arc::ptr_eq(other, task)) {
+            self.blocked_queue.swap_remove(index)
+        } else if let Some(index) = self.polled_queue.iter().position(|other| Arc::ptr_eq(other, task)) {
+            self.polled_queue.swap_remove(index)
+        } else {
+            return;
+        };
+
+        trace!("Waking task: {}", task.name);
+        *task.state.lock() = TaskState::Ready;
+        self.push_ready(task);
+    }
+
+    /// Move any polled tasks that can now make progress onto the ready queues.
+    fn poll_blocked_tasks(&mut self) {
+        let ready_queues = &mut self.ready_queues;
+        self.polled_queue.retain(|task| {
+            /*
+             * Don't hold the task's state lock while checking its block, as checking it can lock other objects
+             * (e.g. a `Port` polls the objects bound to it, which can include `Task`s).
+             */
+            let block = match *task.state.lock() {
+                TaskState::Blocked(ref block) => block.clone(),
+                _ => panic!("Task in polled queue is not blocked!"),
+            };
+            let can_wake = block.can_wake();
+
+            if can_wake {
+                trace!("Waking task: {}", task.name);
+                *task.state.lock() = TaskState::Ready;
+                ready_queues[task.priority() as usize].push_back(task.clone());
+            }
+
+            !can_wake
+        });
     }
 }
 
@@ -68,12 +178,32 @@ where
 
         let current_state = task.state.lock().clone();
         match current_state {
-            TaskState::Ready => scheduler.ready_queue.push_back(task),
-            TaskState::Blocked(_) => scheduler.blocked_queue.push(task),
+            TaskState::Ready => scheduler.push_ready(task),
+            TaskState::Blocked(block) => scheduler.block(task, block),
             TaskState::Running => panic!("Tried to schedule task that's already running!"),
+            TaskState::Exited(_) => panic!("Tried to schedule task that has exited!"),
+        }
+    }
+
+    /// Change the priority of `task`. If it's waiting to run, it is moved to the back of the queue for its new
+    /// priority.
+    pub fn set_priority(&self, task: &Arc<Task<P>>, priority: Priority) {
+        let mut scheduler = self.for_this_cpu();
+        let old_priority = task.priority();
+        task.store_priority(priority);
+
+        let old_queue = &mut scheduler.ready_queues[old_priority as usize];
+        if let Some(index) = old_queue.iter().position(|other| Arc::ptr_eq(other, task)) {
+            let task = old_queue.remove(index).unwrap();
+            scheduler.push_ready(task);
         }
     }
 
+    /// Make a blocked task ready to run. This is called by the `Waker` registered for the task when it blocked.
+    pub fn wake(&self, task: &Arc<Task<P>>) {
+        self.for_this_cpu().wake(task);
+    }
+
     pub fn for_this_cpu(&self) -> SpinlockGuard<CpuScheduler<P>> {
         // XXX: this will need to take into account which CPU we're running on in the future
         self.task_scheduler.lock()
@@ -108,22 +238,82 @@ where
     /// If the current task is switched away from, it will be placed in the state `new_state`. This
     /// allows the caller to block the current task on a dependency. If a task has been pre-empted
     /// or yields, it should be placed into `TaskState::Ready`.
+    ///
+    /// If there are no other tasks to run, a task that is yielding continues to run. A task that is
+    /// blocking continues to run once whatever it is blocked on has been satisfied, and the CPU is
+    /// idled until then (or until another task becomes ready).
     pub fn schedule(&self, new_state: TaskState) {
-        self.tasklet_scheduler.tick();
+        loop {
+            self.tasklet_scheduler.tick();
+            timer::fire_expired_timers();
+
+            let mut scheduler = self.for_this_cpu();
+            assert!(scheduler.running_task.is_some());
+            if let Some(next_task) = scheduler.choose_next() {
+                Self::switch_to(scheduler, new_state, next_task);
+                return;
+            }
+            drop(scheduler);
+
+            match new_state {
+                TaskState::Ready => {
+                    trace!("No more schedulable tasks. Returning to current one!");
+                    return;
+                }
+                TaskState::Blocked(ref block) if block.can_wake() => return,
+                _ => (),
+            }
 
-        let mut scheduler = self.for_this_cpu();
-        assert!(scheduler.running_task.is_some());
-        if let Some(next_task) = scheduler.choose_next() {
-            Self::switch_to(scheduler, new_state, next_task);
-        } else {
             /*
-             * There aren't any schedulable tasks. For now, we just return to the current one (by
-             * doing nothing here).
-             *
-             * TODO: this should idle the CPU to minimise power use, waking to interrupts + every
-             * so often to run tasklets, and see if any tasks are unblocked.
+             * There is nothing to run, so wait for an interrupt to make something ready. If the current task has
+             * exited, we're still running on its kernel stack, but that's fine as it is only freed once we've
+             * switched away from it.
              */
-            trace!("No more schedulable tasks. Returning to current one!");
+            P::idle();
+        }
+    }
+
+    /// Called by the platform on each tick of its timer that interrupts a userspace task. This pre-empts the
+    /// running task if a task with a higher priority has become ready, or if it has used up its timeslice and
+    /// another task with the same priority is waiting to run. Any `Timer`s whose deadlines have passed are fired
+    /// first, as they may make tasks ready.
+    pub fn timer_tick(&self) {
+        timer::fire_expired_timers();
+
+        let should_preempt = {
+            let mut scheduler = self.for_this_cpu();
+            scheduler.poll_blocked_tasks();
+
+            let running_priority = scheduler.running_task.as_ref().unwrap().priority();
+            let timeslice_expired = P::Clocksource::nanos_since_boot() >= scheduler.timeslice_end;
+            match scheduler.highest_ready_priority() {
+                Some(priority) => {
+                    priority > running_priority || (priority == running_priority && timeslice_expired)
+                }
+                None => false,
+            }
+        };
+
+        if should_preempt {
+            self.schedule(TaskState::Ready);
+        }
+    }
+
+    /// Exit the currently running task with the given status, and switch to another task. The caller must not
+    /// hold any references to the current task, as this never returns to release them. The exited task's
+    /// resources are released later, by `release_exited_tasks`.
+    pub fn exit_current(&self, status: ExitStatus) -> ! {
+        self.schedule(TaskState::Exited(status));
+        unreachable!("Exited task was scheduled again!");
+    }
+
+    /// Release the resources held by tasks that have exited on this CPU. This must be called from a running
+    /// task, so that we are not running on the kernel stack of any of the tasks being released.
+    pub fn release_exited_tasks(&self, vmm: &Vmm<P>) {
+        let exited_tasks = core::mem::take(&mut self.for_this_cpu().exited_tasks);
+        for task in exited_tasks {
+            trace!("Releasing resources of exited task: {}", task.name);
+            task.release_resources(crate::PMM.get(), vmm);
         }
     }
 
@@ -134,14 +324,18 @@ where
 
         *task.state.lock() = TaskState::Running;
         scheduler.running_task = Some(task.clone());
-        task.address_space.switch_to();
+        scheduler.timeslice_end = P::Clocksource::nanos_since_boot() + TIMESLICE_NANOS;
+        task.address_space().switch_to();
 
+        /*
+         * This never returns, so make sure to drop our reference to the task. The scheduler's reference keeps it
+         * alive.
+         */
+        let context = task.context.get() as *const P::TaskContext;
+        drop(task);
         drop(scheduler);
 
-        unsafe {
-            let context = task.context.get() as *const P::TaskContext;
-            P::drop_into_userspace(context)
-        }
+        unsafe { P::drop_into_userspace(context) }
     }
 
     /// This actually performs a context switch between two tasks. It takes ownership of the locked
@@ -164,29 +358,110 @@ where
 
         scheduler.running_task = Some(next_task.clone());
         *scheduler.running_task.as_ref().unwrap().state.lock() = TaskState::Running;
+        scheduler.timeslice_end = P::Clocksource::nanos_since_boot() + TIMESLICE_NANOS;
+        let mut exit_status = None;
         match new_state {
             TaskState::Running => panic!("Tried to switch away from a task to state of Running!"),
             TaskState::Ready => {
                 *current_task.state.lock() = TaskState::Ready;
-                scheduler.ready_queue.push_back(current_task.clone());
+                scheduler.push_ready(current_task.clone());
             }
             TaskState::Blocked(block) => {
                 trace!("Blocking task: {}", current_task.name);
-                *current_task.state.lock() = TaskState::Blocked(block);
-                scheduler.blocked_queue.push(current_task.clone());
+                scheduler.block(current_task.clone(), block);
+            }
+            TaskState::Exited(status) => {
+                trace!("Task '{}' exited: {:?}", current_task.name, status);
+                exit_status = Some(status);
+                scheduler.exited_tasks.push(current_task.clone());
             }
         }
 
-        current_task.address_space.switch_from();
-        next_task.address_space.switch_to();
+        current_task.address_space().switch_from();
+        next_task.address_space().switch_to();
 
         let from_context = current_task.context.get();
         let to_context = scheduler.running_task.as_ref().unwrap().context.get() as *const P::TaskContext;
 
+        /*
+         * An exiting task is only marked as exited once the scheduler has been unlocked, as doing so wakes any
+         * tasks waiting for it to exit.
+         */
         drop(scheduler);
+        if let Some(status) = exit_status {
+            current_task.mark_exited(status);
+        }
+
+        /*
+         * Drop our references to the tasks before switching. If the current task has exited, we never return here
+         * to drop them, which would stop the task from ever being freed. The scheduler's queues keep both tasks
+         * alive.
+         */
+        drop(current_task);
+        drop(next_task);
 
         unsafe {
             P::context_switch(from_context, to_context);
         }
     }
 }
+
+/// Wakes a blocked task by making it ready to run. Kernel objects aren't generic over the platform, so each task
+/// has a `Waker` made from one of these, which is registered with the objects it blocks on. It only holds a weak
+/// reference, so a task isn't kept alive by the objects it was blocked on.
+pub struct TaskWaker<P>(Weak<Task<P>>)
+where
+    P: Platform;
+
+impl<P> TaskWaker<P>
+where
+    P: Platform,
+{
+    pub fn new_waker(task: Weak<Task<P>>) -> Waker {
+        Waker::from(Arc::new(TaskWaker(task)))
+    }
+}
+
+impl<P> Wake for TaskWaker<P>
+where
+    P: Platform,
+{
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if let Some(task) = self.0.upgrade() {
+            P::scheduler().wake(&task);
+        }
+    }
+}
+
+/// The wakers of the tasks that are blocked on a kernel object. When the object becomes ready (e.g. a message
+/// arrives at a `ChannelEnd`), it wakes them all, and they're moved straight onto the ready queues.
+#[derive(Default, Debug)]
+pub struct WaitQueue(Spinlock<Vec<Waker>>);
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        WaitQueue(Spinlock::new(Vec::new()))
+    }
+
+    /// Register `waker` to be woken the next time the object becomes ready. A waker is only registered once,
+    /// however many times its task blocks on the same object.
+    pub fn register(&self, waker: &Waker) {
+        let mut wakers = self.0.lock();
+        if !wakers.iter().any(|other| other.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+    }
+
+    /// Wake every registered waker. They're removed from the queue, and are woken after it has been unlocked, so
+    /// they can register themselves again.
+    pub fn wake_all(&self) {
+        let wakers = mem::take(&mut *self.0.lock());
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
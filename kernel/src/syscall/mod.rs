@@ -1,49 +1,86 @@
 mod validation;
 
 use crate::{
+    clocksource::Clocksource,
+    futex::FUTEXES,
     object::{
-        address_space::AddressSpace,
-        channel::{ChannelEnd, Message},
+        address_space::{AddressSpace, FaultAccess},
+        channel::{ChannelEnd, Message, QueueLimits},
         event::Event,
         interrupt::Interrupt,
         memory_object::MemoryObject,
-        task::{Task, TaskState},
+        port::Port,
+        task::{RemoveHandlesError, Task, TaskBlock, TaskState},
+        timer::Timer,
         KernelObject,
         KernelObjectType,
     },
+    quota::{Charge, Quotas},
     scheduler::Scheduler,
     vmm::Vmm,
     Platform,
 };
-use alloc::{string::ToString, sync::Arc};
+use alloc::{string::ToString, sync::Arc, vec::Vec};
 use bit_field::BitField;
-use core::{convert::TryFrom, sync::atomic::Ordering};
-use hal::memory::{Flags, FrameSize, PAddr, PageTable, Size4KiB, VAddr};
+use core::{convert::TryFrom, mem, sync::atomic::Ordering};
+use hal::memory::{Flags, FrameSize, PAddr, PageTable, PagingError, Size4KiB, VAddr};
 use poplar::{
+    caps::Capabilities,
     syscall::{
         self,
         result::{handle_to_syscall_repr, status_to_syscall_repr, status_with_payload_to_syscall_repr},
         AckInterruptError,
+        BindToPortError,
+        ChannelCallDetails,
+        ChannelCallError,
+        ClearEventError,
+        CloneMemoryObjectError,
+        CloseHandleError,
         CreateAddressSpaceError,
         CreateChannelError,
+        CreateEventError,
         CreateMemoryObjectError,
+        CreatePortError,
+        CreateTimerError,
+        DuplicateHandleError,
         EarlyLogError,
+        ExitStatus,
         FramebufferInfo,
+        FutexWaitError,
+        FutexWakeError,
+        GetExitStatusError,
         GetFramebufferError,
         GetMessageError,
         MapMemoryObjectError,
         MemoryObjectFlags,
+        ObjectDetails,
+        ObjectInfo,
+        ObjectInfoError,
         PciGetInfoError,
         PollInterestError,
+        PortPacket,
+        Priority,
+        ProtectMappingError,
         ResizeMemoryObjectError,
         SendMessageError,
+        SetPriorityError,
+        SetThreadPointerError,
+        SetTimerError,
+        SignalEventError,
+        Signals,
         SpawnTaskDetails,
         SpawnTaskError,
+        SpawnThreadError,
+        TaskStatus,
+        UnmapMemoryObjectError,
         WaitForEventError,
         WaitForInterruptError,
+        WaitForMessageError,
+        WaitPortError,
         CHANNEL_MAX_NUM_HANDLES,
     },
     Handle,
+    HandleRights,
 };
 use tracing::{info, warn};
 use validation::{UserPointer, UserSlice, UserString};
@@ -61,10 +98,14 @@ pub fn handle_syscall<P>(
     c: usize,
     d: usize,
     e: usize,
+    f: usize,
 ) -> usize
 where
     P: Platform,
 {
+    // Release the resources of any tasks that have exited since we were last in the kernel
+    scheduler.release_exited_tasks(vmm);
+
     // Clone the current task out of the scheduler as we can't hold a lock on the scheduler
     let task = {
         let cpu_scheduler = scheduler.for_this_cpu();
@@ -72,8 +113,8 @@ where
     };
 
     // info!(
-    //     "[{}] Syscall! number = {}, a = {:#x}, b = {:#x}, c = {:#x}, d = {:#x}, e = {:#x}",
-    //     task.name, number, a, b, c, d, e
+    //     "[{}] Syscall! number = {}, a = {:#x}, b = {:#x}, c = {:#x}, d = {:#x}, e = {:#x}, f = {:#x}",
+    //     task.name, number, a, b, c, d, e, f
     // );
 
     match number {
@@ -82,10 +123,10 @@ where
         syscall::SYSCALL_GET_FRAMEBUFFER => handle_to_syscall_repr(get_framebuffer(&task, a)),
         syscall::SYSCALL_CREATE_MEMORY_OBJECT => handle_to_syscall_repr(create_memory_object(&task, a, b, c)),
         syscall::SYSCALL_MAP_MEMORY_OBJECT => status_to_syscall_repr(map_memory_object(&task, a, b, c, d)),
-        syscall::SYSCALL_CREATE_CHANNEL => handle_to_syscall_repr(create_channel(&task, a)),
-        syscall::SYSCALL_SEND_MESSAGE => status_to_syscall_repr(send_message(&task, a, b, c, d, e)),
+        syscall::SYSCALL_CREATE_CHANNEL => handle_to_syscall_repr(create_channel(&task, a, b, c)),
+        syscall::SYSCALL_SEND_MESSAGE => status_to_syscall_repr(send_message(&task, a, b, c, d, e, f)),
         syscall::SYSCALL_GET_MESSAGE => status_with_payload_to_syscall_repr(get_message(&task, a, b, c, d, e)),
-        syscall::SYSCALL_WAIT_FOR_MESSAGE => todo!(),
+        syscall::SYSCALL_WAIT_FOR_MESSAGE => status_to_syscall_repr(wait_for_message(scheduler, &task, a)),
         syscall::SYSCALL_PCI_GET_INFO => status_with_payload_to_syscall_repr(pci_get_info(&task, a, b)),
         syscall::SYSCALL_WAIT_FOR_EVENT => status_to_syscall_repr(wait_for_event(scheduler, &task, a, b)),
         syscall::SYSCALL_POLL_INTEREST => status_with_payload_to_syscall_repr(poll_interest(&task, a)),
@@ -94,6 +135,36 @@ where
         syscall::SYSCALL_RESIZE_MEMORY_OBJECT => status_to_syscall_repr(resize_memory_object(&task, a, b)),
         syscall::SYSCALL_WAIT_FOR_INTERRUPT => status_to_syscall_repr(wait_for_interrupt(scheduler, &task, a, b)),
         syscall::SYSCALL_ACK_INTERRUPT => status_to_syscall_repr(ack_interrupt(&task, a)),
+        syscall::SYSCALL_CLOSE_HANDLE => status_to_syscall_repr(close_handle(&task, a)),
+        syscall::SYSCALL_DUPLICATE_HANDLE => handle_to_syscall_repr(duplicate_handle(&task, a, b)),
+        syscall::SYSCALL_EXIT_TASK => {
+            // `exit_current` never returns, so we need to drop our reference to the task first
+            drop(task);
+            scheduler.exit_current(ExitStatus::Exited(a))
+        }
+        syscall::SYSCALL_GET_EXIT_STATUS => status_to_syscall_repr(get_exit_status(&task, a, b)),
+        syscall::SYSCALL_CREATE_PORT => handle_to_syscall_repr(create_port(&task)),
+        syscall::SYSCALL_BIND_TO_PORT => status_to_syscall_repr(bind_to_port(&task, a, b, c, d)),
+        syscall::SYSCALL_WAIT_PORT => status_to_syscall_repr(wait_port(scheduler, &task, a, b, c)),
+        syscall::SYSCALL_GET_TIME => P::Clocksource::nanos_since_boot() as usize,
+        syscall::SYSCALL_CREATE_TIMER => handle_to_syscall_repr(create_timer(&task)),
+        syscall::SYSCALL_SET_TIMER => status_to_syscall_repr(set_timer(&task, a, b)),
+        syscall::SYSCALL_UNMAP_MEMORY_OBJECT => status_to_syscall_repr(unmap_memory_object(&task, a, b)),
+        syscall::SYSCALL_CLONE_MEMORY_OBJECT => handle_to_syscall_repr(clone_memory_object(&task, a)),
+        syscall::SYSCALL_PROTECT_MAPPING => status_to_syscall_repr(protect_mapping(&task, a, b, c, d)),
+        syscall::SYSCALL_OBJECT_INFO => status_to_syscall_repr(object_info(&task, a, b)),
+        syscall::SYSCALL_PEEK_MESSAGE => status_with_payload_to_syscall_repr(peek_message(&task, a)),
+        syscall::SYSCALL_CHANNEL_CALL => {
+            status_with_payload_to_syscall_repr(channel_call(scheduler, &task, a, b, c))
+        }
+        syscall::SYSCALL_CREATE_EVENT => handle_to_syscall_repr(create_event(&task)),
+        syscall::SYSCALL_SIGNAL_EVENT => status_to_syscall_repr(signal_event(&task, a)),
+        syscall::SYSCALL_CLEAR_EVENT => status_to_syscall_repr(clear_event(&task, a)),
+        syscall::SYSCALL_FUTEX_WAIT => status_to_syscall_repr(futex_wait(scheduler, vmm, &task, a, b, c)),
+        syscall::SYSCALL_FUTEX_WAKE => status_with_payload_to_syscall_repr(futex_wake(vmm, &task, a, b)),
+        syscall::SYSCALL_SPAWN_THREAD => handle_to_syscall_repr(spawn_thread(&task, a, b, c, scheduler, vmm)),
+        syscall::SYSCALL_SET_THREAD_POINTER => status_to_syscall_repr(set_thread_pointer(a, vmm)),
+        syscall::SYSCALL_SET_PRIORITY => status_to_syscall_repr(set_priority(scheduler, &task, a, b)),
 
         _ => {
             warn!("Process made system call with invalid syscall number: {}", number);
@@ -114,6 +185,10 @@ fn early_log<P>(task: &Arc<Task<P>>, str_length: usize, str_address: usize) -> R
 where
     P: Platform,
 {
+    if !task.capabilities.contains(Capabilities::EARLY_LOGGING) {
+        return Err(EarlyLogError::TaskDoesNotHaveCorrectCapability);
+    }
+
     // Check if the message is too long
     if str_length > 8192 {
         return Err(EarlyLogError::MessageTooLong);
@@ -132,8 +207,12 @@ fn get_framebuffer<P>(task: &Arc<Task<P>>, info_address: usize) -> Result<Handle
 where
     P: Platform,
 {
+    if !task.capabilities.contains(Capabilities::GET_FRAMEBUFFER) {
+        return Err(GetFramebufferError::AccessDenied);
+    }
+
     let (info, memory_object) = crate::FRAMEBUFFER.try_get().ok_or(GetFramebufferError::NoFramebufferCreated)?;
-    let handle = task.handles.add(memory_object.clone());
+    let handle = task.handles.add(memory_object.clone()).map_err(|_| GetFramebufferError::QuotaExceeded)?;
 
     UserPointer::new(info_address as *mut FramebufferInfo, true)
         .validate_write(*info)
@@ -157,29 +236,41 @@ where
     let size = align_up(size, Size4KiB::SIZE);
     let flags = MemoryObjectFlags::from_bits_truncate(flags as u32);
 
-    // TODO: do something more sensible with this when we have a concept of physical memory "ownership"
+    let object_flags = Flags {
+        writable: flags.contains(MemoryObjectFlags::WRITABLE),
+        executable: flags.contains(MemoryObjectFlags::EXECUTABLE),
+        user_accessible: true,
+        ..Default::default()
+    };
+
+    if flags.contains(MemoryObjectFlags::LAZY) {
+        /*
+         * Lazily-committed objects aren't backed by contiguous physical memory, so they don't have a physical
+         * address we can return.
+         */
+        if physical_address_ptr != 0x0 {
+            return Err(CreateMemoryObjectError::LazyObjectHasNoPhysicalAddress);
+        }
+        let memory_object =
+            MemoryObject::new_lazy(task.id(), size, object_flags, Some(Charge::new(task.quotas.frames.clone())));
+        return task.handles.add(memory_object).map_err(|_| CreateMemoryObjectError::QuotaExceeded);
+    }
+
     assert!(size % Size4KiB::SIZE == 0);
-    let physical_start = crate::PMM.get().alloc(size / Size4KiB::SIZE);
+    let num_frames = size / Size4KiB::SIZE;
+    let charge = task.quotas.frames.charge(num_frames).map_err(|_| CreateMemoryObjectError::QuotaExceeded)?;
+    let physical_start = crate::PMM.get().alloc(num_frames).ok_or(CreateMemoryObjectError::OutOfMemory)?;
 
-    let memory_object = MemoryObject::new(
-        task.id(),
-        physical_start,
-        size,
-        Flags {
-            writable: flags.contains(MemoryObjectFlags::WRITABLE),
-            executable: flags.contains(MemoryObjectFlags::EXECUTABLE),
-            user_accessible: true,
-            ..Default::default()
-        },
-    );
+    let memory_object = MemoryObject::new_pmm_backed(task.id(), physical_start, size, object_flags, Some(charge));
 
     if physical_address_ptr != 0x0 {
         UserPointer::new(physical_address_ptr as *mut PAddr, true)
             .validate_write(physical_start)
             .map_err(|()| CreateMemoryObjectError::InvalidPhysicalAddressPointer)?;
+        memory_object.mark_physical_address_exposed();
     }
 
-    Ok(task.handles.add(memory_object))
+    task.handles.add(memory_object).map_err(|_| CreateMemoryObjectError::QuotaExceeded)
 }
 
 fn map_memory_object<P>(
@@ -197,40 +288,59 @@ where
     let address_space_handle =
         Handle::try_from(address_space_handle).map_err(|_| MapMemoryObjectError::InvalidAddressSpaceHandle)?;
 
-    let memory_object = task
+    let (memory_object, memory_object_rights) = task
         .handles
-        .get(memory_object_handle)
-        .ok_or(MapMemoryObjectError::InvalidMemoryObjectHandle)?
+        .get_with_rights(memory_object_handle)
+        .ok_or(MapMemoryObjectError::InvalidMemoryObjectHandle)?;
+    if !memory_object_rights.contains(HandleRights::MAP) {
+        return Err(MapMemoryObjectError::MemoryObjectCannotBeMapped);
+    }
+    let memory_object = memory_object
         .downcast_arc::<MemoryObject>()
         .ok()
         .ok_or(MapMemoryObjectError::InvalidMemoryObjectHandle)?;
 
-    let (virtual_address, write_to_ptr) = if virtual_address == 0x0 {
-        /*
-         * No virtual address supplied: we should find a suitable area of the virtual address space
-         * to map the object to, and write the address to the supplied pointer.
-         */
-        todo!()
-    } else {
-        // TODO: we need to actually validate that the supplied address is canonical and all that jazz
-        (VAddr::new(virtual_address), false)
+    /*
+     * Handles that cannot modify a memory object can still map it, but only read-only.
+     */
+    let flags = {
+        let mut flags = memory_object.flags();
+        flags.writable &= memory_object_rights.contains(HandleRights::MODIFY);
+        flags
     };
 
-    if address_space_handle == Handle::ZERO {
+    let address_space = if address_space_handle == Handle::ZERO {
         /*
          * If the AddressSpace handle is the zero handle, we map the MemoryObject into the calling task's
          * address space.
          */
-        task.address_space.map_memory_object(memory_object.clone(), virtual_address, &crate::PMM.get())?;
+        task.address_space()
     } else {
-        task.handles
-            .get(address_space_handle)
-            .ok_or(MapMemoryObjectError::InvalidAddressSpaceHandle)?
+        let (address_space, address_space_rights) = task
+            .handles
+            .get_with_rights(address_space_handle)
+            .ok_or(MapMemoryObjectError::InvalidAddressSpaceHandle)?;
+        if !address_space_rights.contains(HandleRights::MODIFY) {
+            return Err(MapMemoryObjectError::AddressSpaceCannotBeModified);
+        }
+        address_space
             .downcast_arc::<AddressSpace<P>>()
             .ok()
             .ok_or(MapMemoryObjectError::InvalidAddressSpaceHandle)?
-            .map_memory_object(memory_object.clone(), virtual_address, &crate::PMM.get())?;
-    }
+    };
+
+    let (virtual_address, write_to_ptr) = if virtual_address == 0x0 {
+        /*
+         * No virtual address supplied: the address space finds a suitable area to map the object to, and we
+         * write the address it chose to the supplied pointer.
+         */
+        (address_space.map_memory_object_anywhere(memory_object, flags, &crate::PMM.get())?, true)
+    } else {
+        // TODO: we need to actually validate that the supplied address is canonical and all that jazz
+        let virtual_address = VAddr::new(virtual_address);
+        address_space.map_memory_object_with_flags(memory_object, virtual_address, flags, &crate::PMM.get())?;
+        (virtual_address, false)
+    };
 
     /*
      * Only write to the pointer if: 1) we had to allocate an address 2) the caller wants to know,
@@ -244,13 +354,144 @@ where
     Ok(())
 }
 
-fn create_channel<P>(task: &Arc<Task<P>>, other_end_address: usize) -> Result<Handle, CreateChannelError>
+fn unmap_memory_object<P>(
+    task: &Arc<Task<P>>,
+    address_space_handle: usize,
+    virtual_address: usize,
+) -> Result<(), UnmapMemoryObjectError>
 where
     P: Platform,
 {
-    let (end_a, end_b) = ChannelEnd::new_channel(task.id());
-    let end_a_handle = task.handles.add(end_a);
-    let end_b_handle = task.handles.add(end_b);
+    let address_space_handle =
+        Handle::try_from(address_space_handle).map_err(|_| UnmapMemoryObjectError::InvalidAddressSpaceHandle)?;
+    let virtual_address = VAddr::new(virtual_address);
+
+    /*
+     * As with mapping, the zero handle refers to the calling task's address space. Dropping the returned memory
+     * object releases the address space's reference to it, freeing it if nothing else refers to it.
+     */
+    if address_space_handle == Handle::ZERO {
+        task.address_space().unmap_memory_object(virtual_address)?;
+    } else {
+        let (address_space, address_space_rights) = task
+            .handles
+            .get_with_rights(address_space_handle)
+            .ok_or(UnmapMemoryObjectError::InvalidAddressSpaceHandle)?;
+        if !address_space_rights.contains(HandleRights::MODIFY) {
+            return Err(UnmapMemoryObjectError::AddressSpaceCannotBeModified);
+        }
+        address_space
+            .downcast_arc::<AddressSpace<P>>()
+            .ok()
+            .ok_or(UnmapMemoryObjectError::InvalidAddressSpaceHandle)?
+            .unmap_memory_object(virtual_address)?;
+    }
+
+    Ok(())
+}
+
+fn protect_mapping<P>(
+    task: &Arc<Task<P>>,
+    address_space_handle: usize,
+    address: usize,
+    size: usize,
+    flags: usize,
+) -> Result<(), ProtectMappingError>
+where
+    P: Platform,
+{
+    let address_space_handle =
+        Handle::try_from(address_space_handle).map_err(|_| ProtectMappingError::InvalidAddressSpaceHandle)?;
+    let address = VAddr::new(address);
+    let flags = MemoryObjectFlags::from_bits_truncate(flags as u32);
+    let writable = flags.contains(MemoryObjectFlags::WRITABLE);
+    let executable = flags.contains(MemoryObjectFlags::EXECUTABLE);
+
+    // As with mapping, the zero handle refers to the calling task's address space
+    if address_space_handle == Handle::ZERO {
+        task.address_space().protect_mapping(address, size, writable, executable, crate::PMM.get())
+    } else {
+        let (address_space, address_space_rights) = task
+            .handles
+            .get_with_rights(address_space_handle)
+            .ok_or(ProtectMappingError::InvalidAddressSpaceHandle)?;
+        if !address_space_rights.contains(HandleRights::MODIFY) {
+            return Err(ProtectMappingError::AddressSpaceCannotBeModified);
+        }
+        address_space
+            .downcast_arc::<AddressSpace<P>>()
+            .ok()
+            .ok_or(ProtectMappingError::InvalidAddressSpaceHandle)?
+            .protect_mapping(address, size, writable, executable, crate::PMM.get())
+    }
+}
+
+fn clone_memory_object<P>(
+    task: &Arc<Task<P>>,
+    memory_object_handle: usize,
+) -> Result<Handle, CloneMemoryObjectError>
+where
+    P: Platform,
+{
+    let memory_object_handle =
+        Handle::try_from(memory_object_handle).map_err(|_| CloneMemoryObjectError::InvalidMemoryObjectHandle)?;
+    let (memory_object, rights) = task
+        .handles
+        .get_with_rights(memory_object_handle)
+        .ok_or(CloneMemoryObjectError::InvalidMemoryObjectHandle)?;
+    if !rights.contains(HandleRights::MAP) {
+        return Err(CloneMemoryObjectError::MemoryObjectCannotBeMapped);
+    }
+    let memory_object = memory_object
+        .downcast_arc::<MemoryObject>()
+        .ok()
+        .ok_or(CloneMemoryObjectError::InvalidMemoryObjectHandle)?;
+
+    let clone = memory_object.clone_cow(task.id(), Some(Charge::new(task.quotas.frames.clone())))?;
+
+    /*
+     * The original's pages are now shared with the clone, so they need to be mapped read-only wherever the original
+     * is mapped. We unmap them, and they're faulted back in with the correct permissions when they're next touched.
+     */
+    let size = memory_object.size();
+    for mapping in memory_object.mappings() {
+        if let Some(address_space) =
+            mapping.address_space.upgrade().and_then(|object| object.downcast_arc::<AddressSpace<P>>().ok())
+        {
+            address_space.page_table.lock().unmap_area(mapping.address, size);
+        }
+    }
+
+    task.handles.add(clone).map_err(|_| CloneMemoryObjectError::QuotaExceeded)
+}
+
+fn create_channel<P>(
+    task: &Arc<Task<P>>,
+    other_end_address: usize,
+    max_messages: usize,
+    max_bytes: usize,
+) -> Result<Handle, CreateChannelError>
+where
+    P: Platform,
+{
+    // A limit of `0` selects the default for that limit
+    let mut limits = QueueLimits::default();
+    if max_messages != 0 {
+        limits.max_messages = max_messages;
+    }
+    if max_bytes != 0 {
+        limits.max_bytes = max_bytes;
+    }
+
+    let (end_a, end_b) = ChannelEnd::new_channel(task.id(), limits);
+    let end_a_handle = task.handles.add(end_a).map_err(|_| CreateChannelError::QuotaExceeded)?;
+    let end_b_handle = match task.handles.add(end_b) {
+        Ok(handle) => handle,
+        Err(_) => {
+            task.handles.remove(end_a_handle);
+            return Err(CreateChannelError::QuotaExceeded);
+        }
+    };
 
     let mut other_end_ptr = UserPointer::new(other_end_address as *mut Handle, true);
     other_end_ptr.validate_write(end_b_handle).map_err(|()| CreateChannelError::InvalidHandleAddress)?;
@@ -265,57 +506,111 @@ fn send_message<P>(
     num_bytes: usize,
     handles_address: usize,
     num_handles: usize,
+    transaction: usize,
 ) -> Result<(), SendMessageError>
 where
     P: Platform,
 {
-    use poplar::syscall::CHANNEL_MAX_NUM_BYTES;
+    let channel_handle = Handle::try_from(channel_handle).map_err(|_| SendMessageError::InvalidChannelHandle)?;
+    // If this message is the reply to a call, this is the call's transaction ID. Otherwise, it's `0`.
+    let transaction = u16::try_from(transaction).map_err(|_| SendMessageError::InvalidTransaction)?;
 
-    if num_bytes > CHANNEL_MAX_NUM_BYTES {
-        return Err(SendMessageError::TooManyBytes);
-    }
-    if num_handles > CHANNEL_MAX_NUM_HANDLES {
-        return Err(SendMessageError::TooManyHandles);
+    let (channel, channel_rights) =
+        task.handles.get_with_rights(channel_handle).ok_or(SendMessageError::InvalidChannelHandle)?;
+    if !channel_rights.contains(HandleRights::SEND) {
+        return Err(SendMessageError::ChannelCannotSend);
     }
+    let channel = channel.downcast_arc::<ChannelEnd>().ok().ok_or(SendMessageError::NotAChannel)?;
 
-    let channel_handle = Handle::try_from(channel_handle).map_err(|_| SendMessageError::InvalidChannelHandle)?;
-    let bytes = if num_bytes == 0 {
-        &[]
-    } else {
-        UserSlice::new(byte_address as *mut u8, num_bytes)
-            .validate_read()
-            .map_err(|()| SendMessageError::BytesAddressInvalid)?
-    };
-    let handles = if num_handles == 0 {
-        &[]
+    let message = OutgoingMessage::new(task, byte_address, num_bytes, handles_address, num_handles)?;
+    if transaction == 0 {
+        channel.send(message.bytes.len(), || message.into_message(task, 0))
     } else {
-        UserSlice::new(handles_address as *mut Handle, num_handles)
-            .validate_read()
-            .map_err(|()| SendMessageError::HandlesAddressInvalid)?
-    };
-    let handle_objects = {
-        let mut arr = [const { None }; CHANNEL_MAX_NUM_HANDLES];
-        for (i, handle) in handles.iter().enumerate() {
-            arr[i] = match task.handles.get(*handle) {
-                Some(object) => Some(object.clone()),
-                None => return Err(SendMessageError::InvalidTransferredHandle),
-            };
+        channel.reply(transaction, || message.into_message(task, transaction))
+    }
+}
 
-            /*
-             * We're transferring the handle's object, so we remove the handle to it from the sending task.
-             */
-            task.handles.remove(*handle);
+/// A message being sent by a task. Its bytes and handles have been copied out of userspace and charged to the
+/// sending task, but the handles it transfers are only checked and removed from the task when it is turned into a
+/// `Message` with `into_message`.
+struct OutgoingMessage {
+    bytes: Vec<u8>,
+    handles: [Handle; CHANNEL_MAX_NUM_HANDLES],
+    num_handles: usize,
+    charge: Charge,
+}
+
+impl OutgoingMessage {
+    fn new<P>(
+        task: &Arc<Task<P>>,
+        byte_address: usize,
+        num_bytes: usize,
+        handles_address: usize,
+        num_handles: usize,
+    ) -> Result<OutgoingMessage, SendMessageError>
+    where
+        P: Platform,
+    {
+        use poplar::syscall::CHANNEL_MAX_NUM_BYTES;
+
+        if num_bytes > CHANNEL_MAX_NUM_BYTES {
+            return Err(SendMessageError::TooManyBytes);
+        }
+        if num_handles > CHANNEL_MAX_NUM_HANDLES {
+            return Err(SendMessageError::TooManyHandles);
         }
-        arr
-    };
 
-    task.handles
-        .get(channel_handle)
-        .ok_or(SendMessageError::InvalidChannelHandle)?
-        .downcast_arc::<ChannelEnd>()
-        .ok()
-        .ok_or(SendMessageError::NotAChannel)?
-        .send(Message { bytes: bytes.to_vec(), handle_objects })
+        let bytes = if num_bytes == 0 {
+            &[]
+        } else {
+            UserSlice::new(byte_address as *mut u8, num_bytes)
+                .validate_read()
+                .map_err(|()| SendMessageError::BytesAddressInvalid)?
+        };
+
+        /*
+         * Copy the handles out of userspace, so they can't be changed under us between checking and removing them.
+         */
+        let mut handles = [Handle(0); CHANNEL_MAX_NUM_HANDLES];
+        if num_handles != 0 {
+            let user_handles = UserSlice::new(handles_address as *mut Handle, num_handles)
+                .validate_read()
+                .map_err(|()| SendMessageError::HandlesAddressInvalid)?;
+            handles[..num_handles].copy_from_slice(user_handles);
+        }
+
+        /*
+         * The message's bytes are charged to the sending task until the message is received.
+         */
+        let charge = task.quotas.channel_bytes.charge(bytes.len()).map_err(|_| SendMessageError::QuotaExceeded)?;
+
+        Ok(OutgoingMessage { bytes: bytes.to_vec(), handles, num_handles, charge })
+    }
+
+    /// Create the `Message` to queue, removing the handles it transfers from the sending task. This should only
+    /// be called once we know the message can be delivered. If any of the handles can't be transferred, none of
+    /// them are removed.
+    fn into_message<P>(self, task: &Arc<Task<P>>, transaction: u16) -> Result<Message, SendMessageError>
+    where
+        P: Platform,
+    {
+        let removed =
+            task.handles.remove_all(&self.handles[..self.num_handles], HandleRights::TRANSFER).map_err(|err| {
+                match err {
+                    RemoveHandlesError::InvalidHandle | RemoveHandlesError::DuplicateHandle => {
+                        SendMessageError::InvalidTransferredHandle
+                    }
+                    RemoveHandlesError::MissingRights => SendMessageError::CannotTransferHandle,
+                }
+            })?;
+
+        let mut handle_objects = [const { None }; CHANNEL_MAX_NUM_HANDLES];
+        for (i, handle) in removed.into_iter().enumerate() {
+            handle_objects[i] = Some(handle);
+        }
+
+        Ok(Message { bytes: self.bytes, handle_objects, charge: Some(self.charge), transaction })
+    }
 }
 
 fn get_message<P>(
@@ -331,51 +626,221 @@ where
 {
     let channel_handle = Handle::try_from(channel_handle).map_err(|_| GetMessageError::InvalidChannelHandle)?;
 
-    let channel = task
-        .handles
-        .get(channel_handle)
-        .ok_or(GetMessageError::InvalidChannelHandle)?
-        .downcast_arc::<ChannelEnd>()
-        .ok()
-        .ok_or(GetMessageError::NotAChannel)?;
+    let (channel, channel_rights) =
+        task.handles.get_with_rights(channel_handle).ok_or(GetMessageError::InvalidChannelHandle)?;
+    if !channel_rights.contains(HandleRights::RECEIVE) {
+        return Err(GetMessageError::ChannelCannotReceive);
+    }
+    let channel = channel.downcast_arc::<ChannelEnd>().ok().ok_or(GetMessageError::NotAChannel)?;
 
     channel.receive(|message| {
-        let num_handles = message.num_handles();
+        let transaction = message.transaction;
+        let mut status = deliver_message(task, message, bytes_address, bytes_len, handles_address, handles_len)?;
+        status.set_bits(48..64, transaction as usize);
+        Ok(status)
+    })
+}
 
-        if message.bytes.len() > bytes_len {
-            return Err((message, GetMessageError::BytesBufferTooSmall));
-        }
-        if num_handles > handles_len {
-            return Err((message, GetMessageError::HandlesBufferTooSmall));
+/// Copy a received message into the buffers provided by `task`, and add the handles it transfers to the task.
+/// Returns the number of bytes in bits `16..32` and the number of handles in bits `32..48`. If the message can't be
+/// delivered, it is returned alongside the error so it can be received again later.
+fn deliver_message<P>(
+    task: &Arc<Task<P>>,
+    message: Message,
+    bytes_address: usize,
+    bytes_len: usize,
+    handles_address: usize,
+    handles_len: usize,
+) -> Result<usize, (Message, GetMessageError)>
+where
+    P: Platform,
+{
+    let num_handles = message.num_handles();
+
+    if message.bytes.len() > bytes_len {
+        return Err((message, GetMessageError::BytesBufferTooSmall));
+    }
+    if num_handles > handles_len {
+        return Err((message, GetMessageError::HandlesBufferTooSmall));
+    }
+
+    if bytes_len > 0 && bytes_address != 0x0 {
+        let byte_buffer = match UserSlice::new(bytes_address as *mut u8, message.bytes.len()).validate_write() {
+            Ok(buffer) => buffer,
+            Err(()) => return Err((message, GetMessageError::BytesAddressInvalid)),
+        };
+        byte_buffer.copy_from_slice(&message.bytes);
+    }
+
+    if handles_len > 0 && handles_address != 0x0 {
+        let handles_buffer = match UserSlice::new(handles_address as *mut Handle, num_handles).validate_write() {
+            Ok(buffer) => buffer,
+            Err(()) => return Err((message, GetMessageError::HandlesAddressInvalid)),
+        };
+        for i in 0..num_handles {
+            let (object, rights) = message.handle_objects[i].as_ref().unwrap();
+            match task.handles.add_with_rights(object.clone(), *rights) {
+                Ok(handle) => handles_buffer[i] = handle,
+                Err(_) => {
+                    // Remove the handles we've already added, so the message can be received again later
+                    for &handle in &handles_buffer[0..i] {
+                        task.handles.remove(handle);
+                    }
+                    return Err((message, GetMessageError::QuotaExceeded));
+                }
+            }
         }
+    }
 
-        if bytes_len > 0 && bytes_address != 0x0 {
-            let byte_buffer = match UserSlice::new(bytes_address as *mut u8, message.bytes.len()).validate_write()
-            {
-                Ok(buffer) => buffer,
-                Err(()) => return Err((message, GetMessageError::BytesAddressInvalid)),
-            };
-            byte_buffer.copy_from_slice(&message.bytes);
+    let mut status = 0;
+    status.set_bits(16..32, message.bytes.len());
+    status.set_bits(32..48, num_handles);
+    Ok(status)
+}
+
+fn peek_message<P>(task: &Arc<Task<P>>, channel_handle: usize) -> Result<usize, GetMessageError>
+where
+    P: Platform,
+{
+    let channel_handle = Handle::try_from(channel_handle).map_err(|_| GetMessageError::InvalidChannelHandle)?;
+
+    let (channel, channel_rights) =
+        task.handles.get_with_rights(channel_handle).ok_or(GetMessageError::InvalidChannelHandle)?;
+    if !channel_rights.contains(HandleRights::RECEIVE) {
+        return Err(GetMessageError::ChannelCannotReceive);
+    }
+    let channel = channel.downcast_arc::<ChannelEnd>().ok().ok_or(GetMessageError::NotAChannel)?;
+
+    let (num_bytes, num_handles) = channel.peek()?;
+    let mut status = 0;
+    status.set_bits(16..32, num_bytes);
+    status.set_bits(32..48, num_handles);
+    Ok(status)
+}
+
+fn channel_call<P>(
+    scheduler: &Scheduler<P>,
+    task: &Arc<Task<P>>,
+    channel_handle: usize,
+    details_address: usize,
+    block: usize,
+) -> Result<usize, ChannelCallError>
+where
+    P: Platform,
+{
+    let channel_handle = Handle::try_from(channel_handle).map_err(|_| ChannelCallError::InvalidChannelHandle)?;
+    let block = block != 0;
+
+    let (channel, channel_rights) =
+        task.handles.get_with_rights(channel_handle).ok_or(ChannelCallError::InvalidChannelHandle)?;
+    if !channel_rights.contains(HandleRights::SEND | HandleRights::RECEIVE) {
+        return Err(ChannelCallError::ChannelCannotCall);
+    }
+    let channel = channel.downcast_arc::<ChannelEnd>().ok().ok_or(ChannelCallError::NotAChannel)?;
+
+    let mut details_ptr = UserPointer::new(details_address as *mut ChannelCallDetails, true);
+    let mut details = details_ptr.validate_read().map_err(|()| ChannelCallError::DetailsAddressInvalid)?;
+
+    /*
+     * A transaction ID of `0` means we're making a new call. Otherwise, the task is collecting the reply to a call
+     * it has already made.
+     */
+    let transaction = if details.transaction == 0 {
+        let message = OutgoingMessage::new(
+            task,
+            details.bytes_ptr as usize,
+            details.bytes_len,
+            details.handles_ptr as usize,
+            details.handles_len,
+        )?;
+        let transaction =
+            channel.call(message.bytes.len(), |transaction| message.into_message(task, transaction))?;
+
+        details.transaction = transaction;
+        details_ptr.validate_write(details).map_err(|()| ChannelCallError::DetailsAddressInvalid)?;
+
+        // If the task doesn't want to wait, it'll collect the reply later
+        if !block {
+            return Ok(0);
         }
+        transaction
+    } else {
+        details.transaction
+    };
 
-        if handles_len > 0 && handles_address != 0x0 {
-            let handles_buffer = match UserSlice::new(handles_address as *mut Handle, num_handles).validate_write()
-            {
-                Ok(buffer) => buffer,
-                Err(()) => return Err((message, GetMessageError::HandlesAddressInvalid)),
-            };
-            for i in 0..num_handles {
-                handles_buffer[i] = task.handles.add(message.handle_objects[i].as_ref().unwrap().clone());
-            }
+    /*
+     * Wait for the reply. If the other end is closed before replying, the reply will never arrive, so we give up
+     * on the call.
+     */
+    let reply = loop {
+        if let Some(reply) = channel.take_reply(transaction)? {
+            break reply;
+        }
+        if channel.is_peer_closed() {
+            channel.cancel_call(transaction);
+            return Err(ChannelCallError::OtherEndDisconnected);
         }
+        if !block {
+            return Err(ChannelCallError::NoReply);
+        }
+        scheduler.schedule(TaskState::Blocked(TaskBlock::OnCall(channel.clone(), transaction)));
+    };
 
-        let mut status = 0;
-        status.set_bits(16..32, message.bytes.len());
-        status.set_bits(32..48, num_handles);
-        Ok(status)
+    deliver_message(
+        task,
+        reply,
+        details.reply_bytes_ptr as usize,
+        details.reply_bytes_len,
+        details.reply_handles_ptr as usize,
+        details.reply_handles_len,
+    )
+    .map_err(|(reply, err)| {
+        // Keep hold of the reply, so the task can try to collect it again
+        channel.restore_reply(transaction, reply);
+        match err {
+            GetMessageError::BytesAddressInvalid => ChannelCallError::ReplyBytesAddressInvalid,
+            GetMessageError::BytesBufferTooSmall => ChannelCallError::ReplyBytesBufferTooSmall,
+            GetMessageError::HandlesAddressInvalid => ChannelCallError::ReplyHandlesAddressInvalid,
+            GetMessageError::HandlesBufferTooSmall => ChannelCallError::ReplyHandlesBufferTooSmall,
+            GetMessageError::QuotaExceeded => ChannelCallError::QuotaExceeded,
+            _ => unreachable!(),
+        }
     })
 }
 
+fn wait_for_message<P>(
+    scheduler: &Scheduler<P>,
+    task: &Arc<Task<P>>,
+    channel_handle: usize,
+) -> Result<(), WaitForMessageError>
+where
+    P: Platform,
+{
+    let channel_handle =
+        Handle::try_from(channel_handle).map_err(|_| WaitForMessageError::InvalidChannelHandle)?;
+
+    let (channel, channel_rights) =
+        task.handles.get_with_rights(channel_handle).ok_or(WaitForMessageError::InvalidChannelHandle)?;
+    if !channel_rights.contains(HandleRights::RECEIVE) {
+        return Err(WaitForMessageError::ChannelCannotReceive);
+    }
+    let channel = channel.downcast_arc::<ChannelEnd>().ok().ok_or(WaitForMessageError::NotAChannel)?;
+
+    /*
+     * Block the task until there's a message to receive. The task is woken when a message is added to the
+     * channel, but we check again when we're scheduled in case it's been received by another task in the
+     * meantime. If the other end is closed, no message will ever arrive, so we return instead.
+     */
+    while channel.messages.lock().is_empty() {
+        if channel.is_peer_closed() {
+            return Err(WaitForMessageError::PeerClosed);
+        }
+        scheduler.schedule(TaskState::Blocked(TaskBlock::OnChannel(channel.clone())));
+    }
+
+    Ok(())
+}
+
 fn pci_get_info<P>(
     task: &Arc<Task<P>>,
     buffer_address: usize,
@@ -387,6 +852,10 @@ where
     use pci_types::{Bar, MAX_BARS};
     use poplar::ddk::pci::PciDeviceInfo;
 
+    if !task.capabilities.contains(Capabilities::PCI_BUS_DRIVER) {
+        return Err(PciGetInfoError::TaskDoesNotHaveCorrectCapability);
+    }
+
     // TODO: request this through the platform nicely instead of through a huge global
     if let Some(ref pci_info) = *crate::PCI_INFO.read() {
         let num_descriptors = pci_info.devices.len();
@@ -401,7 +870,12 @@ where
                 .map_err(|()| PciGetInfoError::BufferPointerInvalid)?;
 
             for (i, (&address, device)) in pci_info.devices.iter().enumerate() {
-                let interrupt_handle = device.interrupt.clone().map(|interrupt| task.handles.add(interrupt));
+                let interrupt_handle = device
+                    .interrupt
+                    .clone()
+                    .map(|interrupt| task.handles.add(interrupt))
+                    .transpose()
+                    .map_err(|_| PciGetInfoError::QuotaExceeded)?;
 
                 let mut device_descriptor = poplar::ddk::pci::PciDeviceInfo {
                     address,
@@ -431,7 +905,8 @@ where
                                 size as usize,
                                 flags,
                             );
-                            let handle = task.handles.add(memory_object);
+                            let handle =
+                                task.handles.add(memory_object).map_err(|_| PciGetInfoError::QuotaExceeded)?;
                             device_descriptor.bars[i] =
                                 Some(poplar::ddk::pci::Bar::Memory32 { memory_object: handle, size });
                         }
@@ -449,7 +924,8 @@ where
                                 size as usize,
                                 flags,
                             );
-                            let handle = task.handles.add(memory_object);
+                            let handle =
+                                task.handles.add(memory_object).map_err(|_| PciGetInfoError::QuotaExceeded)?;
                             device_descriptor.bars[i] =
                                 Some(poplar::ddk::pci::Bar::Memory64 { memory_object: handle, size });
                         }
@@ -483,33 +959,166 @@ where
 {
     let event_handle = Handle::try_from(event_handle).map_err(|_| WaitForEventError::InvalidEventHandle)?;
     let block = block != 0;
-    let event = task
-        .handles
-        .get(event_handle)
-        .ok_or(WaitForEventError::InvalidEventHandle)?
-        .downcast_arc::<Event>()
-        .ok()
-        .ok_or(WaitForEventError::InvalidEventHandle)?;
+    let object = task.handles.get(event_handle).ok_or(WaitForEventError::InvalidEventHandle)?;
 
-    if block {
-        /*
-         * XXX: This is an extremely simple way of implementing this. We should instead probably block
-         * the task, and spawn a tasklet that is awoken when the event is triggered to unblock it. For
-         * now, though, this will work well enough.
-         */
-        while !event.signalled.load(Ordering::SeqCst) {
-            scheduler.schedule(TaskState::Ready);
+    /*
+     * Waiting on a `Task` waits for it to exit. Unlike an `Event`, this isn't cleared once it has been waited
+     * for - the task stays exited.
+     */
+    if object.typ() == KernelObjectType::Task {
+        let waited_task = object.downcast_arc::<Task<P>>().ok().unwrap();
+        loop {
+            if waited_task.exited.has_exited() {
+                return Ok(());
+            }
+            if !block {
+                return Err(WaitForEventError::NoEvent);
+            }
+            scheduler.schedule(TaskState::Blocked(TaskBlock::OnTaskExit(waited_task.exited.clone())));
         }
-        assert_eq!(Ok(true), event.signalled.compare_exchange(true, false, Ordering::SeqCst, Ordering::SeqCst));
-        Ok(())
-    } else {
-        match event.signalled.compare_exchange(true, false, Ordering::SeqCst, Ordering::SeqCst) {
-            Ok(true) => Ok(()),
-            _ => Err(WaitForEventError::NoEvent),
+    }
+
+    let event = object.downcast_arc::<Event>().ok().ok_or(WaitForEventError::InvalidEventHandle)?;
+
+    /*
+     * Waiting for the event clears it. If several tasks are waiting for the same event, only one of them will
+     * manage to clear it when it is signalled - the others go back to waiting.
+     */
+    loop {
+        if event.signalled.compare_exchange(true, false, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            return Ok(());
+        }
+        if !block {
+            return Err(WaitForEventError::NoEvent);
+        }
+        scheduler.schedule(TaskState::Blocked(TaskBlock::OnEvent(event.clone())));
+    }
+}
+
+pub fn create_event<P>(task: &Arc<Task<P>>) -> Result<Handle, CreateEventError>
+where
+    P: Platform,
+{
+    task.handles.add(Event::new(task.id())).map_err(|_| CreateEventError::QuotaExceeded)
+}
+
+pub fn signal_event<P>(task: &Arc<Task<P>>, event_handle: usize) -> Result<(), SignalEventError>
+where
+    P: Platform,
+{
+    let event_handle = Handle::try_from(event_handle).map_err(|_| SignalEventError::InvalidEventHandle)?;
+    let (event, rights) =
+        task.handles.get_with_rights(event_handle).ok_or(SignalEventError::InvalidEventHandle)?;
+    if !rights.contains(HandleRights::MODIFY) {
+        return Err(SignalEventError::EventCannotBeModified);
+    }
+    let event = event.downcast_arc::<Event>().ok().ok_or(SignalEventError::NotAnEvent)?;
+
+    // Any tasks blocked waiting for the event are made ready
+    event.signal();
+    Ok(())
+}
+
+pub fn clear_event<P>(task: &Arc<Task<P>>, event_handle: usize) -> Result<(), ClearEventError>
+where
+    P: Platform,
+{
+    let event_handle = Handle::try_from(event_handle).map_err(|_| ClearEventError::InvalidEventHandle)?;
+    let (event, rights) = task.handles.get_with_rights(event_handle).ok_or(ClearEventError::InvalidEventHandle)?;
+    if !rights.contains(HandleRights::MODIFY) {
+        return Err(ClearEventError::EventCannotBeModified);
+    }
+    let event = event.downcast_arc::<Event>().ok().ok_or(ClearEventError::NotAnEvent)?;
+
+    event.clear();
+    Ok(())
+}
+
+/// Find the physical address of the futex at `address` in `task`'s address space. The futex is read first, which
+/// makes sure the memory behind it has been committed.
+fn futex_physical_address<P>(task: &Arc<Task<P>>, address: usize, vmm: &Vmm<P>) -> Option<PAddr>
+where
+    P: Platform,
+{
+    if address % mem::align_of::<u32>() != 0 {
+        return None;
+    }
+    UserPointer::new(address as *mut u32, false).validate_read().ok()?;
+
+    /*
+     * If the futex's page is shared copy-on-write, the first write to it copies it into a new frame, which would
+     * leave any tasks waiting on the futex keyed on the old one. If the page can be written, commit it for writing
+     * now, as if the task had written to it, so the futex stays at the same physical address. Pages that aren't
+     * shared, or can't be written, stay in the same frame.
+     */
+    let address_space = task.address_space();
+    address_space.handle_page_fault(
+        VAddr::new(address),
+        FaultAccess::Write,
+        &task.quotas.frames,
+        vmm,
+        crate::PMM.get(),
+    );
+
+    address_space.page_table.lock().translate(VAddr::new(address))
+}
+
+pub fn futex_wait<P>(
+    scheduler: &Scheduler<P>,
+    vmm: &Vmm<P>,
+    task: &Arc<Task<P>>,
+    address: usize,
+    expected: usize,
+    timeout: usize,
+) -> Result<(), FutexWaitError>
+where
+    P: Platform,
+{
+    let physical = futex_physical_address(task, address, vmm).ok_or(FutexWaitError::AddressInvalid)?;
+    let expected = expected as u32;
+    // A timeout of `0` means the task waits until it is woken
+    let deadline =
+        if timeout == 0 { None } else { Some(P::Clocksource::nanos_since_boot().saturating_add(timeout as u64)) };
+
+    let waiter = FUTEXES
+        .wait(physical, deadline, P::Clocksource::nanos_since_boot, || {
+            UserPointer::new(address as *mut u32, false).validate_read() == Ok(expected)
+        })
+        .ok_or(FutexWaitError::ValueChanged)?;
+
+    loop {
+        scheduler.schedule(TaskState::Blocked(TaskBlock::OnFutex(waiter.clone())));
+
+        if waiter.is_woken() {
+            return Ok(());
+        }
+        if waiter.has_timed_out() {
+            /*
+             * The futex might be woken between us timing out and removing the waiter. If it is, we count as
+             * woken, as the task that woke us won't wake anyone else in our place.
+             */
+            return if FUTEXES.cancel(physical, &waiter) { Err(FutexWaitError::TimedOut) } else { Ok(()) };
         }
     }
 }
 
+pub fn futex_wake<P>(
+    vmm: &Vmm<P>,
+    task: &Arc<Task<P>>,
+    address: usize,
+    count: usize,
+) -> Result<usize, FutexWakeError>
+where
+    P: Platform,
+{
+    let physical = futex_physical_address(task, address, vmm).ok_or(FutexWakeError::AddressInvalid)?;
+    let woken = FUTEXES.wake(physical, count);
+
+    let mut status = 0;
+    status.set_bits(16..64, woken);
+    Ok(status)
+}
+
 pub fn poll_interest<P>(task: &Arc<Task<P>>, object_handle: usize) -> Result<usize, PollInterestError>
 where
     P: Platform,
@@ -517,24 +1126,9 @@ where
     let object_handle = Handle::try_from(object_handle).map_err(|_| PollInterestError::InvalidHandle)?;
     let object = task.handles.get(object_handle).ok_or(PollInterestError::InvalidHandle)?;
 
-    let interesting = match object.typ() {
-        KernelObjectType::Channel => {
-            let channel = object.downcast_arc::<ChannelEnd>().ok().unwrap();
-            let messages = channel.messages.lock();
-            messages.len() > 0
-        }
-        KernelObjectType::Event => {
-            let event = object.downcast_arc::<Event>().ok().unwrap();
-            event.signalled.load(Ordering::SeqCst)
-        }
-        KernelObjectType::Interrupt => {
-            let interrupt = object.downcast_arc::<Interrupt>().ok().unwrap();
-            interrupt.triggered.load(Ordering::SeqCst)
-        }
-        _ => Err(PollInterestError::UnsupportedObjectType)?,
-    };
+    let signals = object.poll_signals().ok_or(PollInterestError::UnsupportedObjectType)?;
 
-    Ok(if interesting { 1 << 16 } else { 0 })
+    Ok((signals.bits() as usize) << 16)
 }
 
 pub fn create_address_space<P>(task: &Arc<Task<P>>) -> Result<Handle, CreateAddressSpaceError>
@@ -542,7 +1136,7 @@ where
     P: Platform,
 {
     let address_space = AddressSpace::<P>::new(task.id());
-    Ok(task.handles.add(address_space))
+    task.handles.add(address_space).map_err(|_| CreateAddressSpaceError::QuotaExceeded)
 }
 
 pub fn spawn_task<P>(
@@ -571,8 +1165,25 @@ where
         .ok()
         .ok_or(SpawnTaskError::NotAnAddressSpace)?;
 
-    let handles = Handles::new();
-    handles.add(address_space.clone());
+    /*
+     * A task can only give the tasks it spawns capabilities that it holds itself.
+     */
+    let capabilities = Capabilities::from_bits(details.capabilities).ok_or(SpawnTaskError::CapabilitiesNotHeld)?;
+    if !task.capabilities.contains(capabilities) {
+        return Err(SpawnTaskError::CapabilitiesNotHeld);
+    }
+
+    // Similarly, a task can't allow the tasks it spawns to be given a higher priority than it can be given
+    let max_priority =
+        Priority::try_from(details.max_priority as usize).map_err(|()| SpawnTaskError::PriorityTooHigh)?;
+    if max_priority > task.max_priority {
+        return Err(SpawnTaskError::PriorityTooHigh);
+    }
+
+    // The new task's usage is charged to the calling task too, so it can't be used to get around its quotas
+    let quotas = task.quotas.new_child();
+    let handles = Handles::new(quotas.handles.clone());
+    handles.add(address_space.clone()).map_err(|_| SpawnTaskError::QuotaExceeded)?;
 
     // TODO: we should really be adding the required memory objects to the task, or they could be
     // freed from under us. This could be done by convention using the object transfer array?
@@ -582,17 +1193,129 @@ where
     for to_transfer in handles_to_transfer {
         let handle =
             Handle::try_from(*to_transfer as usize).map_err(|_| SpawnTaskError::InvalidHandleToTransfer)?;
-        let object = task.handles.get(handle).ok_or(SpawnTaskError::InvalidHandleToTransfer)?;
-        handles.add(object);
+        let (object, rights) =
+            task.handles.get_with_rights(handle).ok_or(SpawnTaskError::InvalidHandleToTransfer)?;
+        if !rights.contains(HandleRights::TRANSFER) {
+            return Err(SpawnTaskError::CannotTransferHandle);
+        }
+        handles.add_with_rights(object, rights).map_err(|_| SpawnTaskError::QuotaExceeded)?;
     }
 
     let pmm = crate::PMM.get();
-    let new_task =
-        Task::new(task.id(), address_space, name.to_string(), VAddr::new(details.entry_point), handles, &pmm, vmm)
-            .expect("Failed to create task");
-    scheduler.add_task(new_task.clone());
+    let new_task = Task::new(
+        task.id(),
+        address_space,
+        name.to_string(),
+        VAddr::new(details.entry_point),
+        capabilities,
+        quotas,
+        max_priority,
+        handles,
+        &pmm,
+        vmm,
+    )
+    .map_err(|_| SpawnTaskError::TooManyTasks)?;
+
+    let handle = match task.handles.add(new_task.clone()) {
+        Ok(handle) => handle,
+        Err(_) => {
+            // The new task has never run, so it can be torn down straight away
+            new_task.mark_exited(ExitStatus::Exited(0));
+            new_task.release_resources(&pmm, vmm);
+            return Err(SpawnTaskError::QuotaExceeded);
+        }
+    };
+    scheduler.add_task(new_task);
 
-    Ok(task.handles.add(new_task))
+    Ok(handle)
+}
+
+/// Check that `address` is a canonical address in the lower half of the address space, so it's safe to enter
+/// userspace at, or to use as a thread pointer.
+fn validate_user_address<P>(address: usize, vmm: &Vmm<P>) -> Option<VAddr>
+where
+    P: Platform,
+{
+    let address = VAddr::new(address);
+    if address.canonicalise() != address || address >= vmm.higher_half_start {
+        return None;
+    }
+    Some(address)
+}
+
+pub fn spawn_thread<P>(
+    task: &Arc<Task<P>>,
+    entry_point: usize,
+    argument: usize,
+    thread_pointer: usize,
+    scheduler: &Scheduler<P>,
+    vmm: &Vmm<P>,
+) -> Result<Handle, SpawnThreadError>
+where
+    P: Platform,
+{
+    let entry_point = validate_user_address(entry_point, vmm).ok_or(SpawnThreadError::InvalidEntryPoint)?;
+    let thread_pointer =
+        validate_user_address(thread_pointer, vmm).ok_or(SpawnThreadError::InvalidThreadPointer)?;
+
+    let pmm = crate::PMM.get();
+    let thread = Task::new_thread(task, entry_point, argument, thread_pointer, &pmm, vmm)
+        .map_err(|_| SpawnThreadError::TooManyTasks)?;
+
+    let handle = match task.handles.add(thread.clone()) {
+        Ok(handle) => handle,
+        Err(_) => {
+            // The new thread has never run, so it can be torn down straight away
+            thread.mark_exited(ExitStatus::Exited(0));
+            thread.release_resources(&pmm, vmm);
+            return Err(SpawnThreadError::QuotaExceeded);
+        }
+    };
+    scheduler.add_task(thread);
+
+    Ok(handle)
+}
+
+pub fn set_thread_pointer<P>(thread_pointer: usize, vmm: &Vmm<P>) -> Result<(), SetThreadPointerError>
+where
+    P: Platform,
+{
+    let thread_pointer =
+        validate_user_address(thread_pointer, vmm).ok_or(SetThreadPointerError::InvalidThreadPointer)?;
+    unsafe {
+        P::set_thread_pointer(thread_pointer);
+    }
+    Ok(())
+}
+
+pub fn set_priority<P>(
+    scheduler: &Scheduler<P>,
+    task: &Arc<Task<P>>,
+    task_handle: usize,
+    priority: usize,
+) -> Result<(), SetPriorityError>
+where
+    P: Platform,
+{
+    let priority = Priority::try_from(priority).map_err(|()| SetPriorityError::InvalidPriority)?;
+    let task_handle = Handle::try_from(task_handle).map_err(|_| SetPriorityError::InvalidHandle)?;
+
+    let target = if task_handle == Handle::ZERO {
+        task.clone()
+    } else {
+        let (target, rights) = task.handles.get_with_rights(task_handle).ok_or(SetPriorityError::InvalidHandle)?;
+        if !rights.contains(HandleRights::MODIFY) {
+            return Err(SetPriorityError::TaskCannotBeModified);
+        }
+        target.downcast_arc::<Task<P>>().ok().ok_or(SetPriorityError::NotATask)?
+    };
+
+    if priority > target.max_priority {
+        return Err(SetPriorityError::PriorityTooHigh);
+    }
+
+    scheduler.set_priority(&target, priority);
+    Ok(())
 }
 
 pub fn resize_memory_object<P>(
@@ -605,51 +1328,108 @@ where
 {
     let memory_object_handle =
         Handle::try_from(memory_object_handle).map_err(|_| ResizeMemoryObjectError::InvalidMemoryObjectHandle)?;
-    let memory_object = task
+    let (memory_object, rights) = task
         .handles
-        .get(memory_object_handle)
-        .ok_or(ResizeMemoryObjectError::InvalidMemoryObjectHandle)?
+        .get_with_rights(memory_object_handle)
+        .ok_or(ResizeMemoryObjectError::InvalidMemoryObjectHandle)?;
+    if !rights.contains(HandleRights::MODIFY) {
+        return Err(ResizeMemoryObjectError::MemoryObjectCannotBeModified);
+    }
+    let memory_object = memory_object
         .downcast_arc::<MemoryObject>()
         .ok()
         .ok_or(ResizeMemoryObjectError::InvalidMemoryObjectHandle)?;
 
     /*
-     * TODO: the big remaining question is how we deal with remapping a resized memory object that
-     * is mapped into an address space with multiple tasks, or multiple address spaces. This is not
-     * easy - in the case that one of the tasks is running on another CPU, this involves sending
-     * TLB shootdowns and things... maybe initially just ban this?
-     *
-     * XXX: We don't actually check this currently as we don't track which address spaces a
-     * MemoryObject is mapped into. Probably do this?
-     *
-     * We might only need to do this when unmapping memory objects because I don't think any arch
-     * we're targetting will cache a page not being present?
+     * Find the address spaces the object is mapped into, so we can keep every mapping in sync with the object's
+     * new size.
      */
+    let mappings = memory_object
+        .mappings()
+        .into_iter()
+        .filter_map(|mapping| {
+            let address_space = mapping.address_space.upgrade()?.downcast_arc::<AddressSpace<P>>().ok()?;
+            Some((address_space, mapping))
+        })
+        .collect::<Vec<_>>();
 
+    // TODO: should we require that the size be multiple of the page size, or just up it here?
+    let new_size = mulch::math::align_up(new_size, Size4KiB::SIZE);
     let old_size = memory_object.size();
+
     if new_size > old_size {
-        // Grow the memory object
-        // TODO: should we require that the size be multiple of the page size, or just up it here?
-        let extend_by = mulch::math::align_up(new_size - old_size, Size4KiB::SIZE);
-        let new_backing = crate::PMM.get().alloc(extend_by / Size4KiB::SIZE);
+        /*
+         * Mappings are extended in place, so check that the virtual memory after each one is free before changing
+         * anything. This means a resize either updates all of an object's mappings, or none of them.
+         */
+        let extend_by = new_size - old_size;
+        if !mappings
+            .iter()
+            .all(|(address_space, mapping)| address_space.is_range_free(mapping.address + old_size, extend_by))
+        {
+            return Err(ResizeMemoryObjectError::ResizedObjectCannotBeRemapped);
+        }
+
+        if memory_object.is_lazy() {
+            // The new part of the object will be committed and mapped as it's touched
+            memory_object.extend_lazy(extend_by);
+            return Ok(());
+        }
+
+        let num_frames = extend_by / Size4KiB::SIZE;
+        memory_object.charge_frames(num_frames).map_err(|_| ResizeMemoryObjectError::QuotaExceeded)?;
+        let new_backing = match crate::PMM.get().alloc(num_frames) {
+            Some(new_backing) => new_backing,
+            None => {
+                memory_object.uncharge_frames(num_frames);
+                return Err(ResizeMemoryObjectError::OutOfMemory);
+            }
+        };
         unsafe {
             memory_object.extend(extend_by, new_backing);
         }
 
-        // Map the new region into the current task's address space, if we're already mapped.
-        let mappings = task.address_space.mappings.lock();
-        let mapping = mappings.iter().find(|(_addr, object)| object.id == memory_object.id);
-        if let Some((virtual_addr, object)) = mapping {
-            let new_virtual = *virtual_addr + old_size;
-            task.address_space
-                .page_table
-                .lock()
-                .map_area(new_virtual, new_backing, extend_by, object.flags(), crate::PMM.get())
-                .map_err(|_| ResizeMemoryObjectError::ResizedObjectCannotBeRemapped)?;
+        for (i, (address_space, mapping)) in mappings.iter().enumerate() {
+            let result = address_space.page_table.lock().map_area(
+                mapping.address + old_size,
+                new_backing,
+                extend_by,
+                mapping.flags,
+                crate::PMM.get(),
+            );
+
+            /*
+             * If we can't map the new part of the object everywhere, undo the resize: unmap it from the mappings
+             * we've updated so far (including any part of this one that was mapped), and shrink the object back,
+             * which frees the new memory. We checked that the regions were free, so this can't unmap anything
+             * else.
+             */
+            if let Err(err) = result {
+                for (address_space, mapping) in &mappings[..=i] {
+                    address_space.page_table.lock().unmap_area(mapping.address + old_size, extend_by);
+                }
+                unsafe {
+                    memory_object.shrink(extend_by);
+                }
+
+                return Err(match err {
+                    PagingError::AlreadyMapped => ResizeMemoryObjectError::ResizedObjectCannotBeRemapped,
+                    PagingError::OutOfMemory => ResizeMemoryObjectError::OutOfMemory,
+                });
+            }
         }
     } else if new_size < old_size {
-        // Shrink the memory object
-        todo!()
+        /*
+         * TODO: if a task using one of these address spaces is running on another CPU, we need to shoot down its
+         * TLB entries for the removed region before the memory can be safely freed.
+         */
+        let shrink_by = old_size - new_size;
+        for (address_space, mapping) in &mappings {
+            address_space.page_table.lock().unmap_area(mapping.address + new_size, shrink_by);
+        }
+        unsafe {
+            memory_object.shrink(shrink_by);
+        }
     } else {
         // The memory object is already the correct size. Do nothing.
     }
@@ -666,6 +1446,10 @@ pub fn wait_for_interrupt<P>(
 where
     P: Platform,
 {
+    if !task.capabilities.contains(Capabilities::HANDLE_INTERRUPTS) {
+        return Err(WaitForInterruptError::TaskDoesNotHaveCorrectCapability);
+    }
+
     let interrupt_handle =
         Handle::try_from(interrupt_handle).map_err(|_| WaitForInterruptError::InvalidInterruptHandle)?;
     let block = block != 0;
@@ -703,16 +1487,227 @@ pub fn ack_interrupt<P>(task: &Arc<Task<P>>, interrupt_handle: usize) -> Result<
 where
     P: Platform,
 {
+    if !task.capabilities.contains(Capabilities::HANDLE_INTERRUPTS) {
+        return Err(AckInterruptError::TaskDoesNotHaveCorrectCapability);
+    }
+
     let interrupt_handle =
         Handle::try_from(interrupt_handle).map_err(|_| AckInterruptError::InvalidInterruptHandle)?;
-    let interrupt = task
+    let (interrupt, rights) =
+        task.handles.get_with_rights(interrupt_handle).ok_or(AckInterruptError::InvalidInterruptHandle)?;
+    if !rights.contains(HandleRights::MODIFY) {
+        return Err(AckInterruptError::InterruptCannotBeModified);
+    }
+    let interrupt = interrupt.downcast_arc::<Interrupt>().ok().ok_or(AckInterruptError::InvalidInterruptHandle)?;
+
+    interrupt.rearm::<P>();
+    Ok(())
+}
+
+pub fn close_handle<P>(task: &Arc<Task<P>>, handle: usize) -> Result<(), CloseHandleError>
+where
+    P: Platform,
+{
+    let handle = Handle::try_from(handle).map_err(|_| CloseHandleError::InvalidHandle)?;
+
+    /*
+     * Removing the handle drops the task's reference to the kernel object. If this was the last reference to it,
+     * the object is destroyed and any resources it holds are released.
+     */
+    task.handles.remove(handle).ok_or(CloseHandleError::InvalidHandle)?;
+    Ok(())
+}
+
+pub fn duplicate_handle<P>(
+    task: &Arc<Task<P>>,
+    handle: usize,
+    rights: usize,
+) -> Result<Handle, DuplicateHandleError>
+where
+    P: Platform,
+{
+    let handle = Handle::try_from(handle).map_err(|_| DuplicateHandleError::InvalidHandle)?;
+    let requested_rights = HandleRights::from_bits(rights as u32).ok_or(DuplicateHandleError::InvalidRights)?;
+
+    let (object, rights) = task.handles.get_with_rights(handle).ok_or(DuplicateHandleError::InvalidHandle)?;
+    if !rights.contains(HandleRights::DUPLICATE) {
+        return Err(DuplicateHandleError::CannotDuplicate);
+    }
+    if !rights.contains(requested_rights) {
+        return Err(DuplicateHandleError::InvalidRights);
+    }
+
+    task.handles.add_with_rights(object, requested_rights).map_err(|_| DuplicateHandleError::QuotaExceeded)
+}
+
+pub fn get_exit_status<P>(
+    task: &Arc<Task<P>>,
+    task_handle: usize,
+    status_address: usize,
+) -> Result<(), GetExitStatusError>
+where
+    P: Platform,
+{
+    let task_handle = Handle::try_from(task_handle).map_err(|_| GetExitStatusError::InvalidHandle)?;
+    let exited_task = task
         .handles
-        .get(interrupt_handle)
-        .ok_or(AckInterruptError::InvalidInterruptHandle)?
-        .downcast_arc::<Interrupt>()
+        .get(task_handle)
+        .ok_or(GetExitStatusError::InvalidHandle)?
+        .downcast_arc::<Task<P>>()
         .ok()
-        .ok_or(AckInterruptError::InvalidInterruptHandle)?;
+        .ok_or(GetExitStatusError::InvalidHandle)?;
 
-    interrupt.rearm::<P>();
+    let status = match *exited_task.state.lock() {
+        TaskState::Exited(status) => status,
+        _ => return Err(GetExitStatusError::TaskNotExited),
+    };
+
+    UserPointer::new(status_address as *mut ExitStatus, true)
+        .validate_write(status)
+        .map_err(|()| GetExitStatusError::StatusAddressIsInvalid)
+}
+
+pub fn object_info<P>(task: &Arc<Task<P>>, handle: usize, info_address: usize) -> Result<(), ObjectInfoError>
+where
+    P: Platform,
+{
+    let handle = Handle::try_from(handle).map_err(|_| ObjectInfoError::InvalidHandle)?;
+    let object = task.handles.get(handle).ok_or(ObjectInfoError::InvalidHandle)?;
+
+    let typ = object.typ();
+    let id = object.id().into();
+    let owner = object.owner().into();
+
+    let details = match typ {
+        KernelObjectType::MemoryObject => {
+            let memory_object = object.downcast_arc::<MemoryObject>().ok().unwrap();
+            let object_flags = memory_object.flags();
+            let mut flags = MemoryObjectFlags::empty();
+            flags.set(MemoryObjectFlags::WRITABLE, object_flags.writable);
+            flags.set(MemoryObjectFlags::EXECUTABLE, object_flags.executable);
+            flags.set(MemoryObjectFlags::LAZY, memory_object.is_lazy());
+            ObjectDetails::MemoryObject { size: memory_object.size(), flags }
+        }
+        KernelObjectType::Channel => {
+            let channel = object.downcast_arc::<ChannelEnd>().ok().unwrap();
+            ObjectDetails::Channel { queued_messages: channel.messages.lock().len() }
+        }
+        KernelObjectType::Task => {
+            let info_task = object.downcast_arc::<Task<P>>().ok().unwrap();
+            let status = match *info_task.state.lock() {
+                TaskState::Ready => TaskStatus::Ready,
+                TaskState::Running => TaskStatus::Running,
+                TaskState::Blocked(_) => TaskStatus::Blocked,
+                TaskState::Exited(status) => TaskStatus::Exited(status),
+            };
+            ObjectDetails::Task { status }
+        }
+        _ => ObjectDetails::None,
+    };
+
+    UserPointer::new(info_address as *mut ObjectInfo, true)
+        .validate_write(ObjectInfo { typ, id, owner, details })
+        .map_err(|()| ObjectInfoError::InfoAddressIsInvalid)
+}
+
+pub fn create_port<P>(task: &Arc<Task<P>>) -> Result<Handle, CreatePortError>
+where
+    P: Platform,
+{
+    task.handles.add(Port::new(task.id())).map_err(|_| CreatePortError::QuotaExceeded)
+}
+
+pub fn bind_to_port<P>(
+    task: &Arc<Task<P>>,
+    port_handle: usize,
+    object_handle: usize,
+    key: usize,
+    signals: usize,
+) -> Result<(), BindToPortError>
+where
+    P: Platform,
+{
+    let port_handle = Handle::try_from(port_handle).map_err(|_| BindToPortError::InvalidPortHandle)?;
+    let object_handle = Handle::try_from(object_handle).map_err(|_| BindToPortError::InvalidObjectHandle)?;
+
+    let (port, port_rights) =
+        task.handles.get_with_rights(port_handle).ok_or(BindToPortError::InvalidPortHandle)?;
+    if !port_rights.contains(HandleRights::MODIFY) {
+        return Err(BindToPortError::PortCannotBeModified);
+    }
+    let port = port.downcast_arc::<Port>().ok().ok_or(BindToPortError::NotAPort)?;
+
+    let object = task.handles.get(object_handle).ok_or(BindToPortError::InvalidObjectHandle)?;
+    let signals = Signals::from_bits_truncate(signals as u32);
+    if signals.is_empty() || !object.supports_signals(signals) {
+        return Err(BindToPortError::UnsupportedObjectType);
+    }
+
+    port.bind(key as u64, &object, signals);
+    Ok(())
+}
+
+pub fn wait_port<P>(
+    scheduler: &Scheduler<P>,
+    task: &Arc<Task<P>>,
+    port_handle: usize,
+    packet_address: usize,
+    block: usize,
+) -> Result<(), WaitPortError>
+where
+    P: Platform,
+{
+    let port_handle = Handle::try_from(port_handle).map_err(|_| WaitPortError::InvalidPortHandle)?;
+    let block = block != 0;
+    let port = task
+        .handles
+        .get(port_handle)
+        .ok_or(WaitPortError::InvalidPortHandle)?
+        .downcast_arc::<Port>()
+        .ok()
+        .ok_or(WaitPortError::NotAPort)?;
+
+    let key = loop {
+        if let Some(key) = port.take_packet() {
+            break key;
+        }
+        if !block {
+            return Err(WaitPortError::NoPackets);
+        }
+
+        /*
+         * Block until a packet can be queued. Another task waiting on the same port may take it before we're
+         * scheduled again, so we go round the loop until we actually get one.
+         */
+        scheduler.schedule(TaskState::Blocked(TaskBlock::OnPort(port.clone())));
+    };
+
+    UserPointer::new(packet_address as *mut PortPacket, true)
+        .validate_write(PortPacket { key })
+        .map_err(|()| WaitPortError::PacketAddressInvalid)
+}
+
+pub fn create_timer<P>(task: &Arc<Task<P>>) -> Result<Handle, CreateTimerError>
+where
+    P: Platform,
+{
+    task.handles
+        .add(Timer::new(task.id(), P::Clocksource::nanos_since_boot))
+        .map_err(|_| CreateTimerError::QuotaExceeded)
+}
+
+pub fn set_timer<P>(task: &Arc<Task<P>>, timer_handle: usize, deadline: usize) -> Result<(), SetTimerError>
+where
+    P: Platform,
+{
+    let timer_handle = Handle::try_from(timer_handle).map_err(|_| SetTimerError::InvalidTimerHandle)?;
+    let (timer, rights) = task.handles.get_with_rights(timer_handle).ok_or(SetTimerError::InvalidTimerHandle)?;
+    if !rights.contains(HandleRights::MODIFY) {
+        return Err(SetTimerError::TimerCannotBeModified);
+    }
+    let timer = timer.downcast_arc::<Timer>().ok().ok_or(SetTimerError::NotATimer)?;
+
+    // A deadline of `0` disarms the timer
+    timer.set(if deadline == 0 { None } else { Some(deadline as u64) });
     Ok(())
 }
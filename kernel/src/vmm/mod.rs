@@ -1,10 +1,13 @@
 mod slab_allocator;
 
 use crate::{bootinfo::BootInfo, Platform};
+use alloc::vec::Vec;
 use core::sync::atomic::{AtomicUsize, Ordering};
-use hal::memory::{Flags, FrameSize, PAddr, PageTable, Size4KiB, VAddr};
+use hal::memory::{Flags, FrameSize, PAddr, Page, PageTable, Size4KiB, VAddr};
 use spinning_top::Spinlock;
 
+const KERNEL_STACK_SLOT_SIZE: usize = hal::memory::mebibytes(1);
+
 pub struct Vmm<P: Platform> {
     pub kernel_page_table: Spinlock<P::PageTable>,
     pub higher_half_start: VAddr,
@@ -15,6 +18,8 @@ pub struct Vmm<P: Platform> {
     // TODO: this should be replaced by some sort of tree
     kernel_dynamic_next_available: AtomicUsize,
     kernel_end_of_dynamic_area: VAddr,
+    /// Kernel stack slots that have been freed, and can be reused before allocating new slots in the dynamic area.
+    free_kernel_stack_slots: Spinlock<Vec<VAddr>>,
 }
 
 impl<P> Vmm<P>
@@ -31,6 +36,7 @@ where
             kernel_dynamic_next_available: AtomicUsize::new(usize::from(boot_info.kernel_dynamic_area_base())),
             // TODO: I guess this could not always be the case? Maybe use another constant?
             kernel_end_of_dynamic_area: boot_info.kernel_image_base(),
+            free_kernel_stack_slots: Spinlock::new(Vec::new()),
         }
     }
 
@@ -63,13 +69,20 @@ where
     pub fn alloc_kernel_stack(&self, initial_size: usize) -> Option<Stack> {
         use hal::memory::{Flags, PageTable};
 
-        const KERNEL_STACK_SLOT_SIZE: usize = hal::memory::mebibytes(1);
-
-        let slot_bottom = self.alloc_kernel(KERNEL_STACK_SLOT_SIZE).unwrap();
+        let slot_bottom = match self.free_kernel_stack_slots.lock().pop() {
+            Some(slot_bottom) => slot_bottom,
+            None => self.alloc_kernel(KERNEL_STACK_SLOT_SIZE)?,
+        };
         let top = slot_bottom + KERNEL_STACK_SLOT_SIZE - 1;
         let stack_bottom = top - initial_size + 1;
 
-        let physical_start = crate::PMM.get().alloc(initial_size / Size4KiB::SIZE);
+        let physical_start = match crate::PMM.get().alloc(initial_size / Size4KiB::SIZE) {
+            Some(physical_start) => physical_start,
+            None => {
+                self.free_kernel_stack_slots.lock().push(slot_bottom);
+                return None;
+            }
+        };
         self.kernel_page_table
             .lock()
             .map_area(
@@ -83,6 +96,20 @@ where
 
         Some(Stack { top, slot_bottom, stack_bottom, physical_start })
     }
+
+    /// Free a kernel stack allocated by `alloc_kernel_stack`, unmapping it and freeing its backing memory. The
+    /// slot is kept to be reused by a later stack. The stack must not be in use.
+    pub fn free_kernel_stack(&self, stack: Stack) {
+        let mut kernel_page_table = self.kernel_page_table.lock();
+        for page in Page::<Size4KiB>::starts_with(stack.stack_bottom)..Page::contains(stack.top) + 1 {
+            kernel_page_table.unmap(page);
+        }
+        drop(kernel_page_table);
+
+        let size = usize::from(stack.top) + 1 - usize::from(stack.stack_bottom);
+        crate::PMM.get().free(stack.physical_start, size / Size4KiB::SIZE);
+        self.free_kernel_stack_slots.lock().push(stack.slot_bottom);
+    }
 }
 
 /// Represents a stack, either in kernel-space or user-space. Stacks are allocated in "slots" of fixed size, but
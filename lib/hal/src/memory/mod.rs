@@ -85,6 +85,15 @@ where
     /// Allocate `n` contiguous `Frame`s.
     fn allocate_n(&self, n: usize) -> Range<Frame<S>>;
 
+    /// Allocate a `Frame`, returning `None` if the allocator has run out of memory. Page tables allocate their
+    /// tables with this, so they can report running out of memory as an error.
+    ///
+    /// By default, this calls `allocate`, and so can't fail gracefully. Allocators that can run out of memory
+    /// should override it.
+    fn try_allocate(&self) -> Option<Frame<S>> {
+        Some(self.allocate())
+    }
+
     /// Free `n` frames that were previously allocated by this allocator.
     fn free_n(&self, start: Frame<S>, n: usize);
 }
@@ -43,6 +43,8 @@ impl ops::Add for Flags {
 pub enum PagingError {
     /// The virtual memory that is being mapped is already mapped to another part of physical memory.
     AlreadyMapped,
+    /// A new table was needed, but the frame allocator could not allocate one.
+    OutOfMemory,
 }
 
 /// A `PageTable` allows the manipulation of a set of page-tables.
@@ -107,6 +109,35 @@ where
     fn unmap<S>(&mut self, page: Page<S>) -> Option<Frame<S>>
     where
         S: FrameSize;
+
+    /// Unmap an area of `size` bytes starting at `virtual_start`, which should have been mapped with `map_area`.
+    /// The area is unmapped with whatever page sizes it was mapped with, and its TLB entries are invalidated. Parts
+    /// of the area that are not mapped are skipped. The frames that were mapped are not freed.
+    fn unmap_area(&mut self, virtual_start: VAddr, size: usize);
+
+    /// Change the flags of the area of `size` bytes starting at `virtual_start`, and invalidate its TLB entries.
+    /// Parts of the area that are not mapped are skipped. If part of the area is mapped by a larger page that
+    /// extends outside of it, that page is split into smaller pages first, which may allocate new tables. If a
+    /// table can't be allocated, the area may have been partially changed.
+    fn protect_area<A>(
+        &mut self,
+        virtual_start: VAddr,
+        size: usize,
+        flags: Flags,
+        allocator: &A,
+    ) -> Result<(), PagingError>
+    where
+        A: FrameAllocator<TableSize>;
+
+    /// Free the frames holding the tables that map the lower half of the address space, along with the frame
+    /// holding the top-level table. The frames mapped by these tables are not freed - they are owned by whatever
+    /// mapped them. The higher half is shared with the kernel and so is left alone.
+    ///
+    /// # Safety
+    /// The page tables must not be used (or be active on any processor) afterwards.
+    unsafe fn free_lower_half<A>(&mut self, allocator: &A)
+    where
+        A: FrameAllocator<TableSize>;
 }
 
 #[cfg(test)]
@@ -48,6 +48,16 @@ impl Sstatus {
             asm!("csrc sstatus, {}", in(reg) 1 << 18);
         }
     }
+
+    /// Returns `true` if the `SPP` bit of `sstatus` is clear, meaning the last trap was taken from U-mode. Each
+    /// trap overwrites `SPP`, so this must be read before anything else can trap.
+    pub fn trap_from_user_mode() -> bool {
+        let value: usize;
+        unsafe {
+            asm!("csrr {}, sstatus", out(reg) value);
+        }
+        !value.get_bit(8)
+    }
 }
 
 pub struct Sip(pub usize);
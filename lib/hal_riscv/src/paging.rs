@@ -223,7 +223,8 @@ where
             /*
              * This entry is empty, so we create a new page table, zero it, and return that.
              */
-            self.entries[index].set(Some((allocator.allocate().start, EntryFlags::VALID)), false);
+            let frame = allocator.try_allocate().ok_or(PagingError::OutOfMemory)?;
+            self.entries[index].set(Some((frame.start, EntryFlags::VALID)), false);
             let table = self.next_table_mut(index, physical_base).unwrap();
             table.zero();
             Ok(table)
@@ -290,6 +291,87 @@ impl PageTableImpl<Level4> {
     pub fn satp(&self) -> Satp {
         Satp::Sv48 { asid: 0, root: self.frame.start }
     }
+
+    /// Clear the leaf entry that maps `address`, which may map a 4KiB, 2MiB, or 1GiB page. Returns the size of the
+    /// page that was unmapped, or `None` if `address` is not mapped. The TLB is not invalidated.
+    fn clear_entry(&mut self, address: VAddr) -> Option<usize> {
+        let physical_base = self.physical_base;
+
+        let p3 = self.top_mut().next_table_mut(address.p4_index(), physical_base)?;
+        if p3[address.p3_index()].is_leaf() {
+            p3[address.p3_index()].set(None, true);
+            return Some(Size1GiB::SIZE);
+        }
+
+        let p2 = p3.next_table_mut(address.p3_index(), physical_base)?;
+        if p2[address.p2_index()].is_leaf() {
+            p2[address.p2_index()].set(None, true);
+            return Some(Size2MiB::SIZE);
+        }
+
+        let p1 = p2.next_table_mut(address.p2_index(), physical_base)?;
+        if !p1[address.p1_index()].is_valid() {
+            return None;
+        }
+        p1[address.p1_index()].set(None, true);
+        Some(Size4KiB::SIZE)
+    }
+
+    /// Set the flags of the leaf entry that maps `address`. If it maps a huge page that extends outside of
+    /// `address..end`, the page is split first, so only the part inside the area is changed. Returns the size of
+    /// the page that was changed, or `None` if `address` is not mapped. The TLB is not invalidated.
+    fn protect_entry<A>(
+        &mut self,
+        address: VAddr,
+        end: VAddr,
+        flags: EntryFlags,
+        allocator: &A,
+    ) -> Result<Option<usize>, PagingError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let physical_base = self.physical_base;
+        let fits_in_area = |page_size: usize| {
+            address.is_aligned(page_size) && usize::from(end) - usize::from(address) >= page_size
+        };
+
+        let Some(p3) = self.top_mut().next_table_mut(address.p4_index(), physical_base) else {
+            return Ok(None);
+        };
+        let p3_entry = &mut p3[address.p3_index()];
+        if p3_entry.is_leaf() {
+            if fits_in_area(Size1GiB::SIZE) {
+                p3_entry.set(Some((p3_entry.address().unwrap(), flags)), true);
+                return Ok(Some(Size1GiB::SIZE));
+            }
+            split_huge_page(p3_entry, Size2MiB::SIZE, allocator, physical_base)?;
+        }
+
+        let Some(p2) = p3.next_table_mut(address.p3_index(), physical_base) else {
+            return Ok(None);
+        };
+        let p2_entry = &mut p2[address.p2_index()];
+        if p2_entry.is_leaf() {
+            if fits_in_area(Size2MiB::SIZE) {
+                p2_entry.set(Some((p2_entry.address().unwrap(), flags)), true);
+                return Ok(Some(Size2MiB::SIZE));
+            }
+            split_huge_page(p2_entry, Size4KiB::SIZE, allocator, physical_base)?;
+        }
+
+        let Some(p1) = p2.next_table_mut(address.p2_index(), physical_base) else {
+            return Ok(None);
+        };
+        let p1_entry = &mut p1[address.p1_index()];
+        if !p1_entry.is_valid() {
+            return Ok(None);
+        }
+        let Some(frame) = p1_entry.address() else {
+            return Ok(None);
+        };
+        p1_entry.set(Some((frame, flags)), true);
+        Ok(Some(Size4KiB::SIZE))
+    }
 }
 
 impl fmt::Debug for PageTableImpl<Level4> {
@@ -538,6 +620,99 @@ impl PageTable<Size4KiB> for PageTableImpl<Level4> {
             _ => panic!("Unimplemented page size!"),
         }
     }
+
+    fn unmap_area(&mut self, virtual_start: VAddr, size: usize) {
+        assert!(virtual_start.is_aligned(Size4KiB::SIZE));
+        assert!(size % Size4KiB::SIZE == 0);
+
+        let virtual_end = virtual_start + size;
+        let mut cursor = virtual_start;
+
+        /*
+         * The area may have been mapped with larger pages, so clear whatever entry maps each address, and then skip
+         * over the whole page it mapped.
+         */
+        while cursor < virtual_end {
+            match self.clear_entry(cursor) {
+                Some(page_size) => {
+                    assert!(cursor.is_aligned(page_size));
+                    sfence_vma(None, Some(cursor));
+                    cursor += page_size;
+                }
+                None => cursor += Size4KiB::SIZE,
+            }
+        }
+    }
+
+    fn protect_area<A>(
+        &mut self,
+        virtual_start: VAddr,
+        size: usize,
+        flags: Flags,
+        allocator: &A,
+    ) -> Result<(), PagingError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        assert!(virtual_start.is_aligned(Size4KiB::SIZE));
+        assert!(size % Size4KiB::SIZE == 0);
+
+        let virtual_end = virtual_start + size;
+        let mut cursor = virtual_start;
+
+        while cursor < virtual_end {
+            match self.protect_entry(cursor, virtual_end, EntryFlags::from(flags), allocator)? {
+                Some(page_size) => {
+                    sfence_vma(None, Some(cursor));
+                    cursor += page_size;
+                }
+                None => cursor += Size4KiB::SIZE,
+            }
+        }
+
+        Ok(())
+    }
+
+    unsafe fn free_lower_half<A>(&mut self, allocator: &A)
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let physical_base = self.physical_base;
+        let p4 = self.top_mut();
+
+        for i in 0..(ENTRY_COUNT / 2) {
+            if p4[i].is_leaf() {
+                continue;
+            }
+
+            if let Some(p3) = p4.next_table(i, physical_base) {
+                for j in 0..ENTRY_COUNT {
+                    if p3[j].is_leaf() {
+                        continue;
+                    }
+
+                    if let Some(p2) = p3.next_table(j, physical_base) {
+                        for k in 0..ENTRY_COUNT {
+                            if p2[k].is_leaf() {
+                                continue;
+                            }
+
+                            if let Some(p1_address) = p2[k].address() {
+                                allocator.free_n(Frame::starts_with(p1_address), 1);
+                            }
+                        }
+
+                        allocator.free_n(Frame::starts_with(p3[j].address().unwrap()), 1);
+                    }
+                }
+
+                allocator.free_n(Frame::starts_with(p4[i].address().unwrap()), 1);
+                p4[i].set(None, false);
+            }
+        }
+
+        allocator.free_n(self.frame, 1);
+    }
 }
 
 /*
@@ -547,6 +722,109 @@ impl PageTableImpl<Level3> {
     pub fn satp(&self) -> Satp {
         Satp::Sv39 { asid: 0, root: self.frame.start }
     }
+
+    /// Clear the leaf entry that maps `address`, which may map a 4KiB, 2MiB, or 1GiB page. Returns the size of the
+    /// page that was unmapped, or `None` if `address` is not mapped. The TLB is not invalidated.
+    fn clear_entry(&mut self, address: VAddr) -> Option<usize> {
+        let physical_base = self.physical_base;
+
+        let p3 = self.top_mut();
+        if p3[address.p3_index()].is_leaf() {
+            p3[address.p3_index()].set(None, true);
+            return Some(Size1GiB::SIZE);
+        }
+
+        let p2 = p3.next_table_mut(address.p3_index(), physical_base)?;
+        if p2[address.p2_index()].is_leaf() {
+            p2[address.p2_index()].set(None, true);
+            return Some(Size2MiB::SIZE);
+        }
+
+        let p1 = p2.next_table_mut(address.p2_index(), physical_base)?;
+        if !p1[address.p1_index()].is_valid() {
+            return None;
+        }
+        p1[address.p1_index()].set(None, true);
+        Some(Size4KiB::SIZE)
+    }
+
+    /// Set the flags of the leaf entry that maps `address`. If it maps a huge page that extends outside of
+    /// `address..end`, the page is split first, so only the part inside the area is changed. Returns the size of
+    /// the page that was changed, or `None` if `address` is not mapped. The TLB is not invalidated.
+    fn protect_entry<A>(
+        &mut self,
+        address: VAddr,
+        end: VAddr,
+        flags: EntryFlags,
+        allocator: &A,
+    ) -> Result<Option<usize>, PagingError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let physical_base = self.physical_base;
+        let fits_in_area = |page_size: usize| {
+            address.is_aligned(page_size) && usize::from(end) - usize::from(address) >= page_size
+        };
+
+        let p3 = self.top_mut();
+        let p3_entry = &mut p3[address.p3_index()];
+        if p3_entry.is_leaf() {
+            if fits_in_area(Size1GiB::SIZE) {
+                p3_entry.set(Some((p3_entry.address().unwrap(), flags)), true);
+                return Ok(Some(Size1GiB::SIZE));
+            }
+            split_huge_page(p3_entry, Size2MiB::SIZE, allocator, physical_base)?;
+        }
+
+        let Some(p2) = p3.next_table_mut(address.p3_index(), physical_base) else {
+            return Ok(None);
+        };
+        let p2_entry = &mut p2[address.p2_index()];
+        if p2_entry.is_leaf() {
+            if fits_in_area(Size2MiB::SIZE) {
+                p2_entry.set(Some((p2_entry.address().unwrap(), flags)), true);
+                return Ok(Some(Size2MiB::SIZE));
+            }
+            split_huge_page(p2_entry, Size4KiB::SIZE, allocator, physical_base)?;
+        }
+
+        let Some(p1) = p2.next_table_mut(address.p2_index(), physical_base) else {
+            return Ok(None);
+        };
+        let p1_entry = &mut p1[address.p1_index()];
+        if !p1_entry.is_valid() {
+            return Ok(None);
+        }
+        let Some(frame) = p1_entry.address() else {
+            return Ok(None);
+        };
+        p1_entry.set(Some((frame, flags)), true);
+        Ok(Some(Size4KiB::SIZE))
+    }
+}
+
+/// Replace a leaf entry that maps a huge page with a new table of smaller pages of `page_size` that map the same
+/// memory with the same flags.
+fn split_huge_page<A>(
+    entry: &mut Entry,
+    page_size: usize,
+    allocator: &A,
+    physical_base: VAddr,
+) -> Result<(), PagingError>
+where
+    A: FrameAllocator<Size4KiB>,
+{
+    let address = entry.address().unwrap();
+    let flags = entry.flags();
+
+    let table_frame = allocator.try_allocate().ok_or(PagingError::OutOfMemory)?.start;
+    let table: &mut [Entry; ENTRY_COUNT] = unsafe { &mut *((physical_base + usize::from(table_frame)).mut_ptr()) };
+    for (i, new_entry) in table.iter_mut().enumerate() {
+        new_entry.set(Some((address + i * page_size, flags)), true);
+    }
+
+    entry.set(Some((table_frame, EntryFlags::VALID)), false);
+    Ok(())
 }
 
 impl fmt::Debug for PageTableImpl<Level3> {
@@ -760,6 +1038,89 @@ impl PageTable<Size4KiB> for PageTableImpl<Level3> {
             _ => panic!("Unimplemented page size!"),
         }
     }
+
+    fn unmap_area(&mut self, virtual_start: VAddr, size: usize) {
+        assert!(virtual_start.is_aligned(Size4KiB::SIZE));
+        assert!(size % Size4KiB::SIZE == 0);
+
+        let virtual_end = virtual_start + size;
+        let mut cursor = virtual_start;
+
+        /*
+         * The area may have been mapped with larger pages, so clear whatever entry maps each address, and then skip
+         * over the whole page it mapped.
+         */
+        while cursor < virtual_end {
+            match self.clear_entry(cursor) {
+                Some(page_size) => {
+                    assert!(cursor.is_aligned(page_size));
+                    sfence_vma(None, Some(cursor));
+                    cursor += page_size;
+                }
+                None => cursor += Size4KiB::SIZE,
+            }
+        }
+    }
+
+    fn protect_area<A>(
+        &mut self,
+        virtual_start: VAddr,
+        size: usize,
+        flags: Flags,
+        allocator: &A,
+    ) -> Result<(), PagingError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        assert!(virtual_start.is_aligned(Size4KiB::SIZE));
+        assert!(size % Size4KiB::SIZE == 0);
+
+        let virtual_end = virtual_start + size;
+        let mut cursor = virtual_start;
+
+        while cursor < virtual_end {
+            match self.protect_entry(cursor, virtual_end, EntryFlags::from(flags), allocator)? {
+                Some(page_size) => {
+                    sfence_vma(None, Some(cursor));
+                    cursor += page_size;
+                }
+                None => cursor += Size4KiB::SIZE,
+            }
+        }
+
+        Ok(())
+    }
+
+    unsafe fn free_lower_half<A>(&mut self, allocator: &A)
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let physical_base = self.physical_base;
+        let p3 = self.top_mut();
+
+        for i in 0..(ENTRY_COUNT / 2) {
+            if p3[i].is_leaf() {
+                continue;
+            }
+
+            if let Some(p2) = p3.next_table(i, physical_base) {
+                for j in 0..ENTRY_COUNT {
+                    if p2[j].is_leaf() {
+                        continue;
+                    }
+
+                    if let Some(p1_address) = p2[j].address() {
+                        allocator.free_n(Frame::starts_with(p1_address), 1);
+                    }
+                }
+
+                allocator.free_n(Frame::starts_with(p3[i].address().unwrap()), 1);
+                p3[i].set(None, false);
+            }
+        }
+
+        allocator.free_n(self.frame, 1);
+    }
 }
 
 pub trait VAddrIndices {
@@ -310,6 +310,11 @@ pub macro wrap_handler_with_error_code($name: path) {{
                   pop rbx
                   pop rax
 
+                  /*
+                   * Pop the error code, so the stack is laid out as `iretq` expects.
+                   */
+                  add rsp, 8
+
                   iretq",
                 sym $name
             )
@@ -228,7 +228,8 @@ where
             /*
              * This entry is empty, so we create a new page table, zero it, and return that.
              */
-            self.entries[index].set(Some((allocator.allocate().start, EntryFlags::NON_TERMINAL_FLAGS)));
+            let frame = allocator.try_allocate().ok_or(PagingError::OutOfMemory)?;
+            self.entries[index].set(Some((frame.start, EntryFlags::NON_TERMINAL_FLAGS)));
             let table = self.next_table_mut(index, physical_base).unwrap();
             table.zero();
             Ok(table)
@@ -283,6 +284,110 @@ impl PageTableImpl {
     pub fn p4_mut(&mut self) -> &mut Table<Level4> {
         unsafe { &mut *((self.physical_base + usize::from(self.p4_frame.start)).mut_ptr()) }
     }
+
+    /// Clear the entry that maps `address`, which may map a 4KiB, 2MiB, or 1GiB page. Returns the size of the
+    /// page that was unmapped, or `None` if `address` is not mapped. The TLB is not invalidated.
+    fn clear_entry(&mut self, address: VAddr) -> Option<usize> {
+        let physical_base = self.physical_base;
+
+        let p3 = self.p4_mut().next_table_mut(address.p4_index(), physical_base)?;
+        if p3[address.p3_index()].flags().contains(EntryFlags::HUGE_PAGE) {
+            p3[address.p3_index()].set(None);
+            return Some(Size1GiB::SIZE);
+        }
+
+        let p2 = p3.next_table_mut(address.p3_index(), physical_base)?;
+        if p2[address.p2_index()].flags().contains(EntryFlags::HUGE_PAGE) {
+            p2[address.p2_index()].set(None);
+            return Some(Size2MiB::SIZE);
+        }
+
+        let p1 = p2.next_table_mut(address.p2_index(), physical_base)?;
+        if !p1[address.p1_index()].is_present() {
+            return None;
+        }
+        p1[address.p1_index()].set(None);
+        Some(Size4KiB::SIZE)
+    }
+
+    /// Set the flags of the entry that maps `address`. If it maps a huge page that extends outside of
+    /// `address..end`, the page is split first, so only the part inside the area is changed. Returns the size of
+    /// the page that was changed, or `None` if `address` is not mapped. The TLB is not invalidated.
+    fn protect_entry<A>(
+        &mut self,
+        address: VAddr,
+        end: VAddr,
+        flags: EntryFlags,
+        allocator: &A,
+    ) -> Result<Option<usize>, PagingError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let physical_base = self.physical_base;
+        let fits_in_area = |page_size: usize| {
+            address.is_aligned(page_size) && usize::from(end) - usize::from(address) >= page_size
+        };
+
+        let Some(p3) = self.p4_mut().next_table_mut(address.p4_index(), physical_base) else {
+            return Ok(None);
+        };
+        let p3_entry = &mut p3[address.p3_index()];
+        if p3_entry.flags().contains(EntryFlags::HUGE_PAGE) {
+            if fits_in_area(Size1GiB::SIZE) {
+                p3_entry.set(Some((p3_entry.address().unwrap(), flags | EntryFlags::HUGE_PAGE)));
+                return Ok(Some(Size1GiB::SIZE));
+            }
+            split_huge_page(p3_entry, Size2MiB::SIZE, EntryFlags::HUGE_PAGE, allocator, physical_base)?;
+        }
+
+        let Some(p2) = p3.next_table_mut(address.p3_index(), physical_base) else {
+            return Ok(None);
+        };
+        let p2_entry = &mut p2[address.p2_index()];
+        if p2_entry.flags().contains(EntryFlags::HUGE_PAGE) {
+            if fits_in_area(Size2MiB::SIZE) {
+                p2_entry.set(Some((p2_entry.address().unwrap(), flags | EntryFlags::HUGE_PAGE)));
+                return Ok(Some(Size2MiB::SIZE));
+            }
+            split_huge_page(p2_entry, Size4KiB::SIZE, EntryFlags::empty(), allocator, physical_base)?;
+        }
+
+        let Some(p1) = p2.next_table_mut(address.p2_index(), physical_base) else {
+            return Ok(None);
+        };
+        let p1_entry = &mut p1[address.p1_index()];
+        let Some(frame) = p1_entry.address() else {
+            return Ok(None);
+        };
+        p1_entry.set(Some((frame, flags)));
+        Ok(Some(Size4KiB::SIZE))
+    }
+}
+
+/// Replace an entry that maps a huge page with a new table of smaller pages of `page_size` that map the same memory
+/// with the same flags. `extra_flags` are added to each of the new entries (`HUGE_PAGE` if the smaller pages are
+/// themselves huge pages).
+fn split_huge_page<A>(
+    entry: &mut Entry,
+    page_size: usize,
+    extra_flags: EntryFlags,
+    allocator: &A,
+    physical_base: VAddr,
+) -> Result<(), PagingError>
+where
+    A: FrameAllocator<Size4KiB>,
+{
+    let address = entry.address().unwrap();
+    let flags = entry.flags() - EntryFlags::HUGE_PAGE;
+
+    let table_frame = allocator.try_allocate().ok_or(PagingError::OutOfMemory)?.start;
+    let table: &mut [Entry; ENTRY_COUNT] = unsafe { &mut *((physical_base + usize::from(table_frame)).mut_ptr()) };
+    for (i, new_entry) in table.iter_mut().enumerate() {
+        new_entry.set(Some((address + i * page_size, flags | extra_flags)));
+    }
+
+    entry.set(Some((table_frame, EntryFlags::NON_TERMINAL_FLAGS)));
+    Ok(())
 }
 
 impl fmt::Debug for PageTableImpl {
@@ -535,6 +640,95 @@ impl PageTable<Size4KiB> for PageTableImpl {
             _ => panic!("Unimplemented page size!"),
         }
     }
+
+    fn unmap_area(&mut self, virtual_start: VAddr, size: usize) {
+        assert!(virtual_start.is_aligned(Size4KiB::SIZE));
+        assert!(size % Size4KiB::SIZE == 0);
+
+        let virtual_end = virtual_start + size;
+        let mut cursor = virtual_start;
+
+        /*
+         * The area may have been mapped with larger pages, so clear whatever entry maps each address, and then skip
+         * over the whole page it mapped.
+         */
+        while cursor < virtual_end {
+            match self.clear_entry(cursor) {
+                Some(page_size) => {
+                    assert!(cursor.is_aligned(page_size));
+                    tlb::invalidate_page(cursor);
+                    cursor += page_size;
+                }
+                None => cursor += Size4KiB::SIZE,
+            }
+        }
+    }
+
+    fn protect_area<A>(
+        &mut self,
+        virtual_start: VAddr,
+        size: usize,
+        flags: Flags,
+        allocator: &A,
+    ) -> Result<(), PagingError>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        assert!(virtual_start.is_aligned(Size4KiB::SIZE));
+        assert!(size % Size4KiB::SIZE == 0);
+
+        let virtual_end = virtual_start + size;
+        let mut cursor = virtual_start;
+
+        while cursor < virtual_end {
+            match self.protect_entry(cursor, virtual_end, EntryFlags::from(flags), allocator)? {
+                Some(page_size) => {
+                    tlb::invalidate_page(cursor);
+                    cursor += page_size;
+                }
+                None => cursor += Size4KiB::SIZE,
+            }
+        }
+
+        Ok(())
+    }
+
+    unsafe fn free_lower_half<A>(&mut self, allocator: &A)
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let physical_base = self.physical_base;
+        let p4 = self.p4_mut();
+
+        for i in 0..(ENTRY_COUNT / 2) {
+            if let Some(p3) = p4.next_table(i, physical_base) {
+                for j in 0..ENTRY_COUNT {
+                    if p3[j].flags().contains(EntryFlags::HUGE_PAGE) {
+                        continue;
+                    }
+
+                    if let Some(p2) = p3.next_table(j, physical_base) {
+                        for k in 0..ENTRY_COUNT {
+                            if p2[k].flags().contains(EntryFlags::HUGE_PAGE) {
+                                continue;
+                            }
+
+                            if let Some(p1_address) = p2[k].address() {
+                                allocator.free_n(Frame::starts_with(p1_address), 1);
+                            }
+                        }
+
+                        allocator.free_n(Frame::starts_with(p3[j].address().unwrap()), 1);
+                    }
+                }
+
+                allocator.free_n(Frame::starts_with(p4[i].address().unwrap()), 1);
+                p4[i].set(None);
+            }
+        }
+
+        allocator.free_n(self.p4_frame, 1);
+    }
 }
 
 pub trait VAddrIndices {
@@ -894,5 +1088,29 @@ mod tests {
         {
             unimplemented!()
         }
+
+        fn unmap_area(&mut self, _virtual_start: VAddr, _size: usize) {
+            unimplemented!()
+        }
+
+        fn protect_area<A>(
+            &mut self,
+            _virtual_start: VAddr,
+            _size: usize,
+            _flags: Flags,
+            _allocator: &A,
+        ) -> Result<(), PagingError>
+        where
+            A: FrameAllocator<Size4KiB>,
+        {
+            unimplemented!()
+        }
+
+        unsafe fn free_lower_half<A>(&mut self, _allocator: &A)
+        where
+            A: FrameAllocator<Size4KiB>,
+        {
+            unimplemented!()
+        }
     }
 }
@@ -0,0 +1,96 @@
+//! Capabilities describe what a task is allowed to do, and are encoded in its image. They are stored in an entry
+//! of a `PT_NOTE` segment, with an owner of `POPLAR` and a type of `0`. The descriptor is a stream of capabilities,
+//! each encoded as one or more bytes, and padded with `0x00` bytes. See the book for the full format.
+//!
+//! A task declares its capabilities with the `capabilities!` macro:
+//! ```ignore
+//! poplar::capabilities!(CAP_EARLY_LOGGING, CAP_GET_FRAMEBUFFER);
+//! ```
+
+/// The owner of the note entry that capabilities are encoded in. Note owners are NUL-terminated in the image.
+pub const NOTE_OWNER: &[u8] = b"POPLAR";
+/// The type of the note entry that capabilities are encoded in.
+pub const NOTE_TYPE: u32 = 0;
+
+pub const CAP_PADDING: u8 = 0x00;
+pub const CAP_GET_FRAMEBUFFER: u8 = 0x01;
+pub const CAP_EARLY_LOGGING: u8 = 0x02;
+pub const CAP_SERVICE_PROVIDER: u8 = 0x03;
+pub const CAP_SERVICE_USER: u8 = 0x04;
+pub const CAP_PCI_BUS_DRIVER: u8 = 0x05;
+pub const CAP_HANDLE_INTERRUPTS: u8 = 0x06;
+
+bitflags::bitflags! {
+    /// A decoded set of capabilities.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct Capabilities: u32 {
+        /// Allows the task to get a handle to the framebuffer created by the kernel.
+        const GET_FRAMEBUFFER = 1 << 0;
+        /// Allows the task to log messages through the kernel with `early_log`.
+        const EARLY_LOGGING = 1 << 1;
+        const SERVICE_PROVIDER = 1 << 2;
+        const SERVICE_USER = 1 << 3;
+        /// Allows the task to get information about the PCI devices on the platform with `pci_get_info`.
+        const PCI_BUS_DRIVER = 1 << 4;
+        /// Allows the task to wait for and acknowledge `Interrupt`s.
+        const HANDLE_INTERRUPTS = 1 << 5;
+    }
+}
+
+impl Capabilities {
+    /// Decode a stream of encoded capabilities. Returns `None` if the encoding is invalid.
+    pub fn decode(encoding: &[u8]) -> Option<Capabilities> {
+        let mut capabilities = Capabilities::empty();
+
+        for &byte in encoding {
+            capabilities |= match byte {
+                CAP_PADDING => Capabilities::empty(),
+                CAP_GET_FRAMEBUFFER => Capabilities::GET_FRAMEBUFFER,
+                CAP_EARLY_LOGGING => Capabilities::EARLY_LOGGING,
+                CAP_SERVICE_PROVIDER => Capabilities::SERVICE_PROVIDER,
+                CAP_SERVICE_USER => Capabilities::SERVICE_USER,
+                CAP_PCI_BUS_DRIVER => Capabilities::PCI_BUS_DRIVER,
+                CAP_HANDLE_INTERRUPTS => Capabilities::HANDLE_INTERRUPTS,
+                _ => return None,
+            };
+        }
+
+        Some(capabilities)
+    }
+}
+
+/// The layout of a note entry holding `N` bytes of encoded capabilities. This is placed in the image by the
+/// `capabilities!` macro - it shouldn't need to be used directly.
+#[repr(C, align(4))]
+pub struct CapabilitiesNote<const N: usize> {
+    name_size: u32,
+    desc_size: u32,
+    entry_type: u32,
+    name: [u8; 8],
+    desc: [u8; N],
+}
+
+impl<const N: usize> CapabilitiesNote<N> {
+    pub const fn new(desc: [u8; N]) -> CapabilitiesNote<N> {
+        CapabilitiesNote {
+            // The name includes its NUL terminator, and is padded to a multiple of 4 bytes
+            name_size: NOTE_OWNER.len() as u32 + 1,
+            desc_size: N as u32,
+            entry_type: NOTE_TYPE,
+            name: *b"POPLAR\0\0",
+            desc,
+        }
+    }
+}
+
+/// Declare the capabilities of the task this is used in. Each argument should be the name of one of the `CAP_*`
+/// constants in `poplar::caps`. This should be used at most once in each task's image.
+#[macro_export]
+macro_rules! capabilities {
+    ($($cap:ident),+ $(,)?) => {
+        #[used]
+        #[link_section = ".note.poplar"]
+        static POPLAR_CAPABILITIES: $crate::caps::CapabilitiesNote<{ [$($crate::caps::$cap),*].len() }> =
+            $crate::caps::CapabilitiesNote::new([$($crate::caps::$cap),*]);
+    };
+}
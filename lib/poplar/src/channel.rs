@@ -1,25 +1,61 @@
 use crate::{
-    syscall::{self, CreateChannelError, GetMessageError, SendMessageError, CHANNEL_MAX_NUM_HANDLES},
+    syscall::{
+        self,
+        ChannelCallError,
+        CreateChannelError,
+        CreateMemoryObjectError,
+        GetMessageError,
+        MapMemoryObjectError,
+        MemoryObjectFlags,
+        ObjectDetails,
+        SendMessageError,
+        Signals,
+        TransactionId,
+        WaitForMessageError,
+        CHANNEL_MAX_NUM_BYTES,
+        CHANNEL_MAX_NUM_HANDLES,
+    },
     Handle,
 };
-use alloc::vec::Vec;
-use core::{future::Future, marker::PhantomData, mem, task::Poll};
+use alloc::{vec, vec::Vec};
+use core::{future::Future, marker::PhantomData, mem, ptr, slice, task::Poll};
 use ptah::{DeserializeOwned, Serialize};
 
-// TODO: we now have heap-allocated buffers for sending, but still have bounded receives based on
-// stack sizes. Is there any way of dealing with larger messages on receive?
-const BYTES_BUFFER_SIZE: usize = 2048;
+/*
+ * Messages that are too large to be sent through the kernel are sent "out of line": the encoded message is
+ * written into a `MemoryObject`, preceded by its length as a `u64`, and the `MemoryObject` is sent as the first
+ * handle of a message with no bytes. Messages encoded by `ptah` always have bytes if they have handles, so these
+ * can't be mistaken for normal messages.
+ */
+const OUT_OF_LINE_HEADER_SIZE: usize = mem::size_of::<u64>();
 
 #[derive(Debug)]
 pub enum ChannelSendError {
     FailedToSerialize(ptah::ser::Error),
     SendError(SendMessageError),
+    /// The message was too large to send inline, and creating the `MemoryObject` to send it in failed.
+    FailedToCreatePayload(CreateMemoryObjectError),
+    FailedToMapPayload(MapMemoryObjectError),
 }
 
 #[derive(Debug)]
 pub enum ChannelReceiveError {
     FailedToDeserialize(ptah::de::Error),
     ReceiveError(GetMessageError),
+    WaitError(WaitForMessageError),
+    FailedToMapPayload(MapMemoryObjectError),
+    /// The message was sent out of line, but the handle it was sent in isn't a valid payload.
+    InvalidPayload,
+    /// A call was expected, but the received message was sent normally, so it can't be replied to.
+    NotACall,
+}
+
+#[derive(Debug)]
+pub enum CallError {
+    SendError(ChannelSendError),
+    CallError(ChannelCallError),
+    /// The reply arrived, but could not be decoded.
+    ReceiveError(ChannelReceiveError),
 }
 
 pub struct Channel<S, R>(Handle, PhantomData<(S, R)>)
@@ -43,82 +79,301 @@ where
         Ok((Self::new_from_handle(this_end), other_end))
     }
 
-    pub fn send(&self, message: &S) -> Result<(), ChannelSendError> {
+    /// Create a new channel, limiting how many messages (and bytes) can be queued at each end. See
+    /// `syscall::create_channel_with_limits`.
+    pub fn create_with_limits(
+        max_messages: usize,
+        max_bytes: usize,
+    ) -> Result<(Channel<S, R>, Handle), CreateChannelError> {
+        let (this_end, other_end) = syscall::create_channel_with_limits(max_messages, max_bytes)?;
+        Ok((Self::new_from_handle(this_end), other_end))
+    }
+
+    /// Returns `true` if the other end of the channel has been closed. Messages sent before it was closed may
+    /// still be waiting to be received.
+    pub fn is_peer_closed(&self) -> bool {
+        syscall::poll_interest(self.0).map_or(false, |signals| signals.contains(Signals::PEER_CLOSED))
+    }
+
+    /// Try to send a message down the channel. If the other end's queue is full, this fails with
+    /// `SendMessageError::QueueFull` instead of waiting for space.
+    pub fn try_send(&self, message: &S) -> Result<(), ChannelSendError> {
+        let mut writer = Self::serialize(message)?;
+        writer.send(self.0).map_err(|err| ChannelSendError::SendError(err))
+    }
+
+    /// Send a message down the channel. If the other end's queue is full, this blocks until it has space for the
+    /// message.
+    pub fn send_blocking(&self, message: &S) -> Result<(), ChannelSendError> {
+        let mut writer = Self::serialize(message)?;
+
+        loop {
+            match writer.send(self.0) {
+                Err(SendMessageError::QueueFull) => self.wait_until_writable(),
+                result => return result.map_err(|err| ChannelSendError::SendError(err)),
+            }
+        }
+    }
+
+    /// Asynchronously send a message down the channel. If the other end's queue is full, this waits until it has
+    /// space for the message. The message is serialized straight away, so it doesn't need to outlive the future.
+    pub fn send(&self, message: &S) -> impl Future<Output = Result<(), ChannelSendError>> + '_ {
+        let writer = Self::serialize(message);
+
+        async move {
+            let mut writer = writer?;
+            core::future::poll_fn(|context| match writer.send(self.0) {
+                Err(SendMessageError::QueueFull) => {
+                    crate::rt::runtime().reactor.lock().register_writable(self.0, context.waker().clone());
+                    Poll::Pending
+                }
+                result => Poll::Ready(result.map_err(|err| ChannelSendError::SendError(err))),
+            })
+            .await
+        }
+    }
+
+    /// Make a call through the channel: send a message, and block until the other end replies to it. If the other
+    /// end's queue is full, this first waits for it to have space for the message.
+    pub fn call_blocking(&self, message: &S) -> Result<R, CallError> {
+        let mut writer = Self::serialize(message).map_err(|err| CallError::SendError(err))?;
+
+        let transaction = loop {
+            match writer.begin_call(self.0) {
+                Ok(transaction) => break transaction,
+                Err(ChannelCallError::QueueFull) => self.wait_until_writable(),
+                Err(err) => return Err(CallError::CallError(err)),
+            }
+        };
+        self.get_reply(transaction, true)
+    }
+
+    /// Asynchronously make a call through the channel, resolving to the other end's reply. Like `send`, the
+    /// message is serialized straight away.
+    pub fn call(&self, message: &S) -> impl Future<Output = Result<R, CallError>> + '_ {
+        let writer = Self::serialize(message);
+
+        async move {
+            let mut writer = writer.map_err(|err| CallError::SendError(err))?;
+            let transaction = core::future::poll_fn(|context| match writer.begin_call(self.0) {
+                Err(ChannelCallError::QueueFull) => {
+                    crate::rt::runtime().reactor.lock().register_writable(self.0, context.waker().clone());
+                    Poll::Pending
+                }
+                result => Poll::Ready(result),
+            })
+            .await
+            .map_err(|err| CallError::CallError(err))?;
+
+            core::future::poll_fn(|context| match self.get_reply(transaction, false) {
+                Err(CallError::CallError(ChannelCallError::NoReply)) => {
+                    crate::rt::runtime().reactor.lock().register_reply(self.0, context.waker().clone());
+                    Poll::Pending
+                }
+                result => Poll::Ready(result),
+            })
+            .await
+        }
+    }
+
+    /// Collect the reply to a call, waiting for it to arrive if `block` is `true`. Replies larger than
+    /// `CHANNEL_MAX_NUM_BYTES` are sent out of line, so a buffer of that size can receive any reply.
+    fn get_reply(&self, transaction: TransactionId, block: bool) -> Result<R, CallError> {
+        let mut byte_buffer = vec![0u8; CHANNEL_MAX_NUM_BYTES];
+        let mut handle_buffer = [Handle::ZERO; CHANNEL_MAX_NUM_HANDLES];
+
+        let (bytes, handles) =
+            syscall::get_reply(self.0, transaction, &mut byte_buffer, &mut handle_buffer, block)
+                .map_err(|err| CallError::CallError(err))?;
+        Self::decode_message(bytes, handles).map_err(|err| CallError::ReceiveError(err))
+    }
+
+    /// Reply to a call received with `receive_call` or `try_receive_call`. Replies don't count against the limits
+    /// on the caller's queue, so this never has to wait.
+    pub fn reply(&self, transaction: TransactionId, message: &S) -> Result<(), ChannelSendError> {
+        let mut writer = Self::serialize(message)?;
+        writer.reply(self.0, transaction).map_err(|err| ChannelSendError::SendError(err))
+    }
+
+    fn serialize(message: &S) -> Result<ChannelWriter, ChannelSendError> {
         let mut writer = ChannelWriter::new();
         ptah::to_wire(message, &mut writer).map_err(|err| ChannelSendError::FailedToSerialize(err))?;
-        syscall::send_message(self.0, writer.bytes(), writer.handles())
-            .map_err(|err| ChannelSendError::SendError(err))
+        if writer.byte_buffer.len() > CHANNEL_MAX_NUM_BYTES {
+            writer.move_out_of_line()?;
+        }
+        Ok(writer)
+    }
+
+    /// Block until the other end of the channel has space for another message. This is used outside of the
+    /// async runtime, so we wait on a `Port` of our own.
+    fn wait_until_writable(&self) {
+        let port = syscall::create_port().expect("Failed to create port to wait for channel");
+        syscall::bind_to_port(port, self.0, 0, Signals::WRITABLE).unwrap();
+        syscall::wait_port(port, true).unwrap();
+        syscall::close_handle(port).unwrap();
     }
 
     /// Receive a message from the channel, if there's one waiting. Returns `Ok(None)` if there are no pending
     /// messages to be received.
     pub fn try_receive(&self) -> Result<Option<R>, ChannelReceiveError> {
-        let mut byte_buffer = [0u8; BYTES_BUFFER_SIZE];
-        let mut handle_buffer = [Handle::ZERO; CHANNEL_MAX_NUM_HANDLES];
+        match self.receive_message() {
+            Ok((message, _)) => Ok(Some(message)),
+            Err(ChannelReceiveError::ReceiveError(GetMessageError::NoMessage)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
 
-        match syscall::get_message(self.0, &mut byte_buffer, &mut handle_buffer) {
-            Ok((bytes, handles)) => {
-                // TODO: this looks really bad, but is actually fine (since Handle is just a transparent wrapper
-                // around a `u32`). There might be a better way.
-                let ptah_handles: &[u32] = unsafe { mem::transmute(handles) };
+    /// Wait for a message to arrive via the channel. If the other end of the channel has been closed and all of
+    /// its messages have been received, this returns `GetMessageError::PeerClosed` instead of waiting forever.
+    pub fn receive_blocking(&self) -> Result<R, ChannelReceiveError> {
+        loop {
+            match self.receive_message() {
+                Err(ChannelReceiveError::ReceiveError(GetMessageError::NoMessage)) => {
+                    syscall::wait_for_message(self.0).map_err(|err| ChannelReceiveError::WaitError(err))?;
+                }
+                result => return result.map(|(message, _)| message),
+            }
+        }
+    }
 
-                let message: R = ptah::from_wire(bytes, ptah_handles)
-                    .map_err(|err| ChannelReceiveError::FailedToDeserialize(err))?;
-                Ok(Some(message))
+    /// Asynchronously wait for a message to arrive via the channel. Like `receive_blocking`, this resolves to
+    /// `GetMessageError::PeerClosed` once the other end has been closed and there are no messages left.
+    pub fn receive(&self) -> impl Future<Output = Result<R, ChannelReceiveError>> + '_ {
+        core::future::poll_fn(|context| match self.receive_message() {
+            Err(ChannelReceiveError::ReceiveError(GetMessageError::NoMessage)) => {
+                crate::rt::runtime().reactor.lock().register(self.0, context.waker().clone());
+                Poll::Pending
             }
-            Err(GetMessageError::NoMessage) => Ok(None),
-            Err(err) => Err(ChannelReceiveError::ReceiveError(err)),
+            result => Poll::Ready(result.map(|(message, _)| message)),
+        })
+    }
+
+    /// Receive a call made through the other end of the channel, if there's one waiting. The returned
+    /// `TransactionId` should be passed to `reply`. Messages that were sent normally, instead of as a call, produce
+    /// `ChannelReceiveError::NotACall`.
+    pub fn try_receive_call(&self) -> Result<Option<(R, TransactionId)>, ChannelReceiveError> {
+        match self.receive_message() {
+            Ok((message, Some(transaction))) => Ok(Some((message, transaction))),
+            Ok((_, None)) => Err(ChannelReceiveError::NotACall),
+            Err(ChannelReceiveError::ReceiveError(GetMessageError::NoMessage)) => Ok(None),
+            Err(err) => Err(err),
         }
     }
 
-    /// Wait for a message to arrive via the channel.
-    pub fn receive_blocking(&self) -> Result<R, ChannelReceiveError> {
+    /// Asynchronously wait for a call to arrive via the channel. See `try_receive_call`.
+    pub fn receive_call(&self) -> impl Future<Output = Result<(R, TransactionId), ChannelReceiveError>> + '_ {
+        core::future::poll_fn(|context| match self.receive_message() {
+            Err(ChannelReceiveError::ReceiveError(GetMessageError::NoMessage)) => {
+                crate::rt::runtime().reactor.lock().register(self.0, context.waker().clone());
+                Poll::Pending
+            }
+            Ok((message, Some(transaction))) => Poll::Ready(Ok((message, transaction))),
+            Ok((_, None)) => Poll::Ready(Err(ChannelReceiveError::NotACall)),
+            Err(err) => Poll::Ready(Err(err)),
+        })
+    }
+
+    /// Receive the next message from the channel, into buffers allocated to fit it exactly. If there are no
+    /// messages waiting, this fails with `GetMessageError::NoMessage`. If the message is a call, its transaction ID
+    /// is also returned.
+    fn receive_message(&self) -> Result<(R, Option<TransactionId>), ChannelReceiveError> {
         loop {
-            let mut byte_buffer = [0u8; BYTES_BUFFER_SIZE];
+            let size = syscall::peek_message(self.0).map_err(|err| ChannelReceiveError::ReceiveError(err))?;
+            let mut byte_buffer = vec![0u8; size.num_bytes];
             let mut handle_buffer = [Handle::ZERO; CHANNEL_MAX_NUM_HANDLES];
 
-            match syscall::get_message(self.0, &mut byte_buffer, &mut handle_buffer) {
-                Ok((bytes, handles)) => {
-                    // TODO: this looks really bad, but is actually fine (since Handle is just a transparent wrapper
-                    // around a `u32`). There might be a better way.
-                    let ptah_handles: &[u32] = unsafe { mem::transmute(handles) };
-
-                    let message: R = ptah::from_wire(bytes, ptah_handles)
-                        .map_err(|err| ChannelReceiveError::FailedToDeserialize(err))?;
-                    return Ok(message);
-                }
-                Err(GetMessageError::NoMessage) => {
-                    crate::syscall::yield_to_kernel();
-                }
-                Err(err) => {
-                    return Err(ChannelReceiveError::ReceiveError(err));
+            match syscall::get_message_with_transaction(self.0, &mut byte_buffer, &mut handle_buffer) {
+                Ok((bytes, handles, transaction)) => {
+                    return Ok((Self::decode_message(bytes, handles)?, transaction));
                 }
+                /*
+                 * If the message has been received by someone else since we peeked at it, the next one might be
+                 * larger - try again with new buffers.
+                 */
+                Err(GetMessageError::BytesBufferTooSmall) => continue,
+                Err(err) => return Err(ChannelReceiveError::ReceiveError(err)),
             }
         }
     }
 
-    pub fn receive(&self) -> impl Future<Output = Result<R, ChannelReceiveError>> + '_ {
-        core::future::poll_fn(|context| {
-            let mut byte_buffer = [0u8; BYTES_BUFFER_SIZE];
-            let mut handle_buffer = [Handle::ZERO; CHANNEL_MAX_NUM_HANDLES];
+    fn decode_message(bytes: &[u8], handles: &[Handle]) -> Result<R, ChannelReceiveError> {
+        if bytes.is_empty() && !handles.is_empty() {
+            Self::decode_out_of_line(handles)
+        } else {
+            Self::decode(bytes, handles)
+        }
+    }
 
-            match syscall::get_message(self.0, &mut byte_buffer, &mut handle_buffer) {
-                Ok((bytes, handles)) => {
-                    // TODO: this looks really bad, but is actually fine (since Handle is just a transparent wrapper
-                    // around a `u32`). There might be a better way.
-                    let ptah_handles: &[u32] = unsafe { mem::transmute(handles) };
+    fn decode(bytes: &[u8], handles: &[Handle]) -> Result<R, ChannelReceiveError> {
+        // TODO: this looks really bad, but is actually fine (since Handle is just a transparent wrapper
+        // around a `u32`). There might be a better way.
+        let ptah_handles: &[u32] = unsafe { mem::transmute(handles) };
+        ptah::from_wire(bytes, ptah_handles).map_err(|err| ChannelReceiveError::FailedToDeserialize(err))
+    }
 
-                    let message: R = ptah::from_wire(bytes, ptah_handles)
-                        .map_err(|err| ChannelReceiveError::FailedToDeserialize(err))?;
-                    Poll::Ready(Ok(message))
-                }
-                Err(GetMessageError::NoMessage) => {
-                    crate::rt::RUNTIME.get().reactor.lock().register(self.0, context.waker().clone());
-                    Poll::Pending
-                }
-                Err(err) => Poll::Ready(Err(ChannelReceiveError::ReceiveError(err))),
+    /// Decode a message that was sent out of line. The first handle is the `MemoryObject` holding the message,
+    /// and the rest are the handles sent with it.
+    fn decode_out_of_line(handles: &[Handle]) -> Result<R, ChannelReceiveError> {
+        let payload = handles[0];
+
+        /*
+         * The sender may have kept another handle to the payload, and could resize it while we're reading it,
+         * which would make us fault. Read a copy-on-write clone of it instead, which only we hold a handle to.
+         */
+        let result = match syscall::clone_memory_object(payload) {
+            Ok(clone) => {
+                let result = Self::decode_payload(clone, &handles[1..]);
+                let _ = syscall::close_handle(clone);
+                result
             }
-        })
+            Err(_) => Err(ChannelReceiveError::InvalidPayload),
+        };
+
+        let _ = syscall::close_handle(payload);
+        result
+    }
+
+    /// Decode the message held in `payload`, which no other task can change.
+    fn decode_payload(payload: Handle, handles: &[Handle]) -> Result<R, ChannelReceiveError> {
+        let size = match syscall::object_info(payload) {
+            Ok(info) => match info.details {
+                ObjectDetails::MemoryObject { size, .. } => size,
+                _ => return Err(ChannelReceiveError::InvalidPayload),
+            },
+            Err(_) => return Err(ChannelReceiveError::InvalidPayload),
+        };
+        if size < OUT_OF_LINE_HEADER_SIZE {
+            return Err(ChannelReceiveError::InvalidPayload);
+        }
+
+        let mut address = 0usize;
+        unsafe {
+            syscall::map_memory_object(payload, Handle::ZERO, None, &mut address as *mut usize)
+                .map_err(|err| ChannelReceiveError::FailedToMapPayload(err))?;
+        }
+
+        let len = unsafe { ptr::read(address as *const u64) } as usize;
+        let result = if len <= size - OUT_OF_LINE_HEADER_SIZE {
+            let bytes = unsafe { slice::from_raw_parts((address + OUT_OF_LINE_HEADER_SIZE) as *const u8, len) };
+            Self::decode(bytes, handles)
+        } else {
+            Err(ChannelReceiveError::InvalidPayload)
+        };
+
+        unsafe {
+            syscall::unmap_memory_object(Handle::ZERO, address).unwrap();
+        }
+        result
+    }
+}
+
+impl<S, R> Drop for Channel<S, R>
+where
+    S: Serialize + DeserializeOwned,
+    R: Serialize + DeserializeOwned,
+{
+    fn drop(&mut self) {
+        let _ = syscall::close_handle(self.0);
     }
 }
 
@@ -126,6 +381,9 @@ struct ChannelWriter {
     byte_buffer: Vec<u8>,
     handle_buffer: [Handle; CHANNEL_MAX_NUM_HANDLES],
     num_handles: u8,
+    /// If the message is being sent out of line, the `MemoryObject` holding it. This is closed if the writer is
+    /// dropped without the message being sent.
+    payload: Option<Handle>,
 }
 
 impl ChannelWriter {
@@ -134,6 +392,7 @@ impl ChannelWriter {
             byte_buffer: Vec::new(),
             handle_buffer: [Handle::ZERO; CHANNEL_MAX_NUM_HANDLES],
             num_handles: 0,
+            payload: None,
         }
     }
 
@@ -144,6 +403,72 @@ impl ChannelWriter {
     pub fn handles(&self) -> &[Handle] {
         &self.handle_buffer[0..(self.num_handles as usize)]
     }
+
+    /// Send the written message down `channel`.
+    pub fn send(&mut self, channel: Handle) -> Result<(), SendMessageError> {
+        syscall::send_message(channel, self.bytes(), self.handles())?;
+        // The payload's handle has been transferred along with the message
+        self.payload = None;
+        Ok(())
+    }
+
+    /// Send the written message down `channel` as the reply to the call `transaction`.
+    pub fn reply(&mut self, channel: Handle, transaction: TransactionId) -> Result<(), SendMessageError> {
+        syscall::send_reply(channel, transaction, self.bytes(), self.handles())?;
+        self.payload = None;
+        Ok(())
+    }
+
+    /// Send the written message down `channel` as a call, without waiting for the reply.
+    pub fn begin_call(&mut self, channel: Handle) -> Result<TransactionId, ChannelCallError> {
+        let transaction = syscall::begin_channel_call(channel, self.bytes(), self.handles())?;
+        self.payload = None;
+        Ok(transaction)
+    }
+
+    /// Move the written message into a `MemoryObject`, so it can be sent out of line. The `MemoryObject` takes up
+    /// one of the message's handles.
+    fn move_out_of_line(&mut self) -> Result<(), ChannelSendError> {
+        if self.num_handles as usize + 1 > CHANNEL_MAX_NUM_HANDLES {
+            return Err(ChannelSendError::FailedToSerialize(ptah::ser::Error::WriterFullOfHandles));
+        }
+
+        let size = (OUT_OF_LINE_HEADER_SIZE + self.byte_buffer.len() + 0xfff) & !0xfff;
+        let payload = unsafe { syscall::create_memory_object(size, MemoryObjectFlags::WRITABLE, ptr::null_mut()) }
+            .map_err(|err| ChannelSendError::FailedToCreatePayload(err))?;
+        self.payload = Some(payload);
+
+        let mut address = 0usize;
+        unsafe {
+            syscall::map_memory_object(payload, Handle::ZERO, None, &mut address as *mut usize)
+                .map_err(|err| ChannelSendError::FailedToMapPayload(err))?;
+            ptr::write(address as *mut u64, self.byte_buffer.len() as u64);
+            ptr::copy_nonoverlapping(
+                self.byte_buffer.as_ptr(),
+                (address + OUT_OF_LINE_HEADER_SIZE) as *mut u8,
+                self.byte_buffer.len(),
+            );
+            syscall::unmap_memory_object(Handle::ZERO, address).unwrap();
+        }
+
+        /*
+         * The payload goes before the message's own handles, which then have the slots the receiver will see
+         * once it has removed the payload.
+         */
+        self.handle_buffer.copy_within(0..(self.num_handles as usize), 1);
+        self.handle_buffer[0] = payload;
+        self.num_handles += 1;
+        self.byte_buffer.clear();
+        Ok(())
+    }
+}
+
+impl Drop for ChannelWriter {
+    fn drop(&mut self) {
+        if let Some(payload) = self.payload {
+            let _ = syscall::close_handle(payload);
+        }
+    }
 }
 
 impl<'a> ptah::Writer for &'a mut ChannelWriter {
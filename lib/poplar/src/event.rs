@@ -1,5 +1,5 @@
 use crate::{
-    syscall::{self, WaitForEventError},
+    syscall::{self, ClearEventError, CreateEventError, SignalEventError, WaitForEventError},
     Handle,
 };
 use core::{future::Future, task::Poll};
@@ -11,6 +11,25 @@ impl Event {
         Event(handle)
     }
 
+    /// Create a new `Event`. It starts off cleared.
+    pub fn create() -> Result<Event, CreateEventError> {
+        Ok(Event(syscall::create_event()?))
+    }
+
+    /// Get the handle to the underlying `Event`, to pass it to another task (e.g. in a
+    /// `HandoffProperty::Event`).
+    pub fn handle(&self) -> Handle {
+        self.0
+    }
+
+    pub fn signal(&self) -> Result<(), SignalEventError> {
+        syscall::signal_event(self.0)
+    }
+
+    pub fn clear(&self) -> Result<(), ClearEventError> {
+        syscall::clear_event(self.0)
+    }
+
     pub fn wait_for_event(&self) -> impl Future<Output = ()> + '_ {
         core::future::poll_fn(|context| {
             /*
@@ -21,7 +40,7 @@ impl Event {
             match syscall::wait_for_event(self.0, false) {
                 Ok(()) => Poll::Ready(()),
                 Err(WaitForEventError::NoEvent) => {
-                    crate::rt::RUNTIME.get().reactor.lock().register(self.0, context.waker().clone());
+                    crate::rt::runtime().reactor.lock().register(self.0, context.waker().clone());
                     Poll::Pending
                 }
                 Err(other) => panic!("Error waiting for event: {:?}", other),
@@ -33,3 +52,9 @@ impl Event {
         syscall::wait_for_event(self.0, true).unwrap();
     }
 }
+
+impl Drop for Event {
+    fn drop(&mut self) {
+        let _ = syscall::close_handle(self.0);
+    }
+}
@@ -21,7 +21,7 @@ impl Interrupt {
             match syscall::wait_for_interrupt(self.0, false) {
                 Ok(()) => Poll::Ready(()),
                 Err(WaitForInterruptError::NoInterrupt) => {
-                    crate::rt::RUNTIME.get().reactor.lock().register(self.0, context.waker().clone());
+                    crate::rt::runtime().reactor.lock().register(self.0, context.waker().clone());
                     Poll::Pending
                 }
                 Err(other) => panic!("Error waiting for interrupt: {:?}", other),
@@ -39,3 +39,9 @@ impl Interrupt {
         }
     }
 }
+
+impl Drop for Interrupt {
+    fn drop(&mut self) {
+        let _ = syscall::close_handle(self.0);
+    }
+}
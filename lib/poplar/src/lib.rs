@@ -5,6 +5,7 @@
 #[cfg(feature = "can_alloc")]
 extern crate alloc;
 
+pub mod caps;
 #[cfg(feature = "can_alloc")]
 pub mod channel;
 #[cfg(feature = "ddk")]
@@ -18,6 +19,7 @@ pub mod memory_object;
 #[cfg(feature = "async")]
 pub mod rt;
 pub mod syscall;
+pub mod tls;
 
 use core::num::TryFromIntError;
 
@@ -60,12 +62,15 @@ impl<'de> ptah::Deserialize<'de> for Handle {
     }
 }
 
-// TODO: I don't think rights are implemented at all are they? Work out if we want them / remove
-// this.
 bitflags::bitflags! {
-    struct HandleRights: u32 {
+    /// Each handle carries a set of rights that dictate what its owner can do with the kernel object it refers
+    /// to. Rights can only ever be removed from a handle - a duplicate of a handle can be made with a subset of
+    /// its rights using `syscall::duplicate_handle`.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct HandleRights: u32 {
         /// Whether the handle's owner can use it to modify the kernel object it points to. What is means to
-        /// "modify" a kernel object differs depending on the type of the kernel object.
+        /// "modify" a kernel object differs depending on the type of the kernel object. For `MemoryObject`s,
+        /// handles without this right can only be mapped read-only.
         const MODIFY = 0b1;
         /// Whether the handle can be duplicated.
         const DUPLICATE = 0b10;
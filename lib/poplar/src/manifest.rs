@@ -17,4 +17,6 @@ pub struct BootTask {
     /// The segments that should be loaded into the task's address space. In the format `(virtual
     /// address, handle to MemoryObject)`.
     pub segments: Vec<(usize, u32)>,
+    /// The bits of the `Capabilities` the task's image requests.
+    pub capabilities: u32,
 }
@@ -1,5 +1,12 @@
 use crate::{
-    syscall::{self, CreateMemoryObjectError, MapMemoryObjectError, MemoryObjectFlags, ResizeMemoryObjectError},
+    syscall::{
+        self,
+        CreateMemoryObjectError,
+        MapMemoryObjectError,
+        MemoryObjectFlags,
+        ResizeMemoryObjectError,
+        UnmapMemoryObjectError,
+    },
     Handle,
 };
 use core::ptr;
@@ -56,6 +63,15 @@ impl MemoryObject {
     }
 }
 
+impl Drop for MemoryObject {
+    fn drop(&mut self) {
+        /*
+         * Closing the handle does not unmap the object - the kernel keeps it alive while it is still mapped.
+         */
+        let _ = syscall::close_handle(self.handle);
+    }
+}
+
 #[derive(Debug)]
 pub struct MappedMemoryObject {
     pub inner: MemoryObject,
@@ -81,4 +97,13 @@ impl MappedMemoryObject {
     pub unsafe fn resize(&mut self, new_size: usize) -> Result<(), ResizeMemoryObjectError> {
         unsafe { self.inner.resize(new_size) }
     }
+
+    /// Unmap the object from the task's address space, returning the unmapped `MemoryObject`. This is unsafe
+    /// because nothing can refer to the memory after it has been unmapped.
+    pub unsafe fn unmap(self) -> Result<MemoryObject, UnmapMemoryObjectError> {
+        unsafe {
+            syscall::unmap_memory_object(Handle::ZERO, self.mapped_at)?;
+        }
+        Ok(self.inner)
+    }
 }
@@ -7,43 +7,102 @@ mod reactor;
 pub use maitake;
 
 use self::reactor::Reactor;
-use core::future::Future;
-use maitake::{scheduler::Scheduler, task::JoinHandle};
-use mulch::InitGuard;
+use crate::{syscall, tls, Handle};
+use alloc::boxed::Box;
+use core::{future::Future, task::Waker, time::Duration};
+use maitake::{
+    scheduler::Scheduler,
+    task::JoinHandle,
+    time::{Clock, Sleep, Timeout, Timer},
+};
 use spinning_top::Spinlock;
 
-// TODO: if we want support for multiple tasks in an address space, this needs to be thread-local
-pub(crate) static RUNTIME: InitGuard<Runtime> = InitGuard::uninit();
-
+/// Each thread has its own runtime, which is found through its `ThreadControlBlock`. Futures are run by the
+/// runtime of the thread that spawns them, and register interest in kernel objects with that thread's reactor.
 pub struct Runtime {
     scheduler: Scheduler,
-    // TODO: maintain a timer wheel so time-based futures work in userspace
     pub reactor: Spinlock<Reactor>,
+    /// The timer wheel that drives time-based futures, such as `sleep` and `timeout`.
+    timer: Timer,
+    /// A kernel `Timer` that is set to the next deadline in the timer wheel before the runtime goes to sleep, so
+    /// the runtime is woken up in time to fire it.
+    kernel_timer: Handle,
 }
 
+/// Create a runtime for the calling thread. The runtime is never freed, so this should only be done by threads
+/// that will run it for the rest of their lives.
 pub fn init_runtime() {
-    RUNTIME.initialize(Runtime { scheduler: Scheduler::new(), reactor: Spinlock::new(Reactor::new()) });
+    let tcb = tls::current();
+    assert!(tcb.runtime.get().is_none(), "Runtime has already been initialized for this thread");
+
+    let clock = Clock::new(Duration::from_nanos(1), || syscall::get_time().as_nanos() as u64);
+    let kernel_timer = syscall::create_timer().expect("Failed to create kernel timer for runtime");
+
+    let runtime = Box::leak(Box::new(Runtime {
+        scheduler: Scheduler::new(),
+        reactor: Spinlock::new(Reactor::new()),
+        timer: Timer::new(clock),
+        kernel_timer,
+    }));
+    tcb.runtime.set(Some(runtime));
+}
+
+/// Get the calling thread's runtime. Panics if the thread hasn't initialized one.
+pub(crate) fn runtime() -> &'static Runtime {
+    tls::current().runtime.get().expect("Runtime has not been initialized for this thread")
 }
 
 pub fn enter_loop() {
     loop {
-        crate::syscall::yield_to_kernel();
+        let runtime = runtime();
+        let tick = runtime.scheduler.tick();
+        let turn = runtime.timer.turn();
+
+        /*
+         * If there are still tasks ready to run (including any woken by timers that have just
+         * fired), just pick up any events that have already happened. Otherwise, nothing can make
+         * progress until a kernel object becomes ready or the next timer fires, so sleep until one
+         * of those happens.
+         */
+        if tick.has_remaining || turn.expired > 0 {
+            runtime.reactor.lock().poll(false);
+        } else {
+            let mut reactor = runtime.reactor.lock();
 
-        // TODO: for userspace time - for now we could just have a syscall here to get the time
-        // elapsed to update the timer wheel with. In the future this could be mapped into
-        // userspace using one of those fancy kernel-supplied userspace shim things (like Fuchsia
-        // has/had) or maybe just return it from blocking syscalls??
+            if let Some(time_to_next_deadline) = turn.time_to_next_deadline() {
+                syscall::set_timer(runtime.kernel_timer, Some(syscall::get_time() + time_to_next_deadline))
+                    .unwrap();
+                /*
+                 * The timer wheel is turned every time round the loop, so we don't need to wake anything. The
+                 * registration stays until the timer fires, so only make it once.
+                 */
+                if !reactor.is_registered(runtime.kernel_timer) {
+                    reactor.register(runtime.kernel_timer, Waker::noop().clone());
+                }
+            }
 
-        let runtime = RUNTIME.get();
-        runtime.reactor.lock().poll();
-        runtime.scheduler.tick();
+            reactor.poll(true);
+        }
     }
 }
 
+/// Wait until `duration` has elapsed.
+pub fn sleep(duration: Duration) -> Sleep<'static> {
+    runtime().timer.sleep(duration)
+}
+
+/// Run `future`, giving up on it if it does not complete within `duration`.
+pub fn timeout<F>(duration: Duration, future: F) -> Timeout<'static, F>
+where
+    F: Future,
+{
+    runtime().timer.timeout(duration, future)
+}
+
 pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
 where
     F: Future + Send + 'static,
     F::Output: Send + 'static,
 {
-    RUNTIME.get().scheduler.spawn(future)
+    runtime().scheduler.spawn(future)
 }
@@ -1,34 +1,86 @@
-use crate::Handle;
+use crate::{
+    syscall::{self, Signals, WaitPortError},
+    Handle,
+};
 use alloc::{collections::BTreeMap, vec::Vec};
 use core::task::Waker;
 
 /// The `Reactor` is a component of the Poplar userspace async runtime that processes events from
 /// kernel objects in order to wake futures when they have work to do.
+///
+/// Handles that futures are interested in are bound to a kernel `Port`, using the handle and the
+/// signals of interest as the key. The kernel queues a packet on the port when one of them becomes
+/// ready, which allows the reactor to sleep until there is work to do, instead of polling each
+/// handle in turn. Several futures can be interested in the same signals on a handle (e.g. futures
+/// waiting for the replies to different calls on a channel), in which case they are all woken.
 pub struct Reactor {
-    interests: BTreeMap<Handle, Waker>,
+    port: Handle,
+    interests: BTreeMap<u64, Vec<Waker>>,
 }
 
 impl Reactor {
     pub fn new() -> Reactor {
-        Reactor { interests: BTreeMap::new() }
+        let port = syscall::create_port().expect("Failed to create port for reactor");
+        Reactor { port, interests: BTreeMap::new() }
     }
 
+    /// Wake `waker` when `handle` becomes readable.
     pub fn register(&mut self, handle: Handle, waker: Waker) {
-        self.interests.insert(handle, waker);
+        self.register_for(handle, Signals::READABLE, waker);
     }
 
-    pub fn poll(&mut self) {
-        /*
-         * Make a copy of the current list of handles we're interested in. We do this so we can
-         * later remove events that have been awoken.
-         */
-        let handles: Vec<Handle> = self.interests.keys().copied().collect();
+    /// Wake `waker` when `handle` becomes writable.
+    pub fn register_writable(&mut self, handle: Handle, waker: Waker) {
+        self.register_for(handle, Signals::WRITABLE, waker);
+    }
+
+    /// Wake `waker` when the reply to a call made through the channel `handle` arrives.
+    pub fn register_reply(&mut self, handle: Handle, waker: Waker) {
+        self.register_for(handle, Signals::REPLY, waker);
+    }
+
+    /// Returns `true` if anything is waiting for `handle` to become readable.
+    pub fn is_registered(&self, handle: Handle) -> bool {
+        self.interests.contains_key(&Self::key(handle, Signals::READABLE))
+    }
+
+    /// Different signals on the same handle get different keys, so a future waiting to read from a handle
+    /// doesn't replace one waiting to write to it.
+    fn key(handle: Handle, signals: Signals) -> u64 {
+        handle.0 as u64 | ((signals.bits() as u64) << 32)
+    }
+
+    fn register_for(&mut self, handle: Handle, signals: Signals, waker: Waker) {
+        let key = Self::key(handle, signals);
+        let wakers = self.interests.entry(key).or_default();
+        if wakers.is_empty() {
+            syscall::bind_to_port(self.port, handle, key, signals).unwrap();
+        }
+        wakers.push(waker);
+    }
+
+    /// Wake the futures interested in any handles that have become ready. If `block` is `true`, this
+    /// waits until at least one has.
+    pub fn poll(&mut self, block: bool) {
+        let mut block = block;
 
-        for handle in handles {
-            if crate::syscall::poll_interest(handle).unwrap() {
-                let waker = self.interests.remove(&handle).unwrap();
-                waker.wake();
+        loop {
+            match syscall::wait_port(self.port, block) {
+                Ok(packet) => {
+                    /*
+                     * Bindings are one-shot, so the handle is no longer bound to the port. It will be bound
+                     * again if the woken future is still interested in it.
+                     */
+                    for waker in self.interests.remove(&packet.key).into_iter().flatten() {
+                        waker.wake();
+                    }
+                }
+                Err(WaitPortError::NoPackets) => break,
+                Err(err) => panic!("Error waiting on reactor port: {:?}", err),
             }
+
+            // Only block for the first packet - after that, take the ones that are already queued
+            block = false;
         }
     }
 }
@@ -14,6 +14,9 @@ define_error_type!(GetFramebufferError {
 
     /// The kernel did not create a framebuffer.
     NoFramebufferCreated => 3,
+
+    /// The calling task has reached its quota of handles.
+    QuotaExceeded => 4,
 });
 
 /// Describes how the supplied framebuffer represents pixels.
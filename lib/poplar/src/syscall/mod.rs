@@ -2,7 +2,7 @@ pub mod get_framebuffer;
 pub mod pci;
 pub mod result;
 
-use core::mem::MaybeUninit;
+use core::{mem::MaybeUninit, ptr, sync::atomic::AtomicU32, time::Duration};
 
 pub use get_framebuffer::{get_framebuffer, FramebufferInfo, GetFramebufferError, PixelFormat};
 pub use pci::{pci_get_info, PciGetInfoError};
@@ -19,7 +19,7 @@ cfg_if::cfg_if! {
     }
 }
 
-use crate::Handle;
+use crate::{caps::Capabilities, Handle, HandleRights};
 use bit_field::BitField;
 use result::{define_error_type, handle_from_syscall_repr, status_from_syscall_repr};
 
@@ -40,6 +40,30 @@ pub const SYSCALL_SPAWN_TASK: usize = 15;
 pub const SYSCALL_RESIZE_MEMORY_OBJECT: usize = 16;
 pub const SYSCALL_WAIT_FOR_INTERRUPT: usize = 17;
 pub const SYSCALL_ACK_INTERRUPT: usize = 18;
+pub const SYSCALL_CLOSE_HANDLE: usize = 19;
+pub const SYSCALL_DUPLICATE_HANDLE: usize = 20;
+pub const SYSCALL_EXIT_TASK: usize = 21;
+pub const SYSCALL_GET_EXIT_STATUS: usize = 22;
+pub const SYSCALL_CREATE_PORT: usize = 23;
+pub const SYSCALL_BIND_TO_PORT: usize = 24;
+pub const SYSCALL_WAIT_PORT: usize = 25;
+pub const SYSCALL_GET_TIME: usize = 26;
+pub const SYSCALL_CREATE_TIMER: usize = 27;
+pub const SYSCALL_SET_TIMER: usize = 28;
+pub const SYSCALL_UNMAP_MEMORY_OBJECT: usize = 29;
+pub const SYSCALL_CLONE_MEMORY_OBJECT: usize = 30;
+pub const SYSCALL_PROTECT_MAPPING: usize = 31;
+pub const SYSCALL_OBJECT_INFO: usize = 32;
+pub const SYSCALL_PEEK_MESSAGE: usize = 33;
+pub const SYSCALL_CHANNEL_CALL: usize = 34;
+pub const SYSCALL_CREATE_EVENT: usize = 35;
+pub const SYSCALL_SIGNAL_EVENT: usize = 36;
+pub const SYSCALL_CLEAR_EVENT: usize = 37;
+pub const SYSCALL_FUTEX_WAIT: usize = 38;
+pub const SYSCALL_FUTEX_WAKE: usize = 39;
+pub const SYSCALL_SPAWN_THREAD: usize = 40;
+pub const SYSCALL_SET_THREAD_POINTER: usize = 41;
+pub const SYSCALL_SET_PRIORITY: usize = 42;
 
 pub fn yield_to_kernel() {
     unsafe {
@@ -63,6 +87,12 @@ define_error_type!(CreateMemoryObjectError {
     InvalidFlags => 1,
     InvalidSize => 2,
     InvalidPhysicalAddressPointer => 3,
+    /// A physical address was requested for a lazily-committed `MemoryObject`, which does not have one.
+    LazyObjectHasNoPhysicalAddress => 4,
+    /// The calling task has reached its quota of physical memory or handles.
+    QuotaExceeded => 5,
+    /// There is not enough free physical memory to back the `MemoryObject`.
+    OutOfMemory => 6,
 });
 
 bitflags::bitflags! {
@@ -70,6 +100,9 @@ bitflags::bitflags! {
     pub struct MemoryObjectFlags: u32 {
         const WRITABLE = 1 << 0;
         const EXECUTABLE = 1 << 1;
+        /// Physical memory for each page of the object is only allocated (and zeroed) when the page is first
+        /// accessed, rather than when the object is created.
+        const LAZY = 1 << 2;
     }
 }
 
@@ -90,6 +123,15 @@ define_error_type!(MapMemoryObjectError {
     InvalidAddressSpaceHandle => 2,
     RegionAlreadyMapped => 3,
     AddressPointerInvalid => 4,
+    /// The `MemoryObject` handle must have the `MAP` right to be mapped.
+    MemoryObjectCannotBeMapped => 5,
+    /// The `AddressSpace` handle must have the `MODIFY` right to map memory into it.
+    AddressSpaceCannotBeModified => 6,
+    /// No virtual address was supplied, and there is no free region of the `AddressSpace` large enough to map
+    /// the `MemoryObject` into.
+    AddressSpaceFull => 7,
+    /// There is not enough free physical memory to allocate the page tables needed to map the `MemoryObject`.
+    OutOfMemory => 8,
 });
 
 pub unsafe fn map_memory_object(
@@ -109,20 +151,104 @@ pub unsafe fn map_memory_object(
     })
 }
 
+define_error_type!(UnmapMemoryObjectError {
+    InvalidAddressSpaceHandle => 1,
+    /// The `AddressSpace` handle must have the `MODIFY` right to unmap memory from it.
+    AddressSpaceCannotBeModified => 2,
+    /// There is no `MemoryObject` mapped at the given address.
+    NotMapped => 3,
+});
+
+/// Unmap the `MemoryObject` mapped at `virtual_address` from an `AddressSpace`. If `address_space` is the zero
+/// handle, the object is unmapped from the calling task's address space. The address must be the one the object
+/// was mapped at.
+pub unsafe fn unmap_memory_object(
+    address_space: Handle,
+    virtual_address: usize,
+) -> Result<(), UnmapMemoryObjectError> {
+    status_from_syscall_repr(unsafe {
+        raw::syscall2(SYSCALL_UNMAP_MEMORY_OBJECT, address_space.0 as usize, virtual_address)
+    })
+}
+
+define_error_type!(CloneMemoryObjectError {
+    InvalidMemoryObjectHandle => 1,
+    /// The `MemoryObject` handle must have the `MAP` right to be cloned.
+    MemoryObjectCannotBeMapped => 2,
+    /// The calling task has reached its quota of handles.
+    QuotaExceeded => 3,
+    /// `MemoryObject`s that describe memory the kernel does not own (e.g. the framebuffer, or PCI BARs) can't be
+    /// cloned, and neither can objects whose physical address has been requested (as cloning could move their
+    /// memory).
+    MemoryObjectCannotBeCloned => 4,
+});
+
+/// Create a copy-on-write clone of a `MemoryObject`, returning a handle to the clone. The clone and the original
+/// share memory until one of them writes to it, at which point the written page is copied. The clone has the same
+/// size and flags as the original.
+pub fn clone_memory_object(memory_object: Handle) -> Result<Handle, CloneMemoryObjectError> {
+    handle_from_syscall_repr(unsafe { raw::syscall1(SYSCALL_CLONE_MEMORY_OBJECT, memory_object.0 as usize) })
+}
+
+define_error_type!(ProtectMappingError {
+    InvalidAddressSpaceHandle => 1,
+    /// The `AddressSpace` handle must have the `MODIFY` right to change its mappings.
+    AddressSpaceCannotBeModified => 2,
+    /// The address and size of the region must be multiples of the page size.
+    NotAligned => 3,
+    /// The region must lie within a single mapped `MemoryObject`.
+    NotMapped => 4,
+    /// The region can't be given permissions that the `MemoryObject` was not mapped with.
+    PermissionDenied => 5,
+    /// There is not enough free physical memory to allocate the page tables needed to change the region.
+    OutOfMemory => 6,
+});
+
+/// Change the permissions of the `size` bytes starting at `address` in an `AddressSpace`. If `address_space` is the
+/// zero handle, the calling task's address space is changed. The region must lie within a single mapped
+/// `MemoryObject`, and can only be given permissions that the object was mapped with. Only the `WRITABLE` and
+/// `EXECUTABLE` flags are used - mapped memory is always readable.
+pub unsafe fn protect_mapping(
+    address_space: Handle,
+    address: usize,
+    size: usize,
+    flags: MemoryObjectFlags,
+) -> Result<(), ProtectMappingError> {
+    status_from_syscall_repr(unsafe {
+        raw::syscall4(SYSCALL_PROTECT_MAPPING, address_space.0 as usize, address, size, flags.bits() as usize)
+    })
+}
+
 define_error_type!(CreateChannelError {
     InvalidHandleAddress => 1,
+    /// The calling task has reached its quota of handles.
+    QuotaExceeded => 2,
 });
 
+/// Create a new `Channel`, with the default limits on how many messages can be queued at each end.
 pub fn create_channel() -> Result<(Handle, Handle), CreateChannelError> {
+    create_channel_with_limits(CHANNEL_DEFAULT_MAX_QUEUED_MESSAGES, CHANNEL_DEFAULT_MAX_QUEUED_BYTES)
+}
+
+/// Create a new `Channel`. At most `max_messages` messages, totalling at most `max_bytes` bytes, can be queued
+/// waiting to be received at each end - sending more fails with `SendMessageError::QueueFull`. A limit of `0`
+/// selects the default value. An end with no messages queued can always receive one more message, even if it's
+/// larger than `max_bytes`.
+pub fn create_channel_with_limits(
+    max_messages: usize,
+    max_bytes: usize,
+) -> Result<(Handle, Handle), CreateChannelError> {
     let mut other_end: MaybeUninit<Handle> = MaybeUninit::uninit();
     let one_end = handle_from_syscall_repr(unsafe {
-        raw::syscall1(SYSCALL_CREATE_CHANNEL, other_end.as_mut_ptr() as usize)
+        raw::syscall3(SYSCALL_CREATE_CHANNEL, other_end.as_mut_ptr() as usize, max_messages, max_bytes)
     })?;
     Ok((one_end, unsafe { other_end.assume_init() }))
 }
 
 pub const CHANNEL_MAX_NUM_BYTES: usize = 4096;
 pub const CHANNEL_MAX_NUM_HANDLES: usize = 4;
+pub const CHANNEL_DEFAULT_MAX_QUEUED_MESSAGES: usize = 64;
+pub const CHANNEL_DEFAULT_MAX_QUEUED_BYTES: usize = 16 * CHANNEL_MAX_NUM_BYTES;
 
 define_error_type!(SendMessageError {
     /// The `Channel` handle is invalid.
@@ -131,7 +257,7 @@ define_error_type!(SendMessageError {
     NotAChannel => 2,
     /// The `Channel` handle must have the `SEND` right to use the `send_message` system call.
     ChannelCannotSend => 3,
-    /// A handle to be transferred is invalid.
+    /// A handle to be transferred is invalid, or appears more than once.
     InvalidTransferredHandle => 4,
     /// Transferred handles must have the `TRANSFER` right.
     CannotTransferHandle => 5,
@@ -140,17 +266,51 @@ define_error_type!(SendMessageError {
     HandlesAddressInvalid => 8,
     TooManyHandles => 9,
     OtherEndDisconnected => 10,
+    /// The calling task has reached its quota of bytes queued in `Channel`s. More messages can be sent once the
+    /// ones already sent have been received.
+    QuotaExceeded => 11,
+    /// The other end of the `Channel` already has as many messages queued as it can hold. More messages can be
+    /// sent once some have been received - wait for the `Channel` to become writable.
+    QueueFull => 12,
+    /// The message is a reply to a transaction that the other end of the `Channel` is not waiting for a reply to.
+    InvalidTransaction => 13,
 });
 
+/// Identifies a call made through a `Channel` with `channel_call`, so that its reply can be matched up with it.
+/// Transaction IDs are assigned by the kernel, and are never `0`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TransactionId(pub u16);
+
 pub fn send_message(channel: Handle, bytes: &[u8], handles: &[Handle]) -> Result<(), SendMessageError> {
+    send_message_inner(channel, None, bytes, handles)
+}
+
+/// Send the reply to a call received from a `Channel`. The reply is delivered straight to the task waiting for
+/// it, so it isn't subject to the limits on how many messages the `Channel` can queue.
+pub fn send_reply(
+    channel: Handle,
+    transaction: TransactionId,
+    bytes: &[u8],
+    handles: &[Handle],
+) -> Result<(), SendMessageError> {
+    send_message_inner(channel, Some(transaction), bytes, handles)
+}
+
+fn send_message_inner(
+    channel: Handle,
+    transaction: Option<TransactionId>,
+    bytes: &[u8],
+    handles: &[Handle],
+) -> Result<(), SendMessageError> {
     status_from_syscall_repr(unsafe {
-        raw::syscall5(
+        raw::syscall6(
             SYSCALL_SEND_MESSAGE,
             channel.0 as usize,
             if bytes.len() == 0 { 0x0 } else { bytes.as_ptr() as usize },
             bytes.len(),
             if handles.len() == 0 { 0x0 } else { handles.as_ptr() as usize },
             handles.len(),
+            transaction.map_or(0, |transaction| transaction.0 as usize),
         )
     })
 }
@@ -163,6 +323,14 @@ define_error_type!(GetMessageError {
     BytesBufferTooSmall => 5,
     HandlesAddressInvalid => 6,
     HandlesBufferTooSmall => 7,
+    /// The `Channel` handle must have the `RECEIVE` right to use the `get_message` system call.
+    ChannelCannotReceive => 8,
+    /// There are no messages left to receive, and the other end of the `Channel` has been closed, so no more
+    /// messages will arrive.
+    PeerClosed => 9,
+    /// The calling task would exceed its quota of handles by receiving the handles in the message. The message
+    /// is left in the queue.
+    QuotaExceeded => 10,
 });
 
 pub fn get_message<'b, 'h>(
@@ -170,6 +338,17 @@ pub fn get_message<'b, 'h>(
     byte_buffer: &'b mut [u8],
     handle_buffer: &'h mut [Handle],
 ) -> Result<(&'b mut [u8], &'h mut [Handle]), GetMessageError> {
+    let (bytes, handles, _) = get_message_with_transaction(channel, byte_buffer, handle_buffer)?;
+    Ok((bytes, handles))
+}
+
+/// Receive a message from a `Channel`, like `get_message`. If the message is a call made with `channel_call`, its
+/// transaction ID is also returned, and the reply should be sent with `send_reply`.
+pub fn get_message_with_transaction<'b, 'h>(
+    channel: Handle,
+    byte_buffer: &'b mut [u8],
+    handle_buffer: &'h mut [Handle],
+) -> Result<(&'b mut [u8], &'h mut [Handle], Option<TransactionId>), GetMessageError> {
     let result = unsafe {
         raw::syscall5(
             SYSCALL_GET_MESSAGE,
@@ -184,8 +363,204 @@ pub fn get_message<'b, 'h>(
 
     let valid_bytes_len = result.get_bits(16..32);
     let valid_handles_len = result.get_bits(32..48);
+    let transaction = match result.get_bits(48..64) {
+        0 => None,
+        transaction => Some(TransactionId(transaction as u16)),
+    };
 
-    Ok((&mut byte_buffer[0..valid_bytes_len], &mut handle_buffer[0..valid_handles_len]))
+    Ok((&mut byte_buffer[0..valid_bytes_len], &mut handle_buffer[0..valid_handles_len], transaction))
+}
+
+/// The size of a message waiting to be received from a `Channel`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MessageSize {
+    pub num_bytes: usize,
+    pub num_handles: usize,
+}
+
+/// Get the size of the next message waiting to be received from a `Channel`, without receiving it. This can be
+/// used to allocate buffers of the right size to pass to `get_message`. Fails with the same errors as
+/// `get_message` when there is no message to receive.
+pub fn peek_message(channel: Handle) -> Result<MessageSize, GetMessageError> {
+    let result = unsafe { raw::syscall1(SYSCALL_PEEK_MESSAGE, channel.0 as usize) };
+    status_from_syscall_repr(result.get_bits(0..16))?;
+    Ok(MessageSize { num_bytes: result.get_bits(16..32), num_handles: result.get_bits(32..48) })
+}
+
+define_error_type!(ChannelCallError {
+    /*
+     * The first errors are the same as those of `send_message`, as they come from sending the call's message.
+     */
+    InvalidChannelHandle => 1,
+    NotAChannel => 2,
+    /// The `Channel` handle must have both the `SEND` and `RECEIVE` rights to make calls through it.
+    ChannelCannotCall => 3,
+    InvalidTransferredHandle => 4,
+    CannotTransferHandle => 5,
+    BytesAddressInvalid => 6,
+    TooManyBytes => 7,
+    HandlesAddressInvalid => 8,
+    TooManyHandles => 9,
+    /// The other end of the `Channel` has been closed, either before the call was made or before it replied.
+    OtherEndDisconnected => 10,
+    /// The calling task has reached its quota of bytes queued in `Channel`s, or of handles (if the reply
+    /// transfers handles).
+    QuotaExceeded => 11,
+    QueueFull => 12,
+    DetailsAddressInvalid => 13,
+    /// The `Channel` end already has as many calls waiting for replies as it can queue messages.
+    TooManyCalls => 14,
+    /// The transaction ID passed to collect a reply isn't one of a call made through this `Channel` that is
+    /// waiting for its reply.
+    InvalidTransaction => 15,
+    /// The reply hasn't arrived yet, and the caller does not want the kernel to block.
+    NoReply => 16,
+    ReplyBytesAddressInvalid => 17,
+    /// The buffer for the reply's bytes is too small. The reply is kept, so it can be collected again with a
+    /// larger buffer.
+    ReplyBytesBufferTooSmall => 18,
+    ReplyHandlesAddressInvalid => 19,
+    ReplyHandlesBufferTooSmall => 20,
+});
+
+impl From<SendMessageError> for ChannelCallError {
+    fn from(err: SendMessageError) -> Self {
+        match err {
+            SendMessageError::InvalidChannelHandle => ChannelCallError::InvalidChannelHandle,
+            SendMessageError::NotAChannel => ChannelCallError::NotAChannel,
+            SendMessageError::ChannelCannotSend => ChannelCallError::ChannelCannotCall,
+            SendMessageError::InvalidTransferredHandle => ChannelCallError::InvalidTransferredHandle,
+            SendMessageError::CannotTransferHandle => ChannelCallError::CannotTransferHandle,
+            SendMessageError::BytesAddressInvalid => ChannelCallError::BytesAddressInvalid,
+            SendMessageError::TooManyBytes => ChannelCallError::TooManyBytes,
+            SendMessageError::HandlesAddressInvalid => ChannelCallError::HandlesAddressInvalid,
+            SendMessageError::TooManyHandles => ChannelCallError::TooManyHandles,
+            SendMessageError::OtherEndDisconnected => ChannelCallError::OtherEndDisconnected,
+            SendMessageError::QuotaExceeded => ChannelCallError::QuotaExceeded,
+            SendMessageError::QueueFull => ChannelCallError::QueueFull,
+            SendMessageError::InvalidTransaction => ChannelCallError::InvalidTransaction,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct ChannelCallDetails {
+    pub bytes_ptr: *const u8,
+    pub bytes_len: usize,
+    pub handles_ptr: *const Handle,
+    pub handles_len: usize,
+    pub reply_bytes_ptr: *mut u8,
+    pub reply_bytes_len: usize,
+    pub reply_handles_ptr: *mut Handle,
+    pub reply_handles_len: usize,
+    /// `0` to make a new call, in which case the kernel writes the call's transaction ID here. Otherwise, the
+    /// transaction ID of a call that has already been made, to collect its reply.
+    pub transaction: u16,
+}
+
+impl ChannelCallDetails {
+    fn new(bytes: &[u8], handles: &[Handle], transaction: u16) -> ChannelCallDetails {
+        ChannelCallDetails {
+            bytes_ptr: if bytes.len() == 0 { ptr::null() } else { bytes.as_ptr() },
+            bytes_len: bytes.len(),
+            handles_ptr: if handles.len() == 0 { ptr::null() } else { handles.as_ptr() },
+            handles_len: handles.len(),
+            reply_bytes_ptr: ptr::null_mut(),
+            reply_bytes_len: 0,
+            reply_handles_ptr: ptr::null_mut(),
+            reply_handles_len: 0,
+            transaction,
+        }
+    }
+}
+
+/// Make a call through a `Channel` - send a message, tagged with a new transaction ID, and block until the reply
+/// to it arrives. The reply is written into the given buffers, and is never mixed up with other messages arriving
+/// on the `Channel`. The task receiving the call gets the transaction ID with `get_message_with_transaction`, and
+/// replies with `send_reply`.
+pub fn channel_call<'b, 'h>(
+    channel: Handle,
+    bytes: &[u8],
+    handles: &[Handle],
+    reply_byte_buffer: &'b mut [u8],
+    reply_handle_buffer: &'h mut [Handle],
+) -> Result<(&'b mut [u8], &'h mut [Handle]), ChannelCallError> {
+    let mut details = ChannelCallDetails::new(bytes, handles, 0);
+    channel_call_inner(channel, &mut details, reply_byte_buffer, reply_handle_buffer, true)
+}
+
+/// Make a call through a `Channel`, like `channel_call`, but return as soon as the message has been sent. The reply
+/// can be collected with `get_reply` - the `Channel` asserts `Signals::REPLY` once it has arrived.
+pub fn begin_channel_call(
+    channel: Handle,
+    bytes: &[u8],
+    handles: &[Handle],
+) -> Result<TransactionId, ChannelCallError> {
+    let mut details = ChannelCallDetails::new(bytes, handles, 0);
+    channel_call_inner(channel, &mut details, &mut [], &mut [], false)?;
+    Ok(TransactionId(details.transaction))
+}
+
+/// Collect the reply to a call made with `begin_channel_call`. If the reply hasn't arrived yet and `block` is
+/// `false`, this returns `ChannelCallError::NoReply` instead of waiting for it.
+pub fn get_reply<'b, 'h>(
+    channel: Handle,
+    transaction: TransactionId,
+    reply_byte_buffer: &'b mut [u8],
+    reply_handle_buffer: &'h mut [Handle],
+    block: bool,
+) -> Result<(&'b mut [u8], &'h mut [Handle]), ChannelCallError> {
+    let mut details = ChannelCallDetails::new(&[], &[], transaction.0);
+    channel_call_inner(channel, &mut details, reply_byte_buffer, reply_handle_buffer, block)
+}
+
+fn channel_call_inner<'b, 'h>(
+    channel: Handle,
+    details: &mut ChannelCallDetails,
+    reply_byte_buffer: &'b mut [u8],
+    reply_handle_buffer: &'h mut [Handle],
+    block: bool,
+) -> Result<(&'b mut [u8], &'h mut [Handle]), ChannelCallError> {
+    if reply_byte_buffer.len() != 0 {
+        details.reply_bytes_ptr = reply_byte_buffer.as_mut_ptr();
+        details.reply_bytes_len = reply_byte_buffer.len();
+    }
+    if reply_handle_buffer.len() != 0 {
+        details.reply_handles_ptr = reply_handle_buffer.as_mut_ptr();
+        details.reply_handles_len = reply_handle_buffer.len();
+    }
+
+    let result = unsafe {
+        raw::syscall3(
+            SYSCALL_CHANNEL_CALL,
+            channel.0 as usize,
+            details as *mut ChannelCallDetails as usize,
+            if block { 1 } else { 0 },
+        )
+    };
+    status_from_syscall_repr(result.get_bits(0..16))?;
+
+    let valid_bytes_len = result.get_bits(16..32);
+    let valid_handles_len = result.get_bits(32..48);
+
+    Ok((&mut reply_byte_buffer[0..valid_bytes_len], &mut reply_handle_buffer[0..valid_handles_len]))
+}
+
+define_error_type!(WaitForMessageError {
+    InvalidChannelHandle => 1,
+    NotAChannel => 2,
+    /// The `Channel` handle must have the `RECEIVE` right to wait for messages on it.
+    ChannelCannotReceive => 3,
+    /// There are no messages to receive, and the other end of the `Channel` has been closed, so no more messages
+    /// will arrive.
+    PeerClosed => 4,
+});
+
+/// Block until there is a message waiting to be received on the given `Channel` end. This does not receive the
+/// message - use `get_message` to do that.
+pub fn wait_for_message(channel: Handle) -> Result<(), WaitForMessageError> {
+    status_from_syscall_repr(unsafe { raw::syscall1(SYSCALL_WAIT_FOR_MESSAGE, channel.0 as usize) })
 }
 
 define_error_type!(WaitForEventError {
@@ -194,23 +569,123 @@ define_error_type!(WaitForEventError {
     NoEvent => 3,
 });
 
+/// Wait for an `Event` to be signalled, or for a `Task` to exit. Waiting for an `Event` clears it again, so each
+/// signal wakes a single waiter. If `block` is `false` and the event has not occurred, this returns
+/// `WaitForEventError::NoEvent` instead of waiting.
 pub fn wait_for_event(event: Handle, block: bool) -> Result<(), WaitForEventError> {
     let result = unsafe { raw::syscall2(SYSCALL_WAIT_FOR_EVENT, event.0 as usize, if block { 1 } else { 0 }) };
     status_from_syscall_repr(result)
 }
 
+define_error_type!(CreateEventError {
+    /// The calling task has reached its quota of handles.
+    QuotaExceeded => 1,
+});
+
+/// Create a new `Event`, which starts off cleared. Handles to it can be passed to other tasks so they can wait
+/// for it to be signalled - use `duplicate_handle` to remove the `MODIFY` right if they should only be able to
+/// wait on it.
+pub fn create_event() -> Result<Handle, CreateEventError> {
+    handle_from_syscall_repr(unsafe { raw::syscall0(SYSCALL_CREATE_EVENT) })
+}
+
+define_error_type!(SignalEventError {
+    InvalidEventHandle => 1,
+    NotAnEvent => 2,
+    /// The `Event` handle must have the `MODIFY` right to signal it.
+    EventCannotBeModified => 3,
+});
+
+/// Signal an `Event`, waking a task waiting for it with `wait_for_event`. Signalling an `Event` that is already
+/// signalled does nothing.
+pub fn signal_event(event: Handle) -> Result<(), SignalEventError> {
+    status_from_syscall_repr(unsafe { raw::syscall1(SYSCALL_SIGNAL_EVENT, event.0 as usize) })
+}
+
+define_error_type!(ClearEventError {
+    InvalidEventHandle => 1,
+    NotAnEvent => 2,
+    /// The `Event` handle must have the `MODIFY` right to clear it.
+    EventCannotBeModified => 3,
+});
+
+/// Clear an `Event`, without waiting for it.
+pub fn clear_event(event: Handle) -> Result<(), ClearEventError> {
+    status_from_syscall_repr(unsafe { raw::syscall1(SYSCALL_CLEAR_EVENT, event.0 as usize) })
+}
+
+define_error_type!(FutexWaitError {
+    /// The futex's address is not correctly aligned, or is not mapped.
+    AddressInvalid => 1,
+    /// The futex did not hold the expected value, so the caller should not wait.
+    ValueChanged => 2,
+    /// The timeout expired before the futex was woken.
+    TimedOut => 3,
+});
+
+/// Block until the futex is woken with `futex_wake`, as long as it still holds `expected`. The kernel checks the
+/// value atomically with respect to `futex_wake`, so a wake can't be missed between the caller reading the value
+/// and starting to wait. If `timeout` is `Some`, this gives up with `FutexWaitError::TimedOut` once it has expired.
+/// Wakes can be spurious, so callers should check the state they're waiting for again once this returns.
+pub fn futex_wait(futex: &AtomicU32, expected: u32, timeout: Option<Duration>) -> Result<(), FutexWaitError> {
+    // A timeout of `0` waits forever, so make sure a real timeout is never `0`
+    let timeout = match timeout {
+        Some(timeout) => usize::max(timeout.as_nanos() as usize, 1),
+        None => 0,
+    };
+    status_from_syscall_repr(unsafe {
+        raw::syscall3(SYSCALL_FUTEX_WAIT, futex.as_ptr() as usize, expected as usize, timeout)
+    })
+}
+
+define_error_type!(FutexWakeError {
+    /// The futex's address is not correctly aligned, or is not mapped.
+    AddressInvalid => 1,
+});
+
+/// Wake up to `count` tasks waiting on the futex. Returns the number of tasks that were woken. Pass `usize::MAX`
+/// to wake all of them.
+pub fn futex_wake(futex: &AtomicU32, count: usize) -> Result<usize, FutexWakeError> {
+    let result = unsafe { raw::syscall2(SYSCALL_FUTEX_WAKE, futex.as_ptr() as usize, count) };
+    status_from_syscall_repr(result.get_bits(0..16))?;
+    Ok(result.get_bits(16..64))
+}
+
 define_error_type!(PollInterestError {
     InvalidHandle => 1,
     UnsupportedObjectType => 2,
 });
 
-pub fn poll_interest(object: Handle) -> Result<bool, PollInterestError> {
+bitflags::bitflags! {
+    /// The ways in which a kernel object can be ready to be acted on.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct Signals: u32 {
+        /// The object has something for the task to process - for example, a message has arrived on a
+        /// `Channel`, or an `Event` has been signalled.
+        const READABLE = 1 << 0;
+        /// The object can be written to without failing - for example, the other end of a `Channel` has space
+        /// for another message.
+        const WRITABLE = 1 << 1;
+        /// The reply to a call made through a `Channel` with `begin_channel_call` has arrived, and can be
+        /// collected with `get_reply`. This is also asserted if the other end of the `Channel` has been closed, as
+        /// no more replies will arrive.
+        const REPLY = 1 << 2;
+        /// The other end of a `Channel` has been closed. Messages it sent before closing can still be received.
+        const PEER_CLOSED = 1 << 3;
+    }
+}
+
+/// Get the signals that are currently asserted on a kernel object.
+pub fn poll_interest(object: Handle) -> Result<Signals, PollInterestError> {
     let result = unsafe { raw::syscall1(SYSCALL_POLL_INTEREST, object.0 as usize) };
     status_from_syscall_repr(result.get_bits(0..16))?;
-    Ok(result.get_bits(16..64) != 0)
+    Ok(Signals::from_bits_truncate(result.get_bits(16..48) as u32))
 }
 
-define_error_type!(CreateAddressSpaceError {});
+define_error_type!(CreateAddressSpaceError {
+    /// The calling task has reached its quota of handles.
+    QuotaExceeded => 1,
+});
 
 pub fn create_address_space() -> Result<Handle, CreateAddressSpaceError> {
     handle_from_syscall_repr(unsafe { raw::syscall0(SYSCALL_CREATE_ADDRESS_SPACE) })
@@ -220,8 +695,53 @@ define_error_type!(SpawnTaskError {
     InvalidTaskName => 1,
     NotAnAddressSpace => 2,
     InvalidHandleToTransfer => 3,
+    /// Handles passed to the new task must have the `TRANSFER` right.
+    CannotTransferHandle => 4,
+    /// The new task can only be given capabilities that the calling task holds.
+    CapabilitiesNotHeld => 5,
+    /// Either the calling task has reached its quota of handles, or the new task can't hold the handles being
+    /// passed to it.
+    QuotaExceeded => 6,
+    /// The new task can't be allowed a higher priority than the calling task is allowed.
+    PriorityTooHigh => 7,
+    /// The address space can't hold any more tasks, or the kernel can't create any more.
+    TooManyTasks => 8,
 });
 
+/// How urgently a task needs to run. The scheduler always runs the ready task with the highest priority, and
+/// shares the CPU between ready tasks of the same priority in timeslices.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[repr(u8)]
+pub enum Priority {
+    /// For background work that should only run when nothing else needs to.
+    Low = 0,
+    /// The priority tasks start with.
+    Normal = 1,
+    /// For latency-sensitive tasks, such as drivers for input devices.
+    High = 2,
+    /// The highest priority. A task with this priority that doesn't block starves every other task, so it should
+    /// be used sparingly.
+    Realtime = 3,
+}
+
+impl Priority {
+    pub const NUM_PRIORITIES: usize = 4;
+}
+
+impl TryFrom<usize> for Priority {
+    type Error = ();
+
+    fn try_from(value: usize) -> Result<Priority, ()> {
+        match value {
+            0 => Ok(Priority::Low),
+            1 => Ok(Priority::Normal),
+            2 => Ok(Priority::High),
+            3 => Ok(Priority::Realtime),
+            _ => Err(()),
+        }
+    }
+}
+
 #[repr(C)]
 pub struct SpawnTaskDetails {
     pub name_ptr: *const u8,
@@ -230,12 +750,21 @@ pub struct SpawnTaskDetails {
     pub address_space: u32,
     pub object_array: *const u32,
     pub object_array_len: usize,
+    /// The bits of the `Capabilities` to give the new task.
+    pub capabilities: u32,
+    /// The highest `Priority` the new task can be given with `set_priority`.
+    pub max_priority: u8,
 }
 
+/// Spawn a new task in `address_space`. The task starts with `Priority::Normal` (or `max_priority`, if that's
+/// lower), and can only be given a priority up to `max_priority`, which can't be higher than the calling task's
+/// own limit.
 pub fn spawn_task(
     task_name: &str,
     address_space: Handle,
     entry_point: usize,
+    capabilities: Capabilities,
+    max_priority: Priority,
     objects: &[Handle],
 ) -> Result<Handle, SpawnTaskError> {
     let details = SpawnTaskDetails {
@@ -245,6 +774,8 @@ pub fn spawn_task(
         address_space: address_space.0,
         object_array: objects as *const [Handle] as *const u32,
         object_array_len: objects.len(),
+        capabilities: capabilities.bits(),
+        max_priority: max_priority as u8,
     };
 
     handle_from_syscall_repr(unsafe {
@@ -252,10 +783,73 @@ pub fn spawn_task(
     })
 }
 
+define_error_type!(SpawnThreadError {
+    /// The entry point is not a valid userspace address.
+    InvalidEntryPoint => 1,
+    /// The thread pointer is not a valid userspace address.
+    InvalidThreadPointer => 2,
+    /// The calling task's address space can't hold any more tasks, or the kernel can't create any more.
+    TooManyTasks => 3,
+    /// The calling task has reached its quota of handles.
+    QuotaExceeded => 4,
+});
+
+/// Create a new thread - a task in the calling task's address space, which shares its handles, quotas, and
+/// capabilities. The kernel allocates the thread a stack, and it starts running at `entry_point` with `argument`
+/// as its first argument, and its thread pointer (the FS base on x86_64, or `tp` on RISC-V) set to
+/// `thread_pointer`. Returns a handle to the new `Task`, which can be waited on to find out when it exits.
+///
+/// The entry point must never return - the thread should exit with `exit_task` once it is done.
+pub fn spawn_thread(
+    entry_point: extern "C" fn(usize) -> !,
+    argument: usize,
+    thread_pointer: usize,
+) -> Result<Handle, SpawnThreadError> {
+    handle_from_syscall_repr(unsafe {
+        raw::syscall3(SYSCALL_SPAWN_THREAD, entry_point as usize, argument, thread_pointer)
+    })
+}
+
+define_error_type!(SetThreadPointerError {
+    /// The thread pointer is not a valid userspace address.
+    InvalidThreadPointer => 1,
+});
+
+/// Set the calling task's thread pointer. This is needed on x86_64, where userspace can't change the FS base
+/// itself.
+pub fn set_thread_pointer(thread_pointer: usize) -> Result<(), SetThreadPointerError> {
+    status_from_syscall_repr(unsafe { raw::syscall1(SYSCALL_SET_THREAD_POINTER, thread_pointer) })
+}
+
+define_error_type!(SetPriorityError {
+    InvalidHandle => 1,
+    NotATask => 2,
+    /// The `Task` handle must have the `MODIFY` right to change the task's priority.
+    TaskCannotBeModified => 3,
+    InvalidPriority => 4,
+    /// The priority is higher than the task's owner allows it to have.
+    PriorityTooHigh => 5,
+});
+
+/// Change the priority of a task. Pass `Handle::ZERO` to change the priority of the calling task. Each task can
+/// only be given priorities up to the limit set by the task that spawned it (see `spawn_task`) - threads share the
+/// limit of the task that created them.
+pub fn set_priority(task: Handle, priority: Priority) -> Result<(), SetPriorityError> {
+    status_from_syscall_repr(unsafe { raw::syscall2(SYSCALL_SET_PRIORITY, task.0 as usize, priority as usize) })
+}
+
 define_error_type!(ResizeMemoryObjectError {
     InvalidMemoryObjectHandle => 1,
     NewSizeTooBig => 2,
+    /// The `MemoryObject` is mapped into an address space where the virtual memory following the mapping is
+    /// already in use, so the mapping cannot be extended in place.
     ResizedObjectCannotBeRemapped => 3,
+    /// The `MemoryObject` handle must have the `MODIFY` right to resize it.
+    MemoryObjectCannotBeModified => 4,
+    /// Growing the `MemoryObject` would exceed the physical memory quota of the task that created it.
+    QuotaExceeded => 5,
+    /// There is not enough free physical memory to grow the `MemoryObject`.
+    OutOfMemory => 6,
 });
 
 pub unsafe fn resize_memory_object(memory_object: Handle, new_size: usize) -> Result<(), ResizeMemoryObjectError> {
@@ -267,6 +861,7 @@ pub unsafe fn resize_memory_object(memory_object: Handle, new_size: usize) -> Re
 define_error_type!(WaitForInterruptError {
     InvalidInterruptHandle => 1,
     NoInterrupt => 2,
+    TaskDoesNotHaveCorrectCapability => 3,
 });
 
 pub fn wait_for_interrupt(interrupt_object: Handle, block: bool) -> Result<(), WaitForInterruptError> {
@@ -277,8 +872,270 @@ pub fn wait_for_interrupt(interrupt_object: Handle, block: bool) -> Result<(), W
 
 define_error_type!(AckInterruptError {
     InvalidInterruptHandle => 1,
+    /// The `Interrupt` handle must have the `MODIFY` right to acknowledge it.
+    InterruptCannotBeModified => 2,
+    TaskDoesNotHaveCorrectCapability => 3,
 });
 
 pub fn ack_interrupt(interrupt_object: Handle) -> Result<(), AckInterruptError> {
     status_from_syscall_repr(unsafe { raw::syscall1(SYSCALL_ACK_INTERRUPT, interrupt_object.0 as usize) })
 }
+
+define_error_type!(CloseHandleError {
+    InvalidHandle => 1,
+});
+
+/// Close a handle, removing it from the calling task. If this was the last reference to the underlying kernel
+/// object, the object is destroyed.
+pub fn close_handle(handle: Handle) -> Result<(), CloseHandleError> {
+    status_from_syscall_repr(unsafe { raw::syscall1(SYSCALL_CLOSE_HANDLE, handle.0 as usize) })
+}
+
+define_error_type!(DuplicateHandleError {
+    InvalidHandle => 1,
+    /// The handle must have the `DUPLICATE` right to be duplicated.
+    CannotDuplicate => 2,
+    /// The requested rights are not a subset of the rights of the original handle.
+    InvalidRights => 3,
+    /// The calling task has reached its quota of handles.
+    QuotaExceeded => 4,
+});
+
+/// Create a new handle to the same kernel object as `handle`, with the given set of rights. The new rights must be
+/// a subset of the rights of `handle`, so this can be used to create a more restricted handle to pass to another
+/// task (e.g. a receive-only `Channel` end, or a `MemoryObject` that can only be mapped read-only).
+pub fn duplicate_handle(handle: Handle, rights: HandleRights) -> Result<Handle, DuplicateHandleError> {
+    handle_from_syscall_repr(unsafe {
+        raw::syscall2(SYSCALL_DUPLICATE_HANDLE, handle.0 as usize, rights.bits() as usize)
+    })
+}
+
+/// Exit the calling task with the given exit code. This does not return - the kernel releases the task's
+/// resources (including all of its handles) and schedules another task.
+pub fn exit_task(exit_code: usize) -> ! {
+    unsafe {
+        raw::syscall1(SYSCALL_EXIT_TASK, exit_code);
+    }
+    unreachable!("Returned from exit_task system call!")
+}
+
+/// Describes why a task stopped running. The owner of a `Task` handle can get this using `get_exit_status`, once
+/// the task has exited.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(C)]
+pub enum ExitStatus {
+    /// The task exited itself, using `exit_task`, with the given exit code.
+    Exited(usize),
+    /// The task was terminated by the kernel because it caused a fault.
+    Faulted(Fault),
+}
+
+/// A record of a fault caused by a task.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(C)]
+pub struct Fault {
+    pub kind: FaultKind,
+    /// The address that the task tried to access, if this is meaningful for the kind of fault (e.g. for page
+    /// faults). Otherwise, this is `0`.
+    pub address: usize,
+    /// The address of the instruction that caused the fault.
+    pub instruction_pointer: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum FaultKind {
+    /// The task accessed memory that is not mapped, or accessed mapped memory in a way it is not allowed to.
+    PageFault = 0,
+    /// The task violated a protection check that isn't covered by a more specific kind of fault. This is only
+    /// produced on x86_64.
+    GeneralProtectionFault = 1,
+    /// The task tried to execute an invalid instruction, or one that it does not have the privilege to execute.
+    InvalidOpcode = 2,
+}
+
+define_error_type!(GetExitStatusError {
+    InvalidHandle => 1,
+    /// The task has not exited yet.
+    TaskNotExited => 2,
+    /// The address passed to write the status into is invalid.
+    StatusAddressIsInvalid => 3,
+});
+
+/// Get the exit status of a task that has exited. A `Task` handle can be waited on using `wait_for_event` or
+/// `poll_interest` to find out when the task exits.
+pub fn get_exit_status(task: Handle) -> Result<ExitStatus, GetExitStatusError> {
+    let mut status: MaybeUninit<ExitStatus> = MaybeUninit::uninit();
+    status_from_syscall_repr(unsafe {
+        raw::syscall2(SYSCALL_GET_EXIT_STATUS, task.0 as usize, status.as_mut_ptr() as usize)
+    })?;
+    Ok(unsafe { status.assume_init() })
+}
+
+define_error_type!(CreatePortError {
+    /// The calling task has reached its quota of handles.
+    QuotaExceeded => 1,
+});
+
+/// Create a new `Port`, which can be used to wait on many kernel objects at once.
+pub fn create_port() -> Result<Handle, CreatePortError> {
+    handle_from_syscall_repr(unsafe { raw::syscall0(SYSCALL_CREATE_PORT) })
+}
+
+define_error_type!(BindToPortError {
+    InvalidPortHandle => 1,
+    NotAPort => 2,
+    /// The `Port` handle must have the `MODIFY` right to bind objects to it.
+    PortCannotBeModified => 3,
+    InvalidObjectHandle => 4,
+    /// The object can't be waited on for the given signals, and so can't be bound to a `Port` for them.
+    UnsupportedObjectType => 5,
+});
+
+/// Bind a kernel object to a `Port`. When any of `signals` is asserted on the object (e.g. a message arrives on a
+/// `Channel`, or an `Event` is signalled), a packet carrying `key` is queued on the port. Bindings are one-shot -
+/// once a packet has been queued, the object must be bound again to be told about it again. Binding with a key
+/// that is already bound to the port replaces the existing binding.
+pub fn bind_to_port(port: Handle, object: Handle, key: u64, signals: Signals) -> Result<(), BindToPortError> {
+    status_from_syscall_repr(unsafe {
+        raw::syscall4(
+            SYSCALL_BIND_TO_PORT,
+            port.0 as usize,
+            object.0 as usize,
+            key as usize,
+            signals.bits() as usize,
+        )
+    })
+}
+
+/// A packet queued on a `Port` when an object bound to it becomes ready.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(C)]
+pub struct PortPacket {
+    /// The key the object was bound to the port with.
+    pub key: u64,
+}
+
+define_error_type!(WaitPortError {
+    InvalidPortHandle => 1,
+    NotAPort => 2,
+    /// There are no packets queued on the port, and the caller does not want the kernel to block.
+    NoPackets => 3,
+    PacketAddressInvalid => 4,
+});
+
+/// Take the next packet from a `Port`. If there are no packets queued and `block` is `true`, the calling task is
+/// blocked until one arrives. Otherwise, this returns `WaitPortError::NoPackets`.
+pub fn wait_port(port: Handle, block: bool) -> Result<PortPacket, WaitPortError> {
+    let mut packet: MaybeUninit<PortPacket> = MaybeUninit::uninit();
+    status_from_syscall_repr(unsafe {
+        raw::syscall3(SYSCALL_WAIT_PORT, port.0 as usize, packet.as_mut_ptr() as usize, if block { 1 } else { 0 })
+    })?;
+    Ok(unsafe { packet.assume_init() })
+}
+
+/// Get the current monotonic time, as the time elapsed since boot. This is the clock that `Timer` deadlines are
+/// measured against.
+pub fn get_time() -> Duration {
+    Duration::from_nanos(unsafe { raw::syscall0(SYSCALL_GET_TIME) } as u64)
+}
+
+define_error_type!(CreateTimerError {
+    /// The calling task has reached its quota of handles.
+    QuotaExceeded => 1,
+});
+
+/// Create a new `Timer`. It is created disarmed - use `set_timer` to give it a deadline.
+pub fn create_timer() -> Result<Handle, CreateTimerError> {
+    handle_from_syscall_repr(unsafe { raw::syscall0(SYSCALL_CREATE_TIMER) })
+}
+
+define_error_type!(SetTimerError {
+    InvalidTimerHandle => 1,
+    NotATimer => 2,
+    /// The `Timer` handle must have the `MODIFY` right to set its deadline.
+    TimerCannotBeModified => 3,
+});
+
+/// Set the deadline of a `Timer`, as a time since boot (see `get_time`). Once the deadline has passed, the timer is
+/// ready, and can be waited on with a `Port` or `poll_interest`. Setting the deadline to `None` disarms the timer.
+pub fn set_timer(timer: Handle, deadline: Option<Duration>) -> Result<(), SetTimerError> {
+    // A deadline of `0` disarms the timer, so make sure a real deadline is never `0`
+    let deadline = match deadline {
+        Some(deadline) => usize::max(deadline.as_nanos() as usize, 1),
+        None => 0,
+    };
+    status_from_syscall_repr(unsafe { raw::syscall2(SYSCALL_SET_TIMER, timer.0 as usize, deadline) })
+}
+
+/// The type of a kernel object.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum KernelObjectType {
+    AddressSpace = 0,
+    Task = 1,
+    MemoryObject = 2,
+    Channel = 3,
+    Event = 4,
+    Interrupt = 5,
+    Port = 6,
+    Timer = 7,
+}
+
+/// Information about the kernel object a handle refers to, as returned by `object_info`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(C)]
+pub struct ObjectInfo {
+    pub typ: KernelObjectType,
+    /// The object's ID, which is unique across the system and never reused.
+    pub id: u64,
+    /// The ID of the task that created the object, or `0` if it was created by the kernel.
+    pub owner: u64,
+    pub details: ObjectDetails,
+}
+
+/// Information that is specific to the type of a kernel object.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(C)]
+pub enum ObjectDetails {
+    /// The kernel does not report any more information about this type of object.
+    None,
+    MemoryObject {
+        /// The size of the object, in bytes.
+        size: usize,
+        flags: MemoryObjectFlags,
+    },
+    Channel {
+        /// The number of messages waiting to be received from this end of the channel.
+        queued_messages: usize,
+    },
+    Task {
+        status: TaskStatus,
+    },
+}
+
+/// The scheduling state of a task.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(C)]
+pub enum TaskStatus {
+    Ready,
+    Running,
+    /// The task is waiting on something, such as a message or an interrupt.
+    Blocked,
+    Exited(ExitStatus),
+}
+
+define_error_type!(ObjectInfoError {
+    InvalidHandle => 1,
+    /// The address passed to write the information into is invalid.
+    InfoAddressIsInvalid => 2,
+});
+
+/// Get information about the kernel object that `handle` refers to.
+pub fn object_info(handle: Handle) -> Result<ObjectInfo, ObjectInfoError> {
+    let mut info: MaybeUninit<ObjectInfo> = MaybeUninit::uninit();
+    status_from_syscall_repr(unsafe {
+        raw::syscall2(SYSCALL_OBJECT_INFO, handle.0 as usize, info.as_mut_ptr() as usize)
+    })?;
+    Ok(unsafe { info.assume_init() })
+}
@@ -7,6 +7,8 @@ pub enum PciGetInfoError {
     BufferPointerInvalid,
     BufferNotLargeEnough(u32),
     PlatformDoesNotSupportPci,
+    /// The calling task would exceed its quota of handles by receiving the handles to each device's resources.
+    QuotaExceeded,
 }
 
 // TODO: it would be cool if we could do this with the define_error_type macro
@@ -19,6 +21,7 @@ impl TryFrom<usize> for PciGetInfoError {
             2 => Ok(Self::BufferPointerInvalid),
             3 => Ok(Self::BufferNotLargeEnough(status.get_bits(16..48) as u32)),
             4 => Ok(Self::PlatformDoesNotSupportPci),
+            5 => Ok(Self::QuotaExceeded),
             _ => Err(()),
         }
     }
@@ -35,6 +38,7 @@ impl Into<usize> for PciGetInfoError {
                 result
             }
             Self::PlatformDoesNotSupportPci => 4,
+            Self::QuotaExceeded => 5,
         }
     }
 }
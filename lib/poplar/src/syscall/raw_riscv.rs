@@ -47,3 +47,20 @@ pub unsafe fn syscall5(number: usize, a: usize, b: usize, c: usize, d: usize, e:
     }
     result
 }
+
+pub unsafe fn syscall6(number: usize, a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -> usize {
+    let result: usize;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") number => result,
+            in("a1") a,
+            in("a2") b,
+            in("a3") c,
+            in("a4") d,
+            in("a5") e,
+            in("a6") f
+        );
+    }
+    result
+}
@@ -118,3 +118,22 @@ pub unsafe fn syscall5(number: usize, a: usize, b: usize, c: usize, d: usize, e:
     }
     result
 }
+
+#[inline(never)]
+pub unsafe fn syscall6(number: usize, a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -> usize {
+    let result: usize;
+    unsafe {
+        asm!("syscall",
+            inlateout("rax") f => result,
+            inlateout("rdi") number => _,
+            inlateout("rsi") a => _,
+            inlateout("rdx") b => _,
+            inlateout("r10") c => _,
+            inlateout("r8") d => _,
+            inlateout("r9") e => _,
+            out("rcx") _,
+            out("r11") _,
+        );
+    }
+    result
+}
@@ -0,0 +1,65 @@
+//! Each thread in an address space has a thread pointer, which the kernel saves and restores when it switches
+//! between tasks. On x86_64 this is the FS base, and on RISC-V it is `tp`. Poplar's userspace points it at the
+//! thread's `ThreadControlBlock`, which holds the state that needs to be kept per-thread, such as the thread's
+//! async runtime.
+//!
+//! `std` installs a control block for the main thread before `main` is called, and for each thread it spawns.
+
+#[cfg(feature = "async")]
+use crate::rt::Runtime;
+use crate::syscall;
+#[cfg(feature = "async")]
+use core::cell::Cell;
+
+#[repr(C)]
+pub struct ThreadControlBlock {
+    /// Points back to this control block. Userspace can't read the FS base directly on x86_64, so the thread
+    /// pointer is found by reading this field through it instead. This must be the first field.
+    this: *mut ThreadControlBlock,
+    /// This thread's async runtime, if it has created one with `rt::init_runtime`.
+    #[cfg(feature = "async")]
+    pub(crate) runtime: Cell<Option<&'static Runtime>>,
+}
+
+impl ThreadControlBlock {
+    pub const fn new() -> ThreadControlBlock {
+        ThreadControlBlock {
+            this: core::ptr::null_mut(),
+            #[cfg(feature = "async")]
+            runtime: Cell::new(None),
+        }
+    }
+
+    /// Prepare `tcb` to be used by a new thread, and return the thread pointer the thread should be started with.
+    /// The control block must not move, and must live for as long as the thread does.
+    pub unsafe fn prepare(tcb: *mut ThreadControlBlock) -> usize {
+        unsafe {
+            (*tcb).this = tcb;
+        }
+        tcb as usize
+    }
+
+    /// Make `tcb` the calling thread's control block. The control block must not move, and must live for as long
+    /// as the thread does.
+    pub unsafe fn install(tcb: *mut ThreadControlBlock) {
+        let thread_pointer = unsafe { ThreadControlBlock::prepare(tcb) };
+        syscall::set_thread_pointer(thread_pointer).expect("Failed to set thread pointer");
+    }
+}
+
+/// Get the calling thread's control block. This must only be called once a control block has been installed for
+/// the thread.
+pub fn current() -> &'static ThreadControlBlock {
+    let tcb: *const ThreadControlBlock;
+
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        core::arch::asm!("mov {}, qword ptr fs:[0]", out(reg) tcb, options(nostack, readonly, preserves_flags));
+    }
+    #[cfg(target_arch = "riscv64")]
+    unsafe {
+        core::arch::asm!("mv {}, tp", out(reg) tcb, options(nomem, nostack, preserves_flags));
+    }
+
+    unsafe { &*tcb }
+}
@@ -8,8 +8,6 @@ use poplar::{
 };
 use spinning_top::Spinlock;
 
-/// Virtual address to put the heap at. This could be dynamically chosen in the future.
-const HEAP_START: usize = 0x600000000;
 /// The size of the heap on the first allocation made
 const INITIAL_HEAP_SIZE: usize = 0x4000;
 
@@ -41,9 +39,12 @@ unsafe impl GlobalAlloc for ManagedHeap {
                 let initial_size = usize::max(INITIAL_HEAP_SIZE, layout.size());
 
                 unsafe {
-                    let memory = MemoryObject::create(initial_size, MemoryObjectFlags::WRITABLE).unwrap();
-                    *self.mapped_heap.lock() = Some(memory.map_at(HEAP_START).unwrap());
-                    self.inner.lock().init(HEAP_START as *mut u8, initial_size);
+                    let memory =
+                        MemoryObject::create(initial_size, MemoryObjectFlags::WRITABLE | MemoryObjectFlags::LAZY)
+                            .unwrap();
+                    let memory = memory.map().unwrap();
+                    self.inner.lock().init(memory.mapped_at as *mut u8, initial_size);
+                    *self.mapped_heap.lock() = Some(memory);
 
                     // Recurse to make the allocation so we can extend the heap if needed
                     self.alloc(layout)
@@ -5,12 +5,14 @@
 extern crate alloc as alloc_crate;
 
 pub mod alloc;
+pub mod sync;
+pub mod thread;
 
 /*
  * Public re-exports. Most of this is copied from real `std`, plus our `poplar` library.
  * NOTE: deprecated re-exports, such as `std::i32` (and friends), are not included.
  */
-pub use alloc_crate::{borrow, boxed, collections, fmt, format, rc, slice, str, string, sync, vec};
+pub use alloc_crate::{borrow, boxed, collections, fmt, format, rc, slice, str, string, vec};
 pub use core::{
     any,
     array,
@@ -35,6 +37,7 @@ pub use core::{
     ptr,
     result,
     task,
+    time,
 };
 pub use poplar;
 
@@ -110,15 +113,13 @@ unsafe extern "C" fn rust_entry() -> ! {
         fn main(argc: isize, argv: *const *const u8) -> isize;
     }
 
+    static mut MAIN_THREAD: poplar::tls::ThreadControlBlock = poplar::tls::ThreadControlBlock::new();
     unsafe {
-        main(0, core::ptr::null());
+        poplar::tls::ThreadControlBlock::install(&raw mut MAIN_THREAD);
     }
 
-    poplar::syscall::early_log("Returned from main. Looping.").unwrap();
-    loop {
-        poplar::syscall::yield_to_kernel();
-        // TODO: actually this should call an exit system call or something
-    }
+    let exit_code = unsafe { main(0, core::ptr::null()) };
+    poplar::syscall::exit_task(exit_code as usize)
 }
 
 #[lang = "start"]
@@ -146,7 +147,8 @@ pub fn handle_panic(info: &PanicInfo) -> ! {
     }
     let _ = poplar::syscall::early_log(buffer.as_str());
 
-    loop {}
+    // Exit with the same code as Rust programs on other platforms do when they panic
+    poplar::syscall::exit_task(101)
 }
 
 const PANIC_BUFFER_LEN: usize = 256;
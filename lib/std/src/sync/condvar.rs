@@ -0,0 +1,91 @@
+use super::MutexGuard;
+use core::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+use poplar::syscall::{self, FutexWaitError};
+
+/// A condition variable, which allows threads to sleep until another thread notifies them that some condition
+/// (protected by a `Mutex`) may have changed.
+///
+/// The condition variable holds a counter that is bumped on every notification. Waiters sleep on it as a futex,
+/// so a notification that happens between a waiter unlocking its mutex and sleeping changes the counter, and the
+/// waiter doesn't sleep at all.
+pub struct Condvar {
+    counter: AtomicU32,
+}
+
+/// Whether a wait on a `Condvar` with a timeout returned because the timeout expired.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct WaitTimeoutResult(bool);
+
+impl WaitTimeoutResult {
+    pub fn timed_out(&self) -> bool {
+        self.0
+    }
+}
+
+impl Condvar {
+    pub const fn new() -> Condvar {
+        Condvar { counter: AtomicU32::new(0) }
+    }
+
+    /// Unlock the mutex held by `guard` and sleep until this condition variable is notified, and then lock the
+    /// mutex again. Wakes can be spurious, so the caller should check its condition again when this returns (or
+    /// use `wait_while`).
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        self.wait_optional_timeout(guard, None).0
+    }
+
+    /// Sleep on this condition variable, like `wait`, until `condition` returns `false`.
+    pub fn wait_while<'a, T, F>(&self, mut guard: MutexGuard<'a, T>, mut condition: F) -> MutexGuard<'a, T>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Sleep on this condition variable, like `wait`, but give up once `timeout` has passed.
+    pub fn wait_timeout<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Duration,
+    ) -> (MutexGuard<'a, T>, WaitTimeoutResult) {
+        self.wait_optional_timeout(guard, Some(timeout))
+    }
+
+    /// Wake one of the threads waiting on this condition variable, if there are any.
+    pub fn notify_one(&self) {
+        self.counter.fetch_add(1, Ordering::Relaxed);
+        syscall::futex_wake(&self.counter, 1).unwrap();
+    }
+
+    /// Wake all of the threads waiting on this condition variable.
+    pub fn notify_all(&self) {
+        self.counter.fetch_add(1, Ordering::Relaxed);
+        syscall::futex_wake(&self.counter, usize::MAX).unwrap();
+    }
+
+    fn wait_optional_timeout<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Option<Duration>,
+    ) -> (MutexGuard<'a, T>, WaitTimeoutResult) {
+        let mutex = guard.mutex;
+        let counter = self.counter.load(Ordering::Relaxed);
+        drop(guard);
+
+        let timed_out =
+            matches!(syscall::futex_wait(&self.counter, counter, timeout), Err(FutexWaitError::TimedOut));
+        (mutex.lock(), WaitTimeoutResult(timed_out))
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Condvar {
+        Condvar::new()
+    }
+}
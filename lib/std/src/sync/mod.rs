@@ -0,0 +1,16 @@
+//! Synchronisation primitives. As well as the types from `alloc`, this provides a `Mutex`, `Condvar`, and
+//! `RwLock` that block on the kernel's futexes when they're contended, instead of spinning.
+//!
+//! Unlike real `std`, these locks are never poisoned - a panic exits the panicking thread without unwinding, so
+//! any locks it holds are never released, and no other thread can observe the data it was in the middle of
+//! changing. Locking them therefore returns the guard directly.
+
+mod condvar;
+mod mutex;
+mod rwlock;
+
+pub use alloc_crate::sync::*;
+pub use condvar::{Condvar, WaitTimeoutResult};
+pub use core::sync::atomic;
+pub use mutex::{Mutex, MutexGuard};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
@@ -0,0 +1,202 @@
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU32, Ordering},
+};
+use poplar::syscall;
+
+/*
+ * The lock's state holds the number of readers in its lower bits, or `WRITE_LOCKED` if it's held by a writer. The
+ * `WAITERS` bit is set when threads may be sleeping on the state, so they need to be woken when the lock is
+ * released. All of them are woken, and race to take the lock again.
+ */
+const MASK: u32 = (1 << 30) - 1;
+const WRITE_LOCKED: u32 = MASK;
+const MAX_READERS: u32 = MASK - 1;
+const WAITERS: u32 = 1 << 30;
+
+/// A reader-writer lock, which allows any number of readers or a single writer to hold it at once. Like `Mutex`,
+/// threads that can't take the lock sleep on a futex until it's released. Readers and writers are not queued
+/// fairly, so a steady stream of readers can keep a writer waiting.
+pub struct RwLock<T: ?Sized> {
+    state: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> RwLock<T> {
+        RwLock { state: AtomicU32::new(0), data: UnsafeCell::new(value) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Take the lock for reading, blocking the calling thread until no writer holds it.
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state & MASK < MAX_READERS {
+                if self.state.compare_exchange_weak(state, state + 1, Ordering::Acquire, Ordering::Relaxed).is_ok()
+                {
+                    return RwLockReadGuard { lock: self };
+                }
+                continue;
+            }
+            self.wait(state);
+        }
+    }
+
+    /// Take the lock for reading if no writer holds it, without blocking.
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.state
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |state| {
+                if state & MASK < MAX_READERS {
+                    Some(state + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()
+            .map(|_| RwLockReadGuard { lock: self })
+    }
+
+    /// Take the lock for writing, blocking the calling thread until no other thread holds it.
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state & MASK == 0 {
+                if self
+                    .state
+                    .compare_exchange_weak(state, state | WRITE_LOCKED, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    return RwLockWriteGuard { lock: self };
+                }
+                continue;
+            }
+            self.wait(state);
+        }
+    }
+
+    /// Take the lock for writing if no other thread holds it, without blocking.
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.state
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |state| {
+                if state & MASK == 0 {
+                    Some(state | WRITE_LOCKED)
+                } else {
+                    None
+                }
+            })
+            .ok()
+            .map(|_| RwLockWriteGuard { lock: self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// Sleep until the lock's state changes from `state`, after marking that there are threads waiting.
+    fn wait(&self, state: u32) {
+        if state & WAITERS == 0
+            && self.state.compare_exchange(state, state | WAITERS, Ordering::Relaxed, Ordering::Relaxed).is_err()
+        {
+            return;
+        }
+        let _ = syscall::futex_wait(&self.state, state | WAITERS, None);
+    }
+
+    fn wake_all(&self) {
+        syscall::futex_wake(&self.state, usize::MAX).unwrap();
+    }
+
+    unsafe fn read_unlock(&self) {
+        let state = self.state.fetch_sub(1, Ordering::Release) - 1;
+        // If we were the last reader and there are threads waiting, wake them
+        if state == WAITERS
+            && self.state.compare_exchange(WAITERS, 0, Ordering::Relaxed, Ordering::Relaxed).is_ok()
+        {
+            self.wake_all();
+        }
+    }
+
+    unsafe fn write_unlock(&self) {
+        if self.state.swap(0, Ordering::Release) & WAITERS != 0 {
+            self.wake_all();
+        }
+    }
+}
+
+impl<T: ?Sized + Default> Default for RwLock<T> {
+    fn default() -> RwLock<T> {
+        RwLock::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_read() {
+            Some(guard) => f.debug_struct("RwLock").field("data", &&*guard).finish(),
+            None => f.debug_struct("RwLock").field("data", &format_args!("<locked>")).finish(),
+        }
+    }
+}
+
+/// Provides shared access to the data protected by a `RwLock`. The lock is released when this is dropped.
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for RwLockReadGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            self.lock.read_unlock();
+        }
+    }
+}
+
+/// Provides exclusive access to the data protected by a `RwLock`. The lock is released when this is dropped.
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for RwLockWriteGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            self.lock.write_unlock();
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! Threads, which run in the same address space as the thread that spawns them and share its handles. Each
+//! thread is a separate kernel `Task`, so threads are scheduled (and can block) independently of each other.
+//!
+//! Threads exit independently, too - returning from `main` (or panicking) only exits the calling thread, and
+//! any other threads carry on running.
+
+use crate::sync::{Arc, Mutex};
+use core::any::Any;
+use poplar::{
+    Handle,
+    syscall::{self, WaitForEventError},
+    tls::ThreadControlBlock,
+};
+
+/// The result of joining a thread. If the thread did not return normally (e.g. because it panicked, or caused a
+/// fault), the error holds its `ExitStatus`.
+pub type Result<T> = core::result::Result<T, Box<dyn Any + Send + 'static>>;
+
+/// Spawn a new thread that runs `f`. The returned `JoinHandle` can be used to wait for the thread to finish and
+/// get the value `f` returned. If the handle is dropped, the thread carries on running, detached.
+///
+/// Threads are given a fixed-size stack by the kernel, so they should avoid deep recursion and large stack
+/// allocations. Panics if the thread can't be created.
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let packet = Arc::new(Mutex::new(None));
+    let their_packet = packet.clone();
+    let main = Box::new(move || {
+        let result = f();
+        *their_packet.lock() = Some(result);
+    });
+
+    let mut tcb = Box::new(ThreadControlBlock::new());
+    let thread_pointer = unsafe { ThreadControlBlock::prepare(&mut *tcb) };
+    let start = Box::into_raw(Box::new(ThreadStart { main, tcb }));
+
+    match syscall::spawn_thread(thread_start, start as usize, thread_pointer) {
+        Ok(task) => JoinHandle { task, packet },
+        Err(err) => {
+            drop(unsafe { Box::from_raw(start) });
+            panic!("Failed to spawn thread: {:?}", err);
+        }
+    }
+}
+
+/// Yield the rest of the calling thread's timeslice to other threads.
+pub fn yield_now() {
+    syscall::yield_to_kernel();
+}
+
+/// An owned permission to join a thread, which waits for it to finish.
+pub struct JoinHandle<T> {
+    task: Handle,
+    packet: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Wait for the thread to finish, returning the value its closure returned.
+    pub fn join(self) -> Result<T> {
+        syscall::wait_for_event(self.task, true).expect("Failed to wait for thread to exit");
+        let status = syscall::get_exit_status(self.task).expect("Failed to get exit status of thread");
+
+        match self.packet.lock().take() {
+            Some(result) => Ok(result),
+            None => Err(Box::new(status)),
+        }
+    }
+
+    /// Check whether the thread has finished running, without blocking.
+    pub fn is_finished(&self) -> bool {
+        match syscall::wait_for_event(self.task, false) {
+            Ok(()) => true,
+            Err(WaitForEventError::NoEvent) => false,
+            Err(other) => panic!("Failed to check if thread has exited: {:?}", other),
+        }
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        let _ = syscall::close_handle(self.task);
+    }
+}
+
+struct ThreadStart {
+    main: Box<dyn FnOnce() + Send>,
+    /// The new thread's control block. This is boxed so it stays in the same place when the `ThreadStart` is
+    /// moved.
+    tcb: Box<ThreadControlBlock>,
+}
+
+/// The entry point of new threads. The kernel starts them with a pointer to their `ThreadStart` as the argument.
+#[cfg(target_arch = "x86_64")]
+#[unsafe(naked)]
+extern "C" fn thread_start(_start: usize) -> ! {
+    core::arch::naked_asm!("jmp {}", sym thread_entry)
+}
+
+/// The entry point of new threads. The kernel starts them with a pointer to their `ThreadStart` as the argument.
+/// The kernel doesn't set up `gp` for us, so we do that first, as in `_start`.
+#[cfg(target_arch = "riscv64")]
+#[unsafe(naked)]
+extern "C" fn thread_start(_start: usize) -> ! {
+    core::arch::naked_asm!(
+        "
+        .option push
+        .option norelax
+        lla gp, __global_pointer$
+        .option pop
+        j {}
+        ",
+        sym thread_entry
+    )
+}
+
+extern "C" fn thread_entry(start: usize) -> ! {
+    let ThreadStart { main, tcb } = *unsafe { Box::from_raw(start as *mut ThreadStart) };
+    main();
+
+    // Nothing can use the control block once the thread's closure has returned, so it can be freed
+    drop(tcb);
+    syscall::exit_task(0)
+}
@@ -16,6 +16,34 @@ pub enum EndpointDirection {
     Out,
 }
 
+/// The speed a device is operating at. This is advertised by bus drivers as the integer property
+/// `usb.speed`, and affects how some fields of a device's descriptors should be interpreted.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Speed {
+    Low,
+    Full,
+    High,
+}
+
+impl Speed {
+    pub fn as_property(self) -> u64 {
+        match self {
+            Speed::Low => 0,
+            Speed::Full => 1,
+            Speed::High => 2,
+        }
+    }
+
+    pub fn from_property(value: u64) -> Option<Speed> {
+        match value {
+            0 => Some(Speed::Low),
+            1 => Some(Speed::Full),
+            2 => Some(Speed::High),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum DeviceControlMessage {
     UseConfiguration(u8),
@@ -86,6 +86,8 @@ pub struct MemoryEntry {
 }
 
 pub const LOADED_IMAGE_MAX_SEGMENTS: usize = 3;
+/// The maximum length of the encoded capabilities of a loaded image, in bytes.
+pub const LOADED_IMAGE_MAX_CAPABILITIES: usize = 32;
 
 #[derive(Clone, Copy, Debug)]
 #[repr(C)]
@@ -93,9 +95,13 @@ pub struct LoadedImage {
     pub name_offset: u16,
     pub name_len: u16,
     pub num_segments: u16,
-    pub _reserved0: u16,
+    /// The number of bytes of `capabilities` that are used.
+    pub capabilities_len: u16,
     pub segments: [LoadedSegment; LOADED_IMAGE_MAX_SEGMENTS],
     pub entry_point: u64,
+    /// The image's capabilities, in the encoded form found in its `PT_NOTE` segment. These are not decoded by
+    /// the loader.
+    pub capabilities: [u8; LOADED_IMAGE_MAX_CAPABILITIES],
 }
 
 #[derive(Clone, Copy, Default, Debug)]
@@ -265,9 +265,10 @@ pub fn seed_main(hart_id: u64, fdt_ptr: *const u8) -> ! {
             name_offset,
             name_len: name.len() as u16,
             num_segments: info.num_segments as u16,
-            _reserved0: 0,
+            capabilities_len: info.capabilities_len,
             segments: info.segments,
             entry_point: usize::from(info.entry_point) as u64,
+            capabilities: info.capabilities,
         };
         unsafe {
             boot_info_area.write(info);
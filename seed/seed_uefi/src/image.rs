@@ -105,6 +105,8 @@ pub struct LoadedImageInfo {
     pub entry_point: VAddr,
     pub num_segments: u16,
     pub segments: [seed_bootinfo::LoadedSegment; seed_bootinfo::LOADED_IMAGE_MAX_SEGMENTS],
+    pub capabilities_len: u16,
+    pub capabilities: [u8; seed_bootinfo::LOADED_IMAGE_MAX_CAPABILITIES],
 }
 
 pub fn load_image(
@@ -125,6 +127,8 @@ pub fn load_image(
     let entry_point = VAddr::new(elf.entry_point());
     let mut segments = [seed_bootinfo::LoadedSegment::default(); seed_bootinfo::LOADED_IMAGE_MAX_SEGMENTS];
     let mut num_segments = 0;
+    let mut capabilities = [0u8; seed_bootinfo::LOADED_IMAGE_MAX_CAPABILITIES];
+    let mut capabilities_len = 0;
     for segment in elf.segments() {
         match segment.segment_type() {
             SegmentType::Load if segment.mem_size > 0 => {
@@ -141,11 +145,35 @@ pub fn load_image(
                 segments[num_segments] = segment;
                 num_segments += 1;
             }
+            SegmentType::Note => {
+                /*
+                 * The image's capabilities are encoded in a note entry owned by `POPLAR`. We don't decode them
+                 * here - they're passed on to the kernel as-is.
+                 */
+                for entry in segment.iterate_note_entries(&elf).unwrap() {
+                    let owner = entry.name.strip_suffix(&[0]).unwrap_or(entry.name);
+                    if owner != b"POPLAR" || entry.entry_type != 0 {
+                        continue;
+                    }
+
+                    if entry.desc.len() > seed_bootinfo::LOADED_IMAGE_MAX_CAPABILITIES {
+                        panic!("Loaded image '{}' has too many capabilities!", name);
+                    }
+                    capabilities[0..entry.desc.len()].copy_from_slice(entry.desc);
+                    capabilities_len = entry.desc.len();
+                }
+            }
             _ => (),
         }
     }
 
-    LoadedImageInfo { entry_point, num_segments: num_segments as u16, segments }
+    LoadedImageInfo {
+        entry_point,
+        num_segments: num_segments as u16,
+        segments,
+        capabilities_len: capabilities_len as u16,
+        capabilities,
+    }
 }
 
 fn load_segment(segment: ProgramHeader, elf: &Elf) -> seed_bootinfo::LoadedSegment {
@@ -151,9 +151,10 @@ fn main() -> Status {
             name_offset,
             name_len: name.len() as u16,
             num_segments: info.num_segments as u16,
-            _reserved0: 0,
+            capabilities_len: info.capabilities_len,
             segments: info.segments,
             entry_point: usize::from(info.entry_point) as u64,
+            capabilities: info.capabilities,
         };
         unsafe {
             boot_info_area.write(info);
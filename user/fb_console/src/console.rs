@@ -4,7 +4,14 @@ use ginkgo::{
     vm::{Value, Vm},
 };
 use service_host::ServiceHostClient;
-use std::{fmt::Write, poplar::channel::Channel};
+use std::{
+    fmt::Write,
+    poplar::{
+        channel::Channel,
+        syscall::{self, ObjectDetails},
+        Handle,
+    },
+};
 
 const GINKGO_PRELUDE: &'static str = include_str!("prelude.ginkgo");
 
@@ -46,8 +53,7 @@ impl Console {
                 // TODO: think about how the Ginkgo VM should interact with async stuff. Blocking
                 // til the pbus replies to us is not fantastic. We should probably have some sort
                 // of worker system to delegate these things to / utilise the userspace runtime?
-                platform_bus_inspect.send(&()).unwrap();
-                let reply = platform_bus_inspect.receive_blocking().unwrap();
+                let reply = platform_bus_inspect.call_blocking(&()).unwrap();
 
                 for device in &reply.devices {
                     writeln!(&mut writer, "Device: {}", device.name).unwrap();
@@ -60,6 +66,42 @@ impl Console {
             });
         }
 
+        {
+            let writer = writer.clone();
+            vm.define_native_fn("handles", move |args| {
+                let mut writer = writer.clone();
+                assert!(args.len() == 0);
+
+                /*
+                 * There isn't a way to list the handles a task holds, but the kernel allocates handle values in
+                 * increasing order, so we probe them until we've seen a long enough run that doesn't exist.
+                 */
+                const MAX_MISSING_RUN: u32 = 64;
+                let mut missing = 0;
+                let mut handle = 1;
+                while missing < MAX_MISSING_RUN {
+                    match syscall::object_info(Handle(handle)) {
+                        Ok(info) => {
+                            missing = 0;
+                            writeln!(
+                                &mut writer,
+                                "Handle {}: {:?} (id = {}, owner = {})",
+                                handle, info.typ, info.id, info.owner
+                            )
+                            .unwrap();
+                            if info.details != ObjectDetails::None {
+                                writeln!(&mut writer, "    {:?}", info.details).unwrap();
+                            }
+                        }
+                        Err(_) => missing += 1,
+                    }
+                    handle += 1;
+                }
+
+                Value::Unit
+            });
+        }
+
         Console { vm, writer }
     }
 
@@ -70,7 +70,7 @@ fn spawn_framebuffer(
     std::poplar::rt::spawn(async move {
         writeln!(writer, "Welcome to Poplar!").unwrap();
         write!(writer, "> ").unwrap();
-        control_channel.send(&()).unwrap();
+        control_channel.send_blocking(&()).unwrap();
 
         let mut current_line = String::new();
 
@@ -128,12 +128,14 @@ fn spawn_framebuffer(
             if needs_redraw {
                 // TODO: this obvs won't remove the old cursor - we need a proper thing for that...
                 writer.gfx.lock().framebuffer.draw_rect(mouse_x as usize, mouse_y as usize, 4, 4, 0xffff00ff);
-                control_channel.send(&()).unwrap();
+                control_channel.send_blocking(&()).unwrap();
             }
         }
     });
 }
 
+std::poplar::capabilities!(CAP_EARLY_LOGGING, CAP_SERVICE_USER);
+
 fn main() {
     log::set_logger(&EarlyLogger).unwrap();
     log::set_max_level(log::LevelFilter::Trace);
@@ -151,7 +153,7 @@ fn main() {
         let platform_bus_device_channel: Channel<DeviceDriverMessage, DeviceDriverRequest> =
             service_host_client.subscribe_service("platform_bus.device_driver").unwrap();
         platform_bus_device_channel
-            .send(&DeviceDriverMessage::RegisterInterest(vec![
+            .send_blocking(&DeviceDriverMessage::RegisterInterest(vec![
                 Filter::Matches(String::from("type"), Property::String("framebuffer".to_string())),
                 Filter::Matches(String::from("hid.type"), Property::String("keyboard".to_string())),
                 Filter::Matches(String::from("hid.type"), Property::String("mouse".to_string())),
@@ -162,7 +164,9 @@ fn main() {
             let message = platform_bus_device_channel.receive().await.unwrap();
             match message {
                 DeviceDriverRequest::QuerySupport(name, _) => {
-                    platform_bus_device_channel.send(&DeviceDriverMessage::CanSupport(name, true)).unwrap();
+                    platform_bus_device_channel
+                        .send_blocking(&DeviceDriverMessage::CanSupport(name, true))
+                        .unwrap();
                 }
                 DeviceDriverRequest::HandoffDevice(name, device_info, handoff_info) => {
                     if let Some("framebuffer") = device_info.get_as_str("type") {
@@ -183,8 +187,7 @@ fn main() {
                             Channel::new_from_handle(handoff_info.get_as_channel("channel").unwrap());
 
                         // Map the framebuffer into our address space
-                        const FRAMEBUFFER_ADDDRESS: usize = 0x00000005_00000000;
-                        let framebuffer = unsafe { framebuffer.map_at(FRAMEBUFFER_ADDDRESS).unwrap() };
+                        let framebuffer = unsafe { framebuffer.map().unwrap() };
 
                         spawn_framebuffer(
                             framebuffer,
@@ -1,5 +1,7 @@
 use service_host::ServiceHostClient;
 
+std::poplar::capabilities!(CAP_EARLY_LOGGING, CAP_SERVICE_PROVIDER, CAP_SERVICE_USER);
+
 fn main() {
     std::poplar::syscall::early_log("Hello, World!").unwrap();
     // println!("Hello, world!");
@@ -18,12 +18,8 @@
 
 pub mod input;
 
-use poplar::interrupt::Interrupt;
 use ptah::{Deserialize, Serialize};
-use std::{
-    collections::BTreeMap,
-    poplar::{event::Event, Handle},
-};
+use std::{collections::BTreeMap, poplar::Handle};
 
 type DeviceName = String;
 type PropertyName = String;
@@ -73,11 +69,11 @@ impl HandoffInfo {
         self.0.get(name)?.as_memory_object()
     }
 
-    pub fn get_as_event(&self, name: &str) -> Option<Event> {
+    pub fn get_as_event(&self, name: &str) -> Option<Handle> {
         self.0.get(name)?.as_event()
     }
 
-    pub fn get_as_interrupt(&self, name: &str) -> Option<Interrupt> {
+    pub fn get_as_interrupt(&self, name: &str) -> Option<Handle> {
         self.0.get(name)?.as_interrupt()
     }
 
@@ -172,16 +168,16 @@ impl HandoffProperty {
         }
     }
 
-    pub fn as_event(&self) -> Option<Event> {
+    pub fn as_event(&self) -> Option<Handle> {
         match self {
-            HandoffProperty::Event(value) => Some(Event::new_from_handle(*value)),
+            HandoffProperty::Event(value) => Some(*value),
             _ => None,
         }
     }
 
-    pub fn as_interrupt(&self) -> Option<Interrupt> {
+    pub fn as_interrupt(&self) -> Option<Handle> {
         match self {
-            HandoffProperty::Interrupt(value) => Some(Interrupt::new_from_handle(*value)),
+            HandoffProperty::Interrupt(value) => Some(*value),
             _ => None,
         }
     }
@@ -16,7 +16,11 @@ use spinning_top::RwSpinlock;
 use std::{
     collections::BTreeMap,
     mem,
-    poplar::{channel::Channel, early_logger::EarlyLogger},
+    poplar::{
+        channel::{Channel, ChannelReceiveError},
+        early_logger::EarlyLogger,
+        syscall::GetMessageError,
+    },
     sync::Arc,
 };
 
@@ -131,7 +135,10 @@ impl PlatformBus {
                         Device::Unclaimed { device_info, .. } => {
                             device_driver
                                 .channel
-                                .send(&DeviceDriverRequest::QuerySupport(name.clone(), device_info.clone()))
+                                .send_blocking(&DeviceDriverRequest::QuerySupport(
+                                    name.clone(),
+                                    device_info.clone(),
+                                ))
                                 .unwrap();
                         }
                         _ => {}
@@ -176,6 +183,8 @@ impl PlatformBus {
     }
 }
 
+std::poplar::capabilities!(CAP_EARLY_LOGGING, CAP_SERVICE_PROVIDER, CAP_SERVICE_USER, CAP_PCI_BUS_DRIVER);
+
 pub fn main() {
     log::set_logger(&EarlyLogger).unwrap();
     log::set_max_level(log::LevelFilter::Trace);
@@ -218,7 +227,16 @@ pub fn main() {
                             let platform_bus = platform_bus.clone();
                             async move {
                                 loop {
-                                    match channel.receive().await.unwrap() {
+                                    let message = match channel.receive().await {
+                                        Ok(message) => message,
+                                        Err(ChannelReceiveError::ReceiveError(GetMessageError::PeerClosed)) => {
+                                            info!("Bus driver '{}' disconnected from PlatformBus", driver_name);
+                                            break;
+                                        }
+                                        Err(err) => panic!("Error receiving message from bus driver: {:?}", err),
+                                    };
+
+                                    match message {
                                         BusDriverMessage::RegisterDevice(name, device_info, handoff_info) => {
                                             info!(
                                                 "Registering new device from '{}': Device: {:?}, Handoff: {:?} as {}",
@@ -255,7 +273,8 @@ pub fn main() {
                     ServiceChannelMessage::NewClient { name, channel } => {
                         info!("Device driver '{}' subscribed to PlatformBus!", name);
                         let channel = Arc::new(Channel::new_from_handle(channel));
-                        let device_driver_index = platform_bus.register_device_driver(name, channel.clone());
+                        let device_driver_index =
+                            platform_bus.register_device_driver(name.clone(), channel.clone());
 
                         /*
                          * Each new device driver gets a task to listen for newly registered devices.
@@ -263,7 +282,21 @@ pub fn main() {
                         let platform_bus = platform_bus.clone();
                         std::poplar::rt::spawn(async move {
                             loop {
-                                match channel.receive().await.unwrap() {
+                                let message = match channel.receive().await {
+                                    Ok(message) => message,
+                                    Err(ChannelReceiveError::ReceiveError(GetMessageError::PeerClosed)) => {
+                                        info!("Device driver '{}' disconnected from PlatformBus", name);
+                                        /*
+                                         * Stop offering devices to the driver. Devices it has already claimed
+                                         * stay claimed, as we can't know what state it left them in.
+                                         */
+                                        platform_bus.device_drivers.write()[device_driver_index].filters = None;
+                                        break;
+                                    }
+                                    Err(err) => panic!("Error receiving message from device driver: {:?}", err),
+                                };
+
+                                match message {
                                     DeviceDriverMessage::RegisterInterest(filters) => {
                                         info!("Registering interest for devices with filters: {:?}", filters);
                                         {
@@ -317,7 +350,7 @@ pub fn main() {
                                             {
                                                 device_driver
                                                     .channel
-                                                    .send(&DeviceDriverRequest::HandoffDevice(
+                                                    .send_blocking(&DeviceDriverRequest::HandoffDevice(
                                                         device_name,
                                                         device_info.clone(),
                                                         handoff_info,
@@ -349,10 +382,17 @@ pub fn main() {
                             let platform_bus = platform_bus.clone();
                             async move {
                                 loop {
-                                    match channel.receive().await.unwrap() {
-                                        () => {
+                                    match channel.receive_call().await {
+                                        Ok(((), transaction)) => {
                                             let result = platform_bus.inspect();
-                                            channel.send(&result).unwrap();
+                                            channel.reply(transaction, &result).unwrap();
+                                        }
+                                        Err(ChannelReceiveError::ReceiveError(GetMessageError::PeerClosed)) => {
+                                            info!("Inspect client '{}' disconnected from PlatformBus", name);
+                                            break;
+                                        }
+                                        Err(err) => {
+                                            panic!("Error receiving message from inspect client: {:?}", err)
                                         }
                                     }
                                 }
@@ -50,8 +50,8 @@ impl ServiceHostClient {
     // TODO: probs need async and blocking versions of these? (actually it's quite a lot simpler to
     // just allow blocking here I think. Probs what we'll want in the clients anyway.)
     pub fn register_service(&self, name: impl ToString) -> Result<Channel<(), ServiceChannelMessage>, ()> {
-        self.channel.send(&ServiceHostRequest::RegisterService { name: name.to_string() }).unwrap();
-        match self.channel.receive_blocking().unwrap() {
+        let request = ServiceHostRequest::RegisterService { name: name.to_string() };
+        match self.channel.call_blocking(&request).unwrap() {
             ServiceHostResponse::ServiceRegistered(channel) => Ok(Channel::new_from_handle(channel)),
             _ => {
                 panic!("Received incorrect response to RegisterService request");
@@ -64,8 +64,7 @@ impl ServiceHostClient {
         S: Serialize + DeserializeOwned,
         R: Serialize + DeserializeOwned,
     {
-        self.channel.send(&ServiceHostRequest::SubscribeService(name.to_string())).unwrap();
-        match self.channel.receive_blocking().unwrap() {
+        match self.channel.call_blocking(&ServiceHostRequest::SubscribeService(name.to_string())).unwrap() {
             ServiceHostResponse::SubscribedToService(channel) => Ok(Channel::new_from_handle(channel)),
             _ => {
                 panic!("Received incorrect response to SubscribeService request");
@@ -24,17 +24,120 @@ use log::{info, warn};
 use service_host::{ServiceChannelMessage, ServiceHostRequest, ServiceHostResponse};
 use std::{
     collections::btree_map::BTreeMap,
-    poplar::{channel::Channel, early_logger::EarlyLogger, manifest::BootstrapManifest, Handle},
+    poplar::{
+        caps::Capabilities,
+        channel::{Channel, ChannelReceiveError},
+        early_logger::EarlyLogger,
+        manifest::BootstrapManifest,
+        syscall::{self, ExitStatus, GetMessageError, Priority, Signals},
+        Handle,
+    },
 };
 
+/// The number of times a task can crash and be restarted before we give up on it.
+const MAX_RESTARTS: usize = 3;
+
 pub struct Task {
     name: String,
+    entry_point: usize,
+    /// The capabilities the task's image requested. The kernel only lets us grant capabilities we hold ourselves.
+    capabilities: Capabilities,
     address_space: Handle,
+    /// The task's segments, as they were loaded. These are never mapped directly - each instance of the task gets
+    /// copy-on-write clones of them, so it starts with fresh copies of its writable segments.
     segments: Vec<(Handle, usize)>,
     task: Handle,
     task_channel: Channel<ServiceHostResponse, ServiceHostRequest>,
+    restarts: usize,
+}
+
+impl Task {
+    /// Create an `AddressSpace` and map copy-on-write clones of the given segments into it.
+    fn create_address_space(segments: &[(Handle, usize)]) -> Handle {
+        let address_space = syscall::create_address_space().unwrap();
+        for &(memory_object, map_at) in segments {
+            let clone = syscall::clone_memory_object(memory_object).unwrap();
+            unsafe {
+                syscall::map_memory_object(clone, address_space, Some(map_at), 0x0 as *mut _).unwrap();
+            }
+            // The mapping keeps the clone alive, so we don't need to hold a handle to it
+            let _ = syscall::close_handle(clone);
+        }
+        address_space
+    }
+
+    /// Spawn a task in an `AddressSpace` that has already had the task's segments mapped into it.
+    fn spawn(
+        name: &str,
+        entry_point: usize,
+        capabilities: Capabilities,
+        address_space: Handle,
+    ) -> (Handle, Channel<ServiceHostResponse, ServiceHostRequest>) {
+        // Create a channel to communicate with the task through
+        let (task_channel, channel_handle) = Channel::create().unwrap();
+        // Tasks start at `Normal` priority, but drivers can raise themselves to `High` to keep latency down
+        let task =
+            syscall::spawn_task(name, address_space, entry_point, capabilities, Priority::High, &[channel_handle])
+                .unwrap();
+        (task, task_channel)
+    }
+
+    /// Restart a task that has exited. The new instance is spawned into a fresh `AddressSpace`, so it starts with
+    /// its segments in their initial state.
+    fn restart(&mut self) {
+        let _ = syscall::close_handle(self.task);
+        let _ = syscall::close_handle(self.address_space);
+        self.address_space = Task::create_address_space(&self.segments);
+        let (task, task_channel) =
+            Task::spawn(&self.name, self.entry_point, self.capabilities, self.address_space);
+        self.task = task;
+        self.task_channel = task_channel;
+        self.restarts += 1;
+    }
+
+    /// Check if the task has exited, and restart it if it crashed. Returns `false` if the task has stopped
+    /// running and will not be restarted.
+    fn supervise(&mut self) -> bool {
+        if !syscall::poll_interest(self.task).unwrap().contains(Signals::READABLE) {
+            return true;
+        }
+
+        match syscall::get_exit_status(self.task).unwrap() {
+            ExitStatus::Exited(0) => {
+                info!("Task '{}' exited", self.name);
+                false
+            }
+            status => {
+                warn!("Task '{}' crashed: {:?}", self.name, status);
+                if self.restarts >= MAX_RESTARTS {
+                    warn!("Task '{}' has crashed too many times. Not restarting it.", self.name);
+                    return false;
+                }
+
+                info!("Restarting task '{}'", self.name);
+                self.restart();
+                true
+            }
+        }
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        let _ = syscall::close_handle(self.task);
+        let _ = syscall::close_handle(self.address_space);
+    }
 }
 
+std::poplar::capabilities!(
+    CAP_EARLY_LOGGING,
+    CAP_GET_FRAMEBUFFER,
+    CAP_SERVICE_PROVIDER,
+    CAP_SERVICE_USER,
+    CAP_PCI_BUS_DRIVER,
+    CAP_HANDLE_INTERRUPTS
+);
+
 fn main() {
     log::set_logger(&EarlyLogger).unwrap();
     log::set_max_level(log::LevelFilter::Trace);
@@ -53,70 +156,87 @@ fn main() {
 
     for task in &manifest.boot_tasks {
         info!("Spawning task '{}'", task.name);
-        let address_space = std::poplar::syscall::create_address_space().unwrap();
-        let mut segments = Vec::new();
-        for (map_at, memory_object) in &task.segments {
-            let memory_object = Handle(*memory_object);
-            unsafe {
-                std::poplar::syscall::map_memory_object(
-                    memory_object,
-                    address_space,
-                    Some(*map_at),
-                    0x0 as *mut _,
-                )
-                .unwrap();
-            }
-            segments.push((memory_object, *map_at));
-        }
+        let segments: Vec<(Handle, usize)> =
+            task.segments.iter().map(|&(map_at, memory_object)| (Handle(memory_object), map_at)).collect();
+        let address_space = Task::create_address_space(&segments);
+        let capabilities = Capabilities::from_bits_truncate(task.capabilities);
 
-        // Create a channel to communicate with the task through
-        let (task_channel, channel_handle) = Channel::create().unwrap();
-
-        let spawned_task =
-            std::poplar::syscall::spawn_task(&task.name, address_space, task.entry_point, &[channel_handle])
-                .unwrap();
-        tasks.push(Task { name: task.name.clone(), address_space, segments, task: spawned_task, task_channel });
+        let (spawned_task, task_channel) = Task::spawn(&task.name, task.entry_point, capabilities, address_space);
+        tasks.push(Task {
+            name: task.name.clone(),
+            entry_point: task.entry_point,
+            capabilities,
+            address_space,
+            segments,
+            task: spawned_task,
+            task_channel,
+            restarts: 0,
+        });
     }
 
     // Monitor each task's channel for requests
     // TODO: this should probs be async in the future
     loop {
-        std::poplar::syscall::yield_to_kernel();
+        syscall::yield_to_kernel();
+
+        // Check if any tasks have exited, and restart them if they crashed
+        tasks.retain_mut(|task| task.supervise());
+
+        // Forget about services whose providers have closed their end of the service channel
+        services.retain(|name, service_channel| {
+            if service_channel.is_peer_closed() {
+                info!("Service '{}' has been closed by its provider", name);
+                false
+            } else {
+                true
+            }
+        });
+
         for task in &tasks {
-            if let Some(request) = task.task_channel.try_receive().unwrap() {
-                match request {
-                    ServiceHostRequest::RegisterService { name } => {
-                        // TODO: check for service name conflicts and send back an error
-                        info!("Task '{}' registering new service '{}'", task.name, name);
-                        let (service_channel, channel_handle) = Channel::create().unwrap();
-                        task.task_channel.send(&ServiceHostResponse::ServiceRegistered(channel_handle)).unwrap();
-                        services.insert(name, service_channel);
-                    }
-                    ServiceHostRequest::SubscribeService(name) => {
-                        info!("Task '{}' subscribing to service called '{}'", task.name, name);
-                        if let Some(ref service_channel) = services.get(&name) {
-                            let (channel_a, channel_b) = std::poplar::syscall::create_channel().unwrap();
-                            service_channel
-                                .send(&ServiceChannelMessage::NewClient {
-                                    name: task.name.clone(),
-                                    channel: channel_a,
-                                })
-                                .unwrap();
-                            task.task_channel.send(&ServiceHostResponse::SubscribedToService(channel_b)).unwrap();
-                        } else {
-                            /*
-                             * Now there's more to service registration, we probs need to actually
-                             * handle this... I wonder if we should keep a list of 'waiting' tasks
-                             * that want access to a service, and check it when a new service is
-                             * registered. We defo can't just ignore it (but this should be
-                             * customizable behaviour. Some clients might just want to check if a
-                             * service is available, but not block on it becoming available).
-                             */
-                            warn!("Tried to subscribe to service but it has not been registered!");
-                        }
+            let (request, transaction) = match task.task_channel.try_receive_call() {
+                Ok(Some(call)) => call,
+                Ok(None) => continue,
+                // The task has exited. This is handled when it is next supervised.
+                Err(ChannelReceiveError::ReceiveError(GetMessageError::PeerClosed)) => continue,
+                Err(err) => panic!("Failed to receive request from task '{}': {:?}", task.name, err),
+            };
+
+            match request {
+                ServiceHostRequest::RegisterService { name } => {
+                    // TODO: check for service name conflicts and send back an error
+                    info!("Task '{}' registering new service '{}'", task.name, name);
+                    let (service_channel, channel_handle) = Channel::create().unwrap();
+                    task.task_channel
+                        .reply(transaction, &ServiceHostResponse::ServiceRegistered(channel_handle))
+                        .unwrap();
+                    services.insert(name, service_channel);
+                }
+                ServiceHostRequest::SubscribeService(name) => {
+                    info!("Task '{}' subscribing to service called '{}'", task.name, name);
+                    if let Some(ref service_channel) = services.get(&name) {
+                        let (channel_a, channel_b) = syscall::create_channel().unwrap();
+                        service_channel
+                            .send_blocking(&ServiceChannelMessage::NewClient {
+                                name: task.name.clone(),
+                                channel: channel_a,
+                            })
+                            .unwrap();
+                        task.task_channel
+                            .reply(transaction, &ServiceHostResponse::SubscribedToService(channel_b))
+                            .unwrap();
+                    } else {
+                        /*
+                         * Now there's more to service registration, we probs need to actually
+                         * handle this... I wonder if we should keep a list of 'waiting' tasks
+                         * that want access to a service, and check it when a new service is
+                         * registered. We defo can't just ignore it (but this should be
+                         * customizable behaviour. Some clients might just want to check if a
+                         * service is available, but not block on it becoming available).
+                         */
+                        warn!("Tried to subscribe to service but it has not been registered!");
                     }
-                    ServiceHostRequest::RequestResource(name) => todo!(),
                 }
+                ServiceHostRequest::RequestResource(name) => todo!(),
             }
         }
     }
@@ -9,6 +9,8 @@ use std::{
     },
 };
 
+std::poplar::capabilities!(CAP_EARLY_LOGGING, CAP_GET_FRAMEBUFFER, CAP_SERVICE_USER);
+
 pub fn main() {
     log::set_logger(&EarlyLogger).unwrap();
     log::set_max_level(log::LevelFilter::Trace);
@@ -32,12 +34,6 @@ pub fn main() {
 }
 
 fn make_framebuffer() -> Framebuffer {
-    /*
-     * This is the virtual address the framebuffer will be mapped to in our address space.
-     * NOTE: this address was basically pulled out of thin air.
-     */
-    const FRAMEBUFFER_ADDRESS: usize = 0x00000005_00000000;
-
     let (framebuffer_handle, framebuffer_info) = {
         let mut framebuffer_info: MaybeUninit<FramebufferInfo> = MaybeUninit::uninit();
 
@@ -47,14 +43,16 @@ fn make_framebuffer() -> Framebuffer {
         (framebuffer_handle, unsafe { framebuffer_info.assume_init() })
     };
 
+    // Let the kernel choose where the framebuffer is mapped in our address space
+    let mut framebuffer_address = 0usize;
     unsafe {
-        syscall::map_memory_object(framebuffer_handle, Handle::ZERO, Some(FRAMEBUFFER_ADDRESS), 0x0 as *mut _)
+        syscall::map_memory_object(framebuffer_handle, Handle::ZERO, None, &mut framebuffer_address as *mut usize)
             .unwrap();
     }
     assert_eq!(framebuffer_info.pixel_format, PixelFormat::Bgr32);
 
     Framebuffer::new(
-        FRAMEBUFFER_ADDRESS as *mut u32,
+        framebuffer_address as *mut u32,
         framebuffer_info.width as usize,
         framebuffer_info.height as usize,
         framebuffer_info.stride as usize,
@@ -20,12 +20,14 @@ use std::{
         syscall::MemoryObjectFlags,
     },
     sync::Arc,
+    time::Duration,
 };
 use usb::{
     descriptor::{ConfigurationDescriptor, DescriptorType, DeviceDescriptor},
     setup::{Direction, Recipient, Request, RequestType, RequestTypeType, SetupPacket},
     DeviceControlMessage,
     DeviceResponse,
+    Speed,
 };
 
 pub struct Controller {
@@ -52,14 +54,8 @@ impl Controller {
         let caps = Capabilities::read_from_registers(register_base);
         info!("Capabilites: {:#?}", caps);
 
-        // TODO: once we have kernel virtual address space management, just let it find an address
-        // for us
-        const SCHEDULE_POOL_ADDRESS: usize = 0x00000005_10000000;
         let schedule_pool = RwSpinlock::new(DmaPool::new(unsafe {
-            MemoryObject::create_physical(0x1000, MemoryObjectFlags::WRITABLE)
-                .unwrap()
-                .map_at(SCHEDULE_POOL_ADDRESS)
-                .unwrap()
+            MemoryObject::create_physical(0x1000, MemoryObjectFlags::WRITABLE).unwrap().map().unwrap()
         }));
 
         let registers = RwSpinlock::new(RegisterBlock::new(register_base, caps.cap_length));
@@ -236,7 +232,7 @@ impl Controller {
     }
 
     pub async fn handle_device_connect(&self, port: u8) -> Option<Arc<RwSpinlock<ActiveDevice>>> {
-        self.reset_port(port);
+        self.reset_port(port).await;
 
         unsafe {
             if self.registers.read().read_port_register(port).get(PortStatusControl::PORT_ENABLED) {
@@ -403,6 +399,8 @@ impl Controller {
             properties.insert("usb.class".to_string(), Property::Integer(descriptor.class as u64));
             properties.insert("usb.sub_class".to_string(), Property::Integer(descriptor.sub_class as u64));
             properties.insert("usb.protocol".to_string(), Property::Integer(descriptor.protocol as u64));
+            // EHCI only drives High-Speed devices - others are handed off to a companion controller
+            properties.insert("usb.speed".to_string(), Property::Integer(Speed::High.as_property()));
             // TODO: we should probs include all the configurations to choose from no?
             // Maybe need a list, or just to append numbers idk?
             properties.insert("usb.config0".to_string(), Property::Bytes(config0));
@@ -416,7 +414,7 @@ impl Controller {
             HandoffInfo(properties)
         };
         self.platform_bus_bus_channel
-            .send(&BusDriverMessage::RegisterDevice(name, device_info, handoff_info))
+            .send_blocking(&BusDriverMessage::RegisterDevice(name, device_info, handoff_info))
             .unwrap();
 
         let device = Arc::new(RwSpinlock::new(ActiveDevice {
@@ -524,20 +522,30 @@ impl Controller {
         future.await;
     }
 
-    pub fn reset_port(&self, port: u8) {
+    pub async fn reset_port(&self, port: u8) {
+        /*
+         * Reset the port by setting the PortReset bit, and then clearing it once the device has
+         * been held in reset for long enough. The USB spec requires a reset from a root port to
+         * last at least 50ms. We don't hold the registers across the sleep, so other tasks can
+         * use the controller in the meantime.
+         */
         unsafe {
-            let registers = self.registers.write();
-
-            /*
-             * Reset the port by toggling the PortReset bit and then waiting for it to clear.
-             */
-            registers
+            self.registers
+                .write()
                 .write_port_register(port, PortStatusControl::new().with(PortStatusControl::PORT_RESET, true));
-            // TODO: apparently we're meant to time a duration here???? QEMU doesn't complain about
-            // no delay but I bet real ones do
+        }
+        std::poplar::rt::sleep(Duration::from_millis(50)).await;
+        unsafe {
+            let registers = self.registers.write();
             registers.write_port_register(port, PortStatusControl::new());
             while registers.read_port_register(port).get(PortStatusControl::PORT_RESET) {}
         }
+
+        /*
+         * Devices are given 10ms to recover from the reset before they need to respond to
+         * requests.
+         */
+        std::poplar::rt::sleep(Duration::from_millis(10)).await;
     }
 
     pub fn create_queue(&self, device: u8, endpoint: u8, max_packet_size: u16) -> Arc<RwSpinlock<Queue>> {
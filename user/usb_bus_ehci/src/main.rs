@@ -22,6 +22,7 @@ use std::{
     poplar::{
         channel::Channel,
         early_logger::EarlyLogger,
+        interrupt::Interrupt,
         memory_object::MemoryObject,
         syscall::MemoryObjectFlags,
     },
@@ -122,7 +123,7 @@ impl ActiveDevice {
                     .await;
 
                 self.channel
-                    .send(&DeviceResponse::Descriptor { typ, index, bytes: buffer.read().to_vec() })
+                    .send_blocking(&DeviceResponse::Descriptor { typ, index, bytes: buffer.read().to_vec() })
                     .unwrap();
                 Ok(())
             }
@@ -132,13 +133,15 @@ impl ActiveDevice {
 
                 let mut buffer = controller.schedule_pool.write().create_buffer(packet_size as usize).unwrap();
                 controller.do_interrupt_transfer(&endpoint, buffer.token().unwrap(), false).await;
-                self.channel.send(&DeviceResponse::Data(buffer.read().to_vec())).unwrap();
+                self.channel.send_blocking(&DeviceResponse::Data(buffer.read().to_vec())).unwrap();
                 Ok(())
             }
         }
     }
 }
 
+std::poplar::capabilities!(CAP_EARLY_LOGGING, CAP_SERVICE_USER, CAP_HANDLE_INTERRUPTS);
+
 fn main() {
     log::set_logger(&EarlyLogger).unwrap();
     log::set_max_level(log::LevelFilter::Trace);
@@ -156,7 +159,7 @@ fn main() {
 
     // Tell PlatformBus that we're interested in EHCI controllers.
     platform_bus_device_channel
-        .send(&DeviceDriverMessage::RegisterInterest(vec![Filter::All(vec![
+        .send_blocking(&DeviceDriverMessage::RegisterInterest(vec![Filter::All(vec![
             Filter::Matches(String::from("pci.class"), Property::Integer(0x0c)),
             Filter::Matches(String::from("pci.sub_class"), Property::Integer(0x03)),
             Filter::Matches(String::from("pci.interface"), Property::Integer(0x20)),
@@ -172,14 +175,15 @@ fn main() {
                      * Our filters are specific enough that any device that matches should be an
                      * EHCI controller, so we always say we'll support it here.
                      */
-                    platform_bus_device_channel.send(&DeviceDriverMessage::CanSupport(device_name, true)).unwrap();
+                    platform_bus_device_channel
+                        .send_blocking(&DeviceDriverMessage::CanSupport(device_name, true))
+                        .unwrap();
                 }
                 DeviceDriverRequest::HandoffDevice(device_name, device_info, handoff_info) => {
                     info!("Started driving a EHCI controller: {}", device_name);
 
                     let register_space_size = handoff_info.get_as_integer("pci.bar0.size").unwrap() as usize;
 
-                    // TODO: let the kernel choose the address when it can - we don't care
                     // TODO: this trusts the data from the platform_bus. Maybe we shouldn't do that? One
                     // idea would be a syscall for querying info about the object?
                     let register_space = MemoryObject {
@@ -188,15 +192,12 @@ fn main() {
                         flags: MemoryObjectFlags::WRITABLE,
                         phys_address: None,
                     };
-                    const REGISTER_SPACE_ADDRESS: usize = 0x00000005_00000000;
-                    unsafe {
-                        register_space.map_at(REGISTER_SPACE_ADDRESS).unwrap();
-                    }
+                    let register_space = unsafe { register_space.map().unwrap() };
 
                     let controller = Controller::new(
-                        REGISTER_SPACE_ADDRESS,
+                        register_space.mapped_at,
                         platform_bus_bus_channel.clone(),
-                        handoff_info.get_as_interrupt("pci.interrupt").unwrap(),
+                        Interrupt::new_from_handle(handoff_info.get_as_interrupt("pci.interrupt").unwrap()),
                     );
                     controller.initialize();
 
@@ -25,6 +25,8 @@ use std::poplar::{
  * the EHCI driver.
  */
 
+std::poplar::capabilities!(CAP_EARLY_LOGGING, CAP_SERVICE_USER, CAP_HANDLE_INTERRUPTS);
+
 pub fn main() {
     log::set_logger(&EarlyLogger).unwrap();
     log::set_max_level(log::LevelFilter::Trace);
@@ -39,7 +41,7 @@ pub fn main() {
 
     // Tell PlatformBus that we're interested in XHCI controllers.
     platform_bus_device_channel
-        .send(&DeviceDriverMessage::RegisterInterest(vec![Filter::All(vec![
+        .send_blocking(&DeviceDriverMessage::RegisterInterest(vec![Filter::All(vec![
             Filter::Matches(String::from("pci.class"), Property::Integer(0x0c)),
             Filter::Matches(String::from("pci.sub_class"), Property::Integer(0x03)),
             Filter::Matches(String::from("pci.interface"), Property::Integer(0x30)),
@@ -66,17 +68,14 @@ pub fn main() {
         flags: MemoryObjectFlags::WRITABLE,
         phys_address: None,
     };
-    const REGISTER_SPACE_ADDRESS: usize = 0x00000005_00000000;
-    unsafe {
-        register_space.map_at(REGISTER_SPACE_ADDRESS).unwrap();
-    }
+    let register_space = unsafe { register_space.map().unwrap() };
 
-    let capabilities = unsafe { Capabilities::read_from_registers(REGISTER_SPACE_ADDRESS) };
+    let capabilities = unsafe { Capabilities::read_from_registers(register_space.mapped_at) };
     info!("Capabilites: {:#?}", capabilities);
 
     let mut operational = unsafe {
         OperationRegisters::new(
-            REGISTER_SPACE_ADDRESS + usize::from(capabilities.operation_registers_offset),
+            register_space.mapped_at + usize::from(capabilities.operation_registers_offset),
             capabilities.max_ports,
         )
     };
@@ -10,7 +10,6 @@ use std::{
     ptr,
 };
 
-const MEMORY_AREA_VIRTUAL_ADDRESS: usize = 0x50000000;
 // TODO: how large should the command ring be?
 const COMMAND_RING_NUM_ENTRIES: usize = 32;
 const TRB_SIZE: usize = 16;
@@ -69,12 +68,7 @@ impl MemoryArea {
                 bytes_for_device_context_base_address_array + command_ring_head_padding + bytes_for_command_ring;
             let mut physical_address: MaybeUninit<usize> = MaybeUninit::uninit();
 
-            unsafe {
-                MemoryObject::create_physical(size, MemoryObjectFlags::WRITABLE)
-                    .unwrap()
-                    .map_at(MEMORY_AREA_VIRTUAL_ADDRESS)
-                    .unwrap()
-            }
+            unsafe { MemoryObject::create_physical(size, MemoryObjectFlags::WRITABLE).unwrap().map().unwrap() }
         };
         info!("Memory area is at physical address {:#x}", memory_object.inner.phys_address.unwrap());
 
@@ -94,7 +88,7 @@ impl MemoryArea {
     pub fn set_device_context_entry(&mut self, index: u8, address: u64) {
         unsafe {
             ptr::write_volatile(
-                (MEMORY_AREA_VIRTUAL_ADDRESS + usize::from(index) * mem::size_of::<u64>()) as *mut u64,
+                (self.memory_object.mapped_at + usize::from(index) * mem::size_of::<u64>()) as *mut u64,
                 address,
             );
         }
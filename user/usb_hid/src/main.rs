@@ -15,7 +15,13 @@ use platform_bus::{
 use service_host::ServiceHostClient;
 use std::{
     collections::{BTreeMap, BTreeSet},
-    poplar::{channel::Channel, early_logger::EarlyLogger},
+    poplar::{
+        channel::Channel,
+        early_logger::EarlyLogger,
+        syscall::{self, Priority},
+        Handle,
+    },
+    time::Duration,
 };
 use usb::{
     descriptor::{
@@ -32,13 +38,24 @@ use usb::{
     DeviceControlMessage,
     DeviceResponse,
     EndpointDirection,
+    Speed,
 };
 
+/// How long a key must be held down before it starts repeating.
+const KEY_REPEAT_DELAY: Duration = Duration::from_millis(500);
+/// How often a held-down key repeats, once it has started repeating.
+const KEY_REPEAT_INTERVAL: Duration = Duration::from_millis(33);
+
+std::poplar::capabilities!(CAP_EARLY_LOGGING, CAP_SERVICE_USER);
+
 pub fn main() {
     log::set_logger(&EarlyLogger).unwrap();
     log::set_max_level(log::LevelFilter::Trace);
     info!("USB HID Driver is running!");
 
+    // Input events should be handled promptly, even if other tasks are busy
+    syscall::set_priority(Handle::ZERO, Priority::High).unwrap();
+
     std::poplar::rt::init_runtime();
 
     let service_host_client = ServiceHostClient::new();
@@ -53,7 +70,7 @@ pub fn main() {
     // (we need to parse their configurations to tell if they're HID devices). A HID device is not
     // supposed to indicate its class at the device level so we don't need to test for that.
     platform_bus_device_channel
-        .send(&DeviceDriverMessage::RegisterInterest(vec![Filter::All(vec![
+        .send_blocking(&DeviceDriverMessage::RegisterInterest(vec![Filter::All(vec![
             Filter::Matches(String::from("usb.class"), Property::Integer(0x00)),
             Filter::Matches(String::from("usb.sub_class"), Property::Integer(0x00)),
         ])]))
@@ -87,7 +104,7 @@ pub fn main() {
                         visitor.0
                     };
                     platform_bus_device_channel
-                        .send(&DeviceDriverMessage::CanSupport(device_name, supported))
+                        .send_blocking(&DeviceDriverMessage::CanSupport(device_name, supported))
                         .unwrap();
                 }
                 DeviceDriverRequest::HandoffDevice(device_name, device_info, handoff_info) => {
@@ -96,6 +113,10 @@ pub fn main() {
                     let control_channel: Channel<DeviceControlMessage, DeviceResponse> =
                         Channel::new_from_handle(handoff_info.get_as_channel("usb.channel").unwrap());
 
+                    let speed = device_info
+                        .get_as_integer("usb.speed")
+                        .and_then(Speed::from_property)
+                        .unwrap_or(Speed::Full);
                     let config_info = {
                         // TODO: this assumes only one configuration
                         let bytes = device_info.get_as_bytes("usb.config0").unwrap();
@@ -107,6 +128,7 @@ pub fn main() {
                             interface_setting: u8,
                             endpoint_num: u8,
                             packet_size: u16,
+                            interval: u8,
                             hid_report_len: u16,
                         }
                         impl usb::descriptor::ConfigurationVisitor for ConfigInfo {
@@ -123,6 +145,7 @@ pub fn main() {
                             fn visit_endpoint(&mut self, descriptor: &EndpointDescriptor) {
                                 self.endpoint_num = descriptor.endpoint_address.get(EndpointAddress::NUMBER);
                                 self.packet_size = descriptor.max_packet_size;
+                                self.interval = descriptor.interval;
                             }
 
                             fn visit_hid(&mut self, descriptor: &HidDescriptor) {
@@ -165,13 +188,13 @@ pub fn main() {
                         HandoffInfo(info)
                     };
                     platform_bus_bus_channel
-                        .send(&BusDriverMessage::RegisterDevice(name, device_info, handoff_info))
+                        .send_blocking(&BusDriverMessage::RegisterDevice(name, device_info, handoff_info))
                         .unwrap();
 
                     std::poplar::rt::spawn(async move {
                         // Get the report descriptor
                         control_channel
-                            .send(&DeviceControlMessage::GetInterfaceDescriptor {
+                            .send_blocking(&DeviceControlMessage::GetInterfaceDescriptor {
                                 typ: DescriptorType::Report,
                                 index: 0,
                                 length: config_info.hid_report_len,
@@ -194,10 +217,10 @@ pub fn main() {
                         info!("Parsed report descriptor: {:#?}", report_desc);
 
                         control_channel
-                            .send(&DeviceControlMessage::UseConfiguration(config_info.config_value))
+                            .send_blocking(&DeviceControlMessage::UseConfiguration(config_info.config_value))
                             .unwrap();
                         control_channel
-                            .send(&DeviceControlMessage::OpenEndpoint {
+                            .send_blocking(&DeviceControlMessage::OpenEndpoint {
                                 number: config_info.endpoint_num,
                                 direction: EndpointDirection::In,
                                 max_packet_size: config_info.packet_size,
@@ -205,25 +228,25 @@ pub fn main() {
                             .unwrap();
 
                         /*
-                         * This tracks the keys that are currently pressed, and how many polling
-                         * cycles each has been pressed for. This is at the heart of the driver's
-                         * ability to debounce key presses and then re-add key repetition in
-                         * software.
-                         * TODO: this currently just polls as-fast-as-it-can. We probably want to
-                         * not do that, so add timing or move to the periodic schedule and do it
-                         * properly.
+                         * This tracks the keys that are currently pressed, and when each should
+                         * next be repeated. This is at the heart of the driver's ability to
+                         * debounce key presses and then re-add key repetition in software. We also
+                         * track the state of the modifier keys from the last report, so repeated
+                         * keys are reported with it.
+                         * TODO: we poll the endpoint at its interval from the async schedule. We
+                         * should probably move to the periodic schedule and do it properly.
                          * TODO: some drivers debounce keys that are only pressed for e.g. a few
                          * ms. I don't know if that's needed for real hardware, but something to
                          * consider (esp if we ever get spurious key presses).
-                         * TODO: we don't currently do key repetition, as this requires accurate
-                         * timing of each cycle.
                          */
-                        let mut pressed_keys = BTreeMap::<Usage, u8>::new();
+                        let mut pressed_keys = BTreeMap::<Usage, Duration>::new();
+                        let mut key_state = KeyState::default();
+                        let poll_interval = poll_interval(speed, config_info.interval);
 
                         info!("Listening to reports from HID device '{}'", device_name);
                         loop {
                             control_channel
-                                .send(&DeviceControlMessage::InterruptTransferIn {
+                                .send_blocking(&DeviceControlMessage::InterruptTransferIn {
                                     endpoint: config_info.endpoint_num,
                                     packet_size: config_info.packet_size,
                                 })
@@ -251,22 +274,25 @@ pub fn main() {
 
                                             FieldValue::DynamicValue(Usage::X, value) => {
                                                 if value != 0 {
-                                                    device_channel.send(&InputEvent::RelX(value)).unwrap();
+                                                    device_channel.send(&InputEvent::RelX(value)).await.unwrap();
                                                 }
                                             }
                                             FieldValue::DynamicValue(Usage::Y, value) => {
                                                 if value != 0 {
-                                                    device_channel.send(&InputEvent::RelY(value)).unwrap();
+                                                    device_channel.send(&InputEvent::RelY(value)).await.unwrap();
                                                 }
                                             }
                                             FieldValue::DynamicValue(Usage::Z, value) => {
                                                 if value != 0 {
-                                                    device_channel.send(&InputEvent::RelZ(value)).unwrap();
+                                                    device_channel.send(&InputEvent::RelZ(value)).await.unwrap();
                                                 }
                                             }
                                             FieldValue::DynamicValue(Usage::Wheel, value) => {
                                                 if value != 0 {
-                                                    device_channel.send(&InputEvent::RelWheel(value)).unwrap();
+                                                    device_channel
+                                                        .send(&InputEvent::RelWheel(value))
+                                                        .await
+                                                        .unwrap();
                                                 }
                                             }
                                             FieldValue::DynamicValue(
@@ -292,6 +318,7 @@ pub fn main() {
                                                             key: map_button(usage),
                                                             state: KeyState::default(),
                                                         })
+                                                        .await
                                                         .unwrap();
                                                 } else {
                                                     device_channel
@@ -299,6 +326,7 @@ pub fn main() {
                                                             key: map_button(usage),
                                                             state: KeyState::default(),
                                                         })
+                                                        .await
                                                         .unwrap();
                                                 }
                                             }
@@ -337,14 +365,15 @@ pub fn main() {
                                         }
                                     }
 
+                                    let now = syscall::get_time();
                                     pressed_keys = pressed_keys
                                         .into_iter()
-                                        .filter_map(|(usage, count)| {
+                                        .filter_map(|(usage, next_repeat)| {
                                             if current_keys.take(&usage).is_some() {
-                                                Some((usage, count + 1))
+                                                Some((usage, next_repeat))
                                             } else {
                                                 device_channel
-                                                    .send(&InputEvent::KeyReleased {
+                                                    .send_blocking(&InputEvent::KeyReleased {
                                                         key: map_key_usage(usage),
                                                         state,
                                                     })
@@ -354,15 +383,38 @@ pub fn main() {
                                         })
                                         .collect();
                                     for new_key in current_keys.into_iter() {
-                                        pressed_keys.insert(new_key, 1);
+                                        pressed_keys.insert(new_key, now + KEY_REPEAT_DELAY);
                                         device_channel
                                             .send(&InputEvent::KeyPressed { key: map_key_usage(new_key), state })
+                                            .await
                                             .unwrap();
                                     }
+                                    key_state = state;
                                 }
                                 DeviceResponse::NoData => {}
                                 _ => panic!("Unexpected message during report loop"),
                             }
+
+                            /*
+                             * Repeat any keys that have been held down for long enough. This is
+                             * done every cycle, as devices generally only send a report when
+                             * something changes.
+                             */
+                            let now = syscall::get_time();
+                            for (&usage, next_repeat) in pressed_keys.iter_mut() {
+                                if now >= *next_repeat {
+                                    device_channel
+                                        .send(&InputEvent::KeyPressed {
+                                            key: map_key_usage(usage),
+                                            state: key_state,
+                                        })
+                                        .await
+                                        .unwrap();
+                                    *next_repeat = now + KEY_REPEAT_INTERVAL;
+                                }
+                            }
+
+                            std::poplar::rt::sleep(poll_interval).await;
                         }
                     });
                 }
@@ -373,6 +425,19 @@ pub fn main() {
     std::poplar::rt::enter_loop();
 }
 
+/// Work out how often an interrupt endpoint should be polled from its `bInterval`. On High-Speed
+/// devices, this is an exponent: they should be polled every `2^(interval - 1)` microframes, each of
+/// which is 125µs. On Full- and Low-Speed devices, it is simply a number of 1ms frames.
+fn poll_interval(speed: Speed, interval: u8) -> Duration {
+    match speed {
+        Speed::High => {
+            let exponent = interval.clamp(1, 16) - 1;
+            Duration::from_micros(125 << exponent)
+        }
+        Speed::Full | Speed::Low => Duration::from_millis(interval.max(1) as u64),
+    }
+}
+
 fn map_key_usage(usage: Usage) -> Key {
     match usage {
         Usage::KeyA => Key::KeyA,
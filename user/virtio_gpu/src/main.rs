@@ -201,6 +201,8 @@ impl<'a> VirtioGpu<'a> {
     }
 }
 
+std::poplar::capabilities!(CAP_EARLY_LOGGING, CAP_SERVICE_USER, CAP_HANDLE_INTERRUPTS);
+
 fn main() {
     log::set_logger(&EarlyLogger).unwrap();
     log::set_max_level(log::LevelFilter::Trace);
@@ -215,7 +217,7 @@ fn main() {
         service_host_client.subscribe_service("platform_bus.device_driver").unwrap();
 
     platform_bus_device_channel
-        .send(&DeviceDriverMessage::RegisterInterest(vec![Filter::All(vec![
+        .send_blocking(&DeviceDriverMessage::RegisterInterest(vec![Filter::All(vec![
             Filter::Matches(String::from("pci.vendor_id"), Property::Integer(0x1af4)),
             Filter::Matches(String::from("pci.device_id"), Property::Integer(0x1050)),
         ])]))
@@ -224,7 +226,7 @@ fn main() {
     let (device_info, handoff_info) = loop {
         match platform_bus_device_channel.try_receive().unwrap() {
             Some(DeviceDriverRequest::QuerySupport(name, _)) => {
-                platform_bus_device_channel.send(&DeviceDriverMessage::CanSupport(name, true)).unwrap();
+                platform_bus_device_channel.send_blocking(&DeviceDriverMessage::CanSupport(name, true)).unwrap();
             }
             Some(DeviceDriverRequest::HandoffDevice(name, device_info, handoff_info)) => {
                 info!("Started driving device: {}", name);
@@ -243,17 +245,15 @@ fn main() {
             flags: MemoryObjectFlags::WRITABLE,
             phys_address: None,
         };
-        const BAR_SPACE_ADDRESS: usize = 0x00000005_00000000;
-        unsafe { bar.map_at(BAR_SPACE_ADDRESS).unwrap() }
+        unsafe { bar.map().unwrap() }
     };
-    let interrupt = handoff_info.get_as_interrupt("pci.interrupt").unwrap();
+    let interrupt = Interrupt::new_from_handle(handoff_info.get_as_interrupt("pci.interrupt").unwrap());
 
     let memory_manager = VirtioMemoryManager::new();
     let queue = Virtqueue::new(64, &memory_manager);
     let request_pool = {
         let memory_object = unsafe { MemoryObject::create_physical(0x1000, MemoryObjectFlags::WRITABLE).unwrap() };
-        const REQUEST_POOL_ADDRESS: usize = 0x00000005_20000000;
-        let memory_object = unsafe { memory_object.map_at(REQUEST_POOL_ADDRESS).unwrap() };
+        let memory_object = unsafe { memory_object.map().unwrap() };
         DmaPool::new(memory_object)
     };
 
@@ -293,8 +293,7 @@ fn main() {
         let memory_object = unsafe {
             MemoryObject::create_physical(framebuffer_size as usize, MemoryObjectFlags::WRITABLE).unwrap()
         };
-        const FRAMEBUFFER_ADDDRESS: usize = 0x00000005_30000000;
-        unsafe { memory_object.map_at(FRAMEBUFFER_ADDDRESS).unwrap() }
+        unsafe { memory_object.map().unwrap() }
     };
     gpu.attach_backing(framebuffer_resource, framebuffer.inner.phys_address.unwrap() as u64, framebuffer_size);
     gpu.set_scanout(&scanout_info, framebuffer_resource);
@@ -329,7 +328,7 @@ fn main() {
             HandoffInfo(properties)
         };
         platform_bus_bus_channel
-            .send(&BusDriverMessage::RegisterDevice("virtio-fb".to_string(), device_info, handoff_info))
+            .send_blocking(&BusDriverMessage::RegisterDevice("virtio-fb".to_string(), device_info, handoff_info))
             .unwrap();
         control_channel
     };
@@ -355,8 +354,7 @@ pub struct VirtioMemoryManager {
 impl VirtioMemoryManager {
     pub fn new() -> VirtioMemoryManager {
         let memory_object = unsafe { MemoryObject::create_physical(0x1000, MemoryObjectFlags::WRITABLE).unwrap() };
-        const QUEUE_AREA_ADDRESS: usize = 0x00000005_10000000;
-        let memory_object = unsafe { memory_object.map_at(QUEUE_AREA_ADDRESS).unwrap() };
+        let memory_object = unsafe { memory_object.map().unwrap() };
 
         VirtioMemoryManager { area: memory_object, offset: AtomicUsize::new(0) }
     }